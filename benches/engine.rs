@@ -0,0 +1,203 @@
+//! kvslite 性能基准
+//!
+//! 通过公开的 `Db` API 测量：
+//!
+//! - 写入吞吐：`sync_on_write` 开启 / 关闭
+//! - 读取延迟：冷读（无缓存）/ 缓存命中
+//! - 打开时 replay N 条记录的耗时
+//! - 反复覆盖、删除之后的压缩耗时和压缩比
+//!
+//! 运行：`cargo bench`，或 `cargo bench -- get` 只运行名字包含 `get` 的项目。
+//! 依赖离线可用，这里不使用 criterion，而是一个最小的计时框架：每个项目先预热一轮，
+//! 再取多轮中最快的一次，报告 ops/sec。
+
+use kvslite::{Db, Options};
+use std::hint::black_box;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// 每个项目计时的轮数（取最快的一轮）
+const ROUNDS: usize = 5;
+
+/// 每条记录的 value 大小（字节）
+const VALUE_SIZE: usize = 100;
+
+fn main() {
+    // `cargo bench` 会传入 `--bench`，其余参数作为名字过滤条件
+    let filters: Vec<String> = std::env::args().skip(1).filter(|a| !a.starts_with('-')).collect();
+    let selected = |name: &str| filters.is_empty() || filters.iter().any(|f| name.contains(f));
+
+    println!("{:<32} {:>14} {:>14}", "benchmark", "ops/sec", "per op");
+    for (name, n, bench) in benches() {
+        if selected(name) {
+            let elapsed = run(n, bench);
+            report(name, n, elapsed);
+        }
+    }
+}
+
+/// 一个基准项目：在计时之外准备数据，返回的闭包被计时，执行 `n` 次操作
+///
+/// 闭包交还临时目录，计时结束后才删除
+type Bench = fn(usize) -> Box<dyn FnOnce() -> TempDir>;
+
+fn benches() -> Vec<(&'static str, usize, Bench)> {
+    vec![
+        ("put/sync_on_write=true", 200, put_sync),
+        ("put/sync_on_write=false", 50_000, put_nosync),
+        ("get/cold", 20_000, get_cold),
+        ("get/cached", 20_000, get_cached),
+        ("open/replay", 100_000, replay),
+        ("compact/churned", 10_000, compact),
+    ]
+}
+
+/// 预热一轮后计时 `ROUNDS` 轮，返回最快一轮的耗时
+fn run(n: usize, bench: Bench) -> Duration {
+    bench(n)();
+    (0..ROUNDS)
+        .map(|_| {
+            let f = bench(n);
+            let start = Instant::now();
+            let dir = f();
+            let elapsed = start.elapsed();
+            drop(dir);
+            elapsed
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+fn report(name: &str, n: usize, elapsed: Duration) {
+    let ops = n as f64 / elapsed.as_secs_f64();
+    let per_op = elapsed / n as u32;
+    println!("{:<32} {:>14.0} {:>14?}", name, ops, per_op);
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:08}", i).into_bytes()
+}
+
+fn value(i: usize) -> Vec<u8> {
+    let mut value = vec![0u8; VALUE_SIZE];
+    value[..8].copy_from_slice(&(i as u64).to_le_bytes());
+    value
+}
+
+/// 在新的临时目录中打开数据库；目录删除后数据库文件随之消失，调用方要一直持有它
+fn open(opts: Options) -> (TempDir, Db) {
+    let dir = TempDir::new().expect("create temp dir");
+    let db = Db::open(dir.path(), opts).expect("open db");
+    (dir, db)
+}
+
+/// 写入 `n` 个 key（不计时）
+fn fill(db: &mut Db, n: usize) {
+    for i in 0..n {
+        db.put(&key(i), &value(i)).expect("put");
+    }
+}
+
+/// 打乱访问顺序（固定种子，每次运行相同）
+fn shuffled(n: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..n).collect();
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    for i in (1..n).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        order.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+    order
+}
+
+fn put_with(n: usize, sync_on_write: bool) -> Box<dyn FnOnce() -> TempDir> {
+    let (dir, mut db) = open(Options::builder().sync_on_write(sync_on_write).build());
+    Box::new(move || {
+        fill(&mut db, n);
+        db.sync().expect("sync");
+        dir
+    })
+}
+
+fn put_sync(n: usize) -> Box<dyn FnOnce() -> TempDir> {
+    put_with(n, true)
+}
+
+fn put_nosync(n: usize) -> Box<dyn FnOnce() -> TempDir> {
+    put_with(n, false)
+}
+
+fn get_with(n: usize, cache_capacity_bytes: usize) -> Box<dyn FnOnce() -> TempDir> {
+    let opts = Options::builder()
+        .sync_on_write(false)
+        .cache_capacity_bytes(cache_capacity_bytes)
+        .build();
+    let (dir, mut db) = open(opts);
+    fill(&mut db, n);
+    let order = shuffled(n);
+    // 缓存模式下先读一遍，计时的读取全部命中
+    if cache_capacity_bytes > 0 {
+        for &i in &order {
+            db.get(&key(i)).expect("get");
+        }
+    }
+    Box::new(move || {
+        for &i in &order {
+            black_box(db.get(&key(i)).expect("get"));
+        }
+        dir
+    })
+}
+
+fn get_cold(n: usize) -> Box<dyn FnOnce() -> TempDir> {
+    get_with(n, 0)
+}
+
+fn get_cached(n: usize) -> Box<dyn FnOnce() -> TempDir> {
+    get_with(n, 64 * 1024 * 1024)
+}
+
+/// 重新打开一个包含 `n` 条记录的数据库（不使用 checkpoint，完整 replay）
+fn replay(n: usize) -> Box<dyn FnOnce() -> TempDir> {
+    let opts = Options::builder().sync_on_write(false).checkpoint_interval_bytes(None).build();
+    let (dir, mut db) = open(opts.clone());
+    fill(&mut db, n);
+    db.close().expect("close");
+    Box::new(move || {
+        let db = Db::open(dir.path(), opts).expect("reopen");
+        black_box(db.stats().key_count);
+        drop(db);
+        dir
+    })
+}
+
+/// 压缩比只在第一轮（预热）打印一次
+static REPORT_RATIO: Once = Once::new();
+
+/// `n` 个 key 各覆盖 10 次、删除十分之一之后压缩，同时报告压缩比
+fn compact(n: usize) -> Box<dyn FnOnce() -> TempDir> {
+    let (dir, mut db) = open(Options::builder().sync_on_write(false).build());
+    for round in 0..10 {
+        for i in 0..n {
+            db.put(&key(i), &value(i + round)).expect("put");
+        }
+    }
+    for i in (0..n).step_by(10) {
+        db.delete(&key(i)).expect("delete");
+    }
+    Box::new(move || {
+        let stats = db.compact().expect("compact");
+        REPORT_RATIO.call_once(|| {
+            println!(
+                "  compact: {} -> {} bytes ({:.1}%)",
+                stats.bytes_before,
+                stats.bytes_after,
+                stats.bytes_after as f64 * 100.0 / stats.bytes_before as f64
+            );
+        });
+        drop(db);
+        dir
+    })
+}
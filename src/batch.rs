@@ -0,0 +1,106 @@
+//! 批量写入
+//!
+//! 本模块定义 [`WriteBatch`]：先在内存中积累一串 put/delete 操作，
+//! 再交给 [`Db::write_batch`](crate::Db::write_batch) 一次性原子地写入。
+//!
+//! ## 设计
+//!
+//! `WriteBatch` 只是操作的列表，不访问数据库，也不验证大小：
+//! 验证和编码都在 `write_batch` 中进行，任意一个操作超限时整批被拒绝，
+//! 不写入任何内容。所有记录作为 WAL 中的一个批次追加，整批最多 fsync 一次，
+//! 崩溃后 replay 要么应用整批，要么整批丢弃。
+
+/// 批次中的一个操作
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BatchOp {
+    /// 写入 key
+    Put(Vec<u8>, Vec<u8>),
+    /// 删除 key
+    Delete(Vec<u8>),
+}
+
+/// 一组按顺序应用的 put/delete 操作，见 [`Db::write_batch`](crate::Db::write_batch)
+///
+/// 同一个 key 出现多次时，后面的操作覆盖前面的，与逐个调用 `put`/`delete` 的结果相同。
+///
+/// ## 示例
+///
+/// ```no_run
+/// use kvslite::{Db, Options, WriteBatch};
+///
+/// let mut db = Db::open("data/db1", Options::default()).unwrap();
+/// let mut batch = WriteBatch::new();
+/// for i in 0..100u32 {
+///     batch.put(format!("key{}", i).as_bytes(), b"value");
+/// }
+/// batch.delete(b"old");
+/// db.write_batch(batch).unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// 创建一个空批次
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// 追加一个写入操作
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Put(key.to_vec(), value.to_vec()));
+        self
+    }
+
+    /// 追加一个删除操作
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Delete(key.to_vec()));
+        self
+    }
+
+    /// 批次中的操作数
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// 批次是否为空
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// 清空所有操作，保留已分配的容量以便复用
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    /// 取出所有操作
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_and_clear() {
+        let mut batch = WriteBatch::new();
+        assert!(batch.is_empty());
+
+        batch.put(b"a", b"1").delete(b"b").put(b"a", b"2");
+        assert_eq!(batch.len(), 3);
+        assert_eq!(
+            batch.clone().into_ops(),
+            vec![
+                BatchOp::Put(b"a".to_vec(), b"1".to_vec()),
+                BatchOp::Delete(b"b".to_vec()),
+                BatchOp::Put(b"a".to_vec(), b"2".to_vec()),
+            ]
+        );
+
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+}
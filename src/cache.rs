@@ -0,0 +1,326 @@
+//! Value 缓存
+//!
+//! 本模块实现 `Options::cache_capacity_bytes`：在内存中缓存最近访问的 value，
+//! 命中时 `get` 不需要访问 WAL 文件。
+//!
+//! ## 设计
+//!
+//! 按 LRU 淘汰：每次访问给条目分配一个递增的时间戳，`lru` 按时间戳排序，
+//! 最小的时间戳就是最久未使用的条目。插入和访问都是 O(log n)，
+//! 只用标准库，不需要侵入式链表。
+//!
+//! 容量按 key + value 的字节数计算（不含 HashMap/BTreeMap 自身的开销）。
+//! 单个条目超过总容量时不缓存。
+//!
+//! ## 一致性
+//!
+//! 缓存只保存 value 本身，不保存位置，因此压缩、重写 WAL 后仍然有效。
+//! 所有修改 key 的写操作都必须同步更新或移除对应的缓存条目（由 `Db` 负责）。
+//!
+//! ## 统计
+//!
+//! 只有 `get` 计入命中/未命中（`peek`、`contains` 不计入），缓存关闭时不计数。
+//! 计数器与缓存内容相互独立：`clear` 不清零计数器，`reset_stats` 不清空缓存。
+
+use std::collections::{BTreeMap, HashMap};
+
+/// [`Db::get_cached_only`](crate::Db::get_cached_only) 的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheResult {
+    /// value 在内存中（缓存或内联在索引中）
+    Hit(Vec<u8>),
+    /// key 存在，但 value 不在内存中，需要从 WAL 读取
+    Miss,
+    /// key 不存在
+    Absent,
+}
+
+/// value 缓存的统计信息，见 [`Db::cache_stats`](crate::Db::cache_stats)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CacheStats {
+    /// 缓存的条目数
+    pub entries: usize,
+    /// 缓存的 key + value 总字节数
+    pub bytes: usize,
+    /// 上次重置以来 `get` 在缓存中命中的次数
+    pub hits: u64,
+    /// 上次重置以来 `get` 需要访问 WAL 的次数（内联在索引中的 value 不计入）
+    pub misses: u64,
+}
+
+/// 一个缓存条目
+struct CacheEntry {
+    value: Vec<u8>,
+    /// 最近一次访问的时间戳（`lru` 中的键）
+    tick: u64,
+}
+
+/// LRU value 缓存，容量为 0 时所有操作都是空操作
+pub struct ValueCache {
+    /// 容量（字节）
+    capacity: usize,
+    /// 当前缓存的 key + value 总字节数
+    bytes: usize,
+    /// key -> 缓存条目
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    /// 访问时间戳 -> key
+    lru: BTreeMap<u64, Vec<u8>>,
+    /// 下一个时间戳
+    tick: u64,
+    /// 命中次数
+    hits: u64,
+    /// 未命中次数
+    misses: u64,
+}
+
+impl ValueCache {
+    /// 创建一个容量为 `capacity` 字节的缓存
+    pub fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            bytes: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// 是否启用（容量大于 0）
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// 查找 key，命中时把它标记为最近使用
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let tick = self.next_tick();
+        let Some(entry) = self.entries.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        let key = self.lru.remove(&entry.tick)?;
+        entry.tick = tick;
+        self.lru.insert(tick, key);
+        self.hits += 1;
+        Some(entry.value.clone())
+    }
+
+    /// key 是否在缓存中（不改变 LRU 顺序）
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// 查找 key，不改变 LRU 顺序（只需要 `&self`）
+    pub fn peek(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(|entry| entry.value.as_slice())
+    }
+
+    /// 插入或更新 key 的 value，超出容量时淘汰最久未使用的条目
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.remove(key);
+        if !self.is_enabled() || key.len() + value.len() > self.capacity {
+            return;
+        }
+
+        let tick = self.next_tick();
+        self.bytes += key.len() + value.len();
+        self.lru.insert(tick, key.to_vec());
+        self.entries.insert(
+            key.to_vec(),
+            CacheEntry {
+                value: value.to_vec(),
+                tick,
+            },
+        );
+
+        self.trim();
+    }
+
+    /// 移除 key（不存在时忽略）
+    pub fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.get(key) {
+            self.lru.remove(&entry.tick);
+            self.evict(key);
+        }
+    }
+
+    /// 修改容量，超出新容量的条目按最久未使用的顺序淘汰
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.trim();
+    }
+
+    /// 清空所有条目，保留容量和统计计数
+    pub fn clear(&mut self) {
+        self.entries = HashMap::new();
+        self.lru = BTreeMap::new();
+        self.bytes = 0;
+    }
+
+    /// 当前的条目数、字节数和命中统计
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            bytes: self.bytes,
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+
+    /// 命中/未命中计数清零
+    pub fn reset_stats(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    /// 当前缓存的条目数
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// 淘汰最久未使用的条目，直到总字节数不超过容量
+    fn trim(&mut self) {
+        while self.bytes > self.capacity {
+            match self.lru.pop_first() {
+                Some((_, oldest)) => self.evict(&oldest),
+                None => break,
+            }
+        }
+    }
+
+    /// 从 `entries` 中删除条目并扣减字节数（`lru` 由调用方维护）
+    fn evict(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= key.len() + entry.value.len();
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_and_update() {
+        let mut cache = ValueCache::new(1024);
+        assert_eq!(cache.get(b"a"), None);
+
+        cache.insert(b"a", b"1");
+        assert_eq!(cache.get(b"a"), Some(b"1".to_vec()));
+
+        cache.insert(b"a", b"22");
+        assert_eq!(cache.get(b"a"), Some(b"22".to_vec()));
+        assert_eq!(cache.bytes, 3);
+
+        cache.remove(b"a");
+        assert_eq!(cache.get(b"a"), None);
+        assert_eq!(cache.bytes, 0);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        // 每个条目 1 + 3 = 4 字节，容量放得下 3 个
+        let mut cache = ValueCache::new(12);
+        cache.insert(b"a", b"111");
+        cache.insert(b"b", b"222");
+        cache.insert(b"c", b"333");
+
+        // 访问 a 之后，最久未使用的是 b；peek 不算访问
+        assert!(cache.get(b"a").is_some());
+        assert_eq!(cache.peek(b"b"), Some(&b"222"[..]));
+        cache.insert(b"d", b"444");
+        assert!(!cache.contains(b"b"));
+        assert!(cache.contains(b"a"));
+        assert!(cache.contains(b"c"));
+        assert!(cache.contains(b"d"));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.bytes, 12);
+    }
+
+    #[test]
+    fn test_disabled_and_oversized() {
+        let mut cache = ValueCache::new(0);
+        assert!(!cache.is_enabled());
+        cache.insert(b"a", b"1");
+        assert_eq!(cache.get(b"a"), None);
+
+        // 超过总容量的条目不缓存，并且会移除同一个 key 的旧值
+        let mut cache = ValueCache::new(8);
+        cache.insert(b"a", b"1");
+        cache.insert(b"a", b"too large");
+        assert_eq!(cache.get(b"a"), None);
+        assert_eq!(cache.bytes, 0);
+    }
+
+    #[test]
+    fn test_set_capacity() {
+        let mut cache = ValueCache::new(12);
+        cache.insert(b"a", b"111");
+        cache.insert(b"b", b"222");
+        cache.insert(b"c", b"333");
+        assert!(cache.get(b"a").is_some());
+
+        // 缩小时按 LRU 顺序淘汰
+        cache.set_capacity(8);
+        assert!(!cache.contains(b"b"));
+        assert!(cache.contains(b"a"));
+        assert!(cache.contains(b"c"));
+        assert_eq!(cache.bytes, 8);
+
+        cache.set_capacity(0);
+        assert_eq!(cache.len(), 0);
+        assert!(!cache.is_enabled());
+
+        cache.set_capacity(4);
+        cache.insert(b"d", b"444");
+        assert!(cache.contains(b"d"));
+    }
+
+    #[test]
+    fn test_clear_and_stats() {
+        let mut cache = ValueCache::new(1024);
+        cache.insert(b"a", b"111");
+        cache.insert(b"b", b"222");
+        assert!(cache.get(b"a").is_some());
+        assert!(cache.get(b"missing").is_none());
+        // peek 不计入统计
+        assert!(cache.peek(b"b").is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (2, 8));
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        // 清空不影响计数，重置计数不影响内容
+        cache.clear();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.hits), (0, 0, 1));
+        assert!(cache.get(b"a").is_none());
+        cache.insert(b"c", b"333");
+        cache.reset_stats();
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 1,
+                bytes: 4,
+                hits: 0,
+                misses: 0,
+            }
+        );
+
+        // 缓存关闭时不计数
+        let mut cache = ValueCache::new(0);
+        assert!(cache.get(b"a").is_none());
+        assert_eq!(cache.stats().misses, 0);
+    }
+}
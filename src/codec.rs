@@ -2,29 +2,78 @@
 //!
 //! 本模块负责 WAL（Write-Ahead Log）记录的二进制序列化和反序列化。
 //!
-//! ## 记录格式 (v0.1)
+//! ## 记录格式
 //!
 //! ```text
-//! +-------+--------+---------+------+----------+----------+-----+-------+--------+
-//! | magic | rec_len| version | kind | key_len  | val_len  | key | value | crc32  |
-//! +-------+--------+---------+------+----------+----------+-----+-------+--------+
-//!   4B      4B       1B       1B      4B         4B        var   var     4B
+//! +-------+--------+---------+------+----------+----------+-------+-----+-------+--------+
+//! | magic | rec_len| version | kind | key_len  | val_len  | [seq] | [ts] | key | value | crc32  |
+//! +-------+--------+---------+------+----------+----------+-------+------+-----+-------+--------+
+//!   4B      4B       1B       1B      4B         4B        0/8B    0/8B   var   var     4B
 //! ```
 //!
 //! ### 字段说明
 //!
 //! - `magic`: 固定值 `KVSL` (0x4B56534C)，用于识别记录边界
 //! - `rec_len`: 整个记录的长度（包括 magic 和 crc32），用于快速跳过记录
-//! - `version`: 格式版本号，当前为 1
-//! - `kind`: 记录类型
+//! - `version`: 格式版本号，当前为 2（见下文「格式版本」）
+//! - `kind`: 记录类型（低 4 位）
 //!   - `1` = PUT（写入键值对）
 //!   - `2` = DELETE（删除键）
+//!   - `3` = BATCH（批次头，value 为后续记录条数，见下文）
+//!   - `4` = NOOP（标记记录，key 为空，value 是可选的负载，见下文）
+//!   - `5` = REF（引用记录，key 的 value 与之前的一条 PUT 相同，见下文）
+//!
+//!   v2 起高 4 位是 flags，标记记录携带的可选字段：
+//!   - `0x10` = SEQ（携带 `seq` 字段）
+//!   - `0x20` = TIMESTAMP（携带 `ts` 字段）
+//!   - 其他位保留，必须为 0
 //! - `key_len`: key 的字节长度（little-endian u32）
 //! - `val_len`: value 的字节长度（little-endian u32）
+//! - `seq`: 可选，写入序列号（little-endian u64），仅在设置了 SEQ flag 时出现
+//! - `ts`: 可选，写入时间（UNIX 毫秒，little-endian u64），仅在设置了 TIMESTAMP flag 时出现。
+//!   目前只有 DELETE 记录携带，用于压缩时判断墓碑的年龄
 //! - `key`: key 的字节内容
 //! - `value`: value 的字节内容
 //! - `crc32`: CRC32 校验和，覆盖 `rec_len..value` 的所有字节
 //!
+//! ## 批次（BATCH）
+//!
+//! 一组需要原子提交的记录以一条 BATCH 头记录开始：
+//!
+//! ```text
+//! | BATCH(count=N) | Record 1 | Record 2 | ... | Record N |
+//! ```
+//!
+//! BATCH 头的 key 为空，value 为 N（little-endian u32）。Replay 时只有
+//! N 条记录全部完整，这一组才会生效；否则整组被丢弃（从 BATCH 头处截断）。
+//!
+//! ## 标记记录（NOOP）
+//!
+//! NOOP 记录不改变任何数据，replay 时直接跳过，只有 `WalReader` 会返回它们。
+//! 外部工具可以用它在 WAL 中标记位置（例如写入单调递增的序列号或 MANIFEST 偏移量），
+//! 在文件损坏后扫描已知的负载重新对齐记录边界。
+//!
+//! ## 引用记录（REF）
+//!
+//! 只由开启了 `Options::dedup_values` 的压缩写出：多个 key 的 value 相同时，
+//! 只有第一个 key 写成 PUT，其余的 key 写成 REF 记录，value 固定 24 字节：
+//!
+//! ```text
+//! | record_offset (8B) | value_offset (8B) | value_len (8B) |
+//! ```
+//!
+//! 分别是被引用的 PUT 记录的起始偏移量、其 value 的偏移量和长度（little-endian u64），
+//! 被引用的记录总是位于同一个文件中更早的位置。Replay 时 REF 与 PUT 一样更新索引，
+//! 只是 value 的位置指向被引用的记录。
+//!
+//! ## 格式版本
+//!
+//! - **v1**：`kind` 字节整体是记录类型
+//! - **v2**：`kind` 字节高 4 位是 flags，记录可以携带可选字段（序列号等）
+//!
+//! 不带可选字段时两个版本的字段布局相同，解码器两者都接受。v1 不能携带可选字段。同一个 WAL 文件只使用一个版本写入，
+//! 版本升级由 `Db` 通过重写整个 WAL 完成（见 `Options::upgrade_format`）。
+//!
 //! ## 设计要点
 //!
 //! ### 1. 为什么在开头放 magic？
@@ -54,10 +103,25 @@ use crc32fast::Hasher;
 use std::io::{Read, Write};
 
 /// Magic 字节：KVSL (0x4B56534C)
-const MAGIC: [u8; 4] = *b"KVSL";
+pub(crate) const MAGIC: [u8; 4] = *b"KVSL";
+
+/// 当前格式版本（新记录默认使用的版本）
+pub(crate) const VERSION: u8 = 2;
+
+/// 最早的格式版本
+pub(crate) const VERSION_V1: u8 = 1;
+
+/// v2 起 kind 字节中 flags 所占的位（高 4 位）
+const FLAGS_MASK: u8 = 0xF0;
 
-/// 当前格式版本
-const VERSION: u8 = 1;
+/// flag：记录携带 8 字节的 seq 字段
+const FLAG_SEQ: u8 = 0x10;
+
+/// flag：记录携带 8 字节的 timestamp 字段
+const FLAG_TIMESTAMP: u8 = 0x20;
+
+/// 当前版本能识别的所有 flag
+const KNOWN_FLAGS: u8 = FLAG_SEQ | FLAG_TIMESTAMP;
 
 /// 记录类型：PUT
 const KIND_PUT: u8 = 1;
@@ -65,28 +129,100 @@ const KIND_PUT: u8 = 1;
 /// 记录类型：DELETE
 const KIND_DELETE: u8 = 2;
 
-/// 最大 key 大小：1KB
+/// 记录类型：BATCH（批次头）
+const KIND_BATCH: u8 = 3;
+
+/// 记录类型：NOOP（标记记录）
+const KIND_NOOP: u8 = 4;
+
+/// 记录类型：REF（引用之前一条 PUT 记录的 value）
+const KIND_REF: u8 = 5;
+
+/// REF 记录的 value 长度：record_offset + value_offset + value_len
+pub(crate) const VALUE_REF_LEN: usize = 24;
+
+/// 默认最大 key 大小：1KB
 ///
 /// 限制原因：
 /// - 防止恶意或损坏的数据导致内存耗尽
 /// - 鼓励使用短 key（更高效）
 const MAX_KEY_SIZE: usize = 1024;
 
-/// 最大 value 大小：1MB
+/// 默认最大 value 大小：1MB
 ///
 /// 限制原因：
 /// - kvslite 优化小值存储
 /// - 大文件应该存储在文件系统，kvslite 只存元数据
 const MAX_VALUE_SIZE: usize = 1024 * 1024;
 
-/// 最大记录大小：2MB（为 header + key + value + crc 留出余量）
-const MAX_RECORD_SIZE: usize = 2 * 1024 * 1024;
+/// 解码时预先分配的最大缓冲区大小
+///
+/// 更长的记录边读边扩容：`rec_len` 来自不可信的数据，不能据此一次性分配内存
+const DECODE_PREALLOC: usize = 64 * 1024;
 
 /// 记录头部大小（不包括 key/value/crc）
 ///
 /// magic(4) + rec_len(4) + version(1) + kind(1) + key_len(4) + val_len(4) = 18 字节
 const HEADER_SIZE: usize = 18;
 
+/// 可选字段的最大总长度：seq(8) + timestamp(8)
+const MAX_OPTIONAL_SIZE: usize = 16;
+
+/// key / value 大小限制
+///
+/// 写入时用于拒绝过大的 key/value，解码时用于拒绝过大的记录。
+///
+/// 解码不可信来源的 WAL（备份文件、复制流）时，`rec_len` 会先与
+/// [`Limits::max_record_size`] 比较，超限的记录在分配内存之前就被拒绝。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// 最大 key 大小（字节），默认 1KB
+    pub max_key_size: usize,
+    /// 最大 value 大小（字节），默认 1MB
+    pub max_value_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_key_size: MAX_KEY_SIZE,
+            max_value_size: MAX_VALUE_SIZE,
+        }
+    }
+}
+
+impl Limits {
+    /// 这组限制下允许的最大记录长度（header + 可选字段 + key + value + crc）
+    pub fn max_record_size(&self) -> usize {
+        (HEADER_SIZE + MAX_OPTIONAL_SIZE)
+            .saturating_add(self.max_key_size)
+            .saturating_add(self.max_value_size)
+            .saturating_add(4)
+    }
+
+    /// 验证 key 大小
+    pub(crate) fn check_key(&self, key_len: usize) -> Result<()> {
+        if key_len > self.max_key_size {
+            return Err(Error::KeyTooLarge {
+                size: key_len,
+                max: self.max_key_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// 验证 value 大小
+    pub(crate) fn check_value(&self, value_len: usize) -> Result<()> {
+        if value_len > self.max_value_size {
+            return Err(Error::ValueTooLarge {
+                size: value_len,
+                max: self.max_value_size,
+            });
+        }
+        Ok(())
+    }
+}
+
 /// WAL 记录
 ///
 /// 表示一次写入操作（PUT 或 DELETE）
@@ -98,57 +234,250 @@ pub struct Record {
     pub key: Vec<u8>,
     /// 值（DELETE 时为空）
     pub value: Vec<u8>,
+    /// 写入序列号（v1 记录和 BATCH 头没有序列号）
+    pub seq: Option<u64>,
+    /// 写入时间（UNIX 毫秒），目前只有 v2 的 DELETE 记录携带
+    pub timestamp: Option<u64>,
 }
 
 /// 记录类型
+///
+/// 标记为 `#[non_exhaustive]`：以后的格式版本可能增加新的记录类型，
+/// crate 外部的 `match` 需要一个通配分支。新类型沿用相同的外层格式，
+/// 旧版本可以按 `rec_len` 跳过它们（见 `Options::skip_unknown_kinds`）。
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum RecordKind {
     /// 写入键值对
     Put,
     /// 删除键
     Delete,
+    /// 批次头（value 为后续记录条数）
+    Batch,
+    /// 标记记录（value 为可选负载，replay 时跳过）
+    Noop,
+    /// 引用记录（key 的 value 与之前的一条 PUT 相同，value 为 [`ValueRef`]）
+    Ref,
+}
+
+/// REF 记录指向的 value，见 [`Record::value_ref`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueRef {
+    /// 被引用的 PUT 记录的起始偏移量
+    pub record_offset: u64,
+    /// 被引用的 value 的偏移量
+    pub value_offset: u64,
+    /// 被引用的 value 的长度
+    pub len: usize,
+}
+
+/// 记录头部：定长字段和可选字段，不包括 key、value 和 crc
+///
+/// 由 [`Record::decode_header`] 解析，用于在不读取 key/value 的情况下了解或跳过一条记录
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordHeader {
+    /// 整条记录的长度（包括 magic 和 crc32）
+    pub rec_len: usize,
+    /// 格式版本
+    pub version: u8,
+    /// 记录类型
+    pub kind: RecordKind,
+    /// key 的字节长度
+    pub key_len: usize,
+    /// value 的字节长度
+    pub val_len: usize,
+    /// 写入序列号（没有 SEQ flag 时为 `None`）
+    pub seq: Option<u64>,
+    /// 写入时间（没有 TIMESTAMP flag 时为 `None`）
+    pub timestamp: Option<u64>,
+}
+
+impl RecordHeader {
+    /// 头部本身的长度（定长部分加可选字段），key 从记录起始位置的这个偏移量开始
+    pub fn header_len(&self) -> usize {
+        self.rec_len - self.key_len - self.val_len - 4
+    }
 }
 
 impl Record {
-    /// 创建一个 PUT 记录
+    /// 创建一个 PUT 记录（使用默认大小限制）
     pub fn put(key: Vec<u8>, value: Vec<u8>) -> Result<Self> {
+        Self::put_with_limits(key, value, &Limits::default())
+    }
+
+    /// 创建一个 PUT 记录，按给定的限制验证大小
+    pub fn put_with_limits(key: Vec<u8>, value: Vec<u8>, limits: &Limits) -> Result<Self> {
         // 验证大小限制
-        if key.len() > MAX_KEY_SIZE {
-            return Err(Error::KeyTooLarge {
-                size: key.len(),
-                max: MAX_KEY_SIZE,
-            });
-        }
-        if value.len() > MAX_VALUE_SIZE {
-            return Err(Error::ValueTooLarge {
-                size: value.len(),
-                max: MAX_VALUE_SIZE,
-            });
-        }
+        limits.check_key(key.len())?;
+        limits.check_value(value.len())?;
 
         Ok(Record {
             kind: RecordKind::Put,
             key,
             value,
+            seq: None,
+            timestamp: None,
         })
     }
 
-    /// 创建一个 DELETE 记录
-    pub fn delete(key: Vec<u8>) -> Result<Self> {
-        if key.len() > MAX_KEY_SIZE {
-            return Err(Error::KeyTooLarge {
-                size: key.len(),
-                max: MAX_KEY_SIZE,
-            });
+    /// 用任意的 kind、key 和 value 创建记录，不验证大小
+    ///
+    /// 用于测试和工具：构造超出 [`Limits`] 的记录，检验解码端的大小限制，
+    /// 或者直接构造 BATCH 头、NOOP 等特殊记录。写入 `Db` 的记录应当使用
+    /// [`Record::put_with_limits`] 等带验证的构造函数。编码时仍然会检查
+    /// 整条记录的长度不超出 u32 长度字段的范围。
+    pub fn new_unchecked(kind: RecordKind, key: Vec<u8>, value: Vec<u8>) -> Self {
+        Record {
+            kind,
+            key,
+            value,
+            seq: None,
+            timestamp: None,
         }
+    }
+
+    /// 创建一个 DELETE 记录（使用默认大小限制）
+    pub fn delete(key: Vec<u8>) -> Result<Self> {
+        Self::delete_with_limits(key, &Limits::default())
+    }
+
+    /// 创建一个 DELETE 记录，按给定的限制验证大小
+    pub fn delete_with_limits(key: Vec<u8>, limits: &Limits) -> Result<Self> {
+        limits.check_key(key.len())?;
 
         Ok(Record {
             kind: RecordKind::Delete,
             key,
             value: Vec::new(),
+            seq: None,
+            timestamp: None,
+        })
+    }
+
+    /// 创建一个 BATCH 头记录
+    ///
+    /// `count` 是紧随其后、属于同一批次的记录条数
+    pub fn batch(count: u32) -> Self {
+        Record {
+            kind: RecordKind::Batch,
+            key: Vec::new(),
+            value: count.to_le_bytes().to_vec(),
+            seq: None,
+            timestamp: None,
+        }
+    }
+
+    /// 创建一个 NOOP 标记记录（使用默认大小限制）
+    ///
+    /// `payload` 可以为空，超过 value 大小限制时返回错误
+    pub fn noop(payload: Vec<u8>) -> Result<Self> {
+        Limits::default().check_value(payload.len())?;
+
+        Ok(Record {
+            kind: RecordKind::Noop,
+            key: Vec::new(),
+            value: payload,
+            seq: None,
+            timestamp: None,
+        })
+    }
+
+    /// 为记录附加写入序列号
+    ///
+    /// 带序列号的记录只能按 v2 及以上版本编码
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// 为记录附加写入时间（UNIX 毫秒）
+    ///
+    /// 带时间戳的记录只能按 v2 及以上版本编码
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// 记录设置的 flags（位于 kind 字节高 4 位）
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.seq.is_some() {
+            flags |= FLAG_SEQ;
+        }
+        if self.timestamp.is_some() {
+            flags |= FLAG_TIMESTAMP;
+        }
+        flags
+    }
+
+    /// 可选字段的总长度（字节）
+    fn optional_len(&self) -> usize {
+        (self.seq.is_some() as usize + self.timestamp.is_some() as usize) * 8
+    }
+
+    /// 创建一个 REF 记录：`key` 的 value 是 `target` 指向的 value
+    pub fn reference(key: Vec<u8>, target: ValueRef) -> Self {
+        let mut value = Vec::with_capacity(VALUE_REF_LEN);
+        value.extend_from_slice(&target.record_offset.to_le_bytes());
+        value.extend_from_slice(&target.value_offset.to_le_bytes());
+        value.extend_from_slice(&(target.len as u64).to_le_bytes());
+
+        Record {
+            kind: RecordKind::Ref,
+            key,
+            value,
+            seq: None,
+            timestamp: None,
+        }
+    }
+
+    /// 解析 REF 记录指向的 value
+    ///
+    /// 非 REF 记录或 value 长度不是 24 字节时返回 `None`
+    pub fn value_ref(&self) -> Option<ValueRef> {
+        if self.kind != RecordKind::Ref || self.value.len() != VALUE_REF_LEN {
+            return None;
+        }
+        let u64_at =
+            |i: usize| Some(u64::from_le_bytes(self.value.get(i..i + 8)?.try_into().ok()?));
+        Some(ValueRef {
+            record_offset: u64_at(0)?,
+            value_offset: u64_at(8)?,
+            len: usize::try_from(u64_at(16)?).ok()?,
         })
     }
 
+    /// 解析 BATCH 头记录中的记录条数
+    ///
+    /// 非 BATCH 记录或 value 长度不是 4 字节时返回 `None`
+    pub fn batch_count(&self) -> Option<u32> {
+        if self.kind != RecordKind::Batch {
+            return None;
+        }
+        let bytes: [u8; 4] = self.value.as_slice().try_into().ok()?;
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    /// 编码后的记录总长度（字节）
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SIZE + self.optional_len() + self.key.len() + self.value.len() + 4 // +4 for crc32
+    }
+
+    /// 一条 PUT 记录编码后的长度，不需要构造记录
+    ///
+    /// `with_seq` 表示是否携带序列号（当前格式下 `Db` 写入的记录都携带）
+    pub(crate) fn put_encoded_len(key_len: usize, value_len: usize, with_seq: bool) -> usize {
+        let optional_len = if with_seq { 8 } else { 0 };
+        HEADER_SIZE + optional_len + key_len + value_len + 4
+    }
+
+    /// value 相对于记录起始位置的偏移量（字节）
+    ///
+    /// value 紧跟在 header、可选字段和 key 之后
+    pub fn value_offset(&self) -> u64 {
+        (HEADER_SIZE + self.optional_len() + self.key.len()) as u64
+    }
+
     /// 编码记录到字节流
     ///
     /// ## 返回值
@@ -159,62 +488,144 @@ impl Record {
     /// ## 格式
     ///
     /// ```text
-    /// | magic | rec_len | version | kind | key_len | val_len | key | value | crc32 |
+    /// | magic | rec_len | version | kind | key_len | val_len | [seq] | key | value | crc32 |
     /// ```
     pub fn encode(&self) -> Result<Vec<u8>> {
-        // 计算总长度
-        let rec_len = HEADER_SIZE + self.key.len() + self.value.len() + 4; // +4 for crc32
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// 编码记录并追加到已有缓冲区末尾
+    ///
+    /// 批量写入时可以把多条记录编码进同一个缓冲区，只调用一次 `write_all`
+    pub fn encode_to(&self, buf: &mut Vec<u8>) -> Result<()> {
+        self.encode_to_version(buf, VERSION)
+    }
+
+    /// 按指定的格式版本编码记录并追加到已有缓冲区末尾
+    ///
+    /// WAL 用它保证同一个文件只使用一个版本写入（见模块文档「格式版本」）
+    pub(crate) fn encode_to_version(&self, buf: &mut Vec<u8>, version: u8) -> Result<()> {
+        buf.reserve(self.encoded_len());
+
+        // 1. 写入 magic .. key，同时对 rec_len..key 计算 CRC
+        let mut encoder = self.encode_streaming(self.value.len(), buf, version)?;
+
+        // 2. 写入 value，直接对源数据增量计算 CRC，不必再扫描一遍缓冲区
+        buf.write_all(&self.value)?;
+        encoder.update(&self.value);
+
+        // 3. 写入 crc32（覆盖 rec_len..value）
+        buf.write_all(&encoder.finish())?;
 
-        // 预分配缓冲区
-        let mut buf = Vec::with_capacity(rec_len);
+        Ok(())
+    }
+
+    /// 开始流式编码一条记录：只写出 value 之前的部分，value 由调用方分块写入
+    ///
+    /// ## 参数
+    ///
+    /// - `value_len`: 之后将要写入的 value 总长度（`self.value` 被忽略）
+    /// - `buf`: 头部追加到这里
+    /// - `version`: 格式版本
+    ///
+    /// ## 返回值
+    ///
+    /// 返回的 [`ValueEncoder`] 需要依次喂入全部 value 字节，最后由 `finish` 得到 CRC。
+    /// 拼接起来的字节与 `encode_to_version` 的结果完全相同。
+    pub(crate) fn encode_streaming(
+        &self,
+        value_len: usize,
+        buf: &mut Vec<u8>,
+        version: u8,
+    ) -> Result<ValueEncoder> {
+        let start = buf.len();
+        self.encode_header(buf, version, value_len)?;
+
+        let mut hasher = Hasher::new();
+        hasher.update(&buf[start + 4..]);
+
+        Ok(ValueEncoder {
+            hasher,
+            remaining: value_len,
+        })
+    }
+
+    /// 编码 value 之前的部分：magic | rec_len | version | kind | key_len | val_len | [seq] | key
+    fn encode_header(&self, buf: &mut Vec<u8>, version: u8, value_len: usize) -> Result<()> {
+        if !(VERSION_V1..=VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        // v1 不支持可选字段
+        if version == VERSION_V1 && self.flags() != 0 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        // 计算总长度；长度字段都是 u32，超出范围时在写入任何字节之前拒绝
+        let rec_len = (self.encoded_len() - self.value.len()).saturating_add(value_len);
+        let len_field = |len: usize| {
+            u32::try_from(len).map_err(|_| Error::RecordTooLarge {
+                size: rec_len,
+                max: u32::MAX as usize,
+            })
+        };
+        let rec_len_field = len_field(rec_len)?;
+        let key_len_field = len_field(self.key.len())?;
+        let value_len_field = len_field(value_len)?;
 
         // 1. 写入 magic
         buf.write_all(&MAGIC)?;
 
         // 2. 写入 rec_len
-        buf.write_all(&(rec_len as u32).to_le_bytes())?;
+        buf.write_all(&rec_len_field.to_le_bytes())?;
 
         // 3. 写入 version
-        buf.write_all(&[VERSION])?;
+        buf.write_all(&[version])?;
 
         // 4. 写入 kind
         let kind_byte = match self.kind {
             RecordKind::Put => KIND_PUT,
             RecordKind::Delete => KIND_DELETE,
+            RecordKind::Batch => KIND_BATCH,
+            RecordKind::Noop => KIND_NOOP,
+            RecordKind::Ref => KIND_REF,
         };
-        buf.write_all(&[kind_byte])?;
+        buf.write_all(&[kind_byte | self.flags()])?;
 
         // 5. 写入 key_len
-        buf.write_all(&(self.key.len() as u32).to_le_bytes())?;
+        buf.write_all(&key_len_field.to_le_bytes())?;
 
         // 6. 写入 val_len
-        buf.write_all(&(self.value.len() as u32).to_le_bytes())?;
-
-        // 7. 写入 key
-        buf.write_all(&self.key)?;
+        buf.write_all(&value_len_field.to_le_bytes())?;
 
-        // 8. 写入 value
-        buf.write_all(&self.value)?;
+        // 7. 写入可选字段
+        if let Some(seq) = self.seq {
+            buf.write_all(&seq.to_le_bytes())?;
+        }
+        if let Some(timestamp) = self.timestamp {
+            buf.write_all(&timestamp.to_le_bytes())?;
+        }
 
-        // 9. 计算 CRC32（覆盖 rec_len..value）
-        // 跳过 magic (4 bytes)，从 rec_len 开始计算
-        let crc = {
-            let mut hasher = Hasher::new();
-            hasher.update(&buf[4..]); // 从 rec_len 开始
-            hasher.finalize()
-        };
+        // 8. 写入 key
+        buf.write_all(&self.key)?;
 
-        // 10. 写入 crc32
-        buf.write_all(&crc.to_le_bytes())?;
+        Ok(())
+    }
 
-        Ok(buf)
+    /// 从字节流解码记录（使用默认大小限制）
+    ///
+    /// 等价于 `Record::decode_with_limits(reader, &Limits::default())`
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Option<Record>> {
+        Self::decode_with_limits(reader, &Limits::default())
     }
 
-    /// 从字节流解码记录
+    /// 从字节流解码记录，按给定的限制拒绝过大的记录
     ///
     /// ## 参数
     ///
     /// - `reader`: 实现了 `Read` trait 的对象（通常是文件）
+    /// - `limits`: key/value 大小限制
     ///
     /// ## 返回值
     ///
@@ -223,16 +634,158 @@ impl Record {
     /// - `Err(Error::UnexpectedEof)`: 记录不完整（半写入）
     /// - `Err(Error::CrcMismatch)`: 校验失败（数据损坏）
     /// - `Err(Error::InvalidMagic)`: magic 不匹配（可能不是 WAL 文件）
+    /// - `Err(Error::KeyTooLarge)` / `Err(Error::ValueTooLarge)`: 超出 `limits`
     ///
     /// ## 解码流程
     ///
     /// 1. 读取 magic (4 bytes)
     /// 2. 读取 rec_len (4 bytes)
-    /// 3. 验证 rec_len 是否合理（< MAX_RECORD_SIZE）
+    /// 3. 验证 rec_len 是否合理（<= limits.max_record_size()），在分配内存之前拒绝
     /// 4. 读取剩余字节（rec_len - 8）
     /// 5. 验证 CRC32
     /// 6. 解析字段
-    pub fn decode<R: Read>(reader: &mut R) -> Result<Option<Record>> {
+    ///
+    /// ## 健壮性
+    ///
+    /// 对任意输入字节都只会返回 `Ok`/`Err`，不会 panic：长度字段的运算都经过检查，
+    /// 越界或溢出一律视为 `Error::UnexpectedEof`。
+    pub fn decode_with_limits<R: Read>(reader: &mut R, limits: &Limits) -> Result<Option<Record>> {
+        Ok(Self::decode_with_version(reader, limits)?.map(|(record, _)| record))
+    }
+
+    /// 只解码记录头部，不读取 key、value 和 crc
+    ///
+    /// ## 参数
+    ///
+    /// - `reader`: 位于记录起始位置的字节流
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Some(RecordHeader))`: 头部完整且各长度字段一致，`reader` 停在 key 的第一个字节
+    /// - `Ok(None)`: 到达文件末尾（正常结束）
+    /// - `Err(Error::UnexpectedEof)`: 头部不完整，或 `key_len`/`val_len` 与 `rec_len` 不符
+    /// - `Err(Error::InvalidMagic)` / `Err(Error::UnsupportedVersion)` /
+    ///   `Err(Error::InvalidRecordKind)`: 头部字段不合法
+    ///
+    /// ## 注意
+    ///
+    /// CRC 覆盖整条记录，只读头部无法校验，这里只检查字段之间是否自洽：
+    /// 损坏的头部仍然可能通过。需要可靠的数据时用 [`Record::decode_with_limits`]
+    /// 读取完整记录。头部中的长度不与任何大小限制比较，按它分配内存之前应当先检查。
+    ///
+    /// ## 示例
+    ///
+    /// ```
+    /// use kvslite::{Record, RecordKind};
+    ///
+    /// let bytes = Record::put(b"key".to_vec(), b"value".to_vec()).unwrap().encode().unwrap();
+    /// let mut reader = bytes.as_slice();
+    /// let header = Record::decode_header(&mut reader).unwrap().unwrap();
+    /// assert_eq!(header.kind, RecordKind::Put);
+    /// assert_eq!((header.key_len, header.val_len), (3, 5));
+    /// assert!(reader.starts_with(b"key"));
+    /// ```
+    pub fn decode_header<R: Read>(reader: &mut R) -> Result<Option<RecordHeader>> {
+        // 1. magic：一个字节都没有读到是正常的文件末尾
+        let mut magic = [0u8; 4];
+        match reader.read_exact(&mut magic) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        if magic != MAGIC {
+            return Err(Error::InvalidMagic {
+                expected: MAGIC,
+                actual: magic,
+            });
+        }
+
+        // 2. 其余的定长字段
+        let mut fixed = [0u8; HEADER_SIZE - 4];
+        read_full(reader, &mut fixed)?;
+        let u32_at = |i: usize| {
+            u32::from_le_bytes([fixed[i], fixed[i + 1], fixed[i + 2], fixed[i + 3]])
+        };
+        let rec_len = u32_at(0) as usize;
+        let version = fixed[4];
+        if !(VERSION_V1..=VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let (kind, flags) = parse_kind(version, fixed[5])?;
+        let key_len = u32_at(6) as usize;
+        let val_len = u32_at(10) as usize;
+
+        // 3. 可选字段（按 seq、timestamp 的顺序排列）
+        let mut read_u64 = |present: bool| -> Result<Option<u64>> {
+            if !present {
+                return Ok(None);
+            }
+            let mut bytes = [0u8; 8];
+            read_full(reader, &mut bytes)?;
+            Ok(Some(u64::from_le_bytes(bytes)))
+        };
+        let seq = read_u64(flags & FLAG_SEQ != 0)?;
+        let timestamp = read_u64(flags & FLAG_TIMESTAMP != 0)?;
+
+        // 4. 长度字段必须与 rec_len 一致（来自数据本身，相加可能溢出）
+        let optional_len = (seq.is_some() as usize + timestamp.is_some() as usize) * 8;
+        let expected = (HEADER_SIZE + optional_len + 4)
+            .checked_add(key_len)
+            .and_then(|len| len.checked_add(val_len));
+        if expected != Some(rec_len) {
+            return Err(Error::UnexpectedEof);
+        }
+
+        Ok(Some(RecordHeader {
+            rec_len,
+            version,
+            kind,
+            key_len,
+            val_len,
+            seq,
+            timestamp,
+        }))
+    }
+
+    /// 从字节流解码记录，同时返回记录的格式版本
+    ///
+    /// 与 [`Record::decode_with_limits`] 相同，WAL replay 用返回的版本决定后续追加使用的版本
+    pub(crate) fn decode_with_version<R: Read>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<Option<(Record, u8)>> {
+        Self::decode_checked(reader, limits, true)
+    }
+
+    /// 从字节流解码记录，`verify_crc` 为 false 时跳过 CRC 校验
+    ///
+    /// 跳过校验时仍然检查 magic、版本、长度字段和大小限制，只是不计算 CRC：
+    /// value 中的损坏不会被发现，只适合读取已知完好的文件（见 [`crate::WalReader::set_verify`]）
+    pub(crate) fn decode_checked<R: Read>(
+        reader: &mut R,
+        limits: &Limits,
+        verify_crc: bool,
+    ) -> Result<Option<(Record, u8)>> {
+        match Self::decode_any(reader, limits, verify_crc, false)? {
+            Some(Decoded::Record(record, version)) => Ok(Some((record, version))),
+            Some(Decoded::Unknown { kind, .. }) => Err(Error::InvalidRecordKind(kind)),
+            None => Ok(None),
+        }
+    }
+
+    /// 从字节流解码记录；`skip_unknown` 为 true 时未知类型的记录不是错误
+    ///
+    /// 未知类型的记录按约定与其他记录使用相同的外层格式（magic、rec_len、头部、crc），
+    /// 更新版本的 kvslite 可以增加新的类型。magic、rec_len 和 CRC 都正确时
+    /// 整条记录已经被读过，返回 [`Decoded::Unknown`]，调用方按 `rec_len` 跳过它。
+    /// 已知类型带有未知 flag 的记录仍然是 `Error::InvalidRecordKind`：
+    /// 它的内容无法正确解析，跳过会悄悄丢失数据。
+    pub(crate) fn decode_any<R: Read>(
+        reader: &mut R,
+        limits: &Limits,
+        verify_crc: bool,
+        skip_unknown: bool,
+    ) -> Result<Option<Decoded>> {
         // 1. 读取 magic
         let mut magic = [0u8; 4];
         match reader.read_exact(&mut magic) {
@@ -254,98 +807,206 @@ impl Record {
 
         // 2. 读取 rec_len
         let mut rec_len_bytes = [0u8; 4];
-        reader.read_exact(&mut rec_len_bytes)?;
+        match reader.read_exact(&mut rec_len_bytes) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::UnexpectedEof);
+            }
+            Err(e) => return Err(e.into()),
+        }
         let rec_len = u32::from_le_bytes(rec_len_bytes) as usize;
 
         // 验证 rec_len 是否合理
-        if rec_len < HEADER_SIZE + 4 || rec_len > MAX_RECORD_SIZE {
+        if !(HEADER_SIZE + 4..=limits.max_record_size()).contains(&rec_len) {
             return Err(Error::UnexpectedEof);
         }
 
         // 3. 读取剩余数据（rec_len - magic(4) - rec_len(4)）
+        //    rec_len 可能是损坏的值，按实际读到的数据增长缓冲区，而不是预先分配 rec_len 字节
         let remaining_len = rec_len - 8;
-        let mut remaining = vec![0u8; remaining_len];
-        reader.read_exact(&mut remaining)?;
+        let mut remaining = Vec::with_capacity(remaining_len.min(DECODE_PREALLOC));
+        reader
+            .take(remaining_len as u64)
+            .read_to_end(&mut remaining)?;
+        if remaining.len() < remaining_len {
+            return Err(Error::UnexpectedEof);
+        }
 
         // 4. 验证 CRC32
         // CRC 覆盖 rec_len..value（不包括 magic 和 crc 本身）
         let crc_offset = remaining_len - 4;
-        let stored_crc = u32::from_le_bytes([
-            remaining[crc_offset],
-            remaining[crc_offset + 1],
-            remaining[crc_offset + 2],
-            remaining[crc_offset + 3],
-        ]);
-
-        let computed_crc = {
-            let mut hasher = Hasher::new();
-            hasher.update(&rec_len_bytes); // rec_len
-            hasher.update(&remaining[..crc_offset]); // version..value
-            hasher.finalize()
-        };
+        if verify_crc {
+            let stored_crc = u32::from_le_bytes([
+                remaining[crc_offset],
+                remaining[crc_offset + 1],
+                remaining[crc_offset + 2],
+                remaining[crc_offset + 3],
+            ]);
 
-        if stored_crc != computed_crc {
-            return Err(Error::CrcMismatch {
-                expected: stored_crc,
-                actual: computed_crc,
-            });
+            let computed_crc = {
+                let mut hasher = Hasher::new();
+                hasher.update(&rec_len_bytes); // rec_len
+                hasher.update(&remaining[..crc_offset]); // version..value
+                hasher.finalize()
+            };
+
+            if stored_crc != computed_crc {
+                return Err(Error::CrcMismatch {
+                    expected: stored_crc,
+                    actual: computed_crc,
+                });
+            }
         }
 
         // 5. 解析字段
         let version = remaining[0];
-        if version != VERSION {
+        if !(VERSION_V1..=VERSION).contains(&version) {
             return Err(Error::UnsupportedVersion(version));
         }
 
-        let kind_byte = remaining[1];
-        let kind = match kind_byte {
-            KIND_PUT => RecordKind::Put,
-            KIND_DELETE => RecordKind::Delete,
-            _ => return Err(Error::InvalidRecordKind(kind_byte)),
+        let (kind, flags) = match parse_kind(version, remaining[1]) {
+            Err(Error::InvalidRecordKind(_)) if skip_unknown => {
+                let (kind_byte, flags) = split_kind(version, remaining[1]);
+                if flags & !KNOWN_FLAGS != 0 {
+                    return Err(Error::InvalidRecordKind(remaining[1]));
+                }
+                return Ok(Some(Decoded::Unknown {
+                    kind: kind_byte,
+                    rec_len,
+                }));
+            }
+            parsed => parsed?,
         };
 
-        let key_len = u32::from_le_bytes([
-            remaining[2],
-            remaining[3],
-            remaining[4],
-            remaining[5],
-        ]) as usize;
+        let key_len =
+            u32::from_le_bytes([remaining[2], remaining[3], remaining[4], remaining[5]]) as usize;
 
-        let val_len = u32::from_le_bytes([
-            remaining[6],
-            remaining[7],
-            remaining[8],
-            remaining[9],
-        ]) as usize;
+        let val_len =
+            u32::from_le_bytes([remaining[6], remaining[7], remaining[8], remaining[9]]) as usize;
 
         // 验证长度
-        if key_len > MAX_KEY_SIZE {
-            return Err(Error::KeyTooLarge {
-                size: key_len,
-                max: MAX_KEY_SIZE,
-            });
-        }
-        if val_len > MAX_VALUE_SIZE {
-            return Err(Error::ValueTooLarge {
-                size: val_len,
-                max: MAX_VALUE_SIZE,
-            });
-        }
+        limits.check_key(key_len)?;
+        limits.check_value(val_len)?;
 
-        let data_start = 10; // version(1) + kind(1) + key_len(4) + val_len(4)
-        let key_start = data_start;
-        let key_end = key_start + key_len;
-        let val_end = key_end + val_len;
+        let data_start: usize = 10; // version(1) + kind(1) + key_len(4) + val_len(4)
+        let seq_len = if flags & FLAG_SEQ != 0 { 8 } else { 0 };
+        let timestamp_len = if flags & FLAG_TIMESTAMP != 0 { 8 } else { 0 };
+        let key_start = data_start + seq_len + timestamp_len;
 
-        // 验证数据完整性
-        if val_end > crc_offset {
-            return Err(Error::UnexpectedEof);
-        }
+        // 验证数据完整性（key_len/val_len 来自数据本身，相加可能溢出）
+        let (key_end, val_end) = match key_start
+            .checked_add(key_len)
+            .and_then(|key_end| Some((key_end, key_end.checked_add(val_len)?)))
+        {
+            Some((key_end, val_end)) if val_end <= crc_offset => (key_end, val_end),
+            _ => return Err(Error::UnexpectedEof),
+        };
+
+        // 解析可选字段（按 seq、timestamp 的顺序排列）
+        let read_u64 = |start: usize| -> Result<u64> {
+            let bytes: [u8; 8] = remaining[start..start + 8]
+                .try_into()
+                .map_err(|_| Error::UnexpectedEof)?;
+            Ok(u64::from_le_bytes(bytes))
+        };
+        let seq = if seq_len > 0 {
+            Some(read_u64(data_start)?)
+        } else {
+            None
+        };
+        let timestamp = if timestamp_len > 0 {
+            Some(read_u64(data_start + seq_len)?)
+        } else {
+            None
+        };
 
         let key = remaining[key_start..key_end].to_vec();
         let value = remaining[key_end..val_end].to_vec();
 
-        Ok(Some(Record { kind, key, value }))
+        Ok(Some(Decoded::Record(
+            Record {
+                kind,
+                key,
+                value,
+                seq,
+                timestamp,
+            },
+            version,
+        )))
+    }
+}
+
+/// [`Record::decode_any`] 的结果
+pub(crate) enum Decoded {
+    /// 一条已知类型的记录及其格式版本
+    Record(Record, u8),
+    /// 一条 CRC 正确但类型未知的记录，已经整条读过
+    Unknown {
+        /// 类型字节（不含 flags）
+        kind: u8,
+        /// 整条记录的长度
+        rec_len: usize,
+    },
+}
+
+/// 解析 kind 字节，返回记录类型和 flags
+///
+/// v1 没有 flags，整个字节都是 kind；v2 拆分出 flags 并拒绝未知的 flag
+fn parse_kind(version: u8, byte: u8) -> Result<(RecordKind, u8)> {
+    let (kind_byte, flags) = split_kind(version, byte);
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(Error::InvalidRecordKind(byte));
+    }
+    let kind = match kind_byte {
+        KIND_PUT => RecordKind::Put,
+        KIND_DELETE => RecordKind::Delete,
+        KIND_BATCH => RecordKind::Batch,
+        KIND_NOOP => RecordKind::Noop,
+        KIND_REF => RecordKind::Ref,
+        _ => return Err(Error::InvalidRecordKind(kind_byte)),
+    };
+    Ok((kind, flags))
+}
+
+/// 把 kind 字节拆分为 (类型, flags)，v1 没有 flags
+fn split_kind(version: u8, byte: u8) -> (u8, u8) {
+    if version == VERSION_V1 {
+        (byte, 0)
+    } else {
+        (byte & !FLAGS_MASK, byte & FLAGS_MASK)
+    }
+}
+
+/// 读满 `buf`，数据不足时返回 `Error::UnexpectedEof`
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(Error::UnexpectedEof),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 流式编码中 value 部分的 CRC 状态
+///
+/// 由 [`Record::encode_streaming`] 创建，已经包含头部的 CRC
+pub(crate) struct ValueEncoder {
+    hasher: Hasher,
+    /// 还需要写入的 value 字节数
+    remaining: usize,
+}
+
+impl ValueEncoder {
+    /// 喂入一段 value 字节（调用方保证不超过 `remaining`）
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        debug_assert!(data.len() <= self.remaining);
+        self.hasher.update(data);
+        self.remaining -= data.len();
+    }
+
+    /// 结束编码，返回记录末尾的 crc32 字节
+    pub(crate) fn finish(self) -> [u8; 4] {
+        debug_assert_eq!(self.remaining, 0);
+        self.hasher.finalize().to_le_bytes()
     }
 }
 
@@ -354,6 +1015,300 @@ mod tests {
     use super::*;
     use std::io::Cursor;
 
+    /// 测试用的伪随机数生成器（xorshift64），保证失败可以复现
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    /// 解码一段字节流直到结束或出错，只要求不 panic
+    fn decode_all(bytes: &[u8], limits: &Limits) {
+        let mut cursor = Cursor::new(bytes);
+        for _ in 0..bytes.len() + 1 {
+            match Record::decode_with_version(&mut cursor, limits) {
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => break,
+            }
+        }
+    }
+
+    /// 给 `rec_len..` 之后的内容补上正确的 CRC，让变异后的数据能通过校验、进入字段解析
+    fn with_valid_crc(mut record: Vec<u8>) -> Vec<u8> {
+        if record.len() >= 12 {
+            let crc_offset = record.len() - 4;
+            let crc = crc32fast::hash(&record[4..crc_offset]);
+            record[crc_offset..].copy_from_slice(&crc.to_le_bytes());
+        }
+        record
+    }
+
+    #[test]
+    fn test_decode_never_panics_on_arbitrary_bytes() {
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        let unlimited = Limits {
+            max_key_size: usize::MAX,
+            max_value_size: usize::MAX,
+        };
+        let all_limits = [Limits::default(), unlimited];
+
+        for _ in 0..20_000 {
+            let limits = &all_limits[rng.below(all_limits.len())];
+
+            // 1. 完全随机的字节（一半以 magic 开头，才能走到后面的解析）
+            let len = rng.below(64);
+            let mut bytes = rng.bytes(len);
+            if rng.below(2) == 0 {
+                bytes.splice(0..0, MAGIC);
+            }
+            decode_all(&bytes, limits);
+
+            // 2. 头部字段随机、CRC 正确的记录：rec_len 与实际长度一致，
+            //    key_len/val_len/kind/version 任意
+            let body_len = HEADER_SIZE + 4 - 8 + rng.below(48);
+            let mut record = MAGIC.to_vec();
+            record.extend_from_slice(&((body_len + 8) as u32).to_le_bytes());
+            record.extend(rng.bytes(body_len));
+            if rng.below(2) == 0 {
+                record[8] = VERSION_V1 + rng.below(2) as u8;
+            }
+            decode_all(&with_valid_crc(record), limits);
+
+            // 3. 合法记录的变异：翻转若干字节后重新计算 CRC，或者直接截断
+            let (key_len, val_len) = (rng.below(16), rng.below(32));
+            let key = rng.bytes(key_len);
+            let value = rng.bytes(val_len);
+            let mut record = Record::put(key, value).unwrap();
+            if rng.below(2) == 0 {
+                record = record.with_seq(rng.next());
+            }
+            let mut encoded = record.encode().unwrap();
+            for _ in 0..1 + rng.below(3) {
+                let i = 4 + rng.below(encoded.len() - 4);
+                encoded[i] = rng.next() as u8;
+            }
+            let encoded = if rng.below(2) == 0 {
+                with_valid_crc(encoded)
+            } else {
+                let cut = rng.below(encoded.len());
+                encoded[..cut].to_vec()
+            };
+            decode_all(&encoded, limits);
+        }
+    }
+
+    /// 编码后按所有可能的方式读回，都必须得到原来的记录
+    fn assert_round_trip(record: &Record) {
+        let encoded = record.encode().unwrap();
+        assert_eq!(encoded.len(), record.encoded_len());
+
+        let decoded = Record::decode(&mut encoded.as_slice()).unwrap().unwrap();
+        assert_eq!(&decoded, record);
+
+        let header = Record::decode_header(&mut encoded.as_slice()).unwrap().unwrap();
+        assert_eq!(header.kind, record.kind);
+        assert_eq!((header.key_len, header.val_len), (record.key.len(), record.value.len()));
+        assert_eq!(header.header_len() as u64 + header.key_len as u64, record.value_offset());
+
+        // 连续的两条记录：前一条的 value 不能影响后一条的解码
+        let mut stream = encoded.clone();
+        stream.extend_from_slice(&encoded);
+        let mut cursor = Cursor::new(stream);
+        assert_eq!(&Record::decode(&mut cursor).unwrap().unwrap(), record);
+        assert_eq!(&Record::decode(&mut cursor).unwrap().unwrap(), record);
+        assert!(Record::decode(&mut cursor).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_round_trip_edge_cases() {
+        let embedded = Record::put(b"inner".to_vec(), b"value".to_vec()).unwrap();
+        let mut with_record = b"prefix".to_vec();
+        with_record.extend(embedded.encode().unwrap());
+
+        let keys: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            vec![b'k'; MAX_KEY_SIZE],
+            vec![0u8; 16],
+            vec![0xFF; 16],
+            b"KVSL".to_vec(),
+            b"a\0b\0".to_vec(),
+        ];
+        let values: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            vec![0x5A; MAX_VALUE_SIZE],
+            vec![0u8; 4096],
+            vec![0xFF; 4096],
+            b"nul\0in\0the\0middle\0".to_vec(),
+            b"KVSL".to_vec(),
+            [MAGIC.as_slice(), &[0xFF; 20], MAGIC.as_slice()].concat(),
+            with_record,
+        ];
+
+        for key in &keys {
+            for value in &values {
+                let record = Record::put(key.clone(), value.clone()).unwrap();
+                assert_round_trip(&record);
+                assert_round_trip(&record.clone().with_seq(u64::MAX));
+                assert_round_trip(&record.with_seq(0).with_timestamp(0));
+            }
+            assert_round_trip(&Record::delete(key.clone()).unwrap().with_timestamp(u64::MAX));
+        }
+    }
+
+    #[test]
+    fn test_round_trip_random() {
+        let mut rng = XorShift(0xD1B5_4A32_D192_ED03);
+        for _ in 0..500 {
+            // 长度偏向边界：空、很短、正好在上限
+            let key_len = match rng.below(4) {
+                0 => 0,
+                1 => MAX_KEY_SIZE,
+                _ => rng.below(64),
+            };
+            let val_len = match rng.below(8) {
+                0 => 0,
+                1 => MAX_VALUE_SIZE - rng.below(2),
+                _ => rng.below(512),
+            };
+            let key = rng.bytes(key_len);
+            let mut value = match rng.below(3) {
+                0 => vec![0u8; val_len],
+                1 => vec![0xFF; val_len],
+                _ => rng.bytes(val_len),
+            };
+            // 在随机位置放入 magic
+            if val_len >= MAGIC.len() && rng.below(2) == 0 {
+                let at = rng.below(val_len - MAGIC.len() + 1);
+                value[at..at + MAGIC.len()].copy_from_slice(&MAGIC);
+            }
+
+            let mut record = if rng.below(4) == 0 {
+                Record::delete(key).unwrap()
+            } else {
+                Record::put(key, value).unwrap()
+            };
+            if rng.below(2) == 0 {
+                record = record.with_seq(rng.next());
+            }
+            if rng.below(2) == 0 {
+                record = record.with_timestamp(rng.next());
+            }
+            assert_round_trip(&record);
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_unchecked_oversized() {
+        // 绕过构造时的验证，检验解码端的大小限制
+        let key = vec![b'k'; MAX_KEY_SIZE + 1];
+        let record = Record::new_unchecked(RecordKind::Put, key, Vec::new());
+        let encoded = record.encode().unwrap();
+        let result = Record::decode(&mut encoded.as_slice());
+        assert!(matches!(result, Err(Error::KeyTooLarge { .. })));
+
+        let value = vec![0u8; MAX_VALUE_SIZE + 1];
+        let record = Record::new_unchecked(RecordKind::Put, b"key".to_vec(), value);
+        let encoded = record.encode().unwrap();
+        let result = Record::decode(&mut encoded.as_slice());
+        assert!(matches!(result, Err(Error::ValueTooLarge { .. })));
+
+        // 放宽限制后可以读回
+        let unlimited = Limits {
+            max_key_size: usize::MAX,
+            max_value_size: usize::MAX,
+        };
+        let decoded = Record::decode_with_limits(&mut encoded.as_slice(), &unlimited).unwrap();
+        assert_eq!(decoded, Some(record));
+    }
+
+    #[test]
+    fn test_decode_rejects_overflowing_lengths() {
+        // rec_len 合法，但 key_len/val_len 接近 u32::MAX，相加会越过 crc 位置
+        let mut record = MAGIC.to_vec();
+        record.extend_from_slice(&26u32.to_le_bytes());
+        record.push(VERSION);
+        record.push(KIND_PUT);
+        record.extend_from_slice(&u32::MAX.to_le_bytes());
+        record.extend_from_slice(&u32::MAX.to_le_bytes());
+        record.extend_from_slice(&[0u8; 8]);
+        let record = with_valid_crc(record);
+
+        let unlimited = Limits {
+            max_key_size: usize::MAX,
+            max_value_size: usize::MAX,
+        };
+        let result = Record::decode_with_limits(&mut Cursor::new(record), &unlimited);
+        assert!(matches!(result, Err(Error::UnexpectedEof)));
+
+        // rec_len 声称很大但数据很短：不预先分配，按截断处理
+        let mut record = MAGIC.to_vec();
+        record.extend_from_slice(&u32::MAX.to_le_bytes());
+        record.extend_from_slice(&[0u8; 16]);
+        let result = Record::decode_with_limits(&mut Cursor::new(record), &unlimited);
+        assert!(matches!(result, Err(Error::UnexpectedEof)));
+    }
+
+    #[test]
+    fn test_put_encoded_len() {
+        let record = Record::put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(Record::put_encoded_len(3, 5, false), record.encoded_len());
+        assert_eq!(
+            Record::put_encoded_len(3, 5, true),
+            record.with_seq(1).encoded_len()
+        );
+    }
+
+    #[test]
+    fn test_encode_streaming_matches_encode() {
+        let record = Record::put(b"key".to_vec(), b"streamed value".to_vec())
+            .unwrap()
+            .with_seq(7);
+
+        let mut buf = Vec::new();
+        let header = Record::put(b"key".to_vec(), Vec::new()).unwrap().with_seq(7);
+        let mut encoder = header
+            .encode_streaming(record.value.len(), &mut buf, VERSION)
+            .unwrap();
+        assert_eq!(buf.len() as u64, record.value_offset());
+        for chunk in record.value.chunks(4) {
+            encoder.update(chunk);
+            buf.extend_from_slice(chunk);
+        }
+        buf.extend_from_slice(&encoder.finish());
+
+        assert_eq!(buf, record.encode().unwrap());
+    }
+
+    #[test]
+    fn test_encode_to_crc_covers_rec_len_to_value() {
+        let record = Record::put(b"key".to_vec(), vec![0xAB; 1000])
+            .unwrap()
+            .with_seq(42);
+
+        // 追加到已有数据之后，CRC 只覆盖这条记录的 rec_len..value
+        let mut buf = b"previous".to_vec();
+        record.encode_to(&mut buf).unwrap();
+        let encoded = &buf[8..];
+        assert_eq!(encoded.len(), record.encoded_len());
+
+        let crc_offset = encoded.len() - 4;
+        let crc = crc32fast::hash(&encoded[4..crc_offset]);
+        assert_eq!(&encoded[crc_offset..], &crc.to_le_bytes());
+    }
+
     #[test]
     fn test_encode_decode_put() {
         let record = Record::put(b"hello".to_vec(), b"world".to_vec()).unwrap();
@@ -398,6 +1353,239 @@ mod tests {
         assert!(matches!(result, Err(Error::CrcMismatch { .. })));
     }
 
+    #[test]
+    fn test_encode_decode_batch_header() {
+        let record = Record::batch(3);
+        let encoded = record.encode().unwrap();
+        assert_eq!(encoded.len(), record.encoded_len());
+
+        let mut cursor = Cursor::new(encoded);
+        let decoded = Record::decode(&mut cursor).unwrap().unwrap();
+
+        assert_eq!(decoded.kind, RecordKind::Batch);
+        assert_eq!(decoded.batch_count(), Some(3));
+    }
+
+    #[test]
+    fn test_encode_decode_seq() {
+        let record = Record::put(b"key".to_vec(), b"value".to_vec())
+            .unwrap()
+            .with_seq(42);
+        let encoded = record.encode().unwrap();
+        assert_eq!(encoded.len(), record.encoded_len());
+        assert_eq!(encoded[9], KIND_PUT | FLAG_SEQ);
+
+        // value 的位置考虑了 seq 字段
+        let value_offset = record.value_offset() as usize;
+        assert_eq!(&encoded[value_offset..value_offset + 5], b"value");
+
+        let mut cursor = Cursor::new(encoded);
+        assert_eq!(Record::decode(&mut cursor).unwrap().unwrap(), record);
+
+        // v1 不能携带序列号
+        let result = record.encode_to_version(&mut Vec::new(), VERSION_V1);
+        assert!(matches!(result, Err(Error::UnsupportedVersion(VERSION_V1))));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_encode_rejects_rec_len_overflow() {
+        // 不分配 4GB：用流式编码只写出头部，value 长度由参数给出
+        let record = Record::put(b"key".to_vec(), Vec::new()).unwrap().with_seq(1);
+        let overhead = record.encoded_len();
+        let max_value = u32::MAX as usize - overhead;
+
+        let mut buf = Vec::new();
+        record.encode_streaming(max_value, &mut buf, VERSION).unwrap();
+        assert_eq!(&buf[4..8], &u32::MAX.to_le_bytes());
+
+        let mut buf = Vec::new();
+        let result = record.encode_streaming(max_value + 1, &mut buf, VERSION);
+        match result {
+            Err(Error::RecordTooLarge { size, max }) => {
+                assert_eq!((size, max), (u32::MAX as usize + 1, u32::MAX as usize));
+            }
+            _ => panic!("expected RecordTooLarge"),
+        }
+        // 拒绝时没有写出任何字节
+        assert!(buf.is_empty());
+
+        // 总长度的加法溢出 usize 时饱和，同样被拒绝
+        let result = record.encode_streaming(usize::MAX, &mut buf, VERSION);
+        assert!(matches!(result, Err(Error::RecordTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_encode_decode_timestamp() {
+        let record = Record::delete(b"key".to_vec())
+            .unwrap()
+            .with_seq(7)
+            .with_timestamp(1_700_000_000_000);
+        let encoded = record.encode().unwrap();
+        assert_eq!(encoded.len(), record.encoded_len());
+        assert_eq!(encoded[9], KIND_DELETE | FLAG_SEQ | FLAG_TIMESTAMP);
+        assert_eq!(&encoded[18..26], &7u64.to_le_bytes());
+        assert_eq!(&encoded[26..34], &1_700_000_000_000u64.to_le_bytes());
+
+        let mut cursor = Cursor::new(encoded);
+        assert_eq!(Record::decode(&mut cursor).unwrap().unwrap(), record);
+
+        // 只带时间戳、不带序列号
+        let record = Record::delete(b"key".to_vec()).unwrap().with_timestamp(1);
+        let mut cursor = Cursor::new(record.encode().unwrap());
+        assert_eq!(Record::decode(&mut cursor).unwrap().unwrap(), record);
+    }
+
+    #[test]
+    fn test_decode_header() {
+        let record = Record::delete(b"key".to_vec())
+            .unwrap()
+            .with_seq(7)
+            .with_timestamp(1_700_000_000_000);
+        let encoded = record.encode().unwrap();
+
+        let mut reader = encoded.as_slice();
+        let header = Record::decode_header(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            header,
+            RecordHeader {
+                rec_len: encoded.len(),
+                version: VERSION,
+                kind: RecordKind::Delete,
+                key_len: 3,
+                val_len: 0,
+                seq: Some(7),
+                timestamp: Some(1_700_000_000_000),
+            }
+        );
+        assert_eq!(header.header_len() as u64, record.value_offset() - 3);
+        // 停在 key 的第一个字节
+        assert_eq!(reader.len(), encoded.len() - header.header_len());
+        assert!(reader.starts_with(b"key"));
+
+        // 空输入是正常结束，半个头部是截断
+        assert!(Record::decode_header(&mut &[][..]).unwrap().is_none());
+        assert!(matches!(
+            Record::decode_header(&mut &encoded[..20]),
+            Err(Error::UnexpectedEof)
+        ));
+
+        // key_len 与 rec_len 不一致
+        let mut bad = encoded.clone();
+        bad[10] = 4;
+        assert!(matches!(
+            Record::decode_header(&mut bad.as_slice()),
+            Err(Error::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn test_decode_rejects_unknown_flags() {
+        let record = Record::put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        let mut encoded = record.encode().unwrap();
+
+        // 设置一个未定义的 flag 并重新计算 CRC
+        encoded[9] |= 0x80;
+        let crc_offset = encoded.len() - 4;
+        let crc = crc32fast::hash(&encoded[4..crc_offset]);
+        encoded[crc_offset..].copy_from_slice(&crc.to_le_bytes());
+
+        let mut cursor = Cursor::new(encoded);
+        let result = Record::decode(&mut cursor);
+        assert!(matches!(result, Err(Error::InvalidRecordKind(_))));
+    }
+
+    #[test]
+    fn test_decode_any_skips_unknown_kind() {
+        let record = Record::put(b"key".to_vec(), b"value".to_vec()).unwrap().with_seq(7);
+        let with_kind_byte = |byte: u8| {
+            let mut encoded = record.encode().unwrap();
+            encoded[9] = byte;
+            let crc_offset = encoded.len() - 4;
+            let crc = crc32fast::hash(&encoded[4..crc_offset]);
+            encoded[crc_offset..].copy_from_slice(&crc.to_le_bytes());
+            encoded
+        };
+        let limits = Limits::default();
+
+        // 未知类型（保留 SEQ flag）：跳过时整条读过，否则报错
+        let encoded = with_kind_byte(FLAG_SEQ | 9);
+        let mut cursor = Cursor::new(encoded.clone());
+        match Record::decode_any(&mut cursor, &limits, true, true).unwrap() {
+            Some(Decoded::Unknown { kind, rec_len }) => {
+                assert_eq!((kind, rec_len), (9, encoded.len()));
+            }
+            _ => panic!("expected an unknown record"),
+        }
+        assert_eq!(cursor.position(), encoded.len() as u64);
+        let result = Record::decode_any(&mut Cursor::new(encoded), &limits, true, false);
+        assert!(matches!(result, Err(Error::InvalidRecordKind(9))));
+
+        // 已知类型带未知 flag：无法解析，不能跳过
+        let encoded = with_kind_byte(0x80 | KIND_PUT);
+        let result = Record::decode_any(&mut Cursor::new(encoded), &limits, true, true);
+        assert!(matches!(result, Err(Error::InvalidRecordKind(_))));
+    }
+
+    #[test]
+    fn test_encode_decode_noop() {
+        for payload in [Vec::new(), 42u64.to_le_bytes().to_vec()] {
+            let record = Record::noop(payload.clone()).unwrap();
+            let mut cursor = Cursor::new(record.encode().unwrap());
+            let decoded = Record::decode(&mut cursor).unwrap().unwrap();
+
+            assert_eq!(decoded.kind, RecordKind::Noop);
+            assert!(decoded.key.is_empty());
+            assert_eq!(decoded.value, payload);
+        }
+
+        let result = Record::noop(vec![0u8; MAX_VALUE_SIZE + 1]);
+        assert!(matches!(result, Err(Error::ValueTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_encode_to_appends() {
+        let r1 = Record::put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        let r2 = Record::delete(b"k2".to_vec()).unwrap();
+
+        let mut buf = Vec::new();
+        r1.encode_to(&mut buf).unwrap();
+        r2.encode_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), r1.encoded_len() + r2.encoded_len());
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(Record::decode(&mut cursor).unwrap().unwrap(), r1);
+        assert_eq!(Record::decode(&mut cursor).unwrap().unwrap(), r2);
+        assert!(Record::decode(&mut cursor).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_encode_decode_versions() {
+        let record = Record::put(b"key".to_vec(), b"value".to_vec()).unwrap();
+
+        // 默认使用当前版本
+        let mut cursor = Cursor::new(record.encode().unwrap());
+        let (decoded, version) = Record::decode_with_version(&mut cursor, &Limits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!((decoded, version), (record.clone(), VERSION));
+
+        // v1 记录布局相同，仍然可以解码
+        let mut buf = Vec::new();
+        record.encode_to_version(&mut buf, VERSION_V1).unwrap();
+        assert_eq!(buf.len(), record.encoded_len());
+        assert_eq!(buf[8], VERSION_V1);
+        let mut cursor = Cursor::new(buf);
+        let (decoded, version) = Record::decode_with_version(&mut cursor, &Limits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!((decoded, version), (record.clone(), VERSION_V1));
+
+        // 未知版本
+        let result = record.encode_to_version(&mut Vec::new(), VERSION + 1);
+        assert!(matches!(result, Err(Error::UnsupportedVersion(_))));
+    }
+
     #[test]
     fn test_key_too_large() {
         let large_key = vec![0u8; MAX_KEY_SIZE + 1];
@@ -411,4 +1599,60 @@ mod tests {
         let result = Record::put(b"key".to_vec(), large_value);
         assert!(matches!(result, Err(Error::ValueTooLarge { .. })));
     }
+
+    #[test]
+    fn test_decode_with_limits_rejects_oversized_record() {
+        let record = Record::put(b"key".to_vec(), vec![0u8; 100]).unwrap();
+        let encoded = record.encode().unwrap();
+
+        let limits = Limits {
+            max_key_size: 16,
+            max_value_size: 64,
+        };
+
+        // rec_len 超出限制，在读取 key/value 之前就被拒绝
+        let mut cursor = Cursor::new(encoded.clone());
+        assert!(Record::decode_with_limits(&mut cursor, &limits).is_err());
+        assert_eq!(cursor.position(), 8); // 只读取了 magic + rec_len
+
+        // 默认限制下正常解码
+        let mut cursor = Cursor::new(encoded);
+        assert_eq!(Record::decode(&mut cursor).unwrap().unwrap(), record);
+    }
+
+    #[test]
+    fn test_decode_with_limits_rejects_oversized_key() {
+        // rec_len 在限制之内，但 key 超限
+        let record = Record::put(vec![b'k'; 32], Vec::new()).unwrap();
+        let encoded = record.encode().unwrap();
+
+        let limits = Limits {
+            max_key_size: 16,
+            max_value_size: 64,
+        };
+
+        let mut cursor = Cursor::new(encoded);
+        let result = Record::decode_with_limits(&mut cursor, &limits);
+        assert!(matches!(
+            result,
+            Err(Error::KeyTooLarge { size: 32, max: 16 })
+        ));
+    }
+
+    #[test]
+    fn test_put_with_limits() {
+        let limits = Limits {
+            max_key_size: 4,
+            max_value_size: 4 * 1024 * 1024,
+        };
+
+        // 超出默认限制但在自定义限制之内
+        let large_value = vec![0u8; MAX_VALUE_SIZE + 1];
+        assert!(Record::put_with_limits(b"key".to_vec(), large_value, &limits).is_ok());
+
+        let result = Record::put_with_limits(b"long key".to_vec(), Vec::new(), &limits);
+        assert!(matches!(result, Err(Error::KeyTooLarge { .. })));
+        let result = Record::delete_with_limits(b"long key".to_vec(), &limits);
+        assert!(matches!(result, Err(Error::KeyTooLarge { .. })));
+    }
 }
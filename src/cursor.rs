@@ -0,0 +1,203 @@
+//! 复制游标
+//!
+//! 本模块实现 `Db::save_replication_cursor` / `Db::load_replication_cursor`：
+//! 每个具名的 follower 把自己已经复制到的序列号保存在数据库目录中，重启后从这里继续。
+//!
+//! ## 文件布局
+//!
+//! 每个游标一个文件：`<dir>/cursors/<name>.cursor`，内容是 12 字节：
+//!
+//! ```text
+//! +----------+--------+
+//! | seq (8B) | crc32  |
+//! +----------+--------+
+//! ```
+//!
+//! `crc32` 覆盖 `seq`。文件不完整或校验失败时当作游标不存在，follower 退回全量同步。
+//!
+//! ## 原子性与并发
+//!
+//! 写入时先写一个临时文件并 fsync，再 rename 为正式文件（最后 fsync 目录），
+//! 读取者只会看到旧值或新值。不同名字的游标是不同的文件，互不影响；
+//! 临时文件名包含进程号和一个递增计数，同一个游标被并发保存时各自写自己的临时文件，
+//! 最后一次 rename 的值生效。
+
+use crate::error::Result;
+use crate::wal::sync_dir;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 游标所在的子目录
+const CURSOR_DIRNAME: &str = "cursors";
+
+/// 游标文件的扩展名
+const CURSOR_EXTENSION: &str = "cursor";
+
+/// 游标名的最大长度（字节）
+const MAX_NAME_LEN: usize = 128;
+
+/// 游标文件的长度：seq + crc32
+const CURSOR_FILE_LEN: usize = 12;
+
+/// 临时文件名中的递增计数
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// 原子地保存游标 `name` 的序列号
+pub fn save(dir: &Path, name: &str, seq: u64) -> Result<()> {
+    let path = cursor_path(dir, name)?;
+    let cursor_dir = dir.join(CURSOR_DIRNAME);
+    std::fs::create_dir_all(&cursor_dir)?;
+
+    let mut buf = Vec::with_capacity(CURSOR_FILE_LEN);
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+
+    // 1. 写入自己的临时文件并 fsync
+    let tmp_path = cursor_dir.join(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(&buf)?;
+            file.sync_data()
+        });
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    // 2. 原子地替换
+    if let Err(e) = std::fs::rename(&tmp_path, &path) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    sync_dir(&cursor_dir)?;
+
+    Ok(())
+}
+
+/// 读取游标 `name`
+///
+/// ## 返回值
+///
+/// - `Ok(Some(seq))`: 游标存在且校验通过
+/// - `Ok(None)`: 游标不存在，或文件损坏
+/// - `Err(Error)`: 游标名不合法，或其他 I/O 错误
+pub fn load(dir: &Path, name: &str) -> Result<Option<u64>> {
+    let path = cursor_path(dir, name)?;
+    let buf = match std::fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if buf.len() != CURSOR_FILE_LEN {
+        return Ok(None);
+    }
+
+    let (seq, crc) = buf.split_at(8);
+    if crc32fast::hash(seq).to_le_bytes() != crc {
+        return Ok(None);
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(seq);
+    Ok(Some(u64::from_le_bytes(bytes)))
+}
+
+/// 所有有效游标中最小的序列号，没有任何有效游标时返回 `None`
+///
+/// 损坏的游标与 [`load`] 一样当作不存在：对应的 follower 会退回全量同步。
+pub fn min_seq(dir: &Path) -> Result<Option<u64>> {
+    let entries = match std::fs::read_dir(dir.join(CURSOR_DIRNAME)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut min: Option<u64> = None;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(CURSOR_EXTENSION) {
+            continue;
+        }
+        // 临时文件以 `.` 开头，名字不合法，这里一并跳过
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if let Ok(Some(seq)) = load(dir, name) {
+            min = Some(min.map_or(seq, |min| min.min(seq)));
+        }
+    }
+    Ok(min)
+}
+
+/// 游标文件的路径，游标名不合法时返回 `ErrorKind::InvalidInput` 的 I/O 错误
+///
+/// 名字直接作为文件名，只允许 ASCII 字母、数字、`-`、`_` 和 `.`，且不能以 `.` 开头
+/// （排除 `..` 之类的路径和临时文件）。
+fn cursor_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if !valid {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid replication cursor name: {:?}", name),
+        )
+        .into());
+    }
+
+    Ok(dir
+        .join(CURSOR_DIRNAME)
+        .join(format!("{}.{}", name, CURSOR_EXTENSION)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(load(dir.path(), "replica-1").unwrap(), None);
+        assert_eq!(min_seq(dir.path()).unwrap(), None);
+
+        save(dir.path(), "replica-1", 42).unwrap();
+        save(dir.path(), "replica_2", 7).unwrap();
+        save(dir.path(), "replica-1", 43).unwrap();
+        assert_eq!(load(dir.path(), "replica-1").unwrap(), Some(43));
+        assert_eq!(load(dir.path(), "replica_2").unwrap(), Some(7));
+
+        // 没有残留的临时文件
+        let files = std::fs::read_dir(dir.path().join(CURSOR_DIRNAME)).unwrap().count();
+        assert_eq!(files, 2);
+
+        // 损坏的游标当作不存在
+        let path = cursor_path(dir.path(), "replica_2").unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[0] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(load(dir.path(), "replica_2").unwrap(), None);
+        assert_eq!(min_seq(dir.path()).unwrap(), Some(43));
+        save(dir.path(), "replica-3", 5).unwrap();
+        assert_eq!(min_seq(dir.path()).unwrap(), Some(5));
+
+        // 不合法的名字
+        for name in ["", "..", "../escape", "a/b", ".hidden"] {
+            assert!(save(dir.path(), name, 1).is_err());
+            assert!(load(dir.path(), name).is_err());
+        }
+    }
+}
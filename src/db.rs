@@ -75,23 +75,145 @@
 //!
 //! 未来版本可以增加 LRU 缓存来优化热点数据读取。
 
-use crate::codec::{Record, RecordKind};
-use crate::error::Result;
-use crate::wal::{ReplayStats, Wal};
+use crate::cache::{CacheResult, CacheStats, ValueCache};
+use crate::codec::{
+    Limits, Record, RecordKind, ValueEncoder, ValueRef, MAGIC, VALUE_REF_LEN, VERSION, VERSION_V1,
+};
+use crate::batch::{BatchOp, WriteBatch};
+use crate::cursor;
+use crate::error::{Error, Result};
+use crate::index::{Index, Tombstone, ValuePos};
+use crate::intern::InternTable;
+use crate::lock::DirLock;
+use crate::manifest::Manifest;
+use crate::snapshot;
+use crate::subscribe::{ChangeEvent, ChangeKind, Subscribers, WatchId};
+use crate::syncer::Syncer;
+use crate::wal::{
+    create_new_file, restrict_dir, sync_dir, PreparedRewrite, ReplayStats, ReplayedRecord,
+    RewriteContent, Wal, WalOptions, WalReader, COMPACT_TMP_FILENAME, WAL_FILENAME,
+};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs::File;
+use std::io::{IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::fmt;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-/// Value 在 WAL 文件中的位置信息
-#[derive(Debug, Clone, Copy)]
-struct ValuePos {
-    /// value 的起始偏移量（字节）
-    offset: u64,
-    /// value 的长度（字节）
-    len: usize,
+/// 一个键值对：(key, value)
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+/// 压缩进度回调至少间隔这么多字节调用一次（最后一次调用除外）
+const COMPACT_PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+/// [`Db::scan_prefix_each`] 每次预读多少个 value（按偏移量排序后读取）
+const SCAN_READ_AHEAD: usize = 64;
+
+/// [`Db::health_check`] 抽查多少个 key
+const HEALTH_CHECK_SAMPLE: usize = 4;
+
+/// 压缩进度回调，见 [`Options::on_compact_progress`]
+///
+/// 包装一个 `Fn(bytes_processed, bytes_total)`，克隆时共享同一个闭包。
+#[derive(Clone)]
+pub struct CompactProgress(Arc<dyn Fn(u64, u64) + Send + Sync>);
+
+impl CompactProgress {
+    /// 用闭包创建一个进度回调
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(u64, u64) + Send + Sync + 'static,
+    {
+        CompactProgress(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for CompactProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactProgress(..)")
+    }
+}
+
+/// 写入失败回调，见 [`Options::on_write_error`]
+///
+/// 包装一个 `Fn(&Error)`，克隆时共享同一个闭包。
+#[derive(Clone)]
+pub struct WriteErrorHook(Arc<dyn Fn(&Error) + Send + Sync>);
+
+impl WriteErrorHook {
+    /// 用闭包创建一个写入失败回调
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        WriteErrorHook(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for WriteErrorHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WriteErrorHook(..)")
+    }
+}
+
+/// 冲突解决函数：`(key, ours, theirs) -> value`
+type ResolveFn = Box<dyn FnMut(&[u8], &[u8], &[u8]) -> Vec<u8>>;
+
+/// [`Db::merge_from_dir`] 遇到两边都存在的 key 时如何处理
+pub enum ConflictPolicy {
+    /// 保留 `self` 中的 value
+    KeepSelf,
+    /// 用另一个数据库中的 value 覆盖
+    TakeOther,
+    /// 调用 `f(key, ours, theirs)`，写入它返回的 value
+    Resolve(ResolveFn),
+}
+
+impl ConflictPolicy {
+    /// 用闭包创建一个 [`ConflictPolicy::Resolve`]
+    pub fn resolve<F>(f: F) -> Self
+    where
+        F: FnMut(&[u8], &[u8], &[u8]) -> Vec<u8> + 'static,
+    {
+        ConflictPolicy::Resolve(Box::new(f))
+    }
+}
+
+impl fmt::Debug for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::KeepSelf => f.write_str("KeepSelf"),
+            ConflictPolicy::TakeOther => f.write_str("TakeOther"),
+            ConflictPolicy::Resolve(_) => f.write_str("Resolve(..)"),
+        }
+    }
 }
 
 /// 数据库配置选项
+///
+/// 标记为 `#[non_exhaustive]`：以后的版本会继续增加字段，crate 外部不能用结构体字面量
+/// （包括 `..Options::default()`）构造它。请使用 [`Options::builder`]，
+/// 或者从 `Options::default()` 开始逐个修改字段。
+///
+/// ## 运行时调整
+///
+/// 以下选项可以在打开之后通过 [`Db::set_options`]（或专门的 setter）修改，立即生效：
+///
+/// - `sync_on_write`（[`Db::set_sync_mode`]）
+/// - `cache_capacity_bytes`（[`Db::resize_cache`]）
+/// - `auto_compact_ratio`（[`Db::set_auto_compact_ratio`]）
+/// - `checkpoint_interval_bytes`、`tombstone_ttl`、`dedup_values`、`intern_small_values`、
+///   `on_compact_progress`、`on_write_error`
+///
+/// 其余选项影响磁盘格式、目录锁或打开流程，只在 `open` 时生效，
+/// 试图在运行时修改会返回 `Error::FixedOption`。
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct Options {
     /// 是否在每次写入后同步到磁盘
     ///
@@ -105,36 +227,628 @@ pub struct Options {
     ///
     /// 默认：`true`（安全优先）
     pub sync_on_write: bool,
+
+    /// key / value 大小限制
+    ///
+    /// 同时作用于写入（`put`/`delete` 拒绝超限的 key/value）和打开时的 replay
+    /// （超限的记录被视为损坏）。
+    ///
+    /// 注意：不要把限制调小到已存储数据之下，否则 replay 会把这些记录
+    /// 当作损坏数据截断。
+    ///
+    /// 默认：key 最大 1KB，value 最大 1MB
+    pub limits: Limits,
+
+    /// 自动 checkpoint 的间隔（字节）
+    ///
+    /// - `Some(n)`: 自上次 checkpoint 以来 WAL 增长超过 n 字节后，
+    ///   在写操作结束时自动调用 [`Db::checkpoint`]
+    /// - `None`: 只在手动调用 `checkpoint()` 时写入 MANIFEST
+    ///
+    /// 每次 checkpoint 都会序列化整个索引，间隔太小会拖慢写入。
+    /// 自动 checkpoint 失败不会让触发它的写操作失败，见 [`Db::take_maintenance_error`]。
+    ///
+    /// 默认：`None`
+    pub checkpoint_interval_bytes: Option<u64>,
+
+    /// 以只读模式打开
+    ///
+    /// - 数据库目录和 WAL 文件必须已经存在，不会自动创建
+    /// - replay 遇到不完整的尾部记录只是停下，不截断文件（文件可能正被主库写入）
+    /// - `put`/`delete` 等写操作返回 `Error::ReadOnly`
+    /// - 可以调用 [`Db::tail`] 追上主库新追加的记录
+    ///
+    /// 默认：`false`
+    pub read_only: bool,
+
+    /// 目录锁被占用时，打开数据库最多等待多久
+    ///
+    /// 可写打开时会在目录中的 `LOCK` 文件上加独占锁，防止两个 `Db` 同时写入同一个目录
+    /// （只读打开不加锁）。锁在 `Db` 被 drop 或进程退出时自动释放。
+    ///
+    /// - `None`: 锁被占用时立即返回 `Error::AlreadyOpen`
+    /// - `Some(d)`: 以指数退避反复尝试（最长间隔 100ms），`d` 之内仍未获得锁时
+    ///   返回 `Error::AlreadyOpen`
+    ///
+    /// 适合由进程管理器滚动重启的场景：旧进程可能在新进程启动后才释放锁。
+    ///
+    /// 默认：`None`
+    pub open_lock_timeout: Option<Duration>,
+
+    /// 写缓冲区大小（字节）
+    ///
+    /// - `0`: 每次写操作都立即写入文件（一次 `write` 系统调用）
+    /// - `n > 0`: 写操作先追加到内存缓冲区，累计达到 n 字节后才写入文件，
+    ///   大量小写入时可以显著减少系统调用次数
+    ///
+    /// 只在 `sync_on_write: false` 时有意义：需要 fsync 的写入总是先写入文件。
+    ///
+    /// 与读取的关系：还在缓冲区中的 value 由 `get` 直接从缓冲区返回，
+    /// 刚写入的数据总是可以立即读到。
+    ///
+    /// 持久性：缓冲区中的数据在进程崩溃时会丢失（正常 drop 时会写入文件）。
+    /// 需要保证持久化时调用 [`Db::sync`]。
+    ///
+    /// 默认：`0`
+    pub write_buffer_bytes: usize,
+
+    /// 后台写回队列的容量（字节）
+    ///
+    /// - `None`: 写操作在调用线程上写入文件
+    /// - `Some(n)`: 启动一个后台线程负责写入文件。写操作把编码好的记录放入内存队列后
+    ///   立即返回，不等待 `write` 系统调用；队列中超过 n 字节时写操作阻塞（背压），
+    ///   直到后台线程写完足够的数据，内存占用不会超过 n（加上一个写缓冲区）
+    ///
+    /// 适合突发的大量写入：磁盘短暂跟不上时由队列吸收，持续跟不上时自然限速。
+    /// 配合 `write_buffer_bytes` 使用时，写缓冲区满了才整块放入队列。
+    ///
+    /// 与读取的关系：还在队列中的 value 由 `get` 直接从队列返回，
+    /// 刚写入的数据总是可以立即读到。
+    ///
+    /// 持久性：比 `write_buffer_bytes` 更弱。`put` 返回时数据可能还不在 OS 缓冲区中，
+    /// 进程崩溃时队列中的数据全部丢失（正常 drop 时会等待队列写完）；
+    /// 后台写入失败的错误在下一次写操作或 [`Db::sync`] 时返回。
+    /// 需要保证持久化时必须显式调用 [`Db::sync`]：它等待队列写完再 fsync。
+    /// `sync_on_write: true` 时每次写操作都会这样等待，队列不再起作用。
+    ///
+    /// 启用 `direct_io`（并且实际生效）时忽略此选项；只读模式下同样忽略。
+    ///
+    /// 默认：`None`
+    pub write_back_bytes: Option<usize>,
+
+    /// 打开时把旧格式的 WAL 升级到当前格式
+    ///
+    /// - `true`: 如果 WAL 使用旧格式（例如 v1），`open` 时用当前格式重写整个 WAL
+    ///   （只保留每个 key 的最新值），之后只会写入当前格式
+    /// - `false`: 旧格式照常读取，之后的写入继续使用 WAL 中已有的最高版本，
+    ///   不会在同一个文件中混入新格式的记录
+    ///
+    /// 重写期间 WAL 会被完整复制一次，大数据库的打开时间会相应变长。
+    /// 只读模式下忽略此选项。
+    ///
+    /// 默认：`false`
+    pub upgrade_format: bool,
+
+    /// 后台 fsync 的间隔
+    ///
+    /// - `Some(d)`: 启动一个后台线程，每隔 `d` fsync 一次 WAL，
+    ///   `Db` 被 drop 时线程退出并执行最后一次 fsync
+    /// - `None`: 不启动后台线程，只在 `sync_on_write` 或手动调用 [`Db::sync`] 时 fsync
+    ///
+    /// 用于 `sync_on_write: false` 时给丢数据的范围设一个上限：
+    /// 崩溃（包括断电）最多丢失最近 `d` 时间内的写入。
+    ///
+    /// 注意：后台线程只 fsync 已写入文件的数据。启用 `write_buffer_bytes` 时，
+    /// 还在写缓冲区中的数据不在此保证之内，最坏情况下额外丢失一个缓冲区的写入。
+    /// 后台 fsync 失败时，下一次写操作返回这个错误，数据库进入中毒状态
+    /// （见 [`Db::is_poisoned`]）。只读模式下忽略此选项。
+    ///
+    /// 默认：`None`
+    pub flush_interval: Option<Duration>,
+
+    /// 打开时跳过 WAL 中间的损坏区域继续恢复
+    ///
+    /// - `false`: 遇到第一条损坏记录就截断文件，之后的数据全部丢弃（保守策略）
+    /// - `true`: 遇到损坏记录时向后逐字节寻找下一条完整、CRC 校验通过的记录，
+    ///   从那里继续 replay；跳过的区间记录在 [`ReplayStats`] 的
+    ///   `skipped_bytes` 和 `gaps` 中。文件末尾找不到有效记录时仍然照常截断
+    ///
+    /// 适合从单个坏扇区等局部损坏中抢救数据。代价是被跳过的写入（包括删除）
+    /// 直接丢失，恢复出的状态可能是一个从未真实存在过的中间状态；
+    /// 损坏区域所在的批次整体丢弃，不会只应用一半。
+    ///
+    /// 出现跳过的区间时（非只读模式），`open` 会立即重写 WAL 去掉损坏的字节，
+    /// 之后不开启此选项也能正常打开。
+    ///
+    /// 默认：`false`
+    pub scan_resync: bool,
+
+    /// 打开时发现 WAL 损坏就报错，而不是截断
+    ///
+    /// - `false`: 不完整或损坏的尾部记录被截断，`open` 照常成功（见 [`ReplayStats`]）
+    /// - `true`: replay 需要截断尾部，或 `scan_resync` 需要跳过损坏区间时，
+    ///   `open` 返回 `Error::CorruptedWal { valid_records, truncated_bytes }`，
+    ///   不修改 WAL 文件，由运维人员检查后再决定如何处理
+    ///
+    /// 适合不允许恢复过程静默丢数据的场景。确认可以丢弃损坏部分后，
+    /// 关闭此选项重新打开即可按正常流程恢复。崩溃时写了一半的最后一条记录
+    /// 也算作损坏，因此开启后断电重启可能需要人工介入。只读模式下忽略此选项
+    /// （只读打开本来就不修改文件）。
+    ///
+    /// 默认：`false`
+    pub fail_on_corruption: bool,
+
+    /// replay 时跳过类型未知的记录，而不是把它们当作损坏
+    ///
+    /// 更新版本的 kvslite 可能写入新的记录类型。这些记录与已有的类型使用相同的外层格式
+    /// （magic、rec_len、头部、crc），旧版本不理解它们的含义，但能判断它们是否完整：
+    ///
+    /// - `false`: 未知类型的记录与损坏的记录一样处理，默认会从那里截断 WAL
+    /// - `true`: CRC 正确的未知记录按 `rec_len` 整条跳过，继续 replay 之后的记录，
+    ///   跳过的条数见 [`ReplayStats::skipped_unknown`]
+    ///
+    /// 被跳过的记录不进入索引，这个版本的压缩会把它们丢弃。用于新旧版本混合部署或回滚：
+    /// 开启后旧版本至少不会因为一条新类型的记录丢掉它之后的所有数据。
+    ///
+    /// 默认：`false`
+    pub skip_unknown_kinds: bool,
+
+    /// 新建文件的权限位（仅 Unix），例如 `Some(0o600)`
+    ///
+    /// - `None`: 使用进程的默认权限（受 umask 影响）
+    /// - `Some(mode)`: 新建的 WAL 和 MANIFEST 使用 `mode`，不受 umask 影响；
+    ///   新建数据库时目录同时收紧为 `mode` 加上对应的执行位（`0o600` → `0o700`）
+    ///
+    /// 只作用于新建的文件和目录，不会修改已有数据库的权限；压缩等重写生成的 WAL 沿用原文件的权限。
+    /// 非 Unix 平台上忽略。
+    ///
+    /// 默认：`None`
+    pub file_mode: Option<u32>,
+
+    /// 以 `O_DIRECT` 写入 WAL，绕过 OS 页缓存
+    ///
+    /// - `true`: WAL 的写句柄以 `O_DIRECT` 打开，写入按文件系统块大小对齐，
+    ///   数据不经过页缓存，避免后台回写带来的周期性延迟抖动
+    /// - `false`: 普通的缓冲写入
+    ///
+    /// 对齐的实现：文件最后一个不完整的块保存在内存中，每次写入都从该块起始位置
+    /// 整块写入（末尾用 0 补齐），下一次写入覆盖填充。小记录的写放大最多一个块，
+    /// 建议配合 `write_buffer_bytes` 使用。读取和压缩仍然走普通 I/O。
+    ///
+    /// 平台：只在 Linux、Android、FreeBSD 上生效。其他平台，或文件系统不支持
+    /// `O_DIRECT`（例如 tmpfs）时静默回退到普通写入。`O_DIRECT` 不等于持久化，
+    /// 仍然需要 `sync_on_write` 或 [`Db::sync`] 才能保证数据落盘。
+    ///
+    /// 默认：`false`
+    pub direct_io: bool,
+
+    /// value 缓存的容量（字节）
+    ///
+    /// - `0`: 不缓存，每次 `get` 都从 WAL 读取
+    /// - `n > 0`: 在内存中缓存最近读写的 value（按 key + value 的字节数计算），
+    ///   超出容量时淘汰最久未使用的条目；命中时 `get` 不访问磁盘
+    ///
+    /// `put` 会直接更新缓存，删除、覆盖等写操作会使对应的条目失效，
+    /// 缓存中的 value 总是与索引一致。单个超过容量的 value 不缓存。
+    ///
+    /// 默认：`0`
+    pub cache_capacity_bytes: usize,
+
+    /// 自动压缩的垃圾比例阈值
+    ///
+    /// - `Some(ratio)`: 写操作（`put`、`delete` 等）成功后，如果 WAL 中可以被压缩回收的
+    ///   比例超过 `ratio`，立即执行一次 [`Db::compact`]（与 [`Db::compact_if_needed`] 的
+    ///   判断相同）
+    /// - `None`: 不自动压缩
+    ///
+    /// 压缩在触发它的写操作中同步执行，这次写入的延迟会包含整个压缩。
+    /// 只在写操作中检查，只读的负载不会被压缩阻塞。已经有压缩在进行时
+    /// （[`Db::compact_concurrent`]）不检查，等它结束后的写入再判断。
+    /// `ratio` 应在 0.0 ~ 1.0 之间：
+    /// 太小会让覆盖写频繁地触发压缩，不小于 1.0 时永远不会触发。
+    ///
+    /// 触发压缩的写入在压缩之前已经提交，压缩失败不会让这次写操作返回错误，
+    /// 错误见 [`Db::take_maintenance_error`]。
+    ///
+    /// 默认：`None`
+    pub auto_compact_ratio: Option<f64>,
+
+    /// 内联保存在索引中的 value 的最大长度（字节）
+    ///
+    /// - `0`: 索引只保存 value 的位置，每次 `get` 都需要一次随机读（或命中缓存）
+    /// - `n > 0`: 长度不超过 n 的 value 在写入和 replay 时复制一份放进索引，
+    ///   `get` 直接返回，不访问 WAL，也不占用 value 缓存
+    ///
+    /// 适合以很小的 value（计数器、标志位）为主的负载：多花一些索引内存，
+    /// 省掉几乎所有的随机读。内联的字节数计入 [`Db::index_memory_bytes`]。
+    ///
+    /// 从 MANIFEST 加载的 key 和流式写入（`put_reserve`）的 value 没有经过内存，
+    /// 在第一次 `get` 读到之后才内联。
+    ///
+    /// 默认：`0`
+    pub inline_value_threshold: usize,
+
+    /// 打开时为内存索引预先分配的容量（key 的数量）
+    ///
+    /// - `0`: 按 MANIFEST 中的条目数加上需要 replay 的记录数估算。记录里有大量覆盖和删除时
+    ///   估算偏大，replay 结束后收缩到实际需要的大小
+    /// - `n > 0`: 预先分配能容纳 `n` 个 key 的容量；实际 key 更多时照常扩容
+    ///
+    /// 预先分配避免 replay 过程中索引逐步扩容、反复 rehash，几百万个 key 时能明显缩短
+    /// 打开时间。已知 key 数量时设置为略大于它的值最合适。
+    ///
+    /// 默认：`0`
+    pub initial_index_capacity: usize,
+
+    /// 压缩时保留删除墓碑（DELETE 记录）的时长
+    ///
+    /// - `None`: 压缩丢弃所有 DELETE 记录
+    /// - `Some(ttl)`: 写入时间在 `ttl` 之内的 DELETE 记录在压缩后保留下来，
+    ///   更早的才被丢弃
+    ///
+    /// 用于复制场景：从 [`Db::changes_since`] 同步的副本如果落后于压缩，
+    /// 删除记录被丢弃后它就永远看不到这次删除。`ttl` 应当大于副本可能落后的最长时间。
+    ///
+    /// v2 格式的 DELETE 记录都带有写入时间；v1 记录没有时间，压缩时总是被丢弃。
+    ///
+    /// 默认：`None`
+    pub tombstone_ttl: Option<Duration>,
+
+    /// 键空间前缀
+    ///
+    /// 非空时，按 key 读写的方法（`put`、`get`、`delete`、`scan_prefix_limited`、
+    /// `keys_paginated`、`for_each` 等）在每个 key 前面透明地加上这个前缀，
+    /// 返回的 key 去掉前缀。同一个目录用不同的前缀打开，看到的是互不相交的键空间，
+    /// 前缀之外的 key 对这个句柄不可见。
+    ///
+    /// 作用于整个存储的操作不受影响：`stats`、压缩、`checkpoint`、`changes_since`、
+    /// `apply_record`、`subscribe` 和 `raw_record_at` 看到的都是带前缀的完整 key。
+    ///
+    /// 前缀计入 `limits` 中的 key 大小上限。目录锁仍然只允许一个可写句柄，
+    /// 不同前缀的可写句柄需要依次打开（或者配合只读句柄使用）。
+    ///
+    /// 默认：空（不加前缀）
+    pub key_prefix: Vec<u8>,
+
+    /// 压缩时是否对 value 去重
+    ///
+    /// 开启后，[`Db::compact`]、[`Db::compact_concurrent`] 以及打开时的格式升级重写
+    /// 对每个 value 计算哈希：相同的 value 只写出一次，之后持有相同 value 的 key
+    /// 写成一条 REF 记录，指向第一次写出的位置，这些 key 的索引项共享同一个
+    /// `ValuePos`。适合大量 key 存放相同大块 value 的场景。
+    ///
+    /// - 只有长度超过 24 字节（REF 记录的负载大小）的 value 参与去重
+    /// - 压缩期间为每个不同的 value 保留一个哈希表项（约 48 字节加上哈希表开销），
+    ///   key 数量很多且 value 各不相同时会明显增加压缩的内存占用
+    /// - 哈希命中后会读回已写出的 value 逐字节比较，哈希冲突不会让 key 读到别的 value
+    ///
+    /// 共享的位置是安全的：压缩后的文件只追加不修改，被引用的 value 字节在下一次
+    /// 压缩之前一直有效，即使持有它的 key 之后被覆盖或删除；下一次压缩按新的索引
+    /// 重新写出并重新去重。[`Db::compact_into`] 写出的是完整的 PUT 记录，不做去重。
+    ///
+    /// 默认：`false`
+    pub dedup_values: bool,
+
+    /// 写入时对重复的小 value 只保存一份
+    ///
+    /// - `None`: 每次 `put` 都追加一份完整的 value
+    /// - `Some(max)`: 长度不超过 `max` 字节的 value 在 `put` 时查找最近写入过的相同 value，
+    ///   找到时写一条 REF 记录指向已有的那一份，索引项复用它的 `ValuePos`，
+    ///   不再追加新的副本。适合把同一个标志、枚举值写给大量 key 的负载
+    ///
+    /// 与 [`Options::dedup_values`] 不同，这发生在写入时，不需要等到压缩：
+    ///
+    /// - 只有长度超过 24 字节（REF 记录的负载大小）的 value 参与，更短的 value
+    ///   写成 REF 并不能省空间；旧格式（v1）的 WAL 不做驻留
+    /// - 共享的位置是安全的：WAL 只追加，被引用的 PUT 记录在下一次重写（压缩、格式升级）
+    ///   之前一直在原位，即使最初写入它的 key 之后被覆盖或删除。重写时驻留表被清空，
+    ///   重写后的文件由压缩按新的索引写出
+    /// - 内存：驻留表以 value 内容为键，最多保存 4096 个不同的 value，满了之后整张表清空
+    ///   重新积累，约占 4096 × (`max` + 80) 字节
+    /// - 只有 `put` 驻留；批量写入、流式写入和 follower 应用的变更照常写完整的 value
+    ///
+    /// 默认：`None`
+    pub intern_small_values: Option<usize>,
+
+    /// 压缩进度回调
+    ///
+    /// - `Some(f)`: [`Db::compact`]、[`Db::compact_into`]、[`Db::compact_concurrent`]
+    ///   以及打开时的格式升级重写
+    ///   写出记录的过程中调用 `f(bytes_processed, bytes_total)`，
+    ///   大约每写出 1MB 调用一次，最后一次调用时 `bytes_processed == bytes_total`
+    /// - `None`: 不报告进度
+    ///
+    /// `bytes_total` 是压缩后文件的大小，在开始写入前就已经精确算出，
+    /// 可以直接用来显示百分比。开启 [`Options::dedup_values`] 时它是不去重的大小，
+    /// 只是上限：实际写出的字节更少，写完时补报一次 `(bytes_total, bytes_total)`。
+    /// 回调在执行压缩的线程上同步调用，应当尽快返回。
+    ///
+    /// 默认：`None`
+    pub on_compact_progress: Option<CompactProgress>,
+
+    /// 写入失败回调
+    ///
+    /// - `Some(f)`: 追加或 fsync WAL 时发生 I/O 错误（包括 `Error::DiskFull`），
+    ///   在写操作返回错误之前调用 `f(&error)`
+    /// - `None`: 不回调
+    ///
+    /// 无论是否设置回调，这样的错误都会让数据库进入中毒状态（见 [`Db::is_poisoned`]）。
+    /// 参数检查失败（key 过大、只读模式等）不会触发回调。回调在执行写操作的线程上
+    /// 同步调用，可以用来触发熔断、通知运维，不应再访问这个 `Db`。
+    ///
+    /// 默认：`None`
+    pub on_write_error: Option<WriteErrorHook>,
 }
 
 impl Default for Options {
     fn default() -> Self {
         Options {
             sync_on_write: true,
+            limits: Limits::default(),
+            checkpoint_interval_bytes: None,
+            read_only: false,
+            open_lock_timeout: None,
+            write_buffer_bytes: 0,
+            write_back_bytes: None,
+            upgrade_format: false,
+            flush_interval: None,
+            scan_resync: false,
+            fail_on_corruption: false,
+            skip_unknown_kinds: false,
+            file_mode: None,
+            direct_io: false,
+            cache_capacity_bytes: 0,
+            auto_compact_ratio: None,
+            inline_value_threshold: 0,
+            initial_index_capacity: 0,
+            tombstone_ttl: None,
+            key_prefix: Vec::new(),
+            dedup_values: false,
+            intern_small_values: None,
+            on_compact_progress: None,
+            on_write_error: None,
         }
     }
 }
 
+impl Options {
+    /// 创建一个 [`OptionsBuilder`]，从默认配置开始链式设置
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let opts = Options::builder()
+    ///     .sync_on_write(false)
+    ///     .write_buffer_bytes(64 * 1024)
+    ///     .build();
+    /// let db = Db::open("data/db1", opts).unwrap();
+    /// ```
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+}
+
+/// [`Options`] 的链式构造器，由 [`Options::builder`] 创建
+///
+/// 每个方法对应 `Options` 中的同名字段，含义和默认值见字段文档。
+/// 用构造器写出的代码不会因为 `Options` 新增字段而需要修改。
+#[derive(Debug, Clone, Default)]
+pub struct OptionsBuilder {
+    opts: Options,
+}
+
+impl OptionsBuilder {
+    /// 见 [`Options::sync_on_write`]
+    pub fn sync_on_write(mut self, sync_on_write: bool) -> Self {
+        self.opts.sync_on_write = sync_on_write;
+        self
+    }
+
+    /// 见 [`Options::limits`]
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.opts.limits = limits;
+        self
+    }
+
+    /// 见 [`Options::checkpoint_interval_bytes`]
+    pub fn checkpoint_interval_bytes(mut self, bytes: Option<u64>) -> Self {
+        self.opts.checkpoint_interval_bytes = bytes;
+        self
+    }
+
+    /// 见 [`Options::read_only`]
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.opts.read_only = read_only;
+        self
+    }
+
+    /// 见 [`Options::open_lock_timeout`]
+    pub fn open_lock_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.opts.open_lock_timeout = timeout;
+        self
+    }
+
+    /// 见 [`Options::write_buffer_bytes`]
+    pub fn write_buffer_bytes(mut self, bytes: usize) -> Self {
+        self.opts.write_buffer_bytes = bytes;
+        self
+    }
+
+    /// 见 [`Options::write_back_bytes`]
+    pub fn write_back_bytes(mut self, bytes: Option<usize>) -> Self {
+        self.opts.write_back_bytes = bytes;
+        self
+    }
+
+    /// 见 [`Options::upgrade_format`]
+    pub fn upgrade_format(mut self, upgrade_format: bool) -> Self {
+        self.opts.upgrade_format = upgrade_format;
+        self
+    }
+
+    /// 见 [`Options::flush_interval`]
+    pub fn flush_interval(mut self, interval: Option<Duration>) -> Self {
+        self.opts.flush_interval = interval;
+        self
+    }
+
+    /// 见 [`Options::scan_resync`]
+    pub fn scan_resync(mut self, scan_resync: bool) -> Self {
+        self.opts.scan_resync = scan_resync;
+        self
+    }
+
+    /// 见 [`Options::fail_on_corruption`]
+    pub fn fail_on_corruption(mut self, fail_on_corruption: bool) -> Self {
+        self.opts.fail_on_corruption = fail_on_corruption;
+        self
+    }
+
+    /// 见 [`Options::skip_unknown_kinds`]
+    pub fn skip_unknown_kinds(mut self, skip: bool) -> Self {
+        self.opts.skip_unknown_kinds = skip;
+        self
+    }
+
+    /// 见 [`Options::file_mode`]
+    pub fn file_mode(mut self, mode: Option<u32>) -> Self {
+        self.opts.file_mode = mode;
+        self
+    }
+
+    /// 见 [`Options::direct_io`]
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.opts.direct_io = direct_io;
+        self
+    }
+
+    /// 见 [`Options::cache_capacity_bytes`]
+    pub fn cache_capacity_bytes(mut self, bytes: usize) -> Self {
+        self.opts.cache_capacity_bytes = bytes;
+        self
+    }
+
+    /// 见 [`Options::auto_compact_ratio`]
+    pub fn auto_compact_ratio(mut self, ratio: Option<f64>) -> Self {
+        self.opts.auto_compact_ratio = ratio;
+        self
+    }
+
+    /// 见 [`Options::inline_value_threshold`]
+    pub fn inline_value_threshold(mut self, threshold: usize) -> Self {
+        self.opts.inline_value_threshold = threshold;
+        self
+    }
+
+    /// 见 [`Options::initial_index_capacity`]
+    pub fn initial_index_capacity(mut self, capacity: usize) -> Self {
+        self.opts.initial_index_capacity = capacity;
+        self
+    }
+
+    /// 见 [`Options::tombstone_ttl`]
+    pub fn tombstone_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.opts.tombstone_ttl = ttl;
+        self
+    }
+
+    /// 见 [`Options::key_prefix`]
+    pub fn key_prefix(mut self, prefix: impl Into<Vec<u8>>) -> Self {
+        self.opts.key_prefix = prefix.into();
+        self
+    }
+
+    /// 见 [`Options::dedup_values`]
+    pub fn dedup_values(mut self, enabled: bool) -> Self {
+        self.opts.dedup_values = enabled;
+        self
+    }
+
+    /// 见 [`Options::intern_small_values`]
+    pub fn intern_small_values(mut self, max: Option<usize>) -> Self {
+        self.opts.intern_small_values = max;
+        self
+    }
+
+    /// 见 [`Options::on_compact_progress`]
+    pub fn on_compact_progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(u64, u64) + Send + Sync + 'static,
+    {
+        self.opts.on_compact_progress = Some(CompactProgress::new(f));
+        self
+    }
+
+    /// 见 [`Options::on_write_error`]
+    pub fn on_write_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.opts.on_write_error = Some(WriteErrorHook::new(f));
+        self
+    }
+
+    /// 生成最终的 [`Options`]
+    pub fn build(self) -> Options {
+        self.opts
+    }
+}
+
 /// kvslite 数据库实例
 ///
 /// ## 线程安全性
 ///
-/// `Db` 不是线程安全的（没有实现 `Sync`）。
-///
 /// v0.1 设计为单线程使用：
 /// - 写操作需要 `&mut self`
 /// - 读操作需要 `&mut self`（因为需要 seek 文件）
 ///
+/// `Db` 实现了 `Send + Sync`，只需要 `&self` 的纯内存方法（如 [`Db::stats`]）
+/// 可以在 `RwLock<Db>` 的读锁下被监控线程调用。
+///
 /// 如果需要多线程访问，可以：
-/// - 用 `Arc<Mutex<Db>>` 包装
+/// - 用 [`SharedDb`](crate::SharedDb) 包装（内部是 `Arc<Mutex<Db>>`）
 /// - 等待 v0.6 的并发支持
 pub struct Db {
+    /// 数据库目录
+    dir: PathBuf,
     /// WAL 管理器
     wal: Wal,
     /// 内存索引：key -> value 位置
-    index: HashMap<Vec<u8>, ValuePos>,
+    index: Index,
+    /// 最近读写的 value（容量为 0 时不缓存）
+    cache: ValueCache,
+    /// 最近写入的小 value 的位置（见 [`Options::intern_small_values`]），重写 WAL 时清空
+    interned: InternTable,
     /// 配置选项
     opts: Options,
+    /// 打开时 replay 的统计信息
+    replay_stats: ReplayStats,
+    /// 最近一次 checkpoint 的 WAL 高水位
+    last_checkpoint: u64,
+    /// 最近一次 checkpoint 时最后分配的序列号（没有有效的 checkpoint 时为 0）
+    checkpoint_seq: u64,
+    /// WAL 中 PUT/DELETE 记录的条数（不含 BATCH 头）
+    wal_records: u64,
+    /// 最后分配的写入序列号（0 表示还没有写入）
+    last_seq: u64,
+    /// 变更通知的订阅者
+    subscribers: Subscribers,
+    /// 是否有后台压缩正在进行（与 [`CompactionJob`] 共享，任务结束或丢弃时清除）
+    compacting: Arc<AtomicBool>,
+    /// 写入 WAL 时发生过 I/O 错误，之后的写操作直接失败（见 [`Db::is_poisoned`]）
+    poisoned: bool,
+    /// 写操作之后自动压缩或 checkpoint 失败的最近一个错误（见 [`Db::take_maintenance_error`]）
+    maintenance_error: Option<Error>,
+    /// 后台 fsync 线程（未启用 `flush_interval` 时为 `None`）
+    ///
+    /// 放在 `wal` 之后：drop 时 WAL 先写出缓冲区，线程退出前的最后一次 fsync 能覆盖它
+    syncer: Option<Syncer>,
+    /// 目录锁（只读模式下为 `None`）
+    ///
+    /// 放在最后：其他字段都关闭之后才释放，新的打开者不会看到写了一半的文件
+    _lock: Option<DirLock>,
 }
 
 impl Db {
@@ -153,9 +867,10 @@ impl Db {
     /// ## 行为
     ///
     /// 1. 创建数据库目录（如果不存在）
-    /// 2. 打开 WAL 文件
-    /// 3. 如果 WAL 文件已存在，执行 replay 恢复数据
-    /// 4. 重建内存索引
+    /// 2. 如果存在有效的 MANIFEST，用它初始化索引
+    /// 3. 打开 WAL 文件
+    /// 4. 如果 WAL 文件已存在，执行 replay 恢复数据（有 MANIFEST 时只 replay 高水位之后的记录）
+    /// 5. 重建内存索引
     ///
     /// ## 崩溃恢复
     ///
@@ -173,93 +888,441 @@ impl Db {
     /// let db = Db::open("data/db1", Options::default()).unwrap();
     ///
     /// // 自定义配置
-    /// let opts = Options {
-    ///     sync_on_write: false,  // 性能优先
-    /// };
+    /// let opts = Options::builder()
+    ///     .sync_on_write(false) // 性能优先
+    ///     .build();
     /// let db = Db::open("data/db2", opts).unwrap();
     /// ```
     pub fn open<P: AsRef<Path>>(path: P, opts: Options) -> Result<Self> {
-        // 1. 打开 WAL 并 replay
-        let (wal, records, stats) = Wal::open(path)?;
+        let dir = path.as_ref().to_path_buf();
+        let lock = Self::lock_dir(&dir, &opts)?;
+        Self::open_locked(dir, opts, lock)
+    }
+
+    /// [`Db::open`] 在获得目录锁（只读模式下为 `None`）之后的部分
+    fn open_locked(dir: PathBuf, opts: Options, lock: Option<DirLock>) -> Result<Self> {
+        // 1. 加载 MANIFEST（过期或损坏时退回完整 replay）
+        let manifest = match Manifest::load(&dir)? {
+            Some(manifest) if manifest.matches_wal(&dir.join(WAL_FILENAME))? => Some(manifest),
+            _ => None,
+        };
+        let replay_from = manifest.as_ref().map_or(0, |m| m.wal_offset);
+
+        // 2. 打开 WAL 并 replay
+        let wal_opts = WalOptions {
+            limits: opts.limits,
+            replay_from,
+            read_only: opts.read_only,
+            write_buffer_bytes: opts.write_buffer_bytes,
+            scan_resync: opts.scan_resync,
+            direct_io: opts.direct_io,
+            fail_on_corruption: opts.fail_on_corruption,
+            skip_unknown_kinds: opts.skip_unknown_kinds,
+            file_mode: opts.file_mode,
+        };
+        let (mut wal, records, mut stats) = Wal::open(&dir, &wal_opts)?;
 
-        // 2. 如果发生了截断，打印警告
+        // 3. 如果发生了截断，打印警告
         if stats.truncated_bytes > 0 {
             eprintln!(
                 "Warning: WAL recovery truncated {} bytes ({} corrupted records)",
                 stats.truncated_bytes, stats.corrupted_records
             );
         }
+        if stats.skipped_bytes > 0 {
+            eprintln!(
+                "Warning: WAL recovery skipped {} bytes in {} damaged regions",
+                stats.skipped_bytes,
+                stats.gaps.len()
+            );
+        }
+        if stats.skipped_unknown > 0 {
+            eprintln!(
+                "Warning: WAL recovery skipped {} records of unknown kinds",
+                stats.skipped_unknown
+            );
+        }
+
+        // 4. 重建内存索引
+        let wal_records = manifest.as_ref().map_or(0, |m| m.record_count) + records.len() as u64;
+        let mut last_seq = manifest.as_ref().map_or(0, |m| m.last_seq);
+        let checkpoint_seq = last_seq;
+        let (index, dropped_refs) = Self::rebuild_index(manifest, &records, &mut last_seq, &opts);
+        if dropped_refs > 0 {
+            eprintln!(
+                "Warning: WAL recovery dropped {} REF records whose target was not recovered",
+                dropped_refs
+            );
+        }
+        stats.dropped_refs = dropped_refs;
+        // NOOP 标记可能携带比所有数据记录都大的序列号（压缩后的高水位）
+        let last_seq = last_seq.max(wal.max_seq());
+        let syncer = Self::spawn_syncer(&opts, &wal)?;
+        Self::start_write_back(&opts, &mut wal)?;
+
+        let mut db = Db {
+            dir,
+            wal,
+            index,
+            cache: ValueCache::new(opts.cache_capacity_bytes),
+            interned: InternTable::new(),
+            opts,
+            replay_stats: stats,
+            last_checkpoint: replay_from,
+            checkpoint_seq,
+            wal_records,
+            last_seq,
+            subscribers: Subscribers::default(),
+            poisoned: false,
+            maintenance_error: None,
+            compacting: Arc::new(AtomicBool::new(false)),
+            syncer,
+            _lock: lock,
+        };
 
-        // 3. 重建内存索引
-        let index = Self::rebuild_index(&records, &stats);
+        // 5. 按需把旧格式升级到当前格式；跳过了损坏区域时也重写，
+        //    否则不开启 scan_resync 的下一次 open 会在损坏处截断，丢掉抢救出的数据
+        let upgrade = db.opts.upgrade_format && db.wal.version() < VERSION;
+        let has_gaps = !db.replay_stats.gaps.is_empty();
+        if (upgrade || has_gaps) && !db.opts.read_only {
+            db.rewrite()?;
+        }
 
-        Ok(Db { wal, index, opts })
+        Ok(db)
     }
 
-    /// 从 replay 的记录重建内存索引
-    ///
-    /// ## 逻辑
+    /// 创建一个全新的数据库，目录中已有数据库时报错
     ///
-    /// 顺序扫描所有记录：
-    /// - 遇到 PUT：更新索引（last-write-wins）
-    /// - 遇到 DELETE：从索引中移除
+    /// ## 参数
     ///
-    /// ## 注意
+    /// - `path`: 数据库目录路径（不存在会自动创建）
+    /// - `opts`: 配置选项
     ///
-    /// 这里我们需要知道每个 record 在文件中的具体位置，
-    /// 但 replay 返回的只是 Record 对象。
+    /// ## 返回值
     ///
-    /// 为了计算位置，我们需要重新编码每条 record 来获得其大小。
+    /// - `Ok(Db)`: 新建的空数据库
+    /// - `Err(Error::AlreadyExists)`: 目录中已有 `wal.log`
+    /// - `Err(Error::ReadOnly)`: 只读模式
+    /// - `Err(Error)`: 创建目录或文件失败
     ///
-    /// ## 优化方向（未来版本）
+    /// ## 行为
     ///
-    /// - Replay 时直接返回 (Record, offset, len)
-    /// - 避免重复编码
-    fn rebuild_index(records: &[Record], _stats: &ReplayStats) -> HashMap<Vec<u8>, ValuePos> {
-        let mut index = HashMap::new();
-        let mut offset = 0u64;
-
-        for record in records {
-            // 计算这条记录的大小（需要重新编码）
-            // 这不是最优的，但 v0.1 优先正确性
-            let encoded = record.encode().unwrap();
-            let record_len = encoded.len() as u64;
-
-            match record.kind {
-                RecordKind::Put => {
-                    // 计算 value 在文件中的位置
-                    // value 位于 record 的末尾（crc 之前）
-                    let value_offset_in_record = record_len - 4 - record.value.len() as u64;
-                    let value_pos = ValuePos {
-                        offset: offset + value_offset_in_record,
-                        len: record.value.len(),
-                    };
+    /// 与 [`Db::open`] 的区别只在于存在性检查：`open` 在数据库不存在时创建、
+    /// 存在时加载，适合大多数场景；`create_new` 用于首次部署，保证不会误用已有的数据。
+    /// `wal.log` 以独占方式创建（`O_EXCL`），并发调用时只有一个能成功。
+    pub fn create_new<P: AsRef<Path>>(path: P, opts: Options) -> Result<Self> {
+        if opts.read_only {
+            return Err(Error::ReadOnly);
+        }
 
-                    index.insert(record.key.clone(), value_pos);
-                }
-                RecordKind::Delete => {
-                    // 从索引中移除
-                    index.remove(&record.key);
-                }
+        let dir = path.as_ref();
+        std::fs::create_dir_all(dir)?;
+        match create_new_file(&dir.join(WAL_FILENAME), opts.file_mode) {
+            Ok(_) => {
+                restrict_dir(dir, opts.file_mode)?;
+                sync_dir(dir)?;
             }
-
-            offset += record_len;
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(Error::AlreadyExists);
+            }
+            Err(e) => return Err(e.into()),
         }
 
-        index
+        let mut db = Self::open(dir, opts)?;
+        db.replay_stats.created_new = true;
+        Ok(db)
     }
 
-    /// 写入键值对
+    /// 打开一个已经存在的数据库，不存在时报错而不是新建
     ///
     /// ## 参数
     ///
-    /// - `key`: 键（最大 1KB）
-    /// - `value`: 值（最大 1MB）
+    /// - `path`: 数据库目录路径
+    /// - `opts`: 配置选项
     ///
     /// ## 返回值
     ///
-    /// - `Ok(())`: 写入成功
-    /// - `Err(Error)`: 如果写入失败或超出大小限制
+    /// - `Ok(Db)`: 加载完成的数据库
+    /// - `Err(Error::NotFound)`: 目录中没有 `wal.log`
+    /// - `Err(Error)`: 加载失败，与 [`Db::open`] 相同
+    ///
+    /// ## 行为
+    ///
+    /// 用于只允许挂载已有数据的场景（例如路径写错时不应该悄悄建出一个空库）。
+    /// 检查通过后与 [`Db::open`] 完全相同。
+    pub fn open_existing<P: AsRef<Path>>(path: P, opts: Options) -> Result<Self> {
+        let dir = path.as_ref();
+        if !dir.join(WAL_FILENAME).exists() {
+            return Err(Error::NotFound);
+        }
+
+        Self::open(dir, opts)
+    }
+
+    /// 把大量键值对一次性导入一个全新的数据库
+    ///
+    /// ## 参数
+    ///
+    /// - `path`: 数据库目录路径（不存在会自动创建）
+    /// - `opts`: 配置选项
+    /// - `entries`: 要导入的键值对，同一个 key 出现多次时后者生效
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Db)`: 导入完成后的数据库实例
+    /// - `Err(Error)`: 如果目录中已有数据、某个 key/value 超出大小限制，或写入失败
+    ///
+    /// ## 为什么比循环调用 `put` 快？
+    ///
+    /// - 所有记录经 `BufWriter` 顺序写入，没有逐条 `write` 系统调用
+    /// - 整个导入只 fsync 一次（与 `sync_on_write` 无关）
+    /// - 写入时直接根据累计偏移量构建索引，不需要再 replay
+    ///
+    /// ## 原子性
+    ///
+    /// 数据先写入临时文件，全部完成并 fsync 后才 rename 为 `wal.log`。
+    /// 导入失败或中途崩溃时目录中不会留下部分导入的数据。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let entries = (0..100_000).map(|i| {
+    ///     (format!("key:{}", i).into_bytes(), format!("value:{}", i).into_bytes())
+    /// });
+    /// let mut db = Db::bulk_load("data/imported", Options::default(), entries).unwrap();
+    /// assert_eq!(db.stats().key_count, 100_000);
+    /// ```
+    pub fn bulk_load<P, I>(path: P, opts: Options, entries: I) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let dir = path.as_ref().to_path_buf();
+        let lock = Self::lock_dir(&dir, &opts)?;
+        let wal_opts = WalOptions {
+            limits: opts.limits,
+            replay_from: 0,
+            read_only: opts.read_only,
+            write_buffer_bytes: opts.write_buffer_bytes,
+            scan_resync: opts.scan_resync,
+            direct_io: opts.direct_io,
+            fail_on_corruption: opts.fail_on_corruption,
+            skip_unknown_kinds: opts.skip_unknown_kinds,
+            file_mode: opts.file_mode,
+        };
+
+        // 1. 顺序写入新的 WAL，同时构建索引
+        let mut index = Index::with_inline_threshold(opts.inline_value_threshold);
+        let limits = opts.limits;
+        let ns = opts.key_prefix.as_slice();
+        let records = entries.into_iter().zip(1..).map(|((key, value), seq)| {
+            let key = if ns.is_empty() { key } else { [ns, &key].concat() };
+            Ok(Record::put_with_limits(key, value, &limits)?.with_seq(seq))
+        });
+        let mut wal_records = 0;
+        let mut wal = Wal::create(&dir, &wal_opts, records, |offset, record| {
+            wal_records += 1;
+            Self::index_compacted(&mut index, offset, record);
+        })?;
+        let last_seq = wal.max_seq();
+
+        // 2. 目录中残留的 MANIFEST 不可能对应新的 WAL
+        Manifest::remove(&dir)?;
+        let syncer = Self::spawn_syncer(&opts, &wal)?;
+        Self::start_write_back(&opts, &mut wal)?;
+
+        let replay_stats = ReplayStats {
+            created_new: true,
+            ..ReplayStats::default()
+        };
+
+        Ok(Db {
+            dir,
+            wal,
+            index,
+            cache: ValueCache::new(opts.cache_capacity_bytes),
+            interned: InternTable::new(),
+            opts,
+            replay_stats,
+            last_checkpoint: 0,
+            checkpoint_seq: 0,
+            wal_records,
+            last_seq,
+            subscribers: Subscribers::default(),
+            poisoned: false,
+            maintenance_error: None,
+            compacting: Arc::new(AtomicBool::new(false)),
+            syncer,
+            _lock: lock,
+        })
+    }
+
+    /// 创建目录并获取目录锁（只读模式下不加锁），见 [`Options::open_lock_timeout`]
+    fn lock_dir(dir: &Path, opts: &Options) -> Result<Option<DirLock>> {
+        if opts.read_only {
+            return Ok(None);
+        }
+        std::fs::create_dir_all(dir)?;
+        Ok(Some(DirLock::acquire(dir, opts.open_lock_timeout)?))
+    }
+
+    /// 按 `flush_interval` 启动后台 fsync 线程（只读模式下不启动）
+    fn spawn_syncer(opts: &Options, wal: &Wal) -> Result<Option<Syncer>> {
+        match opts.flush_interval {
+            Some(interval) if !opts.read_only => {
+                Ok(Some(Syncer::spawn(wal.sync_handle(), interval)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 按 `write_back_bytes` 启动后台写回线程（只读模式下不启动）
+    fn start_write_back(opts: &Options, wal: &mut Wal) -> Result<()> {
+        match opts.write_back_bytes {
+            Some(bytes) if !opts.read_only => wal.enable_write_back(bytes),
+            _ => Ok(()),
+        }
+    }
+
+    /// 从 checkpoint 和 replay 的记录重建内存索引
+    ///
+    /// ## 逻辑
+    ///
+    /// 如果有 MANIFEST，先用其中的条目初始化索引；然后顺序扫描高水位之后的所有记录：
+    /// - 遇到 PUT：更新索引（last-write-wins）
+    /// - 遇到 DELETE：从索引中移除
+    ///
+    /// Replay 返回的每条记录都带有其在文件中的起始偏移量，
+    /// value 的位置 = 记录起始偏移量 + header + key 长度，无需重新编码。
+    ///
+    /// 索引的容量按 [`Options::initial_index_capacity`] 一次性预留；没有设置时按条目数
+    /// 与记录数估算，replay 结束后收缩掉估算多出的部分。
+    ///
+    /// 返回索引和被丢弃的 REF 记录数（见 [`Db::apply_records`]）。
+    fn rebuild_index(
+        manifest: Option<Manifest>,
+        records: &[ReplayedRecord],
+        last_seq: &mut u64,
+        opts: &Options,
+    ) -> (Index, usize) {
+        let mut index = Index::with_inline_threshold(opts.inline_value_threshold);
+        let estimated = opts.initial_index_capacity == 0;
+        index.reserve(match opts.initial_index_capacity {
+            0 => manifest.as_ref().map_or(0, |m| m.entries.len()) + records.len(),
+            n => n,
+        });
+
+        let replay_from = manifest.as_ref().map_or(0, |m| m.wal_offset);
+        if let Some(manifest) = manifest {
+            for (key, seq, timestamp) in manifest.tombstones {
+                index.delete(&key, Tombstone { seq, timestamp });
+            }
+            for (key, offset, len, seq, ref_offset) in manifest.entries {
+                let pos = ValuePos {
+                    offset,
+                    len: len as usize,
+                    seq,
+                };
+                match ref_offset {
+                    0 => index.insert(key, pos),
+                    ref_offset => index.insert_ref(key, pos, ref_offset),
+                };
+            }
+        }
+
+        let dropped_refs = Self::apply_records(&mut index, records, last_seq, replay_from);
+        if estimated && index.capacity() > 2 * index.len() {
+            index.shrink_to_fit();
+        }
+        (index, dropped_refs)
+    }
+
+    /// 按顺序把记录应用到索引，同时推进 `last_seq`，返回被丢弃的 REF 记录数
+    ///
+    /// 没有序列号的记录（v1 格式）按出现顺序依次分配 `last_seq + 1`，
+    /// 与它们当初写入时在内存中分配的序列号一致。
+    ///
+    /// REF 记录只指向它的目标 PUT 记录，本身不携带 value，也就无法校验 value 的 CRC。
+    /// 目标必须在 `verified_end` 之前（之前已经 replay 过，或由 MANIFEST 覆盖），
+    /// 或者是 `records` 中确实 replay 到的一条 PUT 记录；否则目标可能落在
+    /// `scan_resync` 跳过的损坏区间里，这条 REF 被丢弃，key 保持之前的状态，
+    /// 而不是指向损坏的字节。
+    fn apply_records(
+        index: &mut Index,
+        records: &[ReplayedRecord],
+        last_seq: &mut u64,
+        verified_end: u64,
+    ) -> usize {
+        // 本批次中 PUT 记录的起始偏移量 -> (value 偏移量, value 长度)，只在有 REF 时收集
+        let has_refs = records.iter().any(|(_, record)| record.kind == RecordKind::Ref);
+        let mut puts: HashMap<u64, (u64, usize)> = HashMap::new();
+        let mut dropped = 0;
+
+        for (offset, record) in records {
+            if matches!(record.kind, RecordKind::Put | RecordKind::Delete | RecordKind::Ref) {
+                *last_seq = record.seq.unwrap_or(*last_seq + 1).max(*last_seq);
+            }
+
+            match record.kind {
+                RecordKind::Put => {
+                    let value_pos = ValuePos {
+                        offset: offset + record.value_offset(),
+                        len: record.value.len(),
+                        seq: record.seq.unwrap_or(*last_seq),
+                    };
+
+                    if has_refs {
+                        puts.insert(*offset, (value_pos.offset, value_pos.len));
+                    }
+                    index.insert_value(record.key.clone(), value_pos, &record.value);
+                }
+                RecordKind::Delete => {
+                    // 从索引中移除，留下墓碑
+                    let tombstone = Tombstone {
+                        seq: record.seq.unwrap_or(*last_seq),
+                        timestamp: record.timestamp,
+                    };
+                    index.delete(&record.key, tombstone);
+                }
+                RecordKind::Ref => {
+                    let target = record.value_ref().filter(|target| {
+                        target.record_offset < verified_end
+                            || puts.get(&target.record_offset)
+                                == Some(&(target.value_offset, target.len))
+                    });
+                    let Some(target) = target else {
+                        dropped += 1;
+                        continue;
+                    };
+                    let value_pos = ValuePos {
+                        offset: target.value_offset,
+                        len: target.len,
+                        seq: record.seq.unwrap_or(*last_seq),
+                    };
+                    index.insert_ref(record.key.clone(), value_pos, *offset);
+                }
+                RecordKind::Batch | RecordKind::Noop => {
+                    // BATCH 头和 NOOP 标记都不影响数据，replay 不会返回它们
+                }
+            }
+        }
+        dropped
+    }
+
+    /// 写入键值对
+    ///
+    /// ## 参数
+    ///
+    /// - `key`: 键（默认最大 1KB，见 `Options::limits`）
+    /// - `value`: 值（默认最大 1MB，见 `Options::limits`）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 写入成功
+    /// - `Err(Error)`: 如果写入失败或超出大小限制；磁盘已满时为 `Error::DiskFull`，
+    ///   这条记录不会留在 WAL 中，释放空间后可以重试
     ///
     /// ## 行为
     ///
@@ -284,31 +1347,188 @@ impl Db {
     /// db.put(b"user:1:age", b"30").unwrap();
     /// ```
     pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        // 1. 创建 PUT 记录（会验证大小）
-        let record = Record::put(key.to_vec(), value.to_vec())?;
+        let key = &*self.ns_key(key);
 
-        // 2. 追加到 WAL
-        let record_offset = self.wal.append(&record, self.opts.sync_on_write)?;
+        // 1. 创建 PUT 记录（会验证大小）；相同的 value 已经驻留时换成 REF 记录
+        let seq = self.last_seq + 1;
+        let record = Record::put_with_limits(key.to_vec(), value.to_vec(), &self.opts.limits)?;
+        let interned = self.internable(value).then(|| self.interned.get(value)).flatten();
+        let record = match interned {
+            Some(target) => self.sequenced(Record::reference(key.to_vec(), target), seq),
+            None => self.sequenced(record, seq),
+        };
 
-        // 3. 计算 value 在文件中的位置
-        // value 在 record 的末尾（crc 之前）
-        let encoded = record.encode()?; // TODO: 优化，避免重复编码
-        let record_len = encoded.len() as u64;
-        let value_offset_in_record = record_len - 4 - value.len() as u64;
-        let value_offset = record_offset + value_offset_in_record;
+        // 2. 追加到 WAL
+        let sync = self.opts.sync_on_write;
+        let record_offset = self.wal_write(|wal| wal.append(&record, sync))?;
+        self.wal_records += 1;
+        self.last_seq = seq;
 
-        // 4. 更新索引
-        self.index.insert(
-            key.to_vec(),
-            ValuePos {
-                offset: value_offset,
-                len: value.len(),
-            },
-        );
+        // 3. 计算 value 在文件中的位置，更新索引和缓存
+        match interned {
+            Some(target) => {
+                let pos = ValuePos {
+                    offset: target.value_offset,
+                    len: target.len,
+                    seq,
+                };
+                self.index.insert_ref(key.to_vec(), pos, record_offset);
+            }
+            None => {
+                let value_offset = record_offset + record.value_offset();
+                let pos = ValuePos {
+                    offset: value_offset,
+                    len: value.len(),
+                    seq,
+                };
+                self.index.insert_value(key.to_vec(), pos, value);
+                if self.internable(value) {
+                    let target = ValueRef {
+                        record_offset,
+                        value_offset,
+                        len: value.len(),
+                    };
+                    self.interned.insert(value, target);
+                }
+            }
+        }
+        self.cache_written(key, value);
+        self.notify_put(key, value, seq);
 
+        self.after_write();
         Ok(())
     }
 
+    /// 写入字符串键值对，等价于 `put(key.as_bytes(), value.as_bytes())`
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let user = "alice";
+    /// db.put_str(&format!("user:{}:name", user), "Alice").unwrap();
+    /// ```
+    pub fn put_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.put(key.as_bytes(), value.as_bytes())
+    }
+
+    /// 预留一个已知长度的 value，返回用于流式写入的 [`ValueWriter`]
+    ///
+    /// ## 参数
+    ///
+    /// - `key`: 键（默认最大 1KB，见 `Options::limits`）
+    /// - `value_len`: value 的总长度（默认最大 1MB，见 `Options::limits`）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(ValueWriter)`: 记录头部已写入 WAL，通过 `Write` 写入 value 后调用 `finish`
+    /// - `Err(Error)`: 如果超出大小限制或写入失败
+    ///
+    /// ## 为什么不直接用 `put`？
+    ///
+    /// `put` 需要调用方先把整个 value 放进内存，编码时还要再复制一次。
+    /// `put_reserve` 把 value 分块直接写进 WAL，同时增量计算 CRC，
+    /// 内存中最多只有一个写缓冲区大小的数据。
+    ///
+    /// ## 语义
+    ///
+    /// - 在 `finish` 成功之前，这次写入不可见：`ValueWriter` 持有 `&mut Db`，
+    ///   期间不能进行其他读写
+    /// - 写入的总字节数必须正好等于 `value_len`：多写的那次 `write` 直接返回错误，
+    ///   `finish` 时长度不一致返回 `Error::ValueLengthMismatch`，记录被放弃
+    /// - 没有调用 `finish` 就 drop 时，同样放弃这条记录（WAL 截断回记录起始位置）
+    /// - 进程在 `finish` 之前崩溃，留下的半条记录会在下次打开时被截断
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    /// use std::io::Write;
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let chunk = vec![0u8; 4096];
+    ///
+    /// let mut writer = db.put_reserve(b"blob", chunk.len() * 64).unwrap();
+    /// for _ in 0..64 {
+    ///     writer.write_all(&chunk).unwrap();
+    /// }
+    /// writer.finish().unwrap();
+    /// ```
+    pub fn put_reserve(&mut self, key: &[u8], value_len: usize) -> Result<ValueWriter<'_>> {
+        // 1. 验证大小（value 还没有内容，单独检查长度）
+        let key = self.ns_key(key).into_owned();
+        let header = Record::put_with_limits(key, Vec::new(), &self.opts.limits)?;
+        self.opts.limits.check_value(value_len)?;
+
+        // 2. 写入记录头部
+        let seq = self.last_seq + 1;
+        let header = self.sequenced(header, seq);
+        let (start, encoder) = self.wal_write(|wal| wal.begin_stream(&header, value_len))?;
+
+        Ok(ValueWriter {
+            value_offset: start + header.value_offset(),
+            start,
+            key: header.key,
+            seq,
+            record_seq: header.seq,
+            value_len,
+            written: 0,
+            encoder: Some(encoder),
+            db: self,
+        })
+    }
+
+    /// 以流的方式读取键对应的值
+    ///
+    /// ## 参数
+    ///
+    /// - `key`: 要查询的键
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Some(ValueReader))`: 找到 key，通过 `Read` 读取 value
+    /// - `Ok(None)`: key 不存在
+    /// - `Err(Error)`: 如果读取失败
+    ///
+    /// ## 为什么不直接用 `get`？
+    ///
+    /// `get` 把整个 value 读进一个 `Vec`。`value_reader` 每次 `read` 只从 WAL 读取
+    /// 调用方缓冲区大小的数据，适合把大 value 交给哈希、网络发送等流式的消费者，
+    /// 内存占用与 value 大小无关。它是 [`Db::put_reserve`] 在读取一侧的对应操作。
+    ///
+    /// ## 语义
+    ///
+    /// - `ValueReader` 持有 `&mut Db`，期间不能进行其他读写，读到的总是打开时的值
+    /// - 读取范围限定在 value 之内，读完后 `read` 返回 0
+    /// - 不查询也不填充缓存；还在写缓冲区中的数据照常可以读到
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// if let Some(mut reader) = db.value_reader(b"blob").unwrap() {
+    ///     let mut out = std::fs::File::create("blob.bin").unwrap();
+    ///     std::io::copy(&mut reader, &mut out).unwrap();
+    /// }
+    /// ```
+    pub fn value_reader(&mut self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        let pos = match self.index.get(&self.ns_key(key)) {
+            Some(pos) => *pos,
+            None => return Ok(None),
+        };
+
+        Ok(Some(ValueReader {
+            wal: &mut self.wal,
+            offset: pos.offset,
+            len: pos.len,
+            read: 0,
+        }))
+    }
+
     /// 读取键对应的值
     ///
     /// ## 参数
@@ -321,6 +1541,9 @@ impl Db {
     /// - `Ok(None)`: key 不存在
     /// - `Err(Error)`: 如果读取失败
     ///
+    /// 启用了 [`Options::cache_capacity_bytes`] 时先查缓存，命中时不访问磁盘；
+    /// 未命中时从 WAL 读取并放入缓存。
+    ///
     /// ## 示例
     ///
     /// ```no_run
@@ -336,157 +1559,7309 @@ impl Db {
     /// assert_eq!(missing, None);
     /// ```
     pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = &*self.ns_key(key);
+
         // 1. 在索引中查找
-        match self.index.get(key) {
-            Some(pos) => {
-                // 2. 从 WAL 读取 value
-                let value = self.wal.read_at(pos.offset, pos.len)?;
-                Ok(Some(value))
-            }
-            None => Ok(None),
+        let pos = match self.index.get(key) {
+            Some(pos) => *pos,
+            None => return Ok(None),
+        };
+
+        // 2. 优先返回内联在索引中的 value，其次是缓存
+        if let Some(value) = self.index.get_inline(key) {
+            return Ok(Some(value.to_vec()));
+        }
+        if let Some(value) = self.cache.get(key) {
+            return Ok(Some(value));
+        }
+
+        // 3. 从 WAL 读取 value：小 value 内联进索引，其余放入缓存
+        let value = self.wal.read_at(pos.offset, pos.len)?;
+        self.index.fill_inline(key, &value);
+        if self.index.get_inline(key).is_none() {
+            self.cache.insert(key, &value);
         }
+        Ok(Some(value))
     }
 
-    /// 删除键
+    /// 检查 key 是否存在，不读取 value
+    ///
+    /// 只查找内存索引：不访问磁盘，不读取 value，也不更新缓存和命中统计，只需要 `&self`。
+    /// 适合写入前的去重检查。设置了 [`Options::key_prefix`] 时，查找前要拼出带前缀的
+    /// 完整 key（一次小的内存分配），与其他按 key 访问的方法相同。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// if !db.contains_key(b"event:42") {
+    ///     db.put(b"event:42", b"payload").unwrap();
+    /// }
+    /// ```
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.index.contains_key(&self.ns_key(key))
+    }
+
+    /// 只从内存中读取 value，不访问磁盘
     ///
     /// ## 参数
     ///
-    /// - `key`: 要删除的键
+    /// - `key`: 要查找的键
     ///
     /// ## 返回值
     ///
-    /// - `Ok(())`: 删除成功（无论 key 是否存在）
-    /// - `Err(Error)`: 如果操作失败
+    /// - `CacheResult::Hit(value)`: value 在缓存中，或内联在索引中
+    ///   （见 [`Options::inline_value_threshold`]）
+    /// - `CacheResult::Miss`: key 存在，但 value 需要从 WAL 读取
+    /// - `CacheResult::Absent`: key 不存在
     ///
     /// ## 行为
     ///
-    /// 1. 写入 DELETE 记录到 WAL
-    /// 2. 从内存索引中移除 key
+    /// 只需要 `&self`，不做任何 I/O，耗时与 WAL 大小和磁盘无关。适合延迟敏感的路径：
+    /// 命中时直接使用，`Miss` 时交给其他线程调用 [`Db::get`] 读取（顺带放入缓存）。
     ///
-    /// ## 注意
+    /// 命中不会刷新缓存条目的 LRU 顺序（那需要 `&mut self`），只靠这个方法读取的热点
+    /// 仍然可能被淘汰。没有启用缓存和内联时，存在的 key 总是返回 `Miss`。
     ///
-    /// - 删除不会立即释放磁盘空间（WAL 是追加的）
-    /// - 需要 Compaction（v0.2）来回收空间
-    /// - 删除不存在的 key 也会写入 WAL（保证操作的持久化语义）
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{CacheResult, Db, Options};
+    ///
+    /// let opts = Options::builder().cache_capacity_bytes(64 * 1024 * 1024).build();
+    /// let db = Db::open("data/db1", opts).unwrap();
+    /// match db.get_cached_only(b"user:1") {
+    ///     CacheResult::Hit(value) => println!("{} bytes", value.len()),
+    ///     CacheResult::Miss => println!("not cached, fetch it in the background"),
+    ///     CacheResult::Absent => println!("no such key"),
+    /// }
+    /// ```
+    pub fn get_cached_only(&self, key: &[u8]) -> CacheResult {
+        let key = &*self.ns_key(key);
+        if !self.index.contains_key(key) {
+            return CacheResult::Absent;
+        }
+        match self.index.get_inline(key).or_else(|| self.cache.peek(key)) {
+            Some(value) => CacheResult::Hit(value.to_vec()),
+            None => CacheResult::Miss,
+        }
+    }
+
+    /// 预估 `get(key)` 的开销，不执行读取
+    ///
+    /// ## 返回值
+    ///
+    /// - `Some(GetCost)`: key 存在；`cached` 表示 value 是否在内存中，`bytes` 是 value 的大小
+    /// - `None`: key 不存在
+    ///
+    /// ## 行为
+    ///
+    /// 与 [`Db::get_cached_only`] 一样只查询内存中的索引和缓存，不做任何 I/O，
+    /// 也不复制 value，不改变缓存的 LRU 顺序。查询计划、预取逻辑可以据此
+    /// 先处理命中内存的 key，把需要读盘的 key 合并后交给 [`Db::read_sorted`]。
+    ///
+    /// 结果只在下一次修改数据库之前成立：写入、删除和缓存淘汰都会改变它。
     ///
     /// ## 示例
     ///
     /// ```no_run
     /// use kvslite::{Db, Options};
     ///
-    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
-    /// db.put(b"key", b"value").unwrap();
-    /// db.delete(b"key").unwrap();
-    ///
-    /// assert_eq!(db.get(b"key").unwrap(), None);
+    /// let db = Db::open("data/db1", Options::default()).unwrap();
+    /// if let Some(cost) = db.get_cost(b"user:1") {
+    ///     if !cost.cached {
+    ///         println!("needs a {} byte disk read", cost.bytes);
+    ///     }
+    /// }
     /// ```
-    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
-        // 1. 创建 DELETE 记录
-        let record = Record::delete(key.to_vec())?;
-
-        // 2. 追加到 WAL
-        self.wal.append(&record, self.opts.sync_on_write)?;
-
-        // 3. 从索引中移除
-        self.index.remove(key);
-
-        Ok(())
+    pub fn get_cost(&self, key: &[u8]) -> Option<GetCost> {
+        let key = &*self.ns_key(key);
+        let pos = self.index.get(key)?;
+        let cached = self.index.get_inline(key).is_some() || self.cache.peek(key).is_some();
+        Some(GetCost {
+            cached,
+            bytes: pos.len,
+            segment: 0,
+        })
     }
 
-    /// 获取数据库统计信息
+    /// 检查一个 key 的索引项是否指向 WAL 中一条完整的 PUT 记录
+    ///
+    /// ## 参数
+    ///
+    /// - `key`: 要检查的键
     ///
     /// ## 返回值
     ///
-    /// 返回一个包含各种统计数据的结构体
-    pub fn stats(&self) -> DbStats {
-        DbStats {
-            key_count: self.index.len(),
-            wal_size: self.wal.size(),
-        }
+    /// - `Ok(None)`: key 不在索引中
+    /// - `Ok(Some(true))`: 索引位置处是一条 CRC 正确的 PUT 记录，key 相同，value 长度一致
+    /// - `Ok(Some(false))`: 索引位置处的记录损坏、被截断或与索引不符
+    /// - `Err(Error)`: 如果读取时发生 I/O 错误
+    ///
+    /// ## 行为
+    ///
+    /// 索引只记录 value 的位置，这里根据 key 长度和格式版本倒推出记录起点，
+    /// 读出整条记录重新解码并校验 CRC。只读取这一条记录，
+    /// 适合排查单个 key 读到"幽灵"数据的问题，不需要扫描整个 WAL。
+    ///
+    /// 去重压缩（见 [`Options::dedup_values`]）后与其他 key 共享 value 的 key
+    /// 由一条 REF 记录写入：这时检查这条 REF 记录，以及它引用的 PUT 记录。
+    pub fn verify_key(&mut self, key: &[u8]) -> Result<Option<bool>> {
+        let key = &*self.ns_key(key);
+        self.verify_entry(key)
     }
-}
 
-/// 数据库统计信息
-#[derive(Debug, Clone)]
-pub struct DbStats {
-    /// 当前 key 的数量
-    pub key_count: usize,
-    /// WAL 文件大小（字节）
-    pub wal_size: u64,
-}
+    /// [`Db::verify_key`] 的实现，`key` 已经带有前缀
+    fn verify_entry(&mut self, key: &[u8]) -> Result<Option<bool>> {
+        let pos = match self.index.get(key) {
+            Some(pos) => *pos,
+            None => return Ok(None),
+        };
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+        if let Some(ref_offset) = self.index.ref_offset(key) {
+            return self.verify_ref(key, pos, ref_offset).map(Some);
+        }
 
-    #[test]
-    fn test_put_get() {
-        let dir = TempDir::new().unwrap();
-        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        let with_seq = self.wal.version() > VERSION_V1;
+        let record_len = Record::put_encoded_len(key.len(), pos.len, with_seq);
+        let header_len = (record_len - pos.len - 4) as u64;
+        let start = match pos.offset.checked_sub(header_len) {
+            Some(start) => start,
+            None => return Ok(Some(false)),
+        };
+
+        let ok = match self.decode_at(start, record_len)? {
+            Some(record) => {
+                record.kind == RecordKind::Put
+                    && record.key == key
+                    && record.value.len() == pos.len
+            }
+            None => false,
+        };
+        Ok(Some(ok))
+    }
+
+    /// 检查 `ref_offset` 处是 `key` 的 REF 记录，且它引用的 PUT 记录完整、value 位于 `pos`
+    fn verify_ref(&mut self, key: &[u8], pos: ValuePos, ref_offset: u64) -> Result<bool> {
+        let ref_len = Record::put_encoded_len(key.len(), VALUE_REF_LEN, true);
+        let target = match self.decode_at(ref_offset, ref_len)? {
+            Some(record) if record.key == key => record.value_ref(),
+            _ => None,
+        };
+        let target = match target {
+            Some(target) if target.value_offset == pos.offset && target.len == pos.len => target,
+            _ => return Ok(false),
+        };
+
+        // 被引用的记录的 key 长度未知，先读头部得到整条记录的长度
+        let bytes = match self.raw_record_at(target.record_offset, true) {
+            Ok(bytes) => bytes,
+            Err(Error::Io(e)) => return Err(Error::Io(e)),
+            Err(_) => return Ok(false),
+        };
+        Ok(match Record::decode_with_limits(&mut bytes.as_slice(), &self.opts.limits) {
+            Ok(Some(record)) => {
+                record.kind == RecordKind::Put
+                    && target.record_offset + record.value_offset() == pos.offset
+                    && record.value.len() == pos.len
+            }
+            _ => false,
+        })
+    }
+
+    /// 读取并解码 `[start, start + len)` 处的一条记录；越界、损坏或不完整时返回 `None`
+    fn decode_at(&mut self, start: u64, len: usize) -> Result<Option<Record>> {
+        let bytes = match self.wal.read_at(start, len) {
+            Ok(bytes) => bytes,
+            Err(Error::OutOfBounds { .. }) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Record::decode_with_limits(&mut bytes.as_slice(), &self.opts.limits).unwrap_or(None))
+    }
+
+    /// 快速检查数据库是否处于可用状态，用于服务的存活 / 就绪探针
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(HealthReport)`: 每一项检查的结果，`healthy` 为所有检查都通过
+    /// - `Err(Error)`: 如果写出写缓冲区失败
+    ///
+    /// ## 行为
+    ///
+    /// 只做有限的工作，耗时与数据量无关，不扫描整个 WAL：
+    ///
+    /// 1. 可写模式下先把写缓冲区写入文件，再比较 `wal.log` 的长度与写入位置
+    /// 2. 检查目录锁仍然有效（`LOCK` 没有被删除或替换）
+    /// 3. 读出最近一条写入（或 replay 到）的记录，校验 CRC
+    /// 4. 抽查索引中的几个 key（见 [`Db::verify_key`]）
+    ///
+    /// 检查失败不会返回错误，而是体现在报告中；读取失败也算作检查失败。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let report = db.health_check().unwrap();
+    /// if !report.healthy {
+    ///     eprintln!("unhealthy: {:?}", report);
+    /// }
+    /// ```
+    pub fn health_check(&mut self) -> Result<HealthReport> {
+        let writable = !self.opts.read_only;
+        if writable && !self.poisoned {
+            self.wal_write(Wal::flush)?;
+        }
+
+        // 1. WAL 文件存在，长度与写入位置一致（O_DIRECT 的块填充和只读时主库的追加除外）
+        let file_len = match std::fs::metadata(self.wal.path()) {
+            Ok(metadata) => Some(metadata.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let wal_size_ok = file_len.is_some_and(|len| match writable && !self.opts.direct_io {
+            true => len == self.wal.size(),
+            false => len >= self.wal.size(),
+        });
+
+        // 2. 目录锁（只读模式不加锁）
+        let lock_ok = match &self._lock {
+            Some(lock) => lock.is_held(&self.dir),
+            None => !writable,
+        };
+
+        // 3. 最后一条记录
+        let last_record_ok = match self.wal.last_record() {
+            Some(offset) => self.raw_record_at(offset, true).is_ok(),
+            None => true,
+        };
+
+        // 4. 抽查索引
+        let sample: Vec<Vec<u8>> = self
+            .index
+            .iter()
+            .take(HEALTH_CHECK_SAMPLE)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut index_ok = true;
+        for key in &sample {
+            index_ok &= matches!(self.verify_entry(key), Ok(Some(true)));
+        }
+
+        let poisoned = self.poisoned;
+        Ok(HealthReport {
+            healthy: file_len.is_some()
+                && wal_size_ok
+                && lock_ok
+                && last_record_ok
+                && index_ok
+                && !poisoned,
+            wal_present: file_len.is_some(),
+            wal_size_ok,
+            lock_ok,
+            last_record_ok,
+            sampled_keys: sample.len(),
+            index_ok,
+            poisoned,
+        })
+    }
+
+    /// 批量读取多个 key 的值，按 value 在文件中的位置顺序读取
+    ///
+    /// ## 参数
+    ///
+    /// - `keys`: 要读取的 key（通常已按 key 排序，但不要求；可以重复）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Vec<Option<Vec<u8>>>)`: 与 `keys` 一一对应，不存在的 key 为 `None`
+    /// - `Err(Error)`: 如果读取失败
+    ///
+    /// ## 为什么比逐个 `get` 快？
+    ///
+    /// key 的顺序与 value 在 WAL 中的位置无关，逐个 `get` 会在文件中来回 seek。
+    /// 这里先在索引中解析出所有 value 的位置，按偏移量排序后顺序读取，
+    /// 磁盘上的访问变成单向扫描（对机械硬盘尤其明显），最后按输入顺序返回结果。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let keys = vec![b"user:1".to_vec(), b"user:2".to_vec(), b"user:3".to_vec()];
+    /// for (key, value) in keys.iter().zip(db.read_sorted(&keys).unwrap()) {
+    ///     println!("{:?} => {:?}", key, value);
+    /// }
+    /// ```
+    pub fn read_sorted(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        // 1. 解析所有存在的 key 的位置
+        let mut positions: Vec<(usize, ValuePos)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| self.index.get(&self.ns_key(key)).map(|pos| (i, *pos)))
+            .collect();
+
+        // 2. 按文件偏移量排序后顺序读取
+        positions.sort_unstable_by_key(|(_, pos)| pos.offset);
+
+        // 3. 放回输入顺序
+        let mut values = vec![None; keys.len()];
+        for (i, pos) in positions {
+            values[i] = Some(self.wal.read_at(pos.offset, pos.len)?);
+        }
+
+        Ok(values)
+    }
+
+    /// 把 key 的 value 直接读入调用方提供的多个缓冲区
+    ///
+    /// ## 参数
+    ///
+    /// - `key`: 要读取的键
+    /// - `bufs`: 按顺序填充的缓冲区（例如网络层准备好的报文头之后的空间）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Some(n))`: 写入 `bufs` 的字节数
+    /// - `Ok(None)`: key 不存在
+    /// - `Err(Error)`: 如果读取失败
+    ///
+    /// ## 行为
+    ///
+    /// value 依次填满 `bufs[0]`、`bufs[1]`……，不经过中间的 `Vec`：
+    /// 在 Linux、Android、FreeBSD 上是一次 `preadv` 系统调用，其他平台退化为逐个缓冲区读取。
+    /// value 在内存中（内联值、缓存）时直接复制。
+    ///
+    /// - value 比缓冲区总容量短：只写入前 `n = value.len()` 字节，最后一个被写到的缓冲区
+    ///   可能只填了一部分，之后的字节和缓冲区保持原样
+    /// - value 比缓冲区总容量长：只读取能放下的前 `n` 字节，`n` 小于 value 的长度。
+    ///   需要完整的 value 时，先用 [`Db::get_cost`] 取得长度再准备缓冲区
+    ///
+    /// 从 WAL 读到的 value 不放入缓存，命中缓存也不更新 LRU 顺序。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use std::io::IoSliceMut;
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let (mut head, mut body) = ([0u8; 16], vec![0u8; 4096]);
+    /// let mut bufs = [IoSliceMut::new(&mut head), IoSliceMut::new(&mut body)];
+    /// if let Some(n) = db.read_value_vectored(b"user:1", &mut bufs).unwrap() {
+    ///     println!("read {} bytes", n);
+    /// }
+    /// ```
+    pub fn read_value_vectored(
+        &mut self,
+        key: &[u8],
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Result<Option<usize>> {
+        let key = &*self.ns_key(key);
+        let Some(&pos) = self.index.get(key) else {
+            return Ok(None);
+        };
+
+        if let Some(value) = self.index.get_inline(key).or_else(|| self.cache.peek(key)) {
+            let mut copied = 0;
+            for buf in bufs.iter_mut() {
+                let take = buf.len().min(value.len() - copied);
+                buf[..take].copy_from_slice(&value[copied..copied + take]);
+                copied += take;
+            }
+            return Ok(Some(copied));
+        }
+
+        self.wal.read_vectored(pos.offset, pos.len, bufs).map(Some)
+    }
+
+    /// 以字符串形式读取键对应的值
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Some(String))`: 找到 key，value 是合法的 UTF-8
+    /// - `Ok(None)`: key 不存在
+    /// - `Err(Error::Utf8)`: 存储的 value 不是合法的 UTF-8
+    /// - `Err(Error)`: 如果读取失败
+    pub fn get_str(&mut self, key: &str) -> Result<Option<String>> {
+        match self.get(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 把一组热点 key 的 value 预先读入缓存
+    ///
+    /// ## 参数
+    ///
+    /// - `keys`: 要预热的 key（不存在的 key 被忽略）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(usize)`: 本次从磁盘读入缓存的 value 数量（已经在缓存中的不计）
+    /// - `Err(Error)`: 如果读取失败
+    ///
+    /// ## 行为
+    ///
+    /// 与 [`Db::read_sorted`] 一样按 value 在 WAL 中的偏移量顺序读取。
+    /// 未启用缓存（`cache_capacity_bytes: 0`）时什么也不做，返回 0。
+    ///
+    /// 预热的总量超过缓存容量时，先读入的条目会被后读入的淘汰。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let opts = Options::builder().cache_capacity_bytes(64 << 20).build();
+    /// let mut db = Db::open("data/db1", opts).unwrap();
+    /// let loaded = db.warm_cache(&[b"config", b"user:1"]).unwrap();
+    /// println!("preloaded {} values", loaded);
+    /// ```
+    pub fn warm_cache(&mut self, keys: &[&[u8]]) -> Result<usize> {
+        if !self.cache.is_enabled() {
+            return Ok(0);
+        }
+
+        let mut positions: Vec<(ValuePos, Cow<[u8]>)> = keys
+            .iter()
+            .map(|key| self.ns_key(key))
+            .filter(|key| !self.cache.contains(key))
+            .filter_map(|key| self.index.get(&key).map(|pos| (*pos, key)))
+            .collect();
+        positions.sort_unstable_by_key(|(pos, _)| pos.offset);
+
+        let mut loaded = 0;
+        for (pos, key) in positions {
+            // 同一个 key 可能重复出现
+            if self.cache.contains(&key) {
+                continue;
+            }
+            let value = self.wal.read_at(pos.offset, pos.len)?;
+            self.cache.insert(&key, &value);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// 按 key 的字节序遍历半开区间 `[start, end)` 内的键值对
+    ///
+    /// ## 参数
+    ///
+    /// - `start`: 起始 key（包含）
+    /// - `end`: 结束 key（不包含）
+    ///
+    /// ## 返回值
+    ///
+    /// 一个 [`RangeIter`]，按 key 的字节序逐个产生 `Ok((key, value))`；
+    /// 读取某个 value 失败时产生对应的 `Err`。`start >= end` 时是一个空的迭代器。
+    ///
+    /// ## 行为
+    ///
+    /// 创建时在索引中找出区间内的所有 key 并排序（只复制 key 和位置），
+    /// 每个 value 在迭代到它时才读取（优先取自内联值和缓存），内存中同时只有一个 value。
+    /// 索引是哈希表，创建迭代器的开销与 key 的总数成正比，与区间大小无关。
+    ///
+    /// 迭代器持有 `&mut self`，遍历期间不能写入，看到的是创建时的一致状态。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// for entry in db.scan(b"user:1:", b"user:1;") {
+    ///     let (key, value) = entry.unwrap();
+    ///     println!("{:?} => {} bytes", key, value.len());
+    /// }
+    /// ```
+    pub fn scan(&mut self, start: &[u8], end: &[u8]) -> RangeIter<'_> {
+        let (start, end) = (self.ns_key(start), self.ns_key(end));
+        self.range_iter(&start, Some(&end))
+    }
+
+    /// 按 key 的字节序遍历所有以 `prefix` 开头的键值对
+    ///
+    /// ## 参数
+    ///
+    /// - `prefix`: key 前缀（空前缀遍历整个数据库）
+    ///
+    /// ## 返回值
+    ///
+    /// 与 [`Db::scan`] 相同的 [`RangeIter`]。
+    ///
+    /// ## 行为
+    ///
+    /// 等价于 `scan(prefix, upper)`，其中 `upper` 是去掉末尾的 `0xFF` 字节后
+    /// 把最后一个字节加一得到的 key：`item:` 对应 `item;`，`a\xff` 对应 `b`。
+    /// 前缀全是 `0xFF`（或为空）时没有这样的上界，一直遍历到最后一个 key。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// for entry in db.scan_prefix(b"item:") {
+    ///     let (key, value) = entry.unwrap();
+    ///     println!("{:?} => {} bytes", key, value.len());
+    /// }
+    /// ```
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> RangeIter<'_> {
+        let start = self.ns_key(prefix).into_owned();
+        let end = prefix_successor(&start);
+        self.range_iter(&start, end.as_deref())
+    }
+
+    /// 创建遍历 `[start, end)` 的 [`RangeIter`]，`start`/`end` 含键空间前缀
+    fn range_iter(&mut self, start: &[u8], end: Option<&[u8]>) -> RangeIter<'_> {
+        let entries: Vec<(Vec<u8>, ValuePos)> = self
+            .index
+            .range_sorted(start, end)
+            .into_iter()
+            .map(|(key, pos)| (key.to_vec(), pos))
+            .collect();
+
+        RangeIter {
+            ns_len: self.opts.key_prefix.len(),
+            entries: entries.into_iter(),
+            db: self,
+        }
+    }
+
+    /// 读取所有以 `prefix` 开头的键值对，数量或总大小超限时提前停止
+    ///
+    /// ## 参数
+    ///
+    /// - `prefix`: key 前缀（空前缀匹配所有 key）
+    /// - `max_entries`: 最多返回多少个键值对
+    /// - `max_bytes`: 返回的 key + value 总字节数上限
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok((entries, truncated))`: 按 key 的字节序排列的键值对；
+    ///   `truncated = true` 表示还有匹配的 key 因为超限没有返回
+    /// - `Err(Error)`: 如果读取失败
+    ///
+    /// ## 为什么需要上限？
+    ///
+    /// 前缀来自不可信输入时，一个过短的前缀可能匹配数百万个 key，
+    /// 把它们的 value 全部读进内存会耗尽内存。这里在读取每个 value 之前
+    /// 就用索引中的长度检查上限，超限的 value 不会被读取。
+    ///
+    /// 一个键值对本身就超过 `max_bytes` 时同样会停止（结果可能为空，`truncated = true`）。
+    /// 需要继续读取时，可以用最后一个 key 作为 [`Db::keys_paginated`] 的游标。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let (entries, truncated) = db.scan_prefix_limited(b"user:", 1000, 1 << 20).unwrap();
+    /// for (key, value) in &entries {
+    ///     println!("{:?} => {} bytes", key, value.len());
+    /// }
+    /// if truncated {
+    ///     println!("more results omitted");
+    /// }
+    /// ```
+    pub fn scan_prefix_limited(
+        &mut self,
+        prefix: &[u8],
+        max_entries: usize,
+        max_bytes: usize,
+    ) -> Result<(Vec<KvPair>, bool)> {
+        let mut entries = Vec::new();
+        let mut bytes = 0usize;
+        let full_prefix = self.ns_key(prefix);
+        let ns_len = self.opts.key_prefix.len();
+
+        // 1. 在索引中按字节序找到所有匹配的 key（返回去掉键空间前缀的 key）
+        for (key, pos) in self.index.prefix_sorted(&full_prefix) {
+            let key = &key[ns_len..];
+
+            // 2. 读取 value 之前检查上限
+            let entry_bytes = key.len() + pos.len;
+            if entries.len() >= max_entries || bytes + entry_bytes > max_bytes {
+                return Ok((entries, true));
+            }
+
+            // 3. 从 WAL 读取 value
+            let value = self.wal.read_at(pos.offset, pos.len)?;
+            entries.push((key.to_vec(), value));
+            bytes += entry_bytes;
+        }
+
+        Ok((entries, false))
+    }
+
+    /// 按 key 的字节序把匹配前缀的键值对逐个交给回调，回调可以随时停止
+    ///
+    /// ## 参数
+    ///
+    /// - `prefix`: key 前缀（空前缀匹配所有 key）
+    /// - `f`: 对每个 `(key, value)` 调用一次；返回 `ControlFlow::Break(())` 时停止扫描
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 扫描完成，或被 `f` 提前停止
+    /// - `Err(Error)`: 如果读取 value 失败
+    ///
+    /// ## 行为
+    ///
+    /// 1. 在索引中按字节序找出所有匹配的 key（只复制 key，不读取 value）
+    /// 2. 每次取接下来的一小段 key（64 个），按 value 在 WAL 中的偏移量排序后顺序读取
+    /// 3. 把这一段按 key 的顺序交给 `f`
+    ///
+    /// 磁盘上的读取是分段的单向扫描，回调看到的顺序仍然是 key 的顺序。
+    /// `f` 返回 `Break` 后立即返回，之后的段不会再读取；同一段中已经预读的 value 被丢弃。
+    /// 内存中只保留一段 value，适合把大范围的结果流式发送给客户端、
+    /// 在客户端断开时停止。value 优先取自内联值和缓存，不更新缓存。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use std::ops::ControlFlow;
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let mut sent = 0;
+    /// db.scan_prefix_each(b"user:", |key, value| {
+    ///     println!("{:?} => {} bytes", key, value.len());
+    ///     sent += 1;
+    ///     if sent == 100 {
+    ///         ControlFlow::Break(())
+    ///     } else {
+    ///         ControlFlow::Continue(())
+    ///     }
+    /// })
+    /// .unwrap();
+    /// ```
+    pub fn scan_prefix_each<F>(&mut self, prefix: &[u8], mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> ControlFlow<()>,
+    {
+        let ns_len = self.opts.key_prefix.len();
+
+        // 1. 按字节序找到所有匹配的 key（读取 value 需要 &mut self，先复制出来）
+        let entries: Vec<(Vec<u8>, ValuePos)> = self
+            .index
+            .prefix_sorted(&self.ns_key(prefix))
+            .into_iter()
+            .map(|(key, pos)| (key.to_vec(), pos))
+            .collect();
+
+        for chunk in entries.chunks(SCAN_READ_AHEAD) {
+            // 2. 按偏移量顺序读取这一段的 value
+            let mut order: Vec<usize> = (0..chunk.len()).collect();
+            order.sort_unstable_by_key(|&i| chunk[i].1.offset);
+            let mut values = vec![Vec::new(); chunk.len()];
+            for i in order {
+                let (key, pos) = &chunk[i];
+                values[i] = self.stored_value(key, *pos)?;
+            }
+
+            // 3. 按 key 的顺序交给回调
+            for ((key, _), value) in chunk.iter().zip(&values) {
+                if f(&key[ns_len..], value).is_break() {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 依次访问每个存活的键值对
+    ///
+    /// ## 参数
+    ///
+    /// - `f`: 对每个 `(key, value)` 调用一次；返回 `Err` 时立即停止遍历
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 所有键值对都已访问
+    /// - `Err(Error)`: `f` 返回的第一个错误，或读取 value 失败
+    ///
+    /// ## 与收集结果的区别
+    ///
+    /// 不构建结果列表，每个 value 读出后交给 `f`，随即释放。
+    /// 适合求和、统计、构建二级索引等只需要折叠一遍的场景。
+    ///
+    /// 访问顺序不确定（取决于内部哈希表），需要按 key 排序时使用
+    /// [`Db::keys_paginated`] 或 [`Db::scan_prefix_limited`]。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let mut total = 0;
+    /// db.for_each(|_key, value| {
+    ///     total += value.len();
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// println!("{} bytes of values", total);
+    /// ```
+    pub fn for_each<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<()>,
+    {
+        for (key, pos) in self.index.iter() {
+            let Some(key) = key.strip_prefix(self.opts.key_prefix.as_slice()) else {
+                continue;
+            };
+            let value = self.wal.read_at(pos.offset, pos.len)?;
+            f(key, &value)?;
+        }
+
+        Ok(())
+    }
+
+    /// 只保留 `f` 返回 `true` 的键值对，其余全部删除
+    ///
+    /// ## 参数
+    ///
+    /// - `f`: 对每个 `(key, value)` 调用一次，返回 `false` 表示删除
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(usize)`: 删除的键的数量
+    /// - `Err(Error)`: 如果读取 value 或写入 WAL 失败（此时没有删除任何键）
+    ///
+    /// ## 行为
+    ///
+    /// 1. 遍历所有存活的键值对，记下 `f` 返回 `false` 的 key
+    /// 2. 把这些 key 的 DELETE 记录作为一个批次追加到 WAL（见 [`Db::multi_delete`]）
+    ///
+    /// 相当于 `HashMap::retain`。没有需要删除的 key 时不写入任何内容。
+    ///
+    /// ## 开销
+    ///
+    /// `f` 需要 value，因此每个键值对都要从 WAL 读取一次（内联在索引中的小 value 除外），
+    /// 耗时与数据总量成正比。只按 key 过滤时，用 [`Db::keys_paginated`] 收集 key
+    /// 再调用 [`Db::multi_delete`] 可以避免这些读取。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// // 删除所有空 value
+    /// let removed = db.retain(|_key, value| !value.is_empty()).unwrap();
+    /// println!("removed {} keys", removed);
+    /// ```
+    pub fn retain<F>(&mut self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        // 1. 找出需要删除的 key
+        let mut doomed = Vec::new();
+        for (key, pos) in self.index.iter() {
+            let Some(user_key) = key.strip_prefix(self.opts.key_prefix.as_slice()) else {
+                continue;
+            };
+            let keep = match self.index.get_inline(key) {
+                Some(value) => f(user_key, value),
+                None => f(user_key, &self.wal.read_at(pos.offset, pos.len)?),
+            };
+            if !keep {
+                doomed.push(user_key.to_vec());
+            }
+        }
+
+        // 2. 一个批次删除
+        if doomed.is_empty() {
+            return Ok(0);
+        }
+        let keys: Vec<&[u8]> = doomed.iter().map(Vec::as_slice).collect();
+        self.multi_delete(&keys)
+    }
+
+    /// 比较两个数据库的内容
+    ///
+    /// ## 参数
+    ///
+    /// - `other`: 要比较的另一个数据库
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(DiffReport)`: 只在一边存在的 key，以及两边 value 不同的 key（都按字节序排列）；
+    ///   [`DiffReport::is_empty`] 为 `true` 表示两个数据库逻辑上相同
+    /// - `Err(Error)`: 如果读取 value 失败
+    ///
+    /// ## 行为
+    ///
+    /// 1. 对比两边的索引，找出只在一边存在的 key
+    /// 2. 两边都存在的 key：value 长度不同时直接判定为不同，不读取 value
+    /// 3. 其余 key 按 `self` 中的偏移量顺序读取两边的 value 逐一比较，
+    ///    `self` 一侧是顺序读
+    ///
+    /// 只比较 key 和 value，不比较序列号、WAL 布局或墓碑：压缩前后的同一个数据库、
+    /// 备份和原库、追上进度的副本和主库，比较结果都应当为空。
+    /// value 从内存（内联值、缓存）或 WAL 读取，不会改变缓存内容。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut primary = Db::open("data/db1", Options::default()).unwrap();
+    /// let mut backup = Db::open("backup/db1", Options::default()).unwrap();
+    ///
+    /// let diff = primary.diff(&mut backup).unwrap();
+    /// assert!(diff.is_empty(), "backup differs: {:?}", diff);
+    /// ```
+    pub fn diff(&mut self, other: &mut Db) -> Result<DiffReport> {
+        let mut report = DiffReport::default();
+
+        // 1. 只在一边存在的 key；两边都存在时先比较长度
+        for (key, _) in other.index.iter() {
+            let Some(key) = other.user_key(key) else {
+                continue;
+            };
+            if !self.index.contains_key(&self.ns_key(key)) {
+                report.only_in_other.push(key.to_vec());
+            }
+        }
+        let mut common = Vec::new();
+        for (key, pos) in self.index.iter() {
+            let Some(key) = self.user_key(key) else {
+                continue;
+            };
+            match other.index.get(&other.ns_key(key)) {
+                None => report.only_in_self.push(key.to_vec()),
+                Some(other_pos) if other_pos.len != pos.len => report.differing.push(key.to_vec()),
+                Some(other_pos) => common.push((key.to_vec(), *pos, *other_pos)),
+            }
+        }
+
+        // 2. 按 self 中的偏移量顺序读取 value 比较
+        common.sort_unstable_by_key(|(_, pos, _)| pos.offset);
+        for (key, pos, other_pos) in common {
+            let ours = self.stored_value(&self.ns_key(&key), pos)?;
+            if ours != other.stored_value(&other.ns_key(&key), other_pos)? {
+                report.differing.push(key);
+            }
+        }
+
+        report.only_in_self.sort_unstable();
+        report.only_in_other.sort_unstable();
+        report.differing.sort_unstable();
+        Ok(report)
+    }
+
+    /// 把另一个目录中的数据库合并进来
+    ///
+    /// ## 参数
+    ///
+    /// - `other`: 另一个数据库的目录
+    /// - `on_conflict`: 两边都存在的 key 如何处理，见 [`ConflictPolicy`]
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(usize)`: 写入 `self` 的 key 的数量
+    /// - `Err(Error)`: 打开或读取 `other` 失败，或者写入失败（之前已写入的 key 保留）
+    ///
+    /// ## 行为
+    ///
+    /// 1. 以只读模式打开 `other`（replay 它的 WAL，不加目录锁，不修改其中的文件），
+    ///    读取时不限制 key/value 大小
+    /// 2. 遍历它所有存活的键值对：`self` 中没有的 key 直接写入；两边都有的按
+    ///    `on_conflict` 决定最终的 value
+    /// 3. 逐个调用 [`Db::put`] 写入，与普通写入一样受 `limits`、`sync_on_write`、
+    ///    `key_prefix` 等选项约束；最终 value 与 `self` 中已有的相同时不写入、不计数
+    ///
+    /// `other` 的墓碑不会传播：只在 `self` 中存在的 key 保持不变。
+    /// `other` 正在被另一个可写的 `Db` 使用时，看到的是打开那一刻的内容。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{ConflictPolicy, Db, Options};
+    ///
+    /// let mut db = Db::open("data/shard0", Options::default()).unwrap();
+    /// let merged = db.merge_from_dir("data/shard1", ConflictPolicy::TakeOther).unwrap();
+    /// println!("merged {} keys", merged);
+    /// ```
+    pub fn merge_from_dir(
+        &mut self,
+        other: impl AsRef<Path>,
+        mut on_conflict: ConflictPolicy,
+    ) -> Result<usize> {
+        let unlimited = Limits {
+            max_key_size: usize::MAX,
+            max_value_size: usize::MAX,
+        };
+        let opts = Options::builder().read_only(true).limits(unlimited).build();
+        let mut source = Db::open(other, opts)?;
+
+        let mut written = 0;
+        source.for_each(|key, theirs| {
+            let value = match (self.get(key)?, &mut on_conflict) {
+                (None, _) => Cow::Borrowed(theirs),
+                (Some(_), ConflictPolicy::KeepSelf) => return Ok(()),
+                (Some(ours), ConflictPolicy::TakeOther) if ours == theirs => return Ok(()),
+                (Some(_), ConflictPolicy::TakeOther) => Cow::Borrowed(theirs),
+                (Some(ours), ConflictPolicy::Resolve(f)) => {
+                    let value = f(key, &ours, theirs);
+                    if value == ours {
+                        return Ok(());
+                    }
+                    Cow::Owned(value)
+                }
+            };
+            self.put(key, &value)?;
+            written += 1;
+            Ok(())
+        })?;
+
+        Ok(written)
+    }
+
+    /// 读取 `key` 的 value（`pos` 是它在索引中的位置），优先使用内联值和缓存，不更新缓存
+    fn stored_value(&mut self, key: &[u8], pos: ValuePos) -> Result<Vec<u8>> {
+        if let Some(value) = self.index.get_inline(key).or_else(|| self.cache.peek(key)) {
+            return Ok(value.to_vec());
+        }
+        self.wal.read_at(pos.offset, pos.len)
+    }
+
+    /// 删除键
+    ///
+    /// ## 参数
+    ///
+    /// - `key`: 要删除的键
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 删除成功（无论 key 是否存在）
+    /// - `Err(Error)`: 如果操作失败
+    ///
+    /// ## 行为
+    ///
+    /// 1. 写入 DELETE 记录到 WAL
+    /// 2. 从内存索引中移除 key
+    ///
+    /// ## 注意
+    ///
+    /// - 删除不会立即释放磁盘空间（WAL 是追加的）
+    /// - 需要 Compaction（v0.2）来回收空间
+    /// - 删除不存在的 key 也会写入 WAL（保证操作的持久化语义）
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// db.put(b"key", b"value").unwrap();
+    /// db.delete(b"key").unwrap();
+    ///
+    /// assert_eq!(db.get(b"key").unwrap(), None);
+    /// ```
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let key = &*self.ns_key(key);
+
+        // 1. 创建 DELETE 记录
+        let seq = self.last_seq + 1;
+        let record = Record::delete_with_limits(key.to_vec(), &self.opts.limits)?;
+        let record = self.sequenced(record, seq);
+
+        // 2. 追加到 WAL
+        let sync = self.opts.sync_on_write;
+        self.wal_write(|wal| wal.append(&record, sync))?;
+        self.wal_records += 1;
+        self.last_seq = seq;
+
+        // 3. 从索引和缓存中移除
+        let tombstone = Tombstone {
+            seq,
+            timestamp: record.timestamp,
+        };
+        let existed = self.index.delete(key, tombstone).is_some();
+        self.cache.remove(key);
+        self.notify_delete(key, seq, existed);
+
+        self.after_write();
+        Ok(())
+    }
+
+    /// 批量删除多个键
+    ///
+    /// ## 参数
+    ///
+    /// - `keys`: 要删除的键列表
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(usize)`: 删除前确实存在的键的数量
+    /// - `Err(Error)`: 如果操作失败或某个 key 超出大小限制
+    ///
+    /// ## 行为
+    ///
+    /// 1. 为每个 key 创建 DELETE 记录（任意一个 key 超限则整体拒绝，不写入任何内容）
+    /// 2. 作为一个批次追加到 WAL：一次写入，最多一次 fsync
+    /// 3. 从内存索引中移除所有 key
+    ///
+    /// ## 崩溃安全性
+    ///
+    /// 与单条 `delete` 相比，sync_on_write=true 时 N 个 key 只需要一次 fsync。
+    /// 批次是原子的：如果崩溃导致批次只写入了一部分，replay 会丢弃整个批次，
+    /// 不会出现"一部分 key 被删除"的中间状态。
+    ///
+    /// 与 `delete` 一样，不存在的 key 也会写入 DELETE 记录。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// db.put(b"a", b"1").unwrap();
+    /// db.put(b"b", b"2").unwrap();
+    ///
+    /// let removed = db.multi_delete(&[b"a", b"b", b"missing"]).unwrap();
+    /// assert_eq!(removed, 2);
+    /// ```
+    pub fn multi_delete(&mut self, keys: &[&[u8]]) -> Result<usize> {
+        let full_keys: Vec<Cow<[u8]>> = keys.iter().map(|key| self.ns_key(key)).collect();
+        let keys: Vec<&[u8]> = full_keys.iter().map(|key| &**key).collect();
+
+        // 1. 创建所有 DELETE 记录（会验证大小）
+        let records = keys
+            .iter()
+            .zip(self.last_seq + 1..)
+            .map(|(key, seq)| {
+                let record = Record::delete_with_limits(key.to_vec(), &self.opts.limits)?;
+                Ok(self.sequenced(record, seq))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // 2. 作为一个批次追加到 WAL
+        let sync = self.opts.sync_on_write;
+        self.wal_write(|wal| wal.append_batch(&records, sync))?;
+        self.wal_records += records.len() as u64;
+        self.last_seq += records.len() as u64;
+
+        // 3. 从索引和缓存中移除，统计删除前存在的 key
+        for key in &keys {
+            self.cache.remove(key);
+        }
+        let first_seq = self.last_seq - records.len() as u64 + 1;
+        let mut removed = 0;
+        for ((key, record), seq) in keys.iter().zip(&records).zip(first_seq..) {
+            let tombstone = Tombstone {
+                seq,
+                timestamp: record.timestamp,
+            };
+            let existed = self.index.delete(key, tombstone).is_some();
+            removed += usize::from(existed);
+            self.notify_delete(key, seq, existed);
+        }
+
+        self.after_write();
+        Ok(removed)
+    }
+
+    /// 把 `from` 的值移动到新键 `to`
+    ///
+    /// ## 参数
+    ///
+    /// - `from`: 原键
+    /// - `to`: 新键（如果已存在会被覆盖）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(true)`: 移动成功
+    /// - `Ok(false)`: `from` 不存在，没有写入任何内容
+    /// - `Err(Error)`: 如果操作失败或 `to` 超出大小限制
+    ///
+    /// ## 行为
+    ///
+    /// 1. 读取 `from` 的值
+    /// 2. 把 PUT(to, value) 和 DELETE(from) 作为一个批次追加到 WAL（最多一次 fsync）
+    /// 3. 更新内存索引
+    ///
+    /// ## 为什么重写 value 而不是复用原来的位置？
+    ///
+    /// 索引只保存 value 的位置，直接让 `to` 指向 `from` 原来的 value 看似更省，
+    /// 但 WAL 中并没有记录这个"别名"关系，重启 replay 后就无法恢复。
+    /// 因此这里会把 value 重新写一份。批次保证了崩溃后要么两条记录都生效，
+    /// 要么都不生效，不会出现两个键都存在或都丢失的情况。
+    ///
+    /// `from == to` 时不写入任何内容，只返回该键是否存在。
+    pub fn rename_key(&mut self, from: &[u8], to: &[u8]) -> Result<bool> {
+        if from == to {
+            return Ok(self.index.contains_key(&self.ns_key(from)));
+        }
+
+        // 1. 读取原值
+        let value = match self.get(from)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let from = &*self.ns_key(from);
+        let to = &*self.ns_key(to);
+
+        // 2. 创建记录（会验证大小）
+        let put_seq = self.last_seq + 1;
+        let put = Record::put_with_limits(to.to_vec(), value, &self.opts.limits)?;
+        let delete = Record::delete_with_limits(from.to_vec(), &self.opts.limits)?;
+        let records = [
+            self.sequenced(put, put_seq),
+            self.sequenced(delete, put_seq + 1),
+        ];
+
+        // 3. 作为一个批次追加到 WAL
+        let sync = self.opts.sync_on_write;
+        let offsets = self.wal_write(|wal| wal.append_batch(&records, sync))?;
+        self.wal_records += records.len() as u64;
+        self.last_seq = put_seq + 1;
+
+        // 4. 更新索引
+        let put = &records[0];
+        self.index.insert_value(
+            to.to_vec(),
+            ValuePos {
+                offset: offsets[0] + put.value_offset(),
+                len: put.value.len(),
+                seq: put_seq,
+            },
+            &put.value,
+        );
+        let tombstone = Tombstone {
+            seq: put_seq + 1,
+            timestamp: records[1].timestamp,
+        };
+        self.index.delete(from, tombstone);
+        self.cache.remove(from);
+        self.cache_written(to, &put.value);
+        self.notify_put(to, &put.value, put_seq);
+        self.notify_delete(from, put_seq + 1, true);
+
+        self.after_write();
+        Ok(true)
+    }
+
+    /// 原子地交换两个 key 的值
+    ///
+    /// ## 参数
+    ///
+    /// - `a`, `b`: 要交换的两个键
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 交换成功
+    /// - `Err(Error)`: 如果操作失败或某个 key 超出大小限制
+    ///
+    /// ## 行为
+    ///
+    /// 1. 读取两个 key 的当前值
+    /// 2. 每个 key 写成一条记录：对方存在时是 PUT(对方的值)，对方不存在时是 DELETE，
+    ///    两条记录作为一个批次追加到 WAL（最多一次 fsync）
+    /// 3. 更新内存索引
+    ///
+    /// 不存在也参与交换：只有 `a` 存在时，交换后 `b` 得到 `a` 的值，`a` 被删除。
+    /// 两个 key 都不存在或 `a == b` 时不写入任何内容。
+    ///
+    /// 与 [`Db::rename_key`] 一样，批次保证了崩溃后要么两条记录都生效，要么都不生效。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// db.put(b"a", b"1").unwrap();
+    /// db.put(b"b", b"2").unwrap();
+    ///
+    /// db.swap(b"a", b"b").unwrap();
+    /// assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+    /// ```
+    pub fn swap(&mut self, a: &[u8], b: &[u8]) -> Result<()> {
+        if a == b {
+            return Ok(());
+        }
+
+        // 1. 读取两个值
+        let value_a = self.get(a)?;
+        let value_b = self.get(b)?;
+        if value_a.is_none() && value_b.is_none() {
+            return Ok(());
+        }
+        let a = &*self.ns_key(a);
+        let b = &*self.ns_key(b);
+
+        // 2. 创建记录（会验证大小）：每个 key 得到对方的值
+        let first_seq = self.last_seq + 1;
+        let records = [(a, value_b), (b, value_a)]
+            .into_iter()
+            .zip(first_seq..)
+            .map(|((key, value), seq)| {
+                let record = match value {
+                    Some(value) => Record::put_with_limits(key.to_vec(), value, &self.opts.limits)?,
+                    None => Record::delete_with_limits(key.to_vec(), &self.opts.limits)?,
+                };
+                Ok(self.sequenced(record, seq))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // 3. 作为一个批次追加到 WAL
+        let sync = self.opts.sync_on_write;
+        let offsets = self.wal_write(|wal| wal.append_batch(&records, sync))?;
+        self.wal_records += records.len() as u64;
+        self.last_seq = first_seq + 1;
+
+        // 4. 更新索引和缓存
+        self.apply_written(&records, offsets, first_seq);
+
+        self.after_write();
+        Ok(())
+    }
+
+    /// 原子地应用一批写入和删除
+    ///
+    /// ## 参数
+    ///
+    /// - `batch`: 按顺序积累的 put/delete 操作，见 [`WriteBatch`]
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 整批已写入（`sync_on_write = true` 时已经落盘）
+    /// - `Err(Error::BatchTooLarge)`: 操作数超出 `u32::MAX`，不写入任何内容
+    /// - `Err(Error)`: 如果写入失败或某个 key/value 超出大小限制
+    ///
+    /// ## 行为
+    ///
+    /// 1. 为每个操作创建 PUT/DELETE 记录（任意一个超限则整体拒绝，不写入任何内容）
+    /// 2. 作为一个批次追加到 WAL：一次写入，最多一次 fsync
+    /// 3. 按顺序更新内存索引和缓存，通知订阅者
+    ///
+    /// 与逐条 `put`/`delete` 相比，sync_on_write=true 时 N 个操作只需要一次 fsync，
+    /// 持久性不变。与 [`Db::multi_delete`] 一样，崩溃后 replay 要么应用整批，要么整批丢弃。
+    /// 空批次不写入任何内容。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options, WriteBatch};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let mut batch = WriteBatch::new();
+    /// batch.put(b"a", b"1").put(b"b", b"2").delete(b"c");
+    /// db.write_batch(batch).unwrap();
+    /// ```
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // 1. 创建所有记录（会验证大小）
+        let first_seq = self.last_seq + 1;
+        let records = batch
+            .into_ops()
+            .into_iter()
+            .zip(first_seq..)
+            .map(|(op, seq)| {
+                let record = match op {
+                    BatchOp::Put(key, value) => {
+                        let key = self.ns_key(&key).into_owned();
+                        Record::put_with_limits(key, value, &self.opts.limits)?
+                    }
+                    BatchOp::Delete(key) => {
+                        let key = self.ns_key(&key).into_owned();
+                        Record::delete_with_limits(key, &self.opts.limits)?
+                    }
+                };
+                Ok(self.sequenced(record, seq))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // 2. 作为一个批次追加到 WAL
+        let sync = self.opts.sync_on_write;
+        let offsets = self.wal_write(|wal| wal.append_batch(&records, sync))?;
+        self.wal_records += records.len() as u64;
+        self.last_seq += records.len() as u64;
+
+        // 3. 更新索引和缓存
+        self.apply_written(&records, offsets, first_seq);
+
+        self.after_write();
+        Ok(())
+    }
+
+    /// 把已经追加到 WAL 的 PUT/DELETE 记录按顺序应用到索引和缓存，并通知订阅者
+    ///
+    /// `offsets` 是 `append_batch` 返回的各条记录的偏移量，`first_seq` 是第一条记录的序列号
+    fn apply_written(&mut self, records: &[Record], offsets: Vec<u64>, first_seq: u64) {
+        for ((record, offset), seq) in records.iter().zip(offsets).zip(first_seq..) {
+            match record.kind {
+                RecordKind::Put => {
+                    self.index.insert_value(
+                        record.key.clone(),
+                        ValuePos {
+                            offset: offset + record.value_offset(),
+                            len: record.value.len(),
+                            seq,
+                        },
+                        &record.value,
+                    );
+                    self.cache_written(&record.key, &record.value);
+                    self.notify_put(&record.key, &record.value, seq);
+                }
+                _ => {
+                    let tombstone = Tombstone {
+                        seq,
+                        timestamp: record.timestamp,
+                    };
+                    let existed = self.index.delete(&record.key, tombstone).is_some();
+                    self.cache.remove(&record.key);
+                    self.notify_delete(&record.key, seq, existed);
+                }
+            }
+        }
+    }
+
+    /// 追上 WAL 中新追加的记录（只读 follower）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(usize)`: 本次新应用到索引的记录数（0 表示没有新数据）
+    /// - `Err(Error)`: 如果读取失败
+    ///
+    /// ## 使用场景
+    ///
+    /// 以 `read_only: true` 打开一个与主库共享（或通过 rsync 同步）的目录，
+    /// 然后周期性调用 `tail()`：
+    ///
+    /// - 读取上次位置之后所有完整的记录，按顺序应用到内存索引
+    /// - 主库正在写入的半条记录（或不完整的批次）会被跳过，下次调用时重试
+    /// - 不会截断或修改任何文件
+    ///
+    /// 只支持主库在末尾追加的情况；如果主库的 WAL 被整体重写，follower 需要重新打开。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let opts = Options::builder().read_only(true).build();
+    /// let mut follower = Db::open("data/primary", opts).unwrap();
+    ///
+    /// loop {
+    ///     let caught_up = follower.tail().unwrap();
+    ///     if caught_up > 0 {
+    ///         println!("applied {} records", caught_up);
+    ///     }
+    ///     std::thread::sleep(std::time::Duration::from_secs(1));
+    /// }
+    /// ```
+    pub fn tail(&mut self) -> Result<usize> {
+        let verified_end = self.wal.size();
+        let records = self.wal.tail()?;
+        for (_, record) in &records {
+            self.cache.remove(&record.key);
+        }
+        Self::apply_records(&mut self.index, &records, &mut self.last_seq, verified_end);
+        self.last_seq = self.last_seq.max(self.wal.max_seq());
+        self.wal_records += records.len() as u64;
+        Ok(records.len())
+    }
+
+    /// 在 WAL 中写入一条 NOOP 标记记录
+    ///
+    /// ## 参数
+    ///
+    /// - `payload`: 标记的负载（可以为空），例如单调递增的序列号
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(u64)`: 标记记录在 WAL 中的起始偏移量
+    /// - `Err(Error)`: 如果负载过大或写入失败
+    ///
+    /// 标记不影响任何数据，replay 时被跳过，只有 [`WalReader`](crate::WalReader)
+    /// 会返回它。外部工具可以用它在 WAL 中对齐位置。
+    pub fn append_marker(&mut self, payload: &[u8]) -> Result<u64> {
+        let record = Record::noop(payload.to_vec())?;
+        let sync = self.opts.sync_on_write;
+        let offset = self.wal_write(|wal| wal.append(&record, sync))?;
+        self.after_write();
+        Ok(offset)
+    }
+
+    /// 把所有已写入的数据持久化到磁盘
+    ///
+    /// 写缓冲区（以及后台写回队列）中的数据先写入文件，然后调用 fsync。
+    /// `sync_on_write: false` 或启用 `write_buffer_bytes`、`write_back_bytes` 时，
+    /// 调用方可以在合适的时机调用它来获得持久化保证。
+    pub fn sync(&mut self) -> Result<()> {
+        self.wal_write(Wal::sync)
+    }
+
+    /// 已写入但还没有 fsync 的字节数，即崩溃（断电）时可能丢失的数据量
+    ///
+    /// 包括写缓冲区、后台写回队列中的数据，以及已经交给 OS 但还没有 fsync 的数据。
+    /// [`Db::sync`]、`sync_on_write` 的写入、`flush_interval` 后台线程的 fsync
+    /// 和压缩都会让它归零。只读模式下总是 0。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let opts = Options::builder().sync_on_write(false).build();
+    /// let mut db = Db::open("data/db1", opts).unwrap();
+    /// db.put(b"key", b"value").unwrap();
+    ///
+    /// // 每积累 64KB 未持久化的数据 fsync 一次
+    /// if db.unsynced_bytes() >= 64 * 1024 {
+    ///     db.sync().unwrap();
+    /// }
+    /// ```
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.wal.unsynced_bytes()
+    }
+
+    /// 是否有已写入但还没有 fsync 的数据（`unsynced_bytes() > 0`）
+    pub fn is_dirty(&self) -> bool {
+        self.unsynced_bytes() > 0
+    }
+
+    /// 关闭数据库
+    ///
+    /// 停止后台 fsync 线程（如果有），然后把写缓冲区中的数据写入文件并 fsync。
+    /// 与直接 drop 不同，持久化失败时会返回错误。只读模式下只停止后台线程。
+    pub fn close(mut self) -> Result<()> {
+        drop(self.syncer.take());
+        if self.opts.read_only {
+            return Ok(());
+        }
+        self.wal.sync()
+    }
+
+    /// 在运行时修改选项
+    ///
+    /// ## 参数
+    ///
+    /// - `opts`: 新的完整选项，通常是 `db.options().clone()` 修改后的结果
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 可以运行时调整的选项（见 [`Options`] 的“运行时调整”）已经生效
+    /// - `Err(Error::FixedOption { name })`: `opts` 修改了只在 `open` 时生效的选项 `name`，
+    ///   此时什么也没有改变
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let mut opts = db.options().clone();
+    /// opts.sync_on_write = false;
+    /// opts.cache_capacity_bytes = 64 << 20;
+    /// db.set_options(opts).unwrap();
+    /// ```
+    pub fn set_options(&mut self, opts: Options) -> Result<()> {
+        let current = &self.opts;
+        let fixed = [
+            ("limits", opts.limits == current.limits),
+            ("read_only", opts.read_only == current.read_only),
+            ("open_lock_timeout", opts.open_lock_timeout == current.open_lock_timeout),
+            ("write_buffer_bytes", opts.write_buffer_bytes == current.write_buffer_bytes),
+            ("write_back_bytes", opts.write_back_bytes == current.write_back_bytes),
+            ("upgrade_format", opts.upgrade_format == current.upgrade_format),
+            ("flush_interval", opts.flush_interval == current.flush_interval),
+            ("scan_resync", opts.scan_resync == current.scan_resync),
+            ("fail_on_corruption", opts.fail_on_corruption == current.fail_on_corruption),
+            ("skip_unknown_kinds", opts.skip_unknown_kinds == current.skip_unknown_kinds),
+            ("file_mode", opts.file_mode == current.file_mode),
+            ("direct_io", opts.direct_io == current.direct_io),
+            (
+                "inline_value_threshold",
+                opts.inline_value_threshold == current.inline_value_threshold,
+            ),
+            (
+                "initial_index_capacity",
+                opts.initial_index_capacity == current.initial_index_capacity,
+            ),
+            ("key_prefix", opts.key_prefix == current.key_prefix),
+        ];
+        if let Some(&(name, _)) = fixed.iter().find(|(_, unchanged)| !unchanged) {
+            return Err(Error::FixedOption { name });
+        }
+
+        self.set_sync_mode(opts.sync_on_write)?;
+        self.resize_cache(opts.cache_capacity_bytes);
+        self.opts.checkpoint_interval_bytes = opts.checkpoint_interval_bytes;
+        self.opts.tombstone_ttl = opts.tombstone_ttl;
+        self.opts.dedup_values = opts.dedup_values;
+        self.opts.intern_small_values = opts.intern_small_values;
+        self.opts.on_compact_progress = opts.on_compact_progress;
+        self.opts.on_write_error = opts.on_write_error;
+        self.set_auto_compact_ratio(opts.auto_compact_ratio);
+        Ok(())
+    }
+
+    /// 当前的选项
+    pub fn options(&self) -> &Options {
+        &self.opts
+    }
+
+    /// 在运行时切换 `sync_on_write`
+    ///
+    /// 从 `false` 切换到 `true` 时先 fsync 一次：之前没有 fsync 的写入也在切换后
+    /// 得到持久化保证。只读模式下只记录设置。
+    pub fn set_sync_mode(&mut self, sync_on_write: bool) -> Result<()> {
+        if sync_on_write && !self.opts.sync_on_write && !self.opts.read_only {
+            self.wal_write(Wal::sync)?;
+        }
+        self.opts.sync_on_write = sync_on_write;
+        Ok(())
+    }
+
+    /// 在运行时调整 value 缓存的容量（字节）
+    ///
+    /// 缩小时立即淘汰最久未使用的条目直到不超过新容量，`0` 清空并关闭缓存；
+    /// 从 `0` 开始启用时缓存是空的，之后的读写逐渐填充。
+    pub fn resize_cache(&mut self, bytes: usize) {
+        self.cache.set_capacity(bytes);
+        self.opts.cache_capacity_bytes = bytes;
+    }
+
+    /// 清空 value 缓存，释放它占用的内存
+    ///
+    /// 只丢弃缓存的 value，索引（包括内联在索引中的小 value）和缓存容量都不变，
+    /// 之后的读取重新填充缓存。命中统计不清零，需要时调用 [`Db::reset_cache_stats`]。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let opts = Options::builder().cache_capacity_bytes(64 * 1024 * 1024).build();
+    /// let mut db = Db::open("data/db1", opts).unwrap();
+    ///
+    /// // 内存紧张时：记录释放了多少，然后清空
+    /// let stats = db.cache_stats();
+    /// db.clear_cache();
+    /// println!("dropped {} cached values ({} bytes)", stats.entries, stats.bytes);
+    /// ```
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+    }
+
+    /// value 缓存的当前状态：条目数、字节数，以及上次重置以来的命中/未命中次数
+    ///
+    /// 只统计经过缓存的 [`Db::get`]：内联在索引中的 value 既不算命中也不算未命中，
+    /// 缓存关闭（容量为 0）时计数保持不变。
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// 命中/未命中计数清零，用于按时间窗口统计命中率，不影响缓存内容
+    pub fn reset_cache_stats(&mut self) {
+        self.cache.reset_stats();
+    }
+
+    /// 在运行时设置自动压缩的阈值（见 [`Options::auto_compact_ratio`]）
+    ///
+    /// 新的阈值从下一次写操作开始生效；当前的垃圾比例已经超过它时，
+    /// 下一次写入就会触发压缩。
+    pub fn set_auto_compact_ratio(&mut self, ratio: Option<f64>) {
+        self.opts.auto_compact_ratio = ratio;
+    }
+
+    /// 写入 checkpoint（MANIFEST）
+    ///
+    /// ## 行为
+    ///
+    /// 1. fsync WAL，保证高水位之前的数据都已落盘
+    /// 2. 把当前索引和 WAL 高水位序列化到 `MANIFEST.tmp` 并 fsync
+    /// 3. 原子地 rename 为 `MANIFEST`
+    ///
+    /// 下次 `open` 时只需要 replay 高水位之后写入的记录，
+    /// 启动时间从 O(所有写入) 变为 O(上次 checkpoint 之后的写入)。
+    ///
+    /// ## 崩溃安全性
+    ///
+    /// MANIFEST 带有 CRC 校验，并记录了高水位处 WAL 记录的 CRC：
+    /// 半写入的 MANIFEST，或与 WAL 不再对应的 MANIFEST，都会在 `open` 时被识别并忽略，
+    /// 退回完整 replay。
+    pub fn checkpoint(&mut self) -> Result<()> {
+        // 1. 确保 WAL 已落盘
+        self.wal_write(Wal::sync)?;
+
+        // 2. 记录高水位和该位置之前最后一条记录的 CRC
+        let wal_offset = self.wal.size();
+        let tail_crc = if wal_offset >= 4 {
+            let bytes = self.wal.read_at(wal_offset - 4, 4)?;
+            let mut tail = [0u8; 4];
+            tail.copy_from_slice(&bytes);
+            u32::from_le_bytes(tail)
+        } else {
+            0
+        };
+
+        // 3. 序列化索引并原子写入
+        let manifest = Manifest {
+            wal_offset,
+            tail_crc,
+            record_count: self.wal_records,
+            last_seq: self.last_seq,
+            entries: self
+                .index
+                .iter()
+                .map(|(key, pos)| {
+                    let ref_offset = self.index.ref_offset(key).unwrap_or(0);
+                    (key.clone(), pos.offset, pos.len as u64, pos.seq, ref_offset)
+                })
+                .collect(),
+            tombstones: self
+                .index
+                .tombstones()
+                .map(|(key, t)| (key.clone(), t.seq, t.timestamp))
+                .collect(),
+        };
+        manifest.store(&self.dir)?;
+
+        self.last_checkpoint = wal_offset;
+        self.checkpoint_seq = self.last_seq;
+        Ok(())
+    }
+
+    /// 分页列出 key
+    ///
+    /// ## 参数
+    ///
+    /// - `after`: 游标，只返回严格大于它的 key；`None` 表示从头开始
+    /// - `limit`: 本页最多返回多少个 key
+    ///
+    /// ## 返回值
+    ///
+    /// 按字节序升序排列的 key。返回的 key 少于 `limit` 时说明已经到末尾。
+    ///
+    /// ## 游标
+    ///
+    /// 把本页最后一个 key 作为下一页的 `after` 即可继续（"加载更多"）。
+    /// 游标只是一个 key，两次调用之间不持有任何借用，`Db` 可以照常读写。
+    ///
+    /// 两页之间发生的写入会反映在后续的页中：游标之后新增的 key 会出现，
+    /// 游标之后被删除的 key 不会出现，游标之前的变化则不会被看到。
+    /// 对于一直在变化的数据，这是预期的行为。
+    ///
+    /// ## 性能
+    ///
+    /// 纯内存操作，不读取 value。索引是无序的，每一页都要遍历一遍所有 key，
+    /// 复杂度 O(key 总数 + limit·log(limit))。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let db = Db::open("data/db1", Options::default()).unwrap();
+    ///
+    /// let mut cursor: Option<Vec<u8>> = None;
+    /// loop {
+    ///     let page = db.keys_paginated(cursor.as_deref(), 100);
+    ///     for key in &page {
+    ///         println!("{}", String::from_utf8_lossy(key));
+    ///     }
+    ///     if page.len() < 100 {
+    ///         break;
+    ///     }
+    ///     cursor = page.last().cloned();
+    /// }
+    /// ```
+    pub fn keys_paginated(&self, after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>> {
+        let ns = self.opts.key_prefix.as_slice();
+        let after = after.map(|after| self.ns_key(after));
+        let mut keys = self.index.keys_after(ns, after.as_deref(), limit);
+        if !ns.is_empty() {
+            for key in &mut keys {
+                key.drain(..ns.len());
+            }
+        }
+        keys
+    }
+
+    /// 遍历所有存活的 key，不读取 value
+    ///
+    /// ## 返回值
+    ///
+    /// 借用内存索引中 key 的迭代器（去掉 [`Options::key_prefix`]），顺序不确定
+    ///
+    /// ## 行为
+    ///
+    /// 纯内存操作，不访问磁盘，只需要 `&self`，也不复制 key。
+    /// 索引是 HashMap，需要按字节序时使用 [`Db::keys_paginated`]（只有 key）
+    /// 或 [`Db::scan`]（同时读取 value）。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let db = Db::open("data/db1", Options::default()).unwrap();
+    /// for key in db.keys() {
+    ///     println!("{}", String::from_utf8_lossy(key));
+    /// }
+    /// ```
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.index.iter().filter_map(|(key, _)| self.user_key(key))
+    }
+
+    /// 所有存活 key 及其 value 长度的快照
+    ///
+    /// ## 返回值
+    ///
+    /// 每个存活 key 一项 `(key, value 长度)`，顺序不确定（索引是 HashMap）
+    ///
+    /// ## 行为
+    ///
+    /// 只复制内存索引，不读取 value，不访问磁盘。适合在启动时构建外部的
+    /// 二级索引等派生结构：先根据 key 和 value 长度决定需要哪些 value，
+    /// 再用 [`Db::read_sorted`] 批量读取。
+    ///
+    /// 返回的是调用时刻的副本，之后的写入不会反映在其中。
+    pub fn index_snapshot(&self) -> Vec<(Vec<u8>, usize)> {
+        self.index
+            .iter()
+            .filter_map(|(key, pos)| Some((self.user_key(key)?.to_vec(), pos.len)))
+            .collect()
+    }
+
+    /// 查询 key 最近一次写入的序列号
+    ///
+    /// ## 返回值
+    ///
+    /// - `Some(seq)`: key 存在，`seq` 是写入当前值的那次操作的序列号
+    /// - `None`: key 不存在（从未写入或已被删除）
+    ///
+    /// ## 序列号
+    ///
+    /// 每次写操作（`put`、`delete`，批次中的每条记录）都会分配一个严格递增的 `u64`
+    /// 序列号，第一个写入是 1。v2 格式下序列号保存在 WAL 记录中；
+    /// v1 格式的记录没有序列号字段，replay 时按记录顺序重新编号（结果与写入时相同）。
+    ///
+    /// 纯内存操作，不访问磁盘。
+    pub fn sequence(&self, key: &[u8]) -> Option<u64> {
+        self.index.get(&self.ns_key(key)).map(|pos| pos.seq)
+    }
+
+    /// 最后分配的写入序列号（还没有任何写入时为 0）
+    ///
+    /// 重新打开数据库后从 replay 看到的最大序列号继续递增，不会倒退
+    /// （包括被删除的 key 和被压缩丢弃的历史写入）。
+    pub fn latest_sequence(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// 最近一次 checkpoint（MANIFEST）覆盖到的序列号
+    ///
+    /// 序列号不大于它的写入都已经包含在 MANIFEST 中，下次打开时不需要 replay。
+    /// 还没有 checkpoint，或 checkpoint 因为压缩、重写失效时为 0。
+    ///
+    /// 打开时加载了有效的 MANIFEST 则从它恢复；[`Db::checkpoint`] 成功后更新。
+    pub fn checkpoint_seq(&self) -> u64 {
+        self.checkpoint_seq
+    }
+
+    /// 持久化保存一个具名的复制游标
+    ///
+    /// ## 参数
+    ///
+    /// - `name`: 游标名（例如 follower 的名字），只能包含 ASCII 字母、数字、`-`、`_`、`.`，
+    ///   不能以 `.` 开头，最长 128 字节
+    /// - `seq`: 已经复制到的序列号
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 已经持久化（fsync），崩溃后 [`Db::load_replication_cursor`] 能读到
+    /// - `Err(Error::ReadOnly)`: 只读模式
+    /// - `Err(Error)`: 游标名不合法（`ErrorKind::InvalidInput`），或写入失败
+    ///
+    /// ## 行为
+    ///
+    /// 游标保存在数据库目录下的 `cursors/<name>.cursor` 中，与 WAL 无关，
+    /// 不占用序列号，也不受压缩影响。每次保存都是 tmp + fsync + rename，
+    /// 读取者只会看到旧值或新值；不同名字的游标互不影响，可以在多个线程中并发保存。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut primary = Db::open("data/primary", Options::default()).unwrap();
+    /// let mut replica = Db::open("data/replica", Options::default()).unwrap();
+    ///
+    /// // 重启后从上次的位置继续
+    /// let from = replica.load_replication_cursor("primary").unwrap().unwrap_or(0);
+    /// for (seq, record) in primary.changes_since(from).unwrap() {
+    ///     replica.apply_record(record).unwrap();
+    ///     replica.save_replication_cursor("primary", seq).unwrap();
+    /// }
+    /// ```
+    pub fn save_replication_cursor(&self, name: &str, seq: u64) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        cursor::save(&self.dir, name, seq)
+    }
+
+    /// 读取一个具名的复制游标
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Some(seq))`: 上次用 [`Db::save_replication_cursor`] 保存的序列号
+    /// - `Ok(None)`: 游标从未保存过，或文件损坏（调用方应当从头同步）
+    /// - `Err(Error)`: 游标名不合法，或读取失败
+    pub fn load_replication_cursor(&self, name: &str) -> Result<Option<u64>> {
+        cursor::load(&self.dir, name)
+    }
+
+    /// 订阅之后的所有变更通知
+    ///
+    /// ## 返回值
+    ///
+    /// 一个 `std::sync::mpsc` 接收端。本实例之后每次提交的写入都会发送一个
+    /// [`ChangeEvent`]：
+    ///
+    /// - `put`、`put_reserve`、`rename_key` 的目标键等写入值的操作：`ChangeKind::Put`
+    /// - `delete`、`multi_delete`（每个 key 一个事件）等删除操作：`ChangeKind::Delete`
+    /// - `compact`：一个 `ChangeKind::Compact` 事件，`key` 为空
+    ///
+    /// ## 行为
+    ///
+    /// 事件在 WAL 追加成功、索引更新之后才发送，订阅者永远不会看到没有提交的写入；
+    /// 写入失败时不发送任何事件。同一个批次中的多个事件按序列号顺序发送。
+    ///
+    /// 通道是无界的，不会阻塞写入。接收端被 drop 后，下一次写入时自动取消订阅。
+    /// 只读 follower 通过 [`Db::tail`] 追上的记录不会产生事件。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{ChangeKind, Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let events = db.subscribe();
+    ///
+    /// db.put(b"key", b"value").unwrap();
+    /// let event = events.recv().unwrap();
+    /// assert_eq!(event.kind, ChangeKind::Put);
+    /// assert_eq!(event.key, b"key");
+    /// ```
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        self.subscribers.subscribe()
+    }
+
+    /// 监视单个 key 的变更
+    ///
+    /// ## 参数
+    ///
+    /// - `key`: 要监视的 key
+    /// - `f`: 回调，参数是 key 的新 value，被删除时为 `None`
+    ///
+    /// ## 返回值
+    ///
+    /// 一个 [`WatchId`]，交给 [`Db::unwatch`] 取消监视。
+    ///
+    /// ## 行为
+    ///
+    /// 与 [`Db::subscribe`] 覆盖同样的写操作，时机也相同：WAL 追加成功、索引更新之后，
+    /// 写入失败时不调用。区别在于：
+    ///
+    /// - 只有这个 key 的写入调用回调，不需要在事件流中过滤
+    /// - 回调直接拿到新的 value，不需要再 `get` 一次
+    /// - 删除一个不存在的 key 不算变更，不调用回调
+    ///
+    /// 回调在执行写入的线程上同步执行，耗时会计入写入的延迟，应当尽快返回
+    /// （例如只把 value 转交给另一个线程）。同一个 key 可以注册多个回调，按注册顺序调用。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let id = db.watch(b"config:timeout", |value| match value {
+    ///     Some(value) => println!("timeout changed: {:?}", value),
+    ///     None => println!("timeout removed, using the default"),
+    /// });
+    ///
+    /// db.put(b"config:timeout", b"30").unwrap();
+    /// db.unwatch(id);
+    /// ```
+    pub fn watch<F>(&mut self, key: &[u8], f: F) -> WatchId
+    where
+        F: Fn(Option<&[u8]>) + Send + 'static,
+    {
+        let key = self.ns_key(key);
+        self.subscribers.watch(&key, f)
+    }
+
+    /// 取消 [`Db::watch`] 注册的回调，返回它是否仍在监视（重复取消返回 `false`）
+    pub fn unwatch(&mut self, id: WatchId) -> bool {
+        self.subscribers.unwatch(id)
+    }
+
+    /// 读取序列号大于 `seq` 的所有变更（复制流）
+    ///
+    /// ## 参数
+    ///
+    /// - `seq`: follower 已应用的最后一个序列号（从头同步时传 0）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Vec<(u64, Record)>)`: `(序列号, 记录)`，只包含 PUT/DELETE，按 WAL 中的顺序排列；
+    ///   记录的 `seq` 字段总是已填好（v1 记录按 replay 的规则补上）
+    /// - `Err(Error::HistoryCompacted)`: `seq` 早于最近一次压缩的历史下限
+    /// - `Err(Error)`: 如果读取失败
+    ///
+    /// ## 实现
+    ///
+    /// 写缓冲区中的数据先写入文件，然后从头顺序扫描 WAL，跳过序列号不大于 `seq` 的记录。
+    /// 复杂度与 WAL 大小成正比；follower 应该定期拉取，让每次返回的变更保持较少。
+    ///
+    /// ## 压缩与历史
+    ///
+    /// 压缩（包括 `upgrade_format` 触发的重写）只保留每个 key 的最新值，
+    /// 之前被覆盖和被删除的写入都被丢弃，新 WAL 以一条历史下限标记开头。
+    /// 对于下限之前的 `seq`，无法再给出完整的增量（丢失的删除无法重放），
+    /// 因此返回 `Error::HistoryCompacted`，follower 需要清空后从 `seq = 0` 重新同步。
+    ///
+    /// `seq = 0` 总是可以的：此时返回的是当前全部存活数据加上之后的变更，
+    /// 应用到一个空的 follower 上得到的状态与主库一致。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut primary = Db::open("data/primary", Options::default()).unwrap();
+    /// let mut replica = Db::open("data/replica", Options::default()).unwrap();
+    ///
+    /// let since = replica.latest_sequence();
+    /// for (_, record) in primary.changes_since(since).unwrap() {
+    ///     replica.apply_record(record).unwrap();
+    /// }
+    /// ```
+    pub fn changes_since(&mut self, seq: u64) -> Result<Vec<(u64, Record)>> {
+        let mut changes = Vec::new();
+        self.scan_wal(|record| {
+            if let Some(floor) = Wal::history_floor(&record) {
+                if seq > 0 && seq < floor {
+                    return Err(Error::HistoryCompacted {
+                        requested: seq,
+                        floor,
+                    });
+                }
+            }
+
+            match (record.kind, record.seq) {
+                (RecordKind::Put | RecordKind::Delete, Some(record_seq)) if record_seq > seq => {
+                    changes.push((record_seq, record));
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        Ok(changes)
+    }
+
+    /// 读取一个 key 仍然保留在 WAL 中的所有历史版本
+    ///
+    /// ## 参数
+    ///
+    /// - `key`: 要查询的键
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Vec<(u64, Option<Vec<u8>>)>)`: `(序列号, value)`，按写入顺序排列；
+    ///   PUT 为 `Some(value)`，DELETE 为 `None`。key 从未写入时为空
+    /// - `Err(Error)`: 如果读取失败
+    ///
+    /// ## 行为
+    ///
+    /// 与 [`Db::changes_since`] 相同：写缓冲区中的数据先写入文件，然后从头顺序扫描 WAL，
+    /// 序列号的规则也相同。复杂度与 WAL 大小成正比，适合审计、撤销等偶尔的查询。
+    ///
+    /// ## 只包含磁盘上还在的版本
+    ///
+    /// 压缩只保留每个 key 的最新值，之前的版本（以及超过 `tombstone_ttl` 的删除）
+    /// 从 WAL 中永久消失。压缩之后，这里只能看到压缩时的值和之后的写入；
+    /// 需要完整历史时不要压缩，或者在压缩前把历史导出。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// for (seq, value) in db.get_all_versions(b"config").unwrap() {
+    ///     match value {
+    ///         Some(value) => println!("#{}: {} bytes", seq, value.len()),
+    ///         None => println!("#{}: deleted", seq),
+    ///     }
+    /// }
+    /// ```
+    pub fn get_all_versions(&mut self, key: &[u8]) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        let key = self.ns_key(key).into_owned();
+        let mut versions = Vec::new();
+        self.scan_wal(|record| {
+            if record.key == key {
+                match record.kind {
+                    RecordKind::Put => versions.push((record.seq.unwrap_or(0), Some(record.value))),
+                    RecordKind::Delete => versions.push((record.seq.unwrap_or(0), None)),
+                    _ => {}
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(versions)
+    }
+
+    /// 从头顺序扫描 WAL 中的每一条记录（包括 NOOP 标记和 BATCH 头），交给 `f`
+    ///
+    /// 写缓冲区中的数据先写入文件，只扫描到当前写入位置（只读模式下文件末尾可能有半条记录）。
+    /// PUT/DELETE 记录的 `seq` 总是已填好：与 replay 相同，没有序列号的记录按顺序编号。
+    /// `f` 返回错误时立即停止。
+    fn scan_wal<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(Record) -> Result<()>,
+    {
+        // 1. 保证写缓冲区中的记录对读取者可见
+        if !self.opts.read_only {
+            self.wal.flush()?;
+        }
+
+        // 2. 从头扫描到当前写入位置
+        let end = self.wal.size();
+        let reader = WalReader::open_with_limits(self.wal.path(), self.opts.limits)?;
+
+        let mut last_seq = 0u64;
+        for item in reader {
+            let (offset, mut record) = item?;
+            if offset >= end {
+                break;
+            }
+
+            if let Some(record_seq) = record.seq {
+                last_seq = last_seq.max(record_seq);
+            }
+            if matches!(record.kind, RecordKind::Put | RecordKind::Delete) {
+                let record_seq = *record.seq.get_or_insert(last_seq + 1);
+                last_seq = last_seq.max(record_seq);
+            }
+            // 去重压缩写出的 REF 记录对读取者表现为一条普通的 PUT
+            if let Some(target) = record.value_ref() {
+                record.value = self.wal.read_at(target.value_offset, target.len)?;
+                record.kind = RecordKind::Put;
+            }
+            f(record)?;
+        }
+
+        Ok(())
+    }
+
+    /// 应用一条来自主库的变更（follower 端）
+    ///
+    /// ## 参数
+    ///
+    /// - `record`: 通常来自主库的 [`Db::changes_since`]
+    ///
+    /// ## 行为
+    ///
+    /// - PUT/DELETE：追加到本地 WAL 并更新索引，保留记录中的序列号，
+    ///   之后 `latest_sequence()` 即为已同步到的位置；没有序列号时分配下一个本地序列号
+    /// - BATCH 头和 NOOP 标记：忽略
+    ///
+    /// 与 `put`/`delete` 一样按本地的 `limits` 检查 key/value 大小，超限时返回
+    /// `Err(Error::KeyTooLarge)`/`Err(Error::ValueTooLarge)`，不写入任何内容：
+    /// replay 和压缩都按本地的 `limits` 解码，写进去的超限记录在下次 open 时会被当作损坏。
+    ///
+    /// ## 注意
+    ///
+    /// - follower 的 `max_key_size`/`max_value_size` 必须不小于主库的，
+    ///   否则主库接受的记录会在 follower 上被拒绝，同步停在这条记录上
+    /// - 主库的批次在变更流中被拆成单条记录，逐条应用时 follower 可能短暂处于
+    ///   批次只应用了一部分的状态。崩溃后从 `latest_sequence()` 继续同步即可补齐。
+    pub fn apply_record(&mut self, record: Record) -> Result<()> {
+        if !matches!(record.kind, RecordKind::Put | RecordKind::Delete) {
+            return Ok(());
+        }
+        self.opts.limits.check_key(record.key.len())?;
+        if record.kind == RecordKind::Put {
+            self.opts.limits.check_value(record.value.len())?;
+        }
+
+        let seq = record.seq.unwrap_or(self.last_seq + 1);
+        let mut record = record;
+        record.seq = None;
+        let record = self.sequenced(record, seq);
+
+        let sync = self.opts.sync_on_write;
+        let record_offset = self.wal_write(|wal| wal.append(&record, sync))?;
+        self.wal_records += 1;
+        self.last_seq = self.last_seq.max(seq);
+
+        match record.kind {
+            RecordKind::Put => {
+                self.index.insert_value(
+                    record.key.clone(),
+                    ValuePos {
+                        offset: record_offset + record.value_offset(),
+                        len: record.value.len(),
+                        seq,
+                    },
+                    &record.value,
+                );
+                self.cache_written(&record.key, &record.value);
+                self.notify_put(&record.key, &record.value, seq);
+            }
+            _ => {
+                let tombstone = Tombstone {
+                    seq,
+                    timestamp: record.timestamp,
+                };
+                let existed = self.index.delete(&record.key, tombstone).is_some();
+                self.cache.remove(&record.key);
+                self.notify_delete(&record.key, seq, existed);
+            }
+        }
+
+        self.after_write();
+        Ok(())
+    }
+
+    /// 读取 WAL 中 `offset` 处一条记录的原始字节（header + key + value + crc）
+    ///
+    /// ## 参数
+    ///
+    /// - `offset`: 记录的起始偏移量（例如遍历 [`WalReader`] 时得到的位置）
+    /// - `verify_crc`: 是否完整解码并校验 CRC
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Vec<u8>)`: 记录在磁盘上的原样字节，可以不经重新编码直接转发
+    /// - `Err(Error::InvalidMagic)`: `offset` 处不是一条记录的开头
+    /// - `Err(Error::UnexpectedEof)`: `rec_len` 不合理，或记录超出了 WAL 末尾
+    /// - `Err(Error)`: `verify_crc` 为 `true` 时解码失败的错误（如 `CrcMismatch`），
+    ///   或读取失败
+    ///
+    /// ## 行为
+    ///
+    /// 先读取 8 字节的 magic + `rec_len`，再读取整条记录。不校验 CRC 时只检查 magic
+    /// 和长度，开销就是一次随机读；BATCH 头和 NOOP 标记也会原样返回。
+    pub fn raw_record_at(&mut self, offset: u64, verify_crc: bool) -> Result<Vec<u8>> {
+        let wal_size = self.wal.size();
+        if offset.saturating_add(8) > wal_size {
+            return Err(Error::UnexpectedEof);
+        }
+
+        // 1. 读取 magic + rec_len
+        let header = self.wal.read_at(offset, 8)?;
+        let magic: [u8; 4] = [header[0], header[1], header[2], header[3]];
+        if magic != MAGIC {
+            return Err(Error::InvalidMagic {
+                expected: MAGIC,
+                actual: magic,
+            });
+        }
+        let rec_len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let min_len = Record::put_encoded_len(0, 0, false);
+        let max_len = self.opts.limits.max_record_size();
+        if !(min_len..=max_len).contains(&rec_len) || offset + rec_len as u64 > wal_size {
+            return Err(Error::UnexpectedEof);
+        }
+
+        // 2. 读取整条记录
+        let bytes = self.wal.read_at(offset, rec_len)?;
+        if verify_crc {
+            Record::decode_with_limits(&mut bytes.as_slice(), &self.opts.limits)?;
+        }
+        Ok(bytes)
+    }
+
+    /// 加上 [`Options::key_prefix`] 后的完整 key（没有前缀时不复制）
+    fn ns_key<'k>(&self, key: &'k [u8]) -> Cow<'k, [u8]> {
+        if self.opts.key_prefix.is_empty() {
+            return Cow::Borrowed(key);
+        }
+        let mut full = Vec::with_capacity(self.opts.key_prefix.len() + key.len());
+        full.extend_from_slice(&self.opts.key_prefix);
+        full.extend_from_slice(key);
+        Cow::Owned(full)
+    }
+
+    /// 去掉 [`Options::key_prefix`]；不属于这个键空间的 key 返回 `None`
+    fn user_key<'k>(&self, key: &'k [u8]) -> Option<&'k [u8]> {
+        key.strip_prefix(self.opts.key_prefix.as_slice())
+    }
+
+    /// 广播一次已提交的写入，并用新的 value 调用监视这个 key 的回调
+    fn notify_put(&self, key: &[u8], value: &[u8], seq: u64) {
+        self.subscribers.notify(ChangeKind::Put, key, seq);
+        self.subscribers.fire_watchers(key, Some(value));
+    }
+
+    /// 广播一次已提交的删除；`existed` 为 `false` 时 key 没有变化，不调用监视回调
+    fn notify_delete(&self, key: &[u8], seq: u64, existed: bool) {
+        self.subscribers.notify(ChangeKind::Delete, key, seq);
+        if existed {
+            self.subscribers.fire_watchers(key, None);
+        }
+    }
+
+    /// 写入 value 后更新缓存：已经内联在索引中的 value 不再占用缓存
+    fn cache_written(&mut self, key: &[u8], value: &[u8]) {
+        if self.index.get_inline(key).is_some() {
+            self.cache.remove(key);
+        } else {
+            self.cache.insert(key, value);
+        }
+    }
+
+    /// 如果 WAL 格式支持，为记录附加序列号（v1 格式不保存序列号）
+    ///
+    /// DELETE 记录同时附加写入时间（已经带有时间的保持不变，例如从主库复制来的记录），
+    /// 压缩时据此判断墓碑的年龄，见 [`Options::tombstone_ttl`]。
+    fn sequenced(&self, record: Record, seq: u64) -> Record {
+        if self.wal.version() == VERSION_V1 {
+            return record;
+        }
+        match record.timestamp {
+            None if record.kind == RecordKind::Delete => {
+                record.with_seq(seq).with_timestamp(now_millis())
+            }
+            _ => record.with_seq(seq),
+        }
+    }
+
+    /// `value` 是否参与写入时驻留（见 [`Options::intern_small_values`]）
+    fn internable(&self, value: &[u8]) -> bool {
+        self.opts.intern_small_values.is_some_and(|max| value.len() <= max)
+            && value.len() > VALUE_REF_LEN
+            && self.wal.version() > VERSION_V1
+    }
+
+    /// 当前 WAL 追加记录使用的格式版本
+    ///
+    /// 新建的数据库总是使用最新版本；旧数据库在没有设置
+    /// [`Options::upgrade_format`] 时保持原来的版本。
+    pub fn format_version(&self) -> u8 {
+        self.wal.version()
+    }
+
+    /// 数据库目录（`open` 时传入的路径）
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// 当前使用的 WAL 文件路径
+    ///
+    /// 目前只有一个 `wal.log`；返回列表是为了将来 WAL 分段后保持接口不变，
+    /// 届时按文件顺序（从旧到新）排列。
+    ///
+    /// 用于诊断、日志或备份工具定位文件。注意 WAL 可能被压缩、重写整体替换，
+    /// 复制文件前应当先停止写入（或调用 [`Db::sync`]）。
+    pub fn wal_paths(&self) -> Vec<PathBuf> {
+        vec![self.wal.path().to_path_buf()]
+    }
+
+    /// WAL 文件在磁盘上实际占用的空间（字节）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(u64)`: [`Db::wal_paths`] 中所有文件实际分配的字节数之和
+    /// - `Err(Error)`: 如果读取文件元数据失败
+    ///
+    /// ## 行为
+    ///
+    /// 与 [`DbStats::wal_size`] 不同：`wal_size` 是逻辑大小（下一条记录的写入偏移量），
+    /// 这里返回的是物理占用。在 Unix 上按 `blocks() * 512` 计算，
+    /// 预分配、块对齐或稀疏文件时两者会不一致；其他平台退化为文件长度。
+    ///
+    /// 适合用于配额和磁盘监控。
+    pub fn disk_usage(&self) -> Result<u64> {
+        let mut total = 0;
+        for path in self.wal_paths() {
+            let meta = std::fs::metadata(&path)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                total += meta.blocks() * 512;
+            }
+            #[cfg(not(unix))]
+            {
+                total += meta.len();
+            }
+        }
+        Ok(total)
+    }
+
+    /// 压缩 WAL：只保留每个 key 的最新值
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(CompactStats)`: 保留的 key 数、被丢弃的记录数、压缩前后的 WAL 大小和耗时
+    /// - `Err(Error::CompactionInProgress)`: 有一个后台压缩（[`Db::compact_concurrent`]）
+    ///   正在进行，它与这里使用同一个临时文件
+    /// - `Err(Error::Poisoned)`: 数据库处于中毒状态（见 [`Db::is_poisoned`]），
+    ///   索引可能缺少已经提交的记录，按索引重写会丢掉它们
+    /// - `Err(Error::CrcMismatch)` 等解码错误: 某个存活 value 所在的旧记录已经损坏，
+    ///   `wal.log` 保持不变
+    /// - `Err(Error)`: 如果写入失败（只读模式下为 `Error::ReadOnly`）
+    ///
+    /// ## 行为
+    ///
+    /// 1. 每个存活的 key 按字节序写成一条 PUT 记录（保留原来的序列号），
+    ///    写入临时文件 `wal.log.compact` 并 fsync。value 所在的旧记录都重新解码、
+    ///    校验 CRC，不会把磁盘上已经损坏的 value 配上新的 CRC 写进新文件
+    /// 2. 原子地 rename 为 `wal.log`，重新打开读写句柄
+    /// 3. 写入时根据新文件中的偏移量直接构建新索引，不需要再 replay
+    ///
+    /// 被覆盖的旧值和 DELETE 记录都被丢弃（设置了 [`Options::tombstone_ttl`] 时，
+    /// 较新的 DELETE 记录保留在所有 PUT 之后），新 WAL 使用当前格式版本。
+    /// 输出只取决于逻辑内容（key、value 和序列号），与写入历史和索引的内部顺序无关，
+    /// 相同内容压缩出的文件逐字节相同，可以直接比较或计算哈希。
+    /// rename 是提交点：之前崩溃时旧 WAL 保持不变。
+    ///
+    /// 压缩会删除 MANIFEST（其中的偏移量不再成立），下次 `open` 完整 replay
+    /// 压缩后的文件，直到下一次 checkpoint。
+    ///
+    /// 设置了 [`Options::on_compact_progress`] 时，写入过程中会报告进度。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let stats = db.compact().unwrap();
+    /// println!("{} -> {} bytes", stats.bytes_before, stats.bytes_after);
+    /// ```
+    pub fn compact(&mut self) -> Result<CompactStats> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.poisoned {
+            return Err(Error::Poisoned);
+        }
+
+        if self.compacting.swap(true, Ordering::AcqRel) {
+            return Err(Error::CompactionInProgress);
+        }
+
+        let start = Instant::now();
+        let bytes_before = self.wal.size();
+        let records_before = self.wal_records;
+        let result = self.rewrite();
+        self.compacting.store(false, Ordering::Release);
+        result?;
+        self.subscribers.notify(ChangeKind::Compact, b"", self.last_seq);
+
+        Ok(CompactStats {
+            keys_kept: self.index.len() as u64,
+            records_dropped: records_before.saturating_sub(self.wal_records),
+            bytes_before,
+            bytes_after: self.wal.size(),
+            duration: start.elapsed(),
+        })
+    }
+
+    /// 垃圾比例超过 `garbage_ratio_threshold` 时压缩 WAL
+    ///
+    /// ## 参数
+    ///
+    /// - `garbage_ratio_threshold`: 0.0 ~ 1.0，例如 `0.5` 表示一半以上是垃圾时才压缩
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Some(CompactStats))`: 执行了压缩
+    /// - `Ok(None)`: 垃圾比例没有超过阈值，什么也没做
+    /// - `Err(Error)`: 如果压缩失败
+    ///
+    /// ## 垃圾比例
+    ///
+    /// `垃圾字节数 / 当前 WAL 大小`，垃圾字节数即 [`DbStats::dead_bytes`]：WAL 大小减去
+    /// 历史下限标记、每个存活 key 一条 PUT 记录（header + 序列号 + key + value + crc，
+    /// 按 WAL 实际的格式版本计算，v1 没有序列号）和按 `tombstone_ttl` 保留的墓碑。
+    /// 计算只用到索引随写入维护的计数（O(1)），不访问磁盘。WAL 为空时比例为 0。
+    ///
+    /// 适合在空闲时机（定时任务、请求间隙）调用，由调用方决定何时承担压缩的开销，
+    /// 不需要后台线程。
+    pub fn compact_if_needed(
+        &mut self,
+        garbage_ratio_threshold: f64,
+    ) -> Result<Option<CompactStats>> {
+        if self.garbage_ratio() > garbage_ratio_threshold {
+            self.compact().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// 当前 WAL 中可以被压缩回收的比例（0.0 ~ 1.0）
+    fn garbage_ratio(&self) -> f64 {
+        let wal_size = self.wal.size();
+        if wal_size == 0 {
+            return 0.0;
+        }
+
+        self.dead_bytes() as f64 / wal_size as f64
+    }
+
+    /// 当前 WAL 中被覆盖的 PUT 和不再需要的墓碑占用的字节数
+    ///
+    /// 覆盖写入和删除不会减少 `live_disk_bytes`，只会增加 WAL 的大小，
+    /// WAL 大小减去存活的记录、压缩时会保留的墓碑（和历史下限标记）就是垃圾。
+    /// 存活记录按 WAL 实际的记录格式计算，v1 的 WAL 中没有序列号，也没有历史下限标记。
+    fn dead_bytes(&self) -> u64 {
+        let floor = if self.wal.version() > VERSION_V1 {
+            Self::floor_bytes(self.last_seq)
+        } else {
+            0
+        };
+        let kept = floor + self.live_disk_bytes() + self.retained_tombstone_bytes();
+        self.wal.size().saturating_sub(kept)
+    }
+
+    /// 压缩时可能保留的墓碑占用的字节数（O(1)）
+    ///
+    /// 设置了 `tombstone_ttl` 时，带写入时间的墓碑都按保留计算：已经过期但还在索引中的
+    /// 墓碑也算在内，它们在下一次压缩或 [`Db::gc_tombstones`] 时才被清除。
+    /// 宁可少算垃圾，也不能让无法缩小 WAL 的压缩被反复触发。
+    fn retained_tombstone_bytes(&self) -> u64 {
+        if self.opts.tombstone_ttl.is_none() {
+            return 0;
+        }
+        let (count, key_bytes) = self.index.timed_tombstones();
+        // 保留的 DELETE 记录带序列号和写入时间
+        let overhead = Record::put_encoded_len(0, 0, true) as u64 + 8;
+        count as u64 * overhead + key_bytes as u64
+    }
+
+    /// 压缩后的文件大小：历史下限标记 + 每个 key 一条 PUT + 保留的墓碑
+    ///
+    /// 压缩总是写出当前格式的记录（带序列号），与原 WAL 的格式版本无关
+    fn compacted_size(&self, tombstones: &[Record]) -> u64 {
+        let live = self.live_bytes(true);
+        let dead: u64 = tombstones.iter().map(|r| r.encoded_len() as u64).sum();
+        Self::floor_bytes(self.last_seq) + live + dead
+    }
+
+    /// 历史下限标记的编码长度（还没有任何写入时没有标记，为 0）
+    fn floor_bytes(last_seq: u64) -> u64 {
+        Wal::history_floor_record(last_seq)
+            .and_then(|record| record.ok())
+            .map_or(0, |record| record.encoded_len() as u64)
+    }
+
+    /// 用当前格式重写 WAL，只保留每个 key 的最新值
+    ///
+    /// 重写前删除 MANIFEST：高水位和偏移量在新文件中都不再成立。
+    /// 如果在 rename 之前崩溃，旧 WAL 保持不变，下次打开时完整 replay；
+    /// 各步骤的崩溃恢复见 [`Wal::recover_compaction`]。
+    fn rewrite(&mut self) -> Result<()> {
+        Manifest::remove(&self.dir)?;
+
+        let live = self.live_entries();
+        let tombstones = self.retained_tombstones()?;
+        let mut progress = ProgressReporter::new(
+            self.opts.on_compact_progress.clone(),
+            self.compacted_size(&tombstones),
+        );
+
+        let content = RewriteContent {
+            live,
+            source_version: self.wal.version(),
+            tombstones,
+            last_seq: self.last_seq,
+            dedup_values: self.opts.dedup_values,
+        };
+        let mut index = Index::with_inline_threshold(self.opts.inline_value_threshold);
+        self.wal.rewrite(content, |offset, record| {
+            progress.written(offset + record.encoded_len() as u64);
+            Self::index_compacted(&mut index, offset, record);
+        })?;
+        progress.finish();
+
+        self.wal_records = (index.len() + index.tombstone_count()) as u64;
+        self.index = index;
+        self.last_checkpoint = 0;
+        self.checkpoint_seq = 0;
+        self.interned.clear();
+        Ok(())
+    }
+
+    /// 重写时要保留的 (key, value 位置, REF 记录的偏移量)，见 [`RewriteContent::live`]
+    ///
+    /// 按 key 的字节序排列：相同的逻辑内容总是得到完全相同的文件
+    fn live_entries(&self) -> Vec<(Vec<u8>, ValuePos, Option<u64>)> {
+        self.index
+            .prefix_sorted(b"")
+            .into_iter()
+            .map(|(key, pos)| (key.to_vec(), pos, self.index.ref_offset(key)))
+            .collect()
+    }
+
+    /// 把写入压缩后 WAL 的一条记录应用到新索引（记录都带有序列号）
+    fn index_compacted(index: &mut Index, offset: u64, record: &Record) {
+        match record.kind {
+            RecordKind::Put => {
+                index.insert_value(
+                    record.key.clone(),
+                    ValuePos {
+                        offset: offset + record.value_offset(),
+                        len: record.value.len(),
+                        seq: record.seq.unwrap_or(0),
+                    },
+                    &record.value,
+                );
+            }
+            RecordKind::Delete => {
+                let tombstone = Tombstone {
+                    seq: record.seq.unwrap_or(0),
+                    timestamp: record.timestamp,
+                };
+                index.delete(&record.key, tombstone);
+            }
+            RecordKind::Ref => {
+                if let Some(target) = record.value_ref() {
+                    let value_pos = ValuePos {
+                        offset: target.value_offset,
+                        len: target.len,
+                        seq: record.seq.unwrap_or(0),
+                    };
+                    index.insert_ref(record.key.clone(), value_pos, offset);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// 压缩时需要保留的墓碑，写成带原序列号和写入时间的 DELETE 记录，按 key 的字节序排列
+    ///
+    /// 只有设置了 `tombstone_ttl` 且写入时间在 TTL 之内的墓碑才保留；
+    /// 没有写入时间的墓碑（v1 记录）无法判断年龄，视为已过期。
+    fn retained_tombstones(&self) -> Result<Vec<Record>> {
+        let ttl = self.opts.tombstone_ttl;
+        if ttl.is_none() {
+            return Ok(Vec::new());
+        }
+        let now = now_millis();
+
+        let mut tombstones: Vec<(&Vec<u8>, Tombstone)> = self
+            .index
+            .tombstones()
+            .filter(|(_, t)| !tombstone_expired(t, ttl, now))
+            .map(|(key, t)| (key, *t))
+            .collect();
+        tombstones.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        tombstones
+            .into_iter()
+            .map(|(key, t)| {
+                let record = Record::delete_with_limits(key.clone(), &self.opts.limits)?
+                    .with_seq(t.seq);
+                Ok(match t.timestamp {
+                    Some(ts) => record.with_timestamp(ts),
+                    None => record,
+                })
+            })
+            .collect()
+    }
+
+    /// 丢弃可以安全丢弃的墓碑，不重写 WAL
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(usize)`: 丢弃的墓碑数量
+    /// - `Err(Error::ReadOnly)`: 只读模式
+    /// - `Err(Error)`: 读取复制游标或写入 checkpoint 失败
+    ///
+    /// ## 何时可以丢弃
+    ///
+    /// 墓碑记录了一个 key 被删除（DELETE 的序列号和写入时间），用来决定压缩时保留哪些
+    /// DELETE 记录，让 follower 也能看到删除。同时满足以下两个条件时，墓碑可以丢弃：
+    ///
+    /// 1. **压缩也不会保留它**：没有设置 [`Options::tombstone_ttl`]，或者删除已经超过 TTL
+    ///    （没有写入时间的 v1 记录视为已超过）
+    /// 2. **所有复制游标都已经越过它**：目录中每个 [`Db::save_replication_cursor`] 保存的序列号
+    ///    都不小于 DELETE 的序列号，没有已知的 follower 还需要这次删除。
+    ///    没有任何游标时这个条件总是成立
+    ///
+    /// 被重新写入的 key 没有墓碑：写入时就已经移除，不计入返回值。
+    ///
+    /// ## 行为
+    ///
+    /// 只修改内存中的索引，WAL 中的 DELETE 记录原样保留，直到下一次压缩。
+    /// 有墓碑被丢弃时写一次 checkpoint（见 [`Db::checkpoint`]），之后的 `open` 从 MANIFEST
+    /// 加载索引，不会从 WAL 中的 DELETE 记录重新生成它们；MANIFEST 丢失而完整 replay 时，
+    /// 这些墓碑会重新出现，再次调用即可丢弃。
+    ///
+    /// 开销是一次遍历墓碑和一次 checkpoint，与数据量无关，比 [`Db::compact`] 轻得多，
+    /// 适合存活数据不多、但删除积累了大量墓碑的场景。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let removed = db.gc_tombstones().unwrap();
+    /// println!("dropped {} tombstones, {} left", removed, db.stats().tombstone_count);
+    /// ```
+    pub fn gc_tombstones(&mut self) -> Result<usize> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let ttl = self.opts.tombstone_ttl;
+        let now = now_millis();
+        let replicated = cursor::min_seq(&self.dir)?;
+        let removed = self.index.retain_tombstones(|_, tombstone| {
+            let pending = replicated.is_some_and(|seq| seq < tombstone.seq);
+            pending || !tombstone_expired(tombstone, ttl, now)
+        });
+
+        if removed > 0 {
+            self.checkpoint()?;
+        }
+        Ok(removed)
+    }
+
+    /// 把所有存活的键值对压缩写入另一个目录，源数据库保持不变
+    ///
+    /// ## 参数
+    ///
+    /// - `dest`: 目标目录（不存在会自动创建），其中不能已有数据
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(CompactStats)`: 保留的 key 数、被丢弃的记录数、源 WAL 和目标 WAL 的大小以及耗时
+    /// - `Err(Error)`: 如果目标目录中已有数据，或读写失败
+    ///
+    /// ## 行为
+    ///
+    /// 每个存活的 key 按字节序写成一条 PUT 记录（保留原来的序列号），
+    /// 被覆盖的旧值和 DELETE 记录都不会出现在目标中。
+    /// 写入方式与 [`Db::bulk_load`] 相同：先写临时文件，fsync 一次后 rename 为 `wal.log`，
+    /// 因此中途失败不会在目标目录中留下不完整的 WAL。
+    ///
+    /// 之后用 `Db::open(dest)` 即可打开一个只包含最新数据的数据库。
+    /// 源数据库的 WAL、MANIFEST 和索引都不会被修改。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let stats = db.compact_into("data/db1-snapshot").unwrap();
+    /// println!("{} -> {} bytes", stats.bytes_before, stats.bytes_after);
+    /// ```
+    pub fn compact_into<P: AsRef<Path>>(&mut self, dest: P) -> Result<CompactStats> {
+        let start = Instant::now();
+        let wal_opts = WalOptions {
+            limits: self.opts.limits,
+            ..WalOptions::default()
+        };
+
+        let tombstones = self.retained_tombstones()?;
+        let kept = (self.index.len() + tombstones.len()) as u64;
+        let mut progress = ProgressReporter::new(
+            self.opts.on_compact_progress.clone(),
+            self.compacted_size(&tombstones),
+        );
+
+        // 按字节序逐个读取存活的 value，边读边写，不把所有 value 放进内存
+        let limits = self.opts.limits;
+        let wal = &mut self.wal;
+        let records = self
+            .index
+            .prefix_sorted(b"")
+            .into_iter()
+            .map(|(key, pos)| {
+                let value = wal.read_at(pos.offset, pos.len)?;
+                Ok(Record::put_with_limits(key.to_vec(), value, &limits)?.with_seq(pos.seq))
+            });
+
+        // 以历史下限标记开头：保留序列号高水位，并标明更早的变更历史已丢弃
+        let records = Wal::history_floor_record(self.last_seq)
+            .into_iter()
+            .chain(records)
+            .chain(tombstones.into_iter().map(Ok));
+
+        let dest_wal = Wal::create(dest, &wal_opts, records, |offset, record| {
+            progress.written(offset + record.encoded_len() as u64);
+        })?;
+
+        Ok(CompactStats {
+            keys_kept: self.index.len() as u64,
+            records_dropped: self.wal_records.saturating_sub(kept),
+            bytes_before: self.wal.size(),
+            bytes_after: dest_wal.size(),
+            duration: start.elapsed(),
+        })
+    }
+
+    /// 把所有存活的键值对导出为一个单文件快照，源数据库保持不变
+    ///
+    /// ## 参数
+    ///
+    /// - `w`: 快照的写入目标（文件、网络连接等，不需要 `Seek`）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 快照已完整写入 `w`（是否落盘由调用方负责，例如 `File::sync_all`）
+    /// - `Err(Error)`: 如果读取 value 或写入失败，`w` 中可能留下不完整的内容
+    ///
+    /// ## 行为
+    ///
+    /// 快照的前半部分与 [`Db::compact_into`] 写出的 `wal.log` 逐字节相同，
+    /// 后面跟着对应的索引（MANIFEST 格式）和一个固定长度的 footer，格式见 `snapshot` 模块。
+    /// 索引在写出每条记录时根据当前偏移量直接构建，不需要再读一遍写出的内容。
+    ///
+    /// 与 `compact_into` 不同，快照是一个可以直接拷贝、上传的文件；
+    /// [`Db::import_snapshot`] 导入时直接使用其中的索引，不需要 replay。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    /// use std::fs::File;
+    /// use std::io::BufWriter;
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let mut out = BufWriter::new(File::create("db1.kvss").unwrap());
+    /// db.export_snapshot(&mut out).unwrap();
+    /// out.into_inner().unwrap().sync_all().unwrap();
+    /// ```
+    pub fn export_snapshot<W: Write>(&mut self, w: &mut W) -> Result<()> {
+        let tombstones = self.retained_tombstones()?;
+        let mut manifest = Manifest {
+            last_seq: self.last_seq,
+            ..Manifest::default()
+        };
+
+        // 1. 与 compact_into 相同的记录序列，边写边记录每个 value 的新位置
+        let limits = self.opts.limits;
+        let wal = &mut self.wal;
+        let live = self.index.prefix_sorted(b"").into_iter().map(|(key, pos)| {
+            let value = wal.read_at(pos.offset, pos.len)?;
+            Ok(Record::put_with_limits(key.to_vec(), value, &limits)?.with_seq(pos.seq))
+        });
+        let records = Wal::history_floor_record(self.last_seq)
+            .into_iter()
+            .chain(live)
+            .chain(tombstones.into_iter().map(Ok));
+
+        let mut buf = Vec::new();
+        for record in records {
+            let record = record?;
+            buf.clear();
+            record.encode_to(&mut buf)?;
+            w.write_all(&buf)?;
+
+            let offset = manifest.wal_offset;
+            let seq = record.seq.unwrap_or(0);
+            match record.kind {
+                RecordKind::Put => {
+                    let value_offset = offset + record.value_offset();
+                    let len = record.value.len() as u64;
+                    manifest.entries.push((record.key, value_offset, len, seq, 0));
+                }
+                RecordKind::Delete => manifest.tombstones.push((record.key, seq, record.timestamp)),
+                _ => {}
+            }
+            manifest.wal_offset += buf.len() as u64;
+            manifest.tail_crc = u32::from_le_bytes(buf[buf.len() - 4..].try_into().expect("crc"));
+        }
+        manifest.record_count = (manifest.entries.len() + manifest.tombstones.len()) as u64;
+
+        // 2. 索引和 footer
+        let encoded = manifest.encode();
+        w.write_all(&encoded)?;
+        let footer = snapshot::Footer {
+            wal_len: manifest.wal_offset,
+            manifest_len: encoded.len() as u64,
+        };
+        w.write_all(&footer.encode())?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// 从 [`Db::export_snapshot`] 导出的快照创建数据库并打开
+    ///
+    /// ## 参数
+    ///
+    /// - `r`: 快照内容
+    /// - `dest`: 目标目录（不存在会自动创建），其中不能已有数据
+    /// - `opts`: 打开导入后的数据库使用的配置
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Db)`: 导入并打开成功
+    /// - `Err(Error::UnsupportedVersion)`: 快照由更新版本的 kvslite 导出
+    /// - `Err(Error::AlreadyExists)`: 目标目录中已有数据
+    /// - `Err(Error::AlreadyOpen)`: 目标目录已经被另一个 `Db` 打开
+    /// - `Err(Error::CrcMismatch)` 等解码错误: 快照的 WAL 部分有损坏的记录，
+    ///   目标目录中不留下 `wal.log`
+    /// - `Err(Error::Io)`: 不是快照文件或内容被截断（`InvalidData`），或读写失败
+    ///
+    /// ## 行为
+    ///
+    /// 先获得目标目录的锁（与 [`Db::open`] 相同），再把快照中的 WAL 部分原样写成
+    /// `wal.log`（先写临时文件，fsync 后 rename），索引部分写成 `MANIFEST`，然后照常打开：
+    /// MANIFEST 与 WAL 吻合，索引直接从 MANIFEST 构建，不 replay 任何记录。
+    ///
+    /// `get` 读取 value 时不校验 CRC，所以 rename 之前会把临时文件完整读回一遍，
+    /// 按 `opts.limits` 逐条解码并校验每条记录的 CRC，导入因此要多读一遍 WAL 部分。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    /// use std::fs::File;
+    /// use std::io::BufReader;
+    ///
+    /// let mut snapshot = BufReader::new(File::open("db1.kvss").unwrap());
+    /// let db = Db::import_snapshot(&mut snapshot, "data/db1-copy", Options::default()).unwrap();
+    /// println!("imported {} keys", db.stats().key_count);
+    /// ```
+    pub fn import_snapshot<R, P>(r: &mut R, dest: P, opts: Options) -> Result<Self>
+    where
+        R: Read + Seek,
+        P: AsRef<Path>,
+    {
+        if opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        // 1. 读取 footer 和索引，先确认快照完整
+        let footer = snapshot::Footer::read(r)?;
+        let manifest_len = usize::try_from(footer.manifest_len)
+            .map_err(|_| snapshot::invalid("index is too large"))?;
+        let mut encoded = vec![0u8; manifest_len];
+        r.seek(SeekFrom::Start(footer.wal_len))?;
+        r.read_exact(&mut encoded)?;
+        let manifest = Manifest::decode(&encoded)
+            .filter(|m| m.wal_offset == footer.wal_len)
+            .ok_or_else(|| snapshot::invalid("corrupted index"))?;
+
+        // 2. 先锁住目标目录，其中不能已有数据
+        let dir = dest.as_ref();
+        let lock = Self::lock_dir(dir, &opts)?;
+        let path = dir.join(WAL_FILENAME);
+        if path.exists() && std::fs::metadata(&path)?.len() > 0 {
+            return Err(Error::AlreadyExists);
+        }
+        restrict_dir(dir, opts.file_mode)?;
+
+        // 3. 拷贝 WAL 部分：临时文件 + fsync + rename
+        let tmp_path = dir.join(snapshot::IMPORT_TMP_FILENAME);
+        let _ = std::fs::remove_file(&tmp_path);
+        let copied = (|| {
+            let mut file = create_new_file(&tmp_path, opts.file_mode)?;
+            r.seek(SeekFrom::Start(0))?;
+            let copied = std::io::copy(&mut r.take(footer.wal_len), &mut file)?;
+            if copied != footer.wal_len {
+                return Err(snapshot::invalid("WAL section is truncated"));
+            }
+            file.sync_data()?;
+
+            // 读回校验：每条记录都能完整解码、CRC 正确（末尾不完整的记录同样报错）
+            let mut reader = WalReader::open_with_limits(&tmp_path, opts.limits)?;
+            reader.try_for_each(|item| item.map(drop))
+        })();
+        if let Err(e) = copied {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        std::fs::rename(&tmp_path, &path)?;
+        sync_dir(dir)?;
+
+        // 4. 写入索引并打开（MANIFEST 的权限沿用 wal.log）
+        manifest.store(dir)?;
+        Self::open_locked(dir.to_path_buf(), opts, lock)
+    }
+
+    /// 压缩 WAL，压缩期间其他线程仍然可以读写数据库
+    ///
+    /// ## 参数
+    ///
+    /// - `db`: 被多个线程共享的数据库（例如 `Arc<Mutex<Db>>` 中的 `Mutex`）
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(CompactStats)`: 与 [`Db::compact`] 相同
+    /// - `Err(Error::CompactionInProgress)`: 已经有一个后台压缩正在进行
+    /// - `Err(Error::Poisoned)`: 数据库处于中毒状态，包括有线程持有 `db` 的锁时 panic
+    ///   （见 [`Db::is_poisoned`]）
+    /// - `Err(Error)`: 如果写入失败（只读模式下为 `Error::ReadOnly`），`wal.log` 保持不变
+    ///
+    /// ## 行为
+    ///
+    /// [`Db::compact`] 在整个压缩期间独占 `&mut self`。这里把压缩分成三步，
+    /// 只有第一步和最后一步持有锁：
+    ///
+    /// 1. **快照**（持锁，只复制索引）：记录存活 key 的位置、要保留的墓碑和当前 WAL 末尾，
+    ///    并打开一个独立的旧 WAL 读句柄
+    /// 2. **写出**（不持锁）：按快照从旧 WAL 读取 value，写入临时文件 `wal.log.compact`
+    ///    并 fsync。这一步占据了压缩的绝大部分时间，期间其他线程照常读写：
+    ///    新的写入仍然追加到旧 WAL
+    /// 3. **切换**（持锁）：把快照之后旧 WAL 中新增的记录补写到新文件末尾，
+    ///    原子地 rename 为 `wal.log`，换上新索引
+    ///
+    /// 第 3 步的耗时与压缩期间新增的写入量成正比，与数据库大小无关。
+    /// 崩溃安全性与 `compact` 相同：rename 是提交点，之前崩溃时旧 WAL 保持不变。
+    ///
+    /// 压缩期间调用 [`Db::compact`] 会返回 `Error::CompactionInProgress`，
+    /// 自动压缩（[`Options::auto_compact_ratio`]）也暂停，WAL 不会在快照之后被重写。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let db = Arc::new(Mutex::new(Db::open("data/db1", Options::default()).unwrap()));
+    ///
+    /// let compactor = Arc::clone(&db);
+    /// let handle = std::thread::spawn(move || Db::compact_concurrent(&compactor));
+    ///
+    /// // 压缩期间照常读写
+    /// db.lock().unwrap().put(b"key", b"value").unwrap();
+    ///
+    /// let stats = handle.join().unwrap().unwrap();
+    /// println!("{} -> {} bytes", stats.bytes_before, stats.bytes_after);
+    /// ```
+    pub fn compact_concurrent(db: &Mutex<Db>) -> Result<CompactStats> {
+        let job = Db::lock_shared(db).begin_compaction()?;
+        let job = job.run()?;
+        Db::lock_shared(db).finish_compaction(job)
+    }
+
+    /// 锁住被多个线程共享的数据库
+    ///
+    /// 持有锁的线程 panic 后锁会中毒，此时 `Db` 可能停在写操作的中途：记录已经写入
+    /// WAL，索引还没有更新。这里照常返回数据库，但把它标记为中毒（见 [`Db::is_poisoned`]），
+    /// 读操作不受影响，写操作和压缩返回 `Error::Poisoned`。锁的中毒不会消失，
+    /// 之后每次加锁都重新标记，[`Db::clear_poison`] 也无法解除，只能重新打开数据库。
+    pub(crate) fn lock_shared(db: &Mutex<Db>) -> MutexGuard<'_, Db> {
+        db.lock().unwrap_or_else(|e| {
+            let mut db = e.into_inner();
+            db.poisoned = true;
+            db
+        })
+    }
+
+    /// 后台压缩第 1 步：在当前状态上创建快照
+    fn begin_compaction(&mut self) -> Result<CompactionJob> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.poisoned {
+            return Err(Error::Poisoned);
+        }
+        if self.compacting.swap(true, Ordering::AcqRel) {
+            return Err(Error::CompactionInProgress);
+        }
+        // 从这里开始，任务被丢弃时会清除 `compacting`
+        let mut job = CompactionJob {
+            source: None,
+            tmp_path: self.wal.path().with_file_name(COMPACT_TMP_FILENAME),
+            limits: self.opts.limits,
+            live: Vec::new(),
+            source_version: self.wal.version(),
+            tombstones: Vec::new(),
+            last_seq: self.last_seq,
+            dedup_values: self.opts.dedup_values,
+            snapshot_end: 0,
+            progress: ProgressReporter::new(None, 0),
+            index: Index::with_inline_threshold(self.opts.inline_value_threshold),
+            prepared: None,
+            start: Instant::now(),
+            compacting: Arc::clone(&self.compacting),
+        };
+
+        let (source, snapshot_end) = self.wal.snapshot_source()?;
+        job.source = Some(source);
+        job.snapshot_end = snapshot_end;
+        job.live = self.live_entries();
+        job.tombstones = self.retained_tombstones()?;
+        job.progress = ProgressReporter::new(
+            self.opts.on_compact_progress.clone(),
+            self.compacted_size(&job.tombstones),
+        );
+        Ok(job)
+    }
+
+    /// 后台压缩第 3 步：补写快照之后的新记录，替换 WAL 和索引
+    fn finish_compaction(&mut self, mut job: CompactionJob) -> Result<CompactStats> {
+        let bytes_before = self.wal.size();
+        let records_before = self.wal_records;
+        // 任务持有 `compacting` 标志，其间 `compact` 不会重写 WAL，快照中的位置一直成立
+        let mut prepared = job.prepared.take().expect("compaction job has been run");
+
+        // 1. 补写快照之后的记录（v1 记录按 replay 的规则补上序列号）；
+        //    REF 记录指向旧文件中的位置，补写成完整的 PUT
+        let mut last_seq = job.last_seq;
+        let mut records = Vec::new();
+        for (_, mut record) in self.wal.records_since(job.snapshot_end)? {
+            if let Some(target) = record.value_ref() {
+                record.value = self.wal.read_at(target.value_offset, target.len)?;
+                record.kind = RecordKind::Put;
+            }
+            if !matches!(record.kind, RecordKind::Put | RecordKind::Delete) {
+                continue;
+            }
+            let seq = record.seq.unwrap_or(last_seq + 1).max(last_seq);
+            last_seq = seq;
+            record.seq = Some(seq);
+            records.push(record);
+        }
+        let index = &mut job.index;
+        prepared.append(records, |offset, record| Self::index_compacted(index, offset, record))?;
+
+        // 2. 替换 WAL（MANIFEST 中的偏移量不再成立）
+        Manifest::remove(&self.dir)?;
+        self.wal.install_rewrite(prepared)?;
+
+        // 3. 换上新索引
+        self.index = std::mem::take(&mut job.index);
+        self.wal_records = (self.index.len() + self.index.tombstone_count()) as u64;
+        self.last_checkpoint = 0;
+        self.checkpoint_seq = 0;
+        self.interned.clear();
+        self.subscribers.notify(ChangeKind::Compact, b"", self.last_seq);
+
+        Ok(CompactStats {
+            keys_kept: self.index.len() as u64,
+            records_dropped: records_before.saturating_sub(self.wal_records),
+            bytes_before,
+            bytes_after: self.wal.size(),
+            duration: job.start.elapsed(),
+        })
+    }
+
+    /// 对 WAL 执行一次写入（追加、fsync）
+    ///
+    /// 已经中毒时直接返回 `Error::Poisoned`，不访问 WAL。后台 fsync 线程
+    /// （见 [`Options::flush_interval`]）失败过时不执行 `f`，返回那次 fsync 的错误。
+    /// `f` 返回 I/O 错误时把数据库标记为中毒，并在返回错误之前调用
+    /// [`Options::on_write_error`]。
+    fn wal_write<T>(&mut self, f: impl FnOnce(&mut Wal) -> Result<T>) -> Result<T> {
+        if self.poisoned {
+            return Err(Error::Poisoned);
+        }
+        let result = self.wal.take_sync_error().and_then(|()| f(&mut self.wal));
+        if let Err(e @ (Error::Io(_) | Error::DiskFull(_))) = &result {
+            self.poisoned = true;
+            if let Some(hook) = &self.opts.on_write_error {
+                (hook.0)(e);
+            }
+        }
+        result
+    }
+
+    /// 之前的写操作是否因为 I/O 错误失败过
+    ///
+    /// 追加或 fsync WAL 失败后（见 [`Options::on_write_error`]）数据库进入中毒状态：
+    /// 之后的写操作（包括 `sync`、`checkpoint` 和压缩）直接返回 `Error::Poisoned`，
+    /// 不再访问磁盘，读操作不受影响。失败的那次写入已经从 WAL 中撤销，
+    /// 之前成功的写入保持不变。
+    ///
+    /// 通过 [`SharedDb`](crate::SharedDb) 或 [`Db::compact_concurrent`] 共享时，
+    /// 持有锁的线程 panic 也会让数据库中毒，并且只能重新打开来解除。
+    ///
+    /// 调用方确认问题已经解决（例如释放了磁盘空间）后，可以调用 [`Db::clear_poison`]
+    /// 继续写入，或者重新打开数据库。
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// 清除中毒状态，允许继续写入
+    pub fn clear_poison(&mut self) {
+        self.poisoned = false;
+    }
+
+    /// 写操作成功后的维护工作
+    ///
+    /// 按 `auto_compact_ratio` 自动压缩，按 `checkpoint_interval_bytes` 自动 checkpoint。
+    /// 写操作此时已经提交，维护失败只记录下来（见 [`Db::take_maintenance_error`]），
+    /// 不作为写操作的错误返回。
+    fn after_write(&mut self) {
+        // 后台压缩进行中时跳过：此时 `compact` 只会返回 `CompactionInProgress`
+        let compacting = self.compacting.load(Ordering::Acquire);
+        if let Some(ratio) = self.opts.auto_compact_ratio.filter(|_| !compacting) {
+            if let Err(e) = self.compact_if_needed(ratio) {
+                self.record_maintenance_error("auto-compaction", e);
+            }
+        }
+        if let Some(interval) = self.opts.checkpoint_interval_bytes {
+            if self.wal.size() - self.last_checkpoint >= interval {
+                if let Err(e) = self.checkpoint() {
+                    self.record_maintenance_error("auto-checkpoint", e);
+                }
+            }
+        }
+    }
+
+    /// 打印并保存维护工作的错误，覆盖还没有被取走的旧错误
+    fn record_maintenance_error(&mut self, task: &str, error: Error) {
+        eprintln!("Warning: {} after write failed: {}", task, error);
+        self.maintenance_error = Some(error);
+    }
+
+    /// 取走写操作之后自动维护失败的最近一个错误
+    ///
+    /// ## 返回值
+    ///
+    /// - `Some(Error)`: 自上次调用以来，按 [`Options::auto_compact_ratio`] 自动压缩或按
+    ///   [`Options::checkpoint_interval_bytes`] 自动 checkpoint 失败过，返回最近的错误
+    /// - `None`: 没有失败过
+    ///
+    /// ## 行为
+    ///
+    /// 自动维护在写操作提交之后执行，失败时写操作仍然返回 `Ok`：数据已经在 WAL 中，
+    /// 只是 WAL 没有被压缩或 MANIFEST 没有更新，下一次写入会重试。
+    /// 错误同时打印到 stderr。
+    pub fn take_maintenance_error(&mut self) -> Option<Error> {
+        self.maintenance_error.take()
+    }
+
+    /// 获取最近一次 `open` 时 WAL replay 的统计信息
+    ///
+    /// ## 使用场景
+    ///
+    /// 监控代码可以在启动后检查恢复过程是否丢弃了数据：
+    /// - `truncated_bytes > 0`：WAL 尾部被截断
+    /// - `corrupted_records > 0`：遇到了损坏或半写入的记录
+    /// - `created_new`：目录中原来没有 WAL，这是第一次启动
+    /// - `recovered_empty`：WAL 存在但没有一条有效记录，数据已因损坏全部丢失
+    /// - `interrupted_compaction`：上次压缩被中断，已经回滚或收尾（数据不受影响）
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let db = Db::open("data/db1", Options::default()).unwrap();
+    /// let stats = db.last_replay_stats();
+    /// if stats.truncated_bytes > 0 {
+    ///     eprintln!("recovery truncated {} bytes", stats.truncated_bytes);
+    /// }
+    /// ```
+    pub fn last_replay_stats(&self) -> &ReplayStats {
+        &self.replay_stats
+    }
+
+    /// 估算内存索引占用的内存（字节）
+    ///
+    /// 包括所有 key 的字节数、每个条目的固定开销（key 的 `Vec` 头、value 位置、
+    /// 哈希表控制字节），以及哈希表中尚未使用的多余容量。
+    /// 大量删除之后可以调用 [`Db::shrink_to_fit`] 回收多余容量。
+    ///
+    /// 纯内存计算，O(1)。
+    pub fn index_memory_bytes(&self) -> usize {
+        self.index.memory_bytes()
+    }
+
+    /// 所有存活的键值对在磁盘上占用的字节数
+    ///
+    /// 每个 key 按一条 PUT 记录计算：头部、序列号（v1 格式的 WAL 没有）、key、value 和 CRC，
+    /// 与 WAL 中这些记录实际占用的字节数相同。与 WAL 大小相比就是精确的垃圾比例：
+    ///
+    /// ```text
+    /// garbage_ratio = 1 - live_disk_bytes / wal_size
+    /// ```
+    ///
+    /// 压缩后的文件还包含历史下限标记和保留的墓碑（见 [`Options::tombstone_ttl`]），
+    /// 这两部分不计入。v1 格式的 WAL 压缩时升级为当前格式，每个 key 多出 8 字节的序列号。
+    ///
+    /// 纯内存计算，O(1)：key 和 value 的总字节数由索引在写入时维护。
+    /// 开启 [`Options::dedup_values`] 时共享的 value 按每个 key 各计一次，
+    /// 结果是压缩后大小的上限。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Db, Options};
+    ///
+    /// let mut db = Db::open("data/db1", Options::default()).unwrap();
+    /// let stats = db.stats();
+    /// let garbage = 1.0 - db.live_disk_bytes() as f64 / stats.wal_size.max(1) as f64;
+    /// if garbage > 0.5 {
+    ///     db.compact().unwrap();
+    /// }
+    /// ```
+    pub fn live_disk_bytes(&self) -> u64 {
+        self.live_bytes(self.wal.version() > VERSION_V1)
+    }
+
+    /// 每个存活 key 一条 PUT 记录的总字节数，`with_seq` 为记录是否带序列号
+    fn live_bytes(&self, with_seq: bool) -> u64 {
+        let overhead = Record::put_encoded_len(0, 0, with_seq) as u64;
+        self.index.len() as u64 * overhead + self.index.payload_bytes()
+    }
+
+    /// 释放内存索引中多余的容量
+    ///
+    /// 哈希表在大量删除后不会自动缩容，长时间运行、key 数量波动很大的进程
+    /// 可以在批量删除之后调用它。不会访问磁盘。
+    pub fn shrink_to_fit(&mut self) {
+        self.index.shrink_to_fit();
+    }
+
+    /// 获取数据库统计信息
+    ///
+    /// ## 返回值
+    ///
+    /// 返回一个包含各种统计数据的结构体
+    ///
+    /// ## 开销
+    ///
+    /// 纯内存计算，不执行任何系统调用（不调用 `metadata()`，不访问文件）：
+    /// 所有字段都来自内存索引和 WAL 内部维护的计数器（如写入位置 `offset`）。
+    /// 因此监控线程可以在 `RwLock` 读锁下调用它，不会因为 I/O 阻塞写入者。
+    pub fn stats(&self) -> DbStats {
+        let cache = self.cache.stats();
+        DbStats {
+            key_count: self.index.len(),
+            wal_size: self.wal.size(),
+            index_bytes: self.index.memory_bytes(),
+            tombstone_count: self.index.tombstone_count(),
+            live_disk_bytes: self.live_disk_bytes(),
+            dead_bytes: self.dead_bytes(),
+            cache_hits: cache.hits,
+            cache_misses: cache.misses,
+        }
+    }
+
+    /// 以 JSON 对象的形式返回 [`Db::stats`]
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(String)`: 一个 JSON 对象（格式见下）
+    /// - `Err(Error)`: 目前不会失败，保留 `Result` 以便将来加入需要 I/O 的字段
+    ///
+    /// ```text
+    /// {"key_count":3,"wal_size":120,"index_bytes":416,"tombstone_count":0,
+    ///  "live_disk_bytes":117,"dead_bytes":0,"cache_hits":10,"cache_misses":2}
+    /// ```
+    ///
+    /// 字段名与 [`DbStats`] 的字段一一对应，可以直接作为监控接口的响应体。
+    pub fn stats_json(&self) -> Result<String> {
+        Ok(self.stats().to_json())
+    }
+}
+
+/// 流式写入一个 value 的句柄，由 [`Db::put_reserve`] 创建
+///
+/// 通过 `std::io::Write` 写入 value 的内容，最后调用 [`ValueWriter::finish`] 提交。
+/// 没有提交就 drop 时，这条记录被放弃。
+pub struct ValueWriter<'a> {
+    db: &'a mut Db,
+    /// 记录在 WAL 中的起始偏移量（放弃时截断到这里）
+    start: u64,
+    /// value 在 WAL 中的起始偏移量
+    value_offset: u64,
+    key: Vec<u8>,
+    /// 分配给这次写入的序列号
+    seq: u64,
+    /// 记录中实际携带的序列号（v1 格式为 `None`）
+    record_seq: Option<u64>,
+    /// 预留的 value 长度
+    value_len: usize,
+    /// 调用方尝试写入的总字节数（包括被拒绝的部分）
+    written: usize,
+    /// CRC 状态，提交或放弃后为 `None`
+    encoder: Option<ValueEncoder>,
+}
+
+impl ValueWriter<'_> {
+    /// 提交：写入 CRC 并更新索引
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`: 写入成功（`sync_on_write` 时已 fsync），之后可以读到新值
+    /// - `Err(Error::ValueLengthMismatch)`: 写入的字节数与预留的长度不一致，记录已放弃
+    /// - `Err(Error)`: 如果写入失败，记录已放弃
+    pub fn finish(mut self) -> Result<()> {
+        let encoder = self.encoder.take().expect("ValueWriter is finished only once");
+
+        // 1. 检查长度
+        if self.written != self.value_len {
+            self.db.wal.abort_stream(self.start)?;
+            return Err(Error::ValueLengthMismatch {
+                expected: self.value_len,
+                actual: self.written,
+            });
+        }
+
+        // 2. 写入 CRC
+        let (crc, seq, sync) = (encoder.finish(), self.record_seq, self.db.opts.sync_on_write);
+        if let Err(e) = self.db.wal_write(|wal| wal.finish_stream(self.start, crc, seq, sync)) {
+            let _ = self.db.wal.abort_stream(self.start);
+            return Err(e);
+        }
+        self.db.wal_records += 1;
+        self.db.last_seq = self.seq;
+
+        // 3. 更新索引：value 没有完整地经过内存，只在需要内联或者有监视回调时从 WAL 读回；
+        //    读取失败不影响已经提交的写入，只是不内联、这次不调用回调
+        let pos = ValuePos {
+            offset: self.value_offset,
+            len: self.value_len,
+            seq: self.seq,
+        };
+        let watched = self.db.subscribers.is_watched(&self.key);
+        let value = (watched || self.db.index.inlines(self.value_len))
+            .then(|| self.db.wal.read_at(self.value_offset, self.value_len).ok())
+            .flatten();
+        match &value {
+            Some(value) => self.db.index.insert_value(self.key.clone(), pos, value),
+            None => self.db.index.insert(self.key.clone(), pos),
+        };
+        self.db.cache.remove(&self.key);
+
+        // 4. 索引更新之后才通知订阅者和监视回调
+        match &value {
+            Some(value) => self.db.notify_put(&self.key, value, self.seq),
+            None => self.db.subscribers.notify(ChangeKind::Put, &self.key, self.seq),
+        }
+
+        self.db.after_write();
+        Ok(())
+    }
+}
+
+impl Write for ValueWriter<'_> {
+    /// 写入一段 value；超出预留长度时整段拒绝，之后的 `finish` 会失败
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let encoder = self.encoder.as_mut().expect("ValueWriter is used after finish");
+
+        if self.written + buf.len() > self.value_len {
+            self.written += buf.len();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "value exceeds reserved length of {} bytes",
+                    self.value_len
+                ),
+            ));
+        }
+
+        self.db.wal_write(|wal| wal.write_stream(buf)).map_err(|e| match e {
+            Error::Io(e) => e,
+            e => std::io::Error::other(e),
+        })?;
+        encoder.update(buf);
+        self.written += buf.len();
+
+        Ok(buf.len())
+    }
+
+    /// value 在 `finish` 之前不会被 fsync，这里什么也不做
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ValueWriter<'_> {
+    /// 没有提交的记录从 WAL 中截断
+    fn drop(&mut self) {
+        if self.encoder.is_some() {
+            let _ = self.db.wal.abort_stream(self.start);
+        }
+    }
+}
+
+/// 流式读取一个 value，见 [`Db::value_reader`]
+pub struct ValueReader<'a> {
+    wal: &'a mut Wal,
+    /// value 在 WAL 中的起始偏移量
+    offset: u64,
+    /// value 的总长度
+    len: usize,
+    /// 已经读取的字节数
+    read: usize,
+}
+
+impl ValueReader<'_> {
+    /// value 的总长度（字节）
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// value 是否为空
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for ValueReader<'_> {
+    /// 从 WAL 中读取 value 的下一段，最多 `buf.len()` 字节
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.len - self.read);
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let offset = self.offset + self.read as u64;
+        self.wal.read_into(offset, &mut buf[..n]).map_err(|e| match e {
+            Error::Io(e) => e,
+            e => std::io::Error::other(e),
+        })?;
+        self.read += n;
+
+        Ok(n)
+    }
+}
+
+/// 按 key 的字节序遍历一个区间的迭代器，见 [`Db::scan`]
+pub struct RangeIter<'a> {
+    db: &'a mut Db,
+    /// 区间内的 key（含键空间前缀）及其位置，按字节序排列
+    entries: std::vec::IntoIter<(Vec<u8>, ValuePos)>,
+    /// 键空间前缀的长度，产生的 key 去掉这个前缀
+    ns_len: usize,
+}
+
+impl Iterator for RangeIter<'_> {
+    type Item = Result<KvPair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mut key, pos) = self.entries.next()?;
+        let value = match self.db.stored_value(&key, pos) {
+            Ok(value) => value,
+            Err(e) => return Some(Err(e)),
+        };
+        key.drain(..self.ns_len);
+        Some(Ok((key, value)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl ExactSizeIterator for RangeIter<'_> {}
+
+/// 大于所有以 `prefix` 开头的 key 的最小 key；前缀为空或全是 `0xFF` 时不存在
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&b| b != 0xFF)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+/// 墓碑是否超出了 `ttl`：没有设置 TTL，或者墓碑没有写入时间（v1 记录）时视为已过期
+fn tombstone_expired(tombstone: &Tombstone, ttl: Option<Duration>, now: u64) -> bool {
+    let Some(ttl) = ttl else {
+        return true;
+    };
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    tombstone.timestamp.is_none_or(|ts| now.saturating_sub(ts) >= ttl)
+}
+
+/// 当前时间（UNIX 毫秒），系统时钟早于 1970 年时为 0
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// 数据库统计信息
+///
+/// 标记为 `#[non_exhaustive]`：以后的版本可能增加统计项。需要自己构造
+/// （例如在测试中模拟）时从 `DbStats::default()` 开始修改字段。
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct DbStats {
+    /// 当前 key 的数量
+    pub key_count: usize,
+    /// WAL 文件大小（字节）
+    pub wal_size: u64,
+    /// 内存索引占用的估算内存（字节），见 [`Db::index_memory_bytes`]
+    pub index_bytes: usize,
+    /// WAL 中仍然保留着 DELETE 记录的已删除 key 的数量，见 [`Options::tombstone_ttl`]
+    pub tombstone_count: usize,
+    /// 存活的键值对在磁盘上占用的字节数，见 [`Db::live_disk_bytes`]
+    pub live_disk_bytes: u64,
+    /// WAL 中可以被压缩回收的字节数（被覆盖的 PUT、墓碑等），见 [`Options::auto_compact_ratio`]
+    pub dead_bytes: u64,
+    /// value 缓存的命中次数，见 [`Db::cache_stats`]
+    pub cache_hits: u64,
+    /// value 缓存的未命中次数，见 [`Db::cache_stats`]
+    pub cache_misses: u64,
+}
+
+impl DbStats {
+    /// 序列化为一个 JSON 对象，字段名与结构体字段相同
+    ///
+    /// 所有字段都是整数，不需要转义，所以不依赖 JSON 库。
+    pub fn to_json(&self) -> String {
+        format!(
+            concat!(
+                "{{\"key_count\":{},\"wal_size\":{},\"index_bytes\":{},",
+                "\"tombstone_count\":{},\"live_disk_bytes\":{},\"dead_bytes\":{},",
+                "\"cache_hits\":{},\"cache_misses\":{}}}"
+            ),
+            self.key_count,
+            self.wal_size,
+            self.index_bytes,
+            self.tombstone_count,
+            self.live_disk_bytes,
+            self.dead_bytes,
+            self.cache_hits,
+            self.cache_misses
+        )
+    }
+}
+
+/// 读取一个 key 的预估开销，见 [`Db::get_cost`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct GetCost {
+    /// value 在内存中（缓存或内联在索引中），`get` 不需要磁盘 I/O
+    pub cached: bool,
+    /// value 的字节数（不在内存中时就是需要从磁盘读取的字节数）
+    pub bytes: usize,
+    /// value 所在的 WAL 文件在 [`Db::wal_paths`] 中的位置（目前只有一个文件，总是 0）
+    pub segment: u32,
+}
+
+/// [`Db::health_check`] 的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HealthReport {
+    /// 所有检查都通过
+    pub healthy: bool,
+    /// `wal.log` 存在
+    pub wal_present: bool,
+    /// `wal.log` 的长度与内存中的写入位置一致
+    pub wal_size_ok: bool,
+    /// 仍然持有目录锁（只读模式不加锁，总是 `true`）
+    pub lock_ok: bool,
+    /// 最近一条写入（或 replay 到）的记录能完整读出，CRC 正确；还没有记录时为 `true`
+    pub last_record_ok: bool,
+    /// 抽查了多少个 key
+    pub sampled_keys: usize,
+    /// 抽查的 key 都指向 WAL 中完整且与索引一致的记录
+    pub index_ok: bool,
+    /// 数据库因为之前的写入错误处于中毒状态（见 [`Db::is_poisoned`]）
+    pub poisoned: bool,
+}
+
+/// [`CompactStats`] 的别名
+pub type CompactionStats = CompactStats;
+
+/// 压缩统计信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactStats {
+    /// 压缩后保留的 key 数量
+    pub keys_kept: u64,
+    /// 被丢弃的记录数（被覆盖的旧值和 DELETE 记录）
+    pub records_dropped: u64,
+    /// 压缩前的 WAL 大小（字节）
+    pub bytes_before: u64,
+    /// 压缩后的 WAL 大小（字节）
+    pub bytes_after: u64,
+    /// 压缩耗时
+    pub duration: Duration,
+}
+
+/// 两个数据库的差异，见 [`Db::diff`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffReport {
+    /// 只在 `self` 中存在的 key（按字节序排列）
+    pub only_in_self: Vec<Vec<u8>>,
+    /// 只在 `other` 中存在的 key（按字节序排列）
+    pub only_in_other: Vec<Vec<u8>>,
+    /// 两边都存在但 value 不同的 key（按字节序排列）
+    pub differing: Vec<Vec<u8>>,
+}
+
+impl DiffReport {
+    /// 两个数据库的内容是否完全相同
+    pub fn is_empty(&self) -> bool {
+        self.only_in_self.is_empty() && self.only_in_other.is_empty() && self.differing.is_empty()
+    }
+}
+
+/// 一次后台压缩（见 [`Db::compact_concurrent`]）
+///
+/// 创建时复制索引快照并打开旧 WAL 的读句柄，[`CompactionJob::run`] 不需要访问 `Db`，
+/// 可以在不持有锁的情况下执行。任务被丢弃时删除临时文件并清除 `compacting` 标志。
+struct CompactionJob {
+    /// 旧 WAL 的读句柄
+    source: Option<File>,
+    /// 新 WAL 的临时文件路径
+    tmp_path: PathBuf,
+    limits: Limits,
+    /// 快照：存活的 key 及其在旧 WAL 中的位置，见 [`Db::live_entries`]
+    live: Vec<(Vec<u8>, ValuePos, Option<u64>)>,
+    /// 旧 WAL 的格式版本
+    source_version: u8,
+    /// 快照：要保留的墓碑
+    tombstones: Vec<Record>,
+    /// 快照时的最新序列号
+    last_seq: u64,
+    /// 见 [`Options::dedup_values`]
+    dedup_values: bool,
+    /// 快照时旧 WAL 的末尾，之后追加的记录在切换时补写
+    snapshot_end: u64,
+    progress: ProgressReporter,
+    /// 新 WAL 的索引
+    index: Index,
+    /// 已写好的新 WAL（`run` 之后）
+    prepared: Option<PreparedRewrite>,
+    start: Instant,
+    compacting: Arc<AtomicBool>,
+}
+
+impl CompactionJob {
+    /// 后台压缩第 2 步：按快照写出新 WAL（不访问 `Db`）
+    fn run(mut self) -> Result<Self> {
+        let mut source = self.source.take().expect("compaction job is run only once");
+        let index = &mut self.index;
+        let progress = &mut self.progress;
+        let content = RewriteContent {
+            live: std::mem::take(&mut self.live),
+            source_version: self.source_version,
+            tombstones: std::mem::take(&mut self.tombstones),
+            last_seq: self.last_seq,
+            dedup_values: self.dedup_values,
+        };
+        let prepared = Wal::write_rewrite(
+            &mut source,
+            self.tmp_path.clone(),
+            self.limits,
+            content,
+            &mut |offset, record| {
+                progress.written(offset + record.encoded_len() as u64);
+                Db::index_compacted(index, offset, record);
+            },
+        )?;
+        progress.finish();
+        self.prepared = Some(prepared);
+        Ok(self)
+    }
+}
+
+impl Drop for CompactionJob {
+    fn drop(&mut self) {
+        // 先删除没有用上的临时文件，再允许下一次后台压缩
+        self.prepared = None;
+        self.compacting.store(false, Ordering::Release);
+    }
+}
+
+/// 按 [`COMPACT_PROGRESS_INTERVAL`] 节流地调用 [`Options::on_compact_progress`]
+struct ProgressReporter {
+    callback: Option<CompactProgress>,
+    total: u64,
+    /// 上一次报告时的已写入字节数
+    reported: u64,
+}
+
+impl ProgressReporter {
+    fn new(callback: Option<CompactProgress>, total: u64) -> Self {
+        ProgressReporter {
+            callback,
+            total,
+            reported: 0,
+        }
+    }
+
+    /// 已经写出了 `processed` 字节：距上次报告超过间隔或写完时调用回调
+    fn written(&mut self, processed: u64) {
+        let Some(callback) = &self.callback else {
+            return;
+        };
+        if processed - self.reported >= COMPACT_PROGRESS_INTERVAL || processed >= self.total {
+            self.reported = processed;
+            (callback.0)(processed, self.total);
+        }
+    }
+
+    /// 写完了：去重让实际写出的字节少于预估的总数时，补报一次 `(total, total)`
+    fn finish(&mut self) {
+        if self.reported < self.total {
+            self.written(self.total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_put_get() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        db.put(b"key1", b"value1").unwrap();
+        db.put(b"key2", b"value2").unwrap();
+
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
+        assert_eq!(db.get(b"key2").unwrap().as_deref(), Some(b"value2" as &[u8]));
+        assert_eq!(db.get(b"key3").unwrap(), None);
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        db.put(b"key", b"value1").unwrap();
+        db.put(b"key", b"value2").unwrap();
+
+        assert_eq!(db.get(b"key").unwrap().as_deref(), Some(b"value2" as &[u8]));
+    }
+
+    #[test]
+    fn test_delete() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        db.put(b"key", b"value").unwrap();
+        assert_eq!(db.get(b"key").unwrap().as_deref(), Some(b"value" as &[u8]));
+
+        db.delete(b"key").unwrap();
+        assert_eq!(db.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_reopen_persistence() {
+        let dir = TempDir::new().unwrap();
+
+        // 写入数据
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"key1", b"value1").unwrap();
+            db.put(b"key2", b"value2").unwrap();
+            db.delete(b"key1").unwrap();
+        }
+
+        // 重新打开，验证持久化
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            assert_eq!(db.get(b"key1").unwrap(), None);
+            assert_eq!(db.get(b"key2").unwrap().as_deref(), Some(b"value2" as &[u8]));
+        }
+    }
+
+    #[test]
+    fn test_multi_delete() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+            db.put(b"c", b"3").unwrap();
+
+            let removed = db.multi_delete(&[b"a", b"b", b"missing"]).unwrap();
+            assert_eq!(removed, 2);
+            assert_eq!(db.get(b"a").unwrap(), None);
+            assert_eq!(db.get(b"b").unwrap(), None);
+            assert_eq!(db.get(b"c").unwrap().as_deref(), Some(b"3" as &[u8]));
+
+            // 空列表不写入任何内容
+            let size = db.stats().wal_size;
+            assert_eq!(db.multi_delete(&[]).unwrap(), 0);
+            assert_eq!(db.stats().wal_size, size);
+        }
+
+        // 重新打开，删除已持久化
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap().as_deref(), Some(b"3" as &[u8]));
+    }
+
+    #[test]
+    fn test_retain() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let opts = Options::builder().inline_value_threshold(4).build();
+            let mut db = Db::open(dir.path(), opts).unwrap();
+            db.put(b"a", b"").unwrap();
+            db.put(b"b", b"keep").unwrap();
+            db.put(b"c", b"a longer value read from the wal").unwrap();
+            db.put(b"tmp:1", b"x").unwrap();
+
+            let removed = db
+                .retain(|key, value| !value.is_empty() && !key.starts_with(b"tmp:"))
+                .unwrap();
+            assert_eq!(removed, 2);
+            assert_eq!(db.get(b"a").unwrap(), None);
+            assert_eq!(db.get(b"tmp:1").unwrap(), None);
+            assert_eq!(db.stats().key_count, 2);
+
+            // 没有需要删除的 key 时不写入任何内容
+            let size = db.stats().wal_size;
+            assert_eq!(db.retain(|_, _| true).unwrap(), 0);
+            assert_eq!(db.stats().wal_size, size);
+        }
+
+        // 重新打开，删除已持久化
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap().as_deref(), Some(b"keep" as &[u8]));
+        assert_eq!(db.stats().key_count, 2);
+    }
+
+    #[test]
+    fn test_diff() {
+        let dir = TempDir::new().unwrap();
+        let mut a = Db::open(dir.path().join("a"), Options::default()).unwrap();
+        let opts = Options::builder().inline_value_threshold(2).build();
+        let mut b = Db::open(dir.path().join("b"), opts).unwrap();
+
+        for db in [&mut a, &mut b] {
+            db.put(b"same", b"value").unwrap();
+            db.put(b"tiny", b"1").unwrap();
+        }
+        a.put(b"same", b"old").unwrap();
+        a.put(b"same", b"value").unwrap();
+        a.compact().unwrap();
+        // 写入历史和 WAL 布局不同，内容相同
+        assert!(a.diff(&mut b).unwrap().is_empty());
+
+        a.put(b"only-a", b"x").unwrap();
+        b.put(b"only-b", b"x").unwrap();
+        a.put(b"tiny", b"2").unwrap();
+        a.put(b"len", b"short").unwrap();
+        b.put(b"len", b"longer").unwrap();
+
+        let report = a.diff(&mut b).unwrap();
+        assert!(!report.is_empty());
+        assert_eq!(report.only_in_self, vec![b"only-a".to_vec()]);
+        assert_eq!(report.only_in_other, vec![b"only-b".to_vec()]);
+        assert_eq!(report.differing, vec![b"len".to_vec(), b"tiny".to_vec()]);
+
+        let reverse = b.diff(&mut a).unwrap();
+        assert_eq!(reverse.only_in_self, report.only_in_other);
+        assert_eq!(reverse.differing, report.differing);
+    }
+
+    #[test]
+    fn test_merge_from_dir() {
+        let dir = TempDir::new().unwrap();
+        let (a_dir, b_dir) = (dir.path().join("a"), dir.path().join("b"));
+        let mut b = Db::open(&b_dir, Options::default()).unwrap();
+        b.put(b"only-b", b"b").unwrap();
+        b.put(b"both", b"from-b").unwrap();
+        b.put(b"same", b"v").unwrap();
+        b.put(b"gone", b"x").unwrap();
+        b.delete(b"gone").unwrap();
+        drop(b);
+
+        let fresh = || {
+            let _ = std::fs::remove_dir_all(&a_dir);
+            let mut a = Db::open(&a_dir, Options::default()).unwrap();
+            a.put(b"only-a", b"a").unwrap();
+            a.put(b"both", b"from-a").unwrap();
+            a.put(b"same", b"v").unwrap();
+            a
+        };
+
+        let mut a = fresh();
+        assert_eq!(a.merge_from_dir(&b_dir, ConflictPolicy::KeepSelf).unwrap(), 1);
+        assert_eq!(a.get(b"only-b").unwrap().as_deref(), Some(b"b" as &[u8]));
+        assert_eq!(a.get(b"both").unwrap().as_deref(), Some(b"from-a" as &[u8]));
+        assert_eq!(a.get(b"only-a").unwrap().as_deref(), Some(b"a" as &[u8]));
+        assert_eq!(a.get(b"gone").unwrap(), None);
+        drop(a);
+
+        // 相同的 value 不重复写入
+        let mut a = fresh();
+        assert_eq!(a.merge_from_dir(&b_dir, ConflictPolicy::TakeOther).unwrap(), 2);
+        assert_eq!(a.get(b"both").unwrap().as_deref(), Some(b"from-b" as &[u8]));
+        drop(a);
+
+        let mut a = fresh();
+        let policy = ConflictPolicy::resolve(|key, ours, theirs| match key {
+            b"both" => [ours, b"+", theirs].concat(),
+            _ => ours.to_vec(),
+        });
+        assert_eq!(a.merge_from_dir(&b_dir, policy).unwrap(), 2);
+        assert_eq!(a.get(b"both").unwrap().as_deref(), Some(b"from-a+from-b" as &[u8]));
+
+        // 不存在的目录
+        let missing = dir.path().join("missing");
+        assert!(a.merge_from_dir(&missing, ConflictPolicy::KeepSelf).is_err());
+    }
+
+    #[test]
+    fn test_multi_delete_rejects_oversized_key() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        let size = db.stats().wal_size;
+        let large_key = vec![0u8; 2048];
+        assert!(db.multi_delete(&[b"a", &large_key]).is_err());
+
+        // 整体拒绝：没有写入，a 仍然存在
+        assert_eq!(db.stats().wal_size, size);
+        assert_eq!(db.get(b"a").unwrap().as_deref(), Some(b"1" as &[u8]));
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        db.put(b"key1", b"value1").unwrap();
+        db.put(b"key2", b"value2").unwrap();
+
+        let stats = db.stats();
+        assert_eq!(stats.key_count, 2);
+        assert!(stats.wal_size > 0);
+    }
+
+    #[test]
+    fn test_live_disk_bytes() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.live_disk_bytes(), 0);
+
+        db.put(b"key", b"value").unwrap();
+        let one = Record::put_encoded_len(3, 5, true) as u64;
+        assert_eq!(db.live_disk_bytes(), one);
+        assert_eq!(db.live_disk_bytes(), db.wal.size());
+
+        // 覆盖和删除产生的垃圾不计入，而是计入 dead_bytes
+        db.put(b"key", b"v").unwrap();
+        db.put(b"gone", b"value").unwrap();
+        db.delete(b"gone").unwrap();
+        let live = db.live_disk_bytes();
+        assert_eq!(live, Record::put_encoded_len(3, 1, true) as u64);
+        assert_eq!(db.stats().live_disk_bytes, live);
+
+        // 压缩写出的就是这些字节（外加历史下限标记），其余都是 dead_bytes
+        let floor = Wal::history_floor_record(db.latest_sequence())
+            .and_then(|record| record.ok())
+            .map_or(0, |record| record.encoded_len() as u64);
+        assert_eq!(db.stats().dead_bytes, db.wal.size() - live - floor);
+        let stats = db.compact().unwrap();
+        assert_eq!(stats.bytes_after, live + floor);
+        assert_eq!(db.live_disk_bytes(), live);
+        assert_eq!(db.stats().dead_bytes, 0);
+    }
+
+    #[test]
+    fn test_intern_small_values() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().intern_small_values(Some(64)).build();
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+
+        let flag = vec![7u8; 40];
+        for i in 0..10 {
+            db.put(format!("key{}", i).as_bytes(), &flag).unwrap();
+        }
+        db.put(b"big", &[7u8; 100]).unwrap();
+        db.put(b"short", b"tiny").unwrap();
+        db.put(b"short2", b"tiny").unwrap();
+
+        // 第一次写入是 PUT，之后相同的 value 写成 REF；超过阈值或太短的 value 不驻留
+        let ref_keys = |db: &Db| -> Vec<Vec<u8>> {
+            WalReader::open(db.wal.path())
+                .unwrap()
+                .map(|item| item.unwrap().1)
+                .filter(|record| record.kind == RecordKind::Ref)
+                .map(|record| record.key)
+                .collect()
+        };
+        db.sync().unwrap();
+        assert_eq!(ref_keys(&db).len(), 9);
+        assert!(!ref_keys(&db).contains(&b"short2".to_vec()));
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            assert_eq!(db.get(key.as_bytes()).unwrap(), Some(flag.clone()));
+            assert_eq!(db.verify_key(key.as_bytes()).unwrap(), Some(true));
+        }
+
+        // 最初写入 value 的 key 被覆盖、删除之后，已有的副本仍然可以被引用
+        db.put(b"key0", b"new").unwrap();
+        db.delete(b"key0").unwrap();
+        db.put(b"later", &flag).unwrap();
+        assert_eq!(db.get(b"later").unwrap(), Some(flag.clone()));
+        assert_eq!(db.verify_key(b"later").unwrap(), Some(true));
+
+        // replay 恢复共享的位置
+        drop(db);
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"key9").unwrap(), Some(flag.clone()));
+        assert_eq!(db.get(b"later").unwrap(), Some(flag.clone()));
+
+        // 重写之后旧位置失效：驻留表清空，下一次写入重新写出完整的 value
+        db.compact().unwrap();
+        db.put(b"after", &flag).unwrap();
+        assert_eq!(db.index.ref_offset(b"after"), None);
+        db.put(b"after2", &flag).unwrap();
+        assert!(db.index.ref_offset(b"after2").is_some());
+        assert_eq!(db.verify_key(b"after2").unwrap(), Some(true));
+
+        // 后台压缩期间写入的 REF 记录指向旧文件，补写时换成完整的 PUT
+        let job = db.begin_compaction().unwrap().run().unwrap();
+        db.put(b"during", &flag).unwrap();
+        assert!(db.index.ref_offset(b"during").is_some());
+        db.finish_compaction(job).unwrap();
+        assert_eq!(db.get(b"during").unwrap(), Some(flag.clone()));
+        assert_eq!(db.verify_key(b"during").unwrap(), Some(true));
+        assert_eq!(db.get(b"key5").unwrap(), Some(flag));
+    }
+
+    #[test]
+    fn test_dedup_values() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().dedup_values(true).build();
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+
+        let shared = vec![7u8; 1000];
+        for i in 0..10 {
+            db.put(format!("key{}", i).as_bytes(), &shared).unwrap();
+        }
+        db.put(b"other", &[8u8; 1000]).unwrap();
+        db.put(b"short", b"tiny").unwrap();
+        db.put(b"short2", b"tiny").unwrap();
+
+        // 相同的大 value 只写出一次
+        let stats = db.compact().unwrap();
+        assert!(stats.bytes_after < 3000);
+        assert!(stats.bytes_after < db.live_disk_bytes());
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            assert_eq!(db.get(key.as_bytes()).unwrap(), Some(shared.clone()));
+            assert_eq!(db.verify_key(key.as_bytes()).unwrap(), Some(true));
+        }
+        assert_eq!(db.get(b"other").unwrap(), Some(vec![8u8; 1000]));
+        assert_eq!(db.get(b"short2").unwrap().as_deref(), Some(b"tiny" as &[u8]));
+
+        // 覆盖持有 value 的 key 后，共享它的 key 仍然读到原来的 value
+        db.put(b"key0", b"new").unwrap();
+        db.delete(b"key1").unwrap();
+        assert_eq!(db.get(b"key2").unwrap(), Some(shared.clone()));
+        assert_eq!(db.verify_key(b"key2").unwrap(), Some(true));
+
+        // 变更流中的 REF 记录表现为 PUT
+        let changes = db.changes_since(0).unwrap();
+        assert!(changes.iter().all(|(_, r)| r.kind != RecordKind::Ref));
+        assert!(changes.iter().any(|(_, r)| r.key == b"key3" && r.value == shared));
+
+        // 完整 replay 和从 checkpoint 打开都能恢复共享的位置
+        drop(db);
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        assert_eq!(db.get(b"key9").unwrap(), Some(shared.clone()));
+        assert_eq!(db.get(b"key0").unwrap().as_deref(), Some(b"new" as &[u8]));
+        db.checkpoint().unwrap();
+        drop(db);
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"key9").unwrap(), Some(shared.clone()));
+        assert_eq!(db.verify_key(b"key9").unwrap(), Some(true));
+        assert_eq!(db.get(b"key1").unwrap(), None);
+
+        // 再次压缩（包括后台压缩）时重新去重
+        db.compact().unwrap();
+        let db = Mutex::new(db);
+        Db::compact_concurrent(&db).unwrap();
+        let mut db = db.into_inner().unwrap();
+        for i in 2..10 {
+            let key = format!("key{}", i);
+            assert_eq!(db.get(key.as_bytes()).unwrap(), Some(shared.clone()));
+            assert_eq!(db.verify_key(key.as_bytes()).unwrap(), Some(true));
+        }
+    }
+
+    #[test]
+    fn test_put_disk_full() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"key1", b"value1").unwrap();
+        let seq = db.latest_sequence();
+
+        // 写入失败时不更新索引，也不消耗序列号
+        db.wal.fail_writes_after(3);
+        assert!(matches!(db.put(b"key2", b"value2"), Err(Error::DiskFull(_))));
+        db.clear_poison();
+        assert!(matches!(db.delete(b"key1"), Err(Error::DiskFull(_))));
+        assert_eq!(db.get(b"key2").unwrap(), None);
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
+        assert_eq!(db.latest_sequence(), seq);
+        assert_eq!(db.stats().wal_size, std::fs::metadata(db.wal.path()).unwrap().len());
+
+        drop(db);
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.last_replay_stats().truncated_bytes, 0);
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
+        assert_eq!(db.get(b"key2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_write_error_poisons_db() {
+        use std::sync::atomic::AtomicUsize;
+
+        let dir = TempDir::new().unwrap();
+        let errors = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&errors);
+        let opts = Options::builder()
+            .limits(Limits {
+                max_key_size: 8,
+                max_value_size: 1024,
+            })
+            .on_write_error(move |e| {
+                assert!(matches!(e, Error::DiskFull(_)));
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        db.put(b"key1", b"value1").unwrap();
+        assert!(!db.is_poisoned());
+
+        // 参数错误不触发回调，也不中毒
+        assert!(matches!(db.put(b"too-long-key", b"v"), Err(Error::KeyTooLarge { .. })));
+        assert!(!db.is_poisoned());
+
+        // I/O 错误：回调一次，之后的写操作直接失败，读不受影响
+        db.wal.fail_writes_after(3);
+        assert!(matches!(db.put(b"key2", b"value2"), Err(Error::DiskFull(_))));
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+        assert!(db.is_poisoned());
+        assert!(matches!(db.put(b"key3", b"value3"), Err(Error::Poisoned)));
+        assert!(matches!(db.delete(b"key1"), Err(Error::Poisoned)));
+        assert!(matches!(db.sync(), Err(Error::Poisoned)));
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
+
+        // 清除后恢复写入
+        db.wal.fail_writes_after(usize::MAX);
+        db.clear_poison();
+        db.put(b"key3", b"value3").unwrap();
+        assert!(!db.is_poisoned());
+
+        // 重新打开后不再中毒
+        db.wal.fail_writes_after(0);
+        assert!(db.put(b"key4", b"value4").is_err());
+        assert!(db.is_poisoned());
+        drop(db);
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert!(!db.is_poisoned());
+        db.put(b"key4", b"value4").unwrap();
+        assert_eq!(db.get(b"key3").unwrap().as_deref(), Some(b"value3" as &[u8]));
+    }
+
+    #[test]
+    fn test_background_sync_error_poisons_db() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options {
+            sync_on_write: false,
+            flush_interval: Some(Duration::from_secs(3600)),
+            ..Options::default()
+        };
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        db.put(b"key1", b"value1").unwrap();
+
+        // 后台线程 fsync 失败：下一次写操作返回这个错误，不写入，数据库中毒
+        let handle = db.wal.sync_handle();
+        handle.set_error(std::io::Error::other("fsync failed").into());
+        assert!(matches!(db.put(b"key2", b"value2"), Err(Error::Io(_))));
+        assert!(db.is_poisoned());
+        assert_eq!(db.get(b"key2").unwrap(), None);
+
+        // 错误只返回一次：清除中毒后恢复写入
+        db.clear_poison();
+        db.put(b"key2", b"value2").unwrap();
+        assert_eq!(db.get(b"key2").unwrap().as_deref(), Some(b"value2" as &[u8]));
+    }
+
+    #[test]
+    fn test_set_options() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().sync_on_write(false).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+
+        // 缓存：从关闭到启用，再缩小
+        db.resize_cache(1024);
+        db.put(b"a", b"1").unwrap();
+        assert_eq!(db.get_cached_only(b"a"), CacheResult::Hit(b"1".to_vec()));
+        db.resize_cache(0);
+        assert_eq!(db.get_cached_only(b"a"), CacheResult::Miss);
+
+        // 切换到 sync_on_write 时之前的写入被 fsync
+        db.set_sync_mode(true).unwrap();
+        assert!(db.options().sync_on_write);
+
+        // 自动压缩：覆盖写让垃圾比例超过阈值后，下一次写入触发压缩
+        db.set_auto_compact_ratio(Some(0.5));
+        for i in 0..10u8 {
+            db.put(b"key", &[i; 100]).unwrap();
+        }
+        assert!(db.stats().wal_size < 400);
+        assert_eq!(db.get(b"key").unwrap(), Some(vec![9u8; 100]));
+
+        // 只在打开时生效的选项不能修改，此时其他选项也保持不变
+        let mut opts = db.options().clone();
+        opts.sync_on_write = false;
+        opts.key_prefix = b"ns:".to_vec();
+        assert!(matches!(
+            db.set_options(opts),
+            Err(Error::FixedOption { name: "key_prefix" })
+        ));
+        assert!(db.options().sync_on_write);
+
+        let mut opts = db.options().clone();
+        opts.sync_on_write = false;
+        opts.auto_compact_ratio = None;
+        opts.cache_capacity_bytes = 64;
+        db.set_options(opts).unwrap();
+        assert!(!db.options().sync_on_write);
+        assert_eq!(db.options().auto_compact_ratio, None);
+        db.get(b"key").unwrap();
+        db.put(b"b", b"2").unwrap();
+        assert_eq!(db.get_cached_only(b"b"), CacheResult::Hit(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_unsynced_bytes() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().sync_on_write(false).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert!(!db.is_dirty());
+
+        db.put(b"key", b"value").unwrap();
+        let one = db.stats().wal_size;
+        assert_eq!(db.unsynced_bytes(), one);
+        db.delete(b"key").unwrap();
+        assert_eq!(db.unsynced_bytes(), db.stats().wal_size);
+        assert!(db.is_dirty());
+
+        db.sync().unwrap();
+        assert!(!db.is_dirty());
+
+        // 后台线程的 fsync 同样计入
+        db.put(b"key", b"value").unwrap();
+        db.wal.sync_handle().sync_data().unwrap();
+        assert_eq!(db.unsynced_bytes(), 0);
+
+        // 压缩后的文件已经 fsync
+        db.put(b"key2", b"value2").unwrap();
+        assert!(db.is_dirty());
+        db.compact().unwrap();
+        assert_eq!(db.unsynced_bytes(), 0);
+
+        db.set_sync_mode(true).unwrap();
+        db.put(b"key3", b"value3").unwrap();
+        assert_eq!(db.unsynced_bytes(), 0);
+
+        // 只读模式
+        drop(db);
+        let db = Db::open(dir.path(), Options::builder().read_only(true).build()).unwrap();
+        assert!(!db.is_dirty());
+    }
+
+    #[test]
+    fn test_dir_and_wal_paths() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"key", b"value").unwrap();
+
+        assert_eq!(db.dir(), dir.path());
+        let paths = db.wal_paths();
+        assert_eq!(paths, vec![dir.path().join(WAL_FILENAME)]);
+        assert_eq!(std::fs::metadata(&paths[0]).unwrap().len(), db.stats().wal_size);
+    }
+
+    #[test]
+    fn test_options_builder() {
+        let opts = Options::builder()
+            .sync_on_write(false)
+            .write_buffer_bytes(4096)
+            .checkpoint_interval_bytes(Some(1 << 20))
+            .flush_interval(Some(Duration::from_millis(50)))
+            .write_back_bytes(Some(1 << 16))
+            .open_lock_timeout(Some(Duration::from_secs(1)))
+            .build();
+        assert!(!opts.sync_on_write);
+        assert_eq!(opts.write_back_bytes, Some(1 << 16));
+        assert_eq!(opts.open_lock_timeout, Some(Duration::from_secs(1)));
+        assert_eq!(opts.write_buffer_bytes, 4096);
+        assert_eq!(opts.checkpoint_interval_bytes, Some(1 << 20));
+        assert_eq!(opts.flush_interval, Some(Duration::from_millis(50)));
+
+        // 没有设置的字段保持默认值
+        let defaults = Options::default();
+        assert_eq!(opts.limits, defaults.limits);
+        assert_eq!(opts.read_only, defaults.read_only);
+        assert_eq!(opts.direct_io, defaults.direct_io);
+    }
+
+    #[test]
+    fn test_verify_key() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"key1", b"value1").unwrap();
+        db.put(b"key2", b"value2").unwrap();
+        db.sync().unwrap();
+
+        assert_eq!(db.verify_key(b"key1").unwrap(), Some(true));
+        assert_eq!(db.verify_key(b"missing").unwrap(), None);
+
+        // 破坏 key1 的 value：CRC 不再匹配，key2 不受影响
+        let offset = db.index.get(b"key1").unwrap().offset;
+        let path = dir.path().join(WAL_FILENAME);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[offset as usize] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(db.verify_key(b"key1").unwrap(), Some(false));
+        assert_eq!(db.verify_key(b"key2").unwrap(), Some(true));
+
+        // 索引指向文件之外
+        let mut pos = *db.index.get(b"key2").unwrap();
+        pos.offset += 1 << 20;
+        db.index.insert(b"key2".to_vec(), pos);
+        assert_eq!(db.verify_key(b"key2").unwrap(), Some(false));
+    }
+
+    #[test]
+    fn test_health_check() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().write_buffer_bytes(4096).build();
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        let report = db.health_check().unwrap();
+        assert!(report.healthy);
+        assert_eq!(report.sampled_keys, 0);
+
+        for i in 0..10 {
+            db.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        // 写缓冲区中的数据先写入文件，长度一致
+        let report = db.health_check().unwrap();
+        assert!(report.healthy, "{:?}", report);
+        assert_eq!(report.sampled_keys, HEALTH_CHECK_SAMPLE);
+
+        // 重新打开后检查 replay 到的最后一条记录；只读模式不加锁
+        drop(db);
+        let read_only = Options {
+            read_only: true,
+            ..opts.clone()
+        };
+        let mut follower = Db::open(dir.path(), read_only).unwrap();
+        assert!(follower.health_check().unwrap().healthy);
+        drop(follower);
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert!(db.health_check().unwrap().healthy);
+
+        // 破坏最后一条记录的 CRC
+        let path = dir.path().join(WAL_FILENAME);
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+        let report = db.health_check().unwrap();
+        assert!(!report.healthy);
+        assert!(!report.last_record_ok);
+        assert!(report.wal_size_ok && report.lock_ok);
+
+        // 文件长度与写入位置不一致，LOCK 被删除
+        bytes.extend_from_slice(b"junk");
+        std::fs::write(&path, &bytes).unwrap();
+        std::fs::remove_file(dir.path().join(crate::lock::LOCK_FILENAME)).unwrap();
+        let report = db.health_check().unwrap();
+        assert!(report.wal_present);
+        assert!(!report.wal_size_ok);
+        assert!(!report.lock_ok);
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.disk_usage().unwrap(), 0);
+
+        db.put(b"key", b"value").unwrap();
+        db.sync().unwrap();
+        let usage = db.disk_usage().unwrap();
+        assert!(usage > 0);
+
+        // 把 WAL 扩展成稀疏文件：逻辑大小变大，实际占用不变
+        #[cfg(unix)]
+        {
+            assert_eq!(usage % 512, 0);
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(dir.path().join(WAL_FILENAME))
+                .unwrap();
+            file.set_len(64 * 1024 * 1024).unwrap();
+            assert!(db.disk_usage().unwrap() < 64 * 1024 * 1024);
+        }
+    }
+
+    #[test]
+    fn test_rename_key() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"old", b"value").unwrap();
+            db.put(b"new", b"overwritten").unwrap();
+
+            assert!(db.rename_key(b"old", b"new").unwrap());
+            assert_eq!(db.get(b"old").unwrap(), None);
+            assert_eq!(db.get(b"new").unwrap().as_deref(), Some(b"value" as &[u8]));
+
+            // 原键不存在
+            let size = db.stats().wal_size;
+            assert!(!db.rename_key(b"missing", b"other").unwrap());
+            assert_eq!(db.stats().wal_size, size);
+
+            // 同名移动
+            assert!(db.rename_key(b"new", b"new").unwrap());
+            assert_eq!(db.stats().wal_size, size);
+        }
+
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get(b"old").unwrap(), None);
+        assert_eq!(db.get(b"new").unwrap().as_deref(), Some(b"value" as &[u8]));
+        assert_eq!(db.stats().key_count, 1);
+    }
+
+    #[test]
+    fn test_get_cached_only() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+        }
+
+        let opts = Options::builder().cache_capacity_bytes(1024).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get_cached_only(b"a"), CacheResult::Miss);
+        assert_eq!(db.get_cached_only(b"missing"), CacheResult::Absent);
+
+        // 读取一次之后命中；写入直接进入缓存
+        db.get(b"a").unwrap();
+        db.put(b"c", b"3").unwrap();
+        assert_eq!(db.get_cached_only(b"a"), CacheResult::Hit(b"1".to_vec()));
+        assert_eq!(db.get_cached_only(b"c"), CacheResult::Hit(b"3".to_vec()));
+        assert_eq!(db.get_cached_only(b"b"), CacheResult::Miss);
+
+        db.delete(b"a").unwrap();
+        assert_eq!(db.get_cached_only(b"a"), CacheResult::Absent);
+    }
+
+    #[test]
+    fn test_read_value_vectored() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder()
+            .write_buffer_bytes(4096)
+            .inline_value_threshold(4)
+            .build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        let value: Vec<u8> = (0..100u8).collect();
+        db.put(b"key", &value).unwrap();
+        db.put(b"tiny", b"abc").unwrap();
+
+        let read = |db: &mut Db, key: &[u8], sizes: &[usize]| {
+            let mut storage: Vec<Vec<u8>> = sizes.iter().map(|&n| vec![0xEE; n]).collect();
+            let mut bufs: Vec<IoSliceMut> =
+                storage.iter_mut().map(|b| IoSliceMut::new(b)).collect();
+            let n = db.read_value_vectored(key, &mut bufs).unwrap();
+            (n, storage.concat())
+        };
+
+        // 还在写缓冲区中 / 已写入文件：value 比缓冲区短，剩余部分保持原样
+        for _ in 0..2 {
+            let (n, bytes) = read(&mut db, b"key", &[10, 30, 200]);
+            assert_eq!(n, Some(100));
+            assert_eq!(&bytes[..100], &value[..]);
+            assert!(bytes[100..].iter().all(|&b| b == 0xEE));
+            db.sync().unwrap();
+        }
+
+        // value 比缓冲区长：只读取能放下的部分
+        let (n, bytes) = read(&mut db, b"key", &[7, 0, 13]);
+        assert_eq!((n, bytes.as_slice()), (Some(20), &value[..20]));
+
+        // 内联的 value 直接复制；不存在的 key
+        let (n, bytes) = read(&mut db, b"tiny", &[2, 2]);
+        assert_eq!((n, bytes.as_slice()), (Some(3), b"abc\xEE" as &[u8]));
+        assert_eq!(read(&mut db, b"missing", &[8]).0, None);
+    }
+
+    #[test]
+    fn test_get_cost() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder()
+            .cache_capacity_bytes(1024)
+            .inline_value_threshold(4)
+            .build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        db.put(b"small", b"1").unwrap();
+        db.put(b"big", &[7u8; 100]).unwrap();
+        // 清空写入时放进缓存的 value
+        db.resize_cache(0);
+        db.resize_cache(1024);
+
+        let cost = |cached, bytes| Some(GetCost { cached, bytes, segment: 0 });
+        assert_eq!(db.get_cost(b"small"), cost(true, 1));
+        assert_eq!(db.get_cost(b"big"), cost(false, 100));
+        assert_eq!(db.get_cost(b"missing"), None);
+
+        // 读取一次之后进入缓存
+        db.get(b"big").unwrap();
+        assert_eq!(db.get_cost(b"big"), cost(true, 100));
+        db.delete(b"big").unwrap();
+        assert_eq!(db.get_cost(b"big"), None);
+    }
+
+    #[test]
+    fn test_inline_values() {
+        let dir = TempDir::new().unwrap();
+        let wal_path = dir.path().join(WAL_FILENAME);
+        let opts = || Options::builder().inline_value_threshold(8).build();
+        let truncate_wal = || {
+            let file = std::fs::OpenOptions::new().write(true).open(&wal_path).unwrap();
+            file.set_len(0).unwrap();
+        };
+
+        {
+            let mut db = Db::open(dir.path(), opts()).unwrap();
+            db.put(b"small", b"1").unwrap();
+            db.put(b"large", b"0123456789").unwrap();
+            db.checkpoint().unwrap();
+            db.put(b"replayed", b"2").unwrap();
+        }
+
+        // replay 的 key 直接内联；MANIFEST 中的 key 第一次读取后内联
+        let mut db = Db::open(dir.path(), opts()).unwrap();
+        assert_eq!(db.get(b"small").unwrap().as_deref(), Some(b"1" as &[u8]));
+        assert!(db.index_memory_bytes() > 0);
+
+        // WAL 被截断后，内联的 value 仍然可以读取，其他 value 需要访问 WAL
+        truncate_wal();
+        assert_eq!(db.get(b"small").unwrap().as_deref(), Some(b"1" as &[u8]));
+        assert_eq!(db.get(b"replayed").unwrap().as_deref(), Some(b"2" as &[u8]));
+        assert!(matches!(db.get(b"large"), Err(Error::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn test_swap() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+
+            db.swap(b"a", b"b").unwrap();
+            assert_eq!(db.get(b"a").unwrap().as_deref(), Some(b"2" as &[u8]));
+            assert_eq!(db.get(b"b").unwrap().as_deref(), Some(b"1" as &[u8]));
+
+            // 只有一个 key 存在：值移到另一个 key，原 key 变为不存在
+            db.swap(b"a", b"c").unwrap();
+            assert_eq!(db.get(b"a").unwrap(), None);
+            assert_eq!(db.get(b"c").unwrap().as_deref(), Some(b"2" as &[u8]));
+
+            // 两个都不存在、同一个 key：不写入
+            let size = db.stats().wal_size;
+            db.swap(b"x", b"y").unwrap();
+            db.swap(b"b", b"b").unwrap();
+            assert_eq!(db.stats().wal_size, size);
+        }
+
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap().as_deref(), Some(b"1" as &[u8]));
+        assert_eq!(db.get(b"c").unwrap().as_deref(), Some(b"2" as &[u8]));
+        assert_eq!(db.stats().key_count, 2);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"old", b"x").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put(b"a", b"1").put(b"b", b"2").delete(b"old").put(b"a", b"3");
+            db.write_batch(batch).unwrap();
+            assert_eq!(db.get(b"a").unwrap().as_deref(), Some(b"3" as &[u8]));
+            assert_eq!(db.get(b"b").unwrap().as_deref(), Some(b"2" as &[u8]));
+            assert_eq!(db.get(b"old").unwrap(), None);
+            assert_eq!(db.latest_sequence(), 5);
+
+            // 任意一个操作超限：整批拒绝，不写入任何内容
+            let size = db.stats().wal_size;
+            let mut batch = WriteBatch::new();
+            batch.put(b"c", b"1").put(b"d", &vec![0; 2 * 1024 * 1024]);
+            assert!(matches!(db.write_batch(batch), Err(Error::ValueTooLarge { .. })));
+            assert_eq!(db.get(b"c").unwrap(), None);
+            assert_eq!(db.stats().wal_size, size);
+
+            // 空批次不写入
+            db.write_batch(WriteBatch::new()).unwrap();
+            assert_eq!(db.stats().wal_size, size);
+        }
+
+        // 整批作为一个 BATCH 写入 WAL，重新打开后 replay 结果相同
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get(b"a").unwrap().as_deref(), Some(b"3" as &[u8]));
+        assert_eq!(db.get(b"old").unwrap(), None);
+        assert_eq!(db.stats().key_count, 2);
+        let mut reader = WalReader::open(dir.path().join(WAL_FILENAME)).unwrap();
+        reader.next().unwrap().unwrap();
+        let (_, header) = reader.next().unwrap().unwrap();
+        assert_eq!(header.kind, RecordKind::Batch);
+    }
+
+    #[test]
+    fn test_custom_limits() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options {
+            limits: Limits {
+                max_key_size: 8,
+                max_value_size: 2 * 1024 * 1024,
+            },
+            ..Options::default()
+        };
+
+        {
+            let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+
+            // 超出默认 1MB 但在自定义限制之内
+            let large_value = vec![0xCD; 1024 * 1024 + 1];
+            db.put(b"large", &large_value).unwrap();
+
+            assert!(matches!(
+                db.put(b"too long key", b"v"),
+                Err(crate::Error::KeyTooLarge { size: 12, max: 8 })
+            ));
+            assert!(db.delete(b"too long key").is_err());
+        }
+
+        // 使用相同的限制重新打开
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"large").unwrap().unwrap().len(), 1024 * 1024 + 1);
+    }
+
+    #[test]
+    fn test_last_replay_stats() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let db = Db::open(dir.path(), Options::default()).unwrap();
+            assert_eq!(db.last_replay_stats().valid_records, 0);
+        }
+
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"key1", b"value1").unwrap();
+            db.put(b"key2", b"value2").unwrap();
+        }
+
+        // 手动追加半写入的记录
+        {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(dir.path().join("wal.log"))
+                .unwrap();
+            file.write_all(b"KVSL torn").unwrap();
+        }
+
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        let stats = db.last_replay_stats();
+        assert_eq!(stats.valid_records, 2);
+        assert_eq!(stats.corrupted_records, 1);
+        assert_eq!(stats.truncated_bytes, 9);
+    }
+
+    #[test]
+    fn test_created_new_and_recovered_empty() {
+        let dir = TempDir::new().unwrap();
+        let wal_path = dir.path().join(WAL_FILENAME);
+
+        // 第一次打开
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        assert!(db.last_replay_stats().created_new);
+        assert!(!db.last_replay_stats().recovered_empty);
+        drop(db);
+
+        // 空的 WAL 不算数据丢失
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert!(!db.last_replay_stats().created_new);
+        assert!(!db.last_replay_stats().recovered_empty);
+        db.put(b"key", b"value").unwrap();
+        drop(db);
+
+        // 第一条记录就损坏，整个文件被截断
+        let mut bytes = std::fs::read(&wal_path).unwrap();
+        bytes[0] ^= 0xFF;
+        std::fs::write(&wal_path, &bytes).unwrap();
+
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        let stats = db.last_replay_stats();
+        assert!(!stats.created_new);
+        assert!(stats.recovered_empty);
+        assert_eq!(stats.truncated_bytes, bytes.len() as u64);
+        assert_eq!(db.stats().key_count, 0);
+    }
+
+    #[test]
+    fn test_stats_does_not_touch_disk() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"key1", b"value1").unwrap();
+        db.put(b"key2", b"value2").unwrap();
+
+        let before = db.stats();
+
+        // 删除整个数据库目录：如果 stats() 访问文件系统就会得到不同的结果或失败
+        std::fs::remove_dir_all(dir.path()).unwrap();
+
+        let after = db.stats();
+        assert_eq!(after.key_count, before.key_count);
+        assert_eq!(after.wal_size, before.wal_size);
+    }
+
+    #[test]
+    fn test_stats_under_rwlock_read_guard() {
+        use std::sync::{Arc, RwLock};
+
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(RwLock::new(Db::open(dir.path(), Options::default()).unwrap()));
+        db.write().unwrap().put(b"key", b"value").unwrap();
+
+        let monitor = {
+            let db = Arc::clone(&db);
+            std::thread::spawn(move || db.read().unwrap().stats().key_count)
+        };
+        assert_eq!(monitor.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_checkpoint_skips_replay() {
+        let dir = TempDir::new().unwrap();
+
+        let high_water = {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            for i in 0..10 {
+                db.put(format!("key{}", i).as_bytes(), b"old").unwrap();
+            }
+            db.delete(b"key0").unwrap();
+            db.checkpoint().unwrap();
+            let high_water = db.stats().wal_size;
+
+            // checkpoint 之后的写入
+            db.put(b"key1", b"new").unwrap();
+            db.delete(b"key2").unwrap();
+            high_water
+        };
+
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        let stats = db.last_replay_stats();
+        assert_eq!(stats.replay_from, high_water);
+        assert_eq!(stats.valid_records, 2);
+
+        assert_eq!(db.stats().key_count, 8);
+        assert_eq!(db.get(b"key0").unwrap(), None);
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"new" as &[u8]));
+        assert_eq!(db.get(b"key2").unwrap(), None);
+        assert_eq!(db.get(b"key9").unwrap().as_deref(), Some(b"old" as &[u8]));
+    }
+
+    #[test]
+    fn test_torn_manifest_falls_back_to_full_replay() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"key1", b"value1").unwrap();
+            db.put(b"key2", b"value2").unwrap();
+            db.checkpoint().unwrap();
+        }
+
+        // 模拟半写入的 MANIFEST
+        let manifest_path = dir.path().join("MANIFEST");
+        let len = std::fs::metadata(&manifest_path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&manifest_path).unwrap();
+        file.set_len(len - 5).unwrap();
+        drop(file);
+
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.last_replay_stats().replay_from, 0);
+        assert_eq!(db.last_replay_stats().valid_records, 2);
+        assert_eq!(db.get(b"key2").unwrap().as_deref(), Some(b"value2" as &[u8]));
+    }
+
+    #[test]
+    fn test_stale_manifest_falls_back_to_full_replay() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"key1", b"value1").unwrap();
+            db.checkpoint().unwrap();
+        }
+
+        // WAL 被替换为不同的内容（例如从备份恢复），长度不小于高水位
+        std::fs::remove_file(dir.path().join("wal.log")).unwrap();
+        {
+            let (mut wal, _, _) = Wal::open(dir.path(), &WalOptions::default()).unwrap();
+            let r = Record::put(b"other".to_vec(), b"value_other".to_vec()).unwrap();
+            wal.append(&r, true).unwrap();
+        }
+
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.last_replay_stats().replay_from, 0);
+        assert_eq!(db.get(b"key1").unwrap(), None);
+        assert_eq!(
+            db.get(b"other").unwrap().as_deref(),
+            Some(b"value_other" as &[u8])
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_seq_and_replication_cursor() {
+        let dir = TempDir::new().unwrap();
+
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+            assert_eq!(db.checkpoint_seq(), 0);
+            db.checkpoint().unwrap();
+            assert_eq!(db.checkpoint_seq(), 2);
+            db.put(b"c", b"3").unwrap();
+            assert_eq!(db.checkpoint_seq(), 2);
+
+            assert_eq!(db.load_replication_cursor("replica").unwrap(), None);
+            db.save_replication_cursor("replica", 2).unwrap();
+            db.save_replication_cursor("backup", 3).unwrap();
+        }
+
+        // 重新打开后 checkpoint 和游标都还在
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.checkpoint_seq(), 2);
+        assert_eq!(db.load_replication_cursor("replica").unwrap(), Some(2));
+        assert_eq!(db.load_replication_cursor("backup").unwrap(), Some(3));
+
+        // 压缩让 MANIFEST 失效，游标不受影响
+        db.compact().unwrap();
+        assert_eq!(db.checkpoint_seq(), 0);
+        assert_eq!(db.load_replication_cursor("replica").unwrap(), Some(2));
+        drop(db);
+
+        let read_only = Options::builder().read_only(true).build();
+        let db = Db::open(dir.path(), read_only).unwrap();
+        assert!(matches!(
+            db.save_replication_cursor("replica", 5),
+            Err(Error::ReadOnly)
+        ));
+        assert_eq!(db.load_replication_cursor("replica").unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_checkpoint_interval() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options {
+            checkpoint_interval_bytes: Some(100),
+            ..Options::default()
+        };
+
+        {
+            let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+            for i in 0..10 {
+                db.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+            }
+        }
+
+        assert!(dir.path().join("MANIFEST").exists());
+
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert!(db.last_replay_stats().replay_from > 0);
+        assert_eq!(db.stats().key_count, 10);
+        assert_eq!(db.get(b"key9").unwrap().as_deref(), Some(b"value" as &[u8]));
+    }
+
+    #[test]
+    fn test_read_only_follower_tail() {
+        let dir = TempDir::new().unwrap();
+        let mut primary = Db::open(dir.path(), Options::default()).unwrap();
+        primary.put(b"key1", b"value1").unwrap();
+
+        let opts = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let mut follower = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(follower.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
+        assert_eq!(follower.tail().unwrap(), 0);
+
+        // 主库继续写入
+        primary.put(b"key2", b"value2").unwrap();
+        primary.multi_delete(&[b"key1"]).unwrap();
+
+        assert_eq!(follower.tail().unwrap(), 2);
+        assert_eq!(follower.get(b"key1").unwrap(), None);
+        assert_eq!(follower.get(b"key2").unwrap().as_deref(), Some(b"value2" as &[u8]));
+        assert_eq!(follower.stats().wal_size, primary.stats().wal_size);
+
+        // follower 不允许写入
+        assert!(matches!(follower.put(b"k", b"v"), Err(crate::Error::ReadOnly)));
+        assert!(matches!(follower.delete(b"k"), Err(crate::Error::ReadOnly)));
+        assert!(matches!(follower.checkpoint(), Err(crate::Error::ReadOnly)));
+    }
+
+    #[test]
+    fn test_index_memory_and_shrink() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options {
+            sync_on_write: false,
+            ..Options::default()
+        };
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.stats().index_bytes, db.index_memory_bytes());
+
+        let keys: Vec<Vec<u8>> = (0..2000).map(|i| format!("key{:05}", i).into_bytes()).collect();
+        for key in &keys {
+            db.put(key, b"v").unwrap();
+        }
+        let full = db.index_memory_bytes();
+        assert!(full >= 2000 * 8);
+
+        let to_delete: Vec<&[u8]> = keys[..1990].iter().map(|k| k.as_slice()).collect();
+        db.multi_delete(&to_delete).unwrap();
+
+        // 删除后容量仍在，shrink_to_fit 之后内存明显下降
+        let before_shrink = db.index_memory_bytes();
+        db.shrink_to_fit();
+        let after_shrink = db.index_memory_bytes();
+        assert!(after_shrink < before_shrink);
+        assert!(after_shrink < full);
+        assert_eq!(db.stats().index_bytes, after_shrink);
+
+        // 数据不受影响
+        assert_eq!(db.stats().key_count, 10);
+        assert_eq!(db.get(b"key01999").unwrap().as_deref(), Some(b"v" as &[u8]));
+    }
+
+    #[test]
+    fn test_initial_index_capacity() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder()
+            .sync_on_write(false)
+            .checkpoint_interval_bytes(None)
+            .build();
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        for round in 0..4 {
+            for i in 0..3000 {
+                db.put(format!("key{:05}", i).as_bytes(), &[round]).unwrap();
+            }
+        }
+        drop(db);
+
+        let reserved = |n: usize| {
+            let mut index = Index::default();
+            index.reserve(n);
+            index.capacity()
+        };
+
+        // 提示足够大：replay 过程中不扩容，容量就是一开始预留的
+        let mut hinted = opts.clone();
+        hinted.initial_index_capacity = 5000;
+        let db = Db::open(dir.path(), hinted).unwrap();
+        assert_eq!(db.index.capacity(), reserved(5000));
+        assert_eq!(db.stats().key_count, 3000);
+        drop(db);
+
+        // 按记录数估算偏大（每个 key 覆盖了 4 次）：replay 后收缩
+        let db = Db::open(dir.path(), opts).unwrap();
+        assert!(db.index.capacity() >= 3000);
+        assert!(db.index.capacity() < reserved(12000));
+        assert_eq!(db.get_cost(b"key02999").map(|c| c.bytes), Some(1));
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let dir = TempDir::new().unwrap();
+
+        let entries = (0..1000)
+            .map(|i| (format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes()))
+            .chain(std::iter::once((b"key0".to_vec(), b"latest".to_vec())));
+
+        {
+            let mut db = Db::bulk_load(dir.path(), Options::default(), entries).unwrap();
+            assert_eq!(db.stats().key_count, 1000);
+            assert_eq!(db.get(b"key0").unwrap().as_deref(), Some(b"latest" as &[u8]));
+            assert_eq!(db.get(b"key999").unwrap().as_deref(), Some(b"value999" as &[u8]));
+
+            // 导入后可以正常写入
+            db.put(b"extra", b"value").unwrap();
+        }
+
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.stats().key_count, 1001);
+        assert_eq!(db.get(b"key0").unwrap().as_deref(), Some(b"latest" as &[u8]));
+        assert_eq!(db.get(b"key500").unwrap().as_deref(), Some(b"value500" as &[u8]));
+        assert_eq!(db.get(b"extra").unwrap().as_deref(), Some(b"value" as &[u8]));
+    }
+
+    #[test]
+    fn test_bulk_load_rejects_existing_data() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"key", b"value").unwrap();
+        }
+
+        let entries = vec![(b"other".to_vec(), b"value".to_vec())];
+        assert!(Db::bulk_load(dir.path(), Options::default(), entries).is_err());
+
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.stats().key_count, 1);
+        assert_eq!(db.get(b"other").unwrap(), None);
+    }
+
+    #[test]
+    fn test_write_buffer_read_your_writes() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options {
+            sync_on_write: false,
+            write_buffer_bytes: 64 * 1024,
+            ..Options::default()
+        };
+
+        {
+            let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"key1", b"value1").unwrap();
+            db.put(b"key2", b"value2").unwrap();
+            db.put(b"key1", b"value3").unwrap();
+
+            // 数据还在写缓冲区中，但可以立即读到
+            let on_disk = std::fs::metadata(dir.path().join("wal.log")).unwrap().len();
+            assert_eq!(on_disk, 0);
+            assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value3" as &[u8]));
+            assert_eq!(db.get(b"key2").unwrap().as_deref(), Some(b"value2" as &[u8]));
+
+            db.sync().unwrap();
+            let on_disk = std::fs::metadata(dir.path().join("wal.log")).unwrap().len();
+            assert_eq!(on_disk, db.stats().wal_size);
+
+            db.delete(b"key2").unwrap();
+        }
+
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value3" as &[u8]));
+        assert_eq!(db.get(b"key2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_stats_json() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"key", b"value").unwrap();
+
+        let stats = db.stats();
+        let expected = format!(
+            concat!(
+                "{{\"key_count\":1,\"wal_size\":{},\"index_bytes\":{},",
+                "\"tombstone_count\":0,\"live_disk_bytes\":{},\"dead_bytes\":0,",
+                "\"cache_hits\":0,\"cache_misses\":0}}"
+            ),
+            stats.wal_size, stats.index_bytes, stats.live_disk_bytes
+        );
+        assert_eq!(db.stats_json().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_create_new_and_open_existing() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("db");
+
+        // 不存在时 open_existing 报错，也不会创建目录
+        let err = Db::open_existing(&path, Options::default());
+        assert!(matches!(err, Err(Error::NotFound)));
+        assert!(!path.exists());
+
+        {
+            let mut db = Db::create_new(&path, Options::default()).unwrap();
+            assert!(db.last_replay_stats().created_new);
+            db.put(b"key", b"value").unwrap();
+        }
+
+        // 已存在时 create_new 报错，数据不受影响
+        let err = Db::create_new(&path, Options::default());
+        assert!(matches!(err, Err(Error::AlreadyExists)));
+
+        let mut db = Db::open_existing(&path, Options::default()).unwrap();
+        assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_value_cache() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().cache_capacity_bytes(1024).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"2").unwrap();
+        db.put(b"c", b"3").unwrap();
+        db.rename_key(b"c", b"d").unwrap();
+        db.delete(b"b").unwrap();
+        db.sync().unwrap();
+
+        // 把 WAL 清零：之后的读取只能来自缓存
+        let path = dir.path().join(WAL_FILENAME);
+        let len = std::fs::metadata(&path).unwrap().len() as usize;
+        std::fs::write(&path, vec![0u8; len]).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), None);
+        assert_eq!(db.get(b"d").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn test_warm_cache() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+            db.put(b"c", b"3").unwrap();
+
+            // 未启用缓存时什么也不做
+            assert_eq!(db.warm_cache(&[b"a"]).unwrap(), 0);
+        }
+
+        let opts = Options::builder().cache_capacity_bytes(1024).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        let keys: [&[u8]; 4] = [b"b", b"a", b"missing", b"a"];
+        assert_eq!(db.warm_cache(&keys).unwrap(), 2);
+        assert_eq!(db.warm_cache(&keys).unwrap(), 0);
+
+        let path = dir.path().join(WAL_FILENAME);
+        let len = std::fs::metadata(&path).unwrap().len() as usize;
+        std::fs::write(&path, vec![0u8; len]).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"c").unwrap(), Some(vec![0u8]));
+    }
+
+    #[test]
+    fn test_clear_cache_and_stats() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().cache_capacity_bytes(1024).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"22").unwrap();
+
+        // 写入时填充缓存，第一次读取命中，不存在的 key 不计入
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"missing").unwrap(), None);
+        let stats = db.cache_stats();
+        assert_eq!((stats.entries, stats.bytes), (2, 5));
+        assert_eq!((stats.hits, stats.misses), (1, 0));
+
+        // 清空后从 WAL 读取：未命中一次，之后重新命中
+        db.clear_cache();
+        assert_eq!(db.cache_stats().entries, 0);
+        assert_eq!(db.get(b"b").unwrap(), Some(b"22".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"22".to_vec()));
+        let stats = db.cache_stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 2, 1));
+        assert_eq!(db.options().cache_capacity_bytes, 1024);
+        let stats = db.stats();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (2, 1));
+
+        db.reset_cache_stats();
+        let stats = db.cache_stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 0, 0));
+    }
+
+    #[test]
+    fn test_raw_record_at() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        let second = db.stats().wal_size;
+        db.put(b"b", b"22").unwrap();
+
+        // 与 WalReader 读到的记录逐字节相同
+        let raw = db.raw_record_at(second, true).unwrap();
+        let mut reader = WalReader::open(dir.path().join(WAL_FILENAME)).unwrap();
+        reader.next().unwrap().unwrap();
+        let (offset, record) = reader.next().unwrap().unwrap();
+        assert_eq!(offset, second);
+        assert_eq!(raw, record.encode().unwrap());
+        assert_eq!(second + raw.len() as u64, db.stats().wal_size);
+
+        // 不是记录开头 / 超出末尾
+        assert!(matches!(
+            db.raw_record_at(second + 1, false),
+            Err(Error::InvalidMagic { .. })
+        ));
+        let end = db.stats().wal_size;
+        assert!(matches!(db.raw_record_at(end, false), Err(Error::UnexpectedEof)));
+
+        // 损坏的 value：不校验时原样返回，校验时报 CRC 错误
+        db.sync().unwrap();
+        let path = dir.path().join(WAL_FILENAME);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[end as usize - 5] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(db.raw_record_at(second, false).unwrap(), bytes[second as usize..]);
+        assert!(matches!(
+            db.raw_record_at(second, true),
+            Err(Error::CrcMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_put_str_get_str() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        db.put_str("名字", "值").unwrap();
+        assert_eq!(db.get_str("名字").unwrap().as_deref(), Some("值"));
+        assert_eq!(db.get(b"missing").unwrap(), None);
+        assert_eq!(db.get_str("missing").unwrap(), None);
+
+        db.put(b"binary", &[0xFF, 0xFE]).unwrap();
+        assert!(matches!(db.get_str("binary"), Err(Error::Utf8(_))));
+    }
+
+    #[test]
+    fn test_index_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"22").unwrap();
+        db.put(b"b", b"333").unwrap();
+        db.put(b"c", b"").unwrap();
+        db.delete(b"a").unwrap();
+
+        let mut snapshot = db.index_snapshot();
+        snapshot.sort();
+        assert_eq!(snapshot, vec![(b"b".to_vec(), 3), (b"c".to_vec(), 0)]);
+    }
+
+    #[test]
+    fn test_keys_paginated() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        for i in 0..25u32 {
+            db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+        }
+        db.delete(b"key10").unwrap();
+
+        // 逐页读取，游标是上一页的最后一个 key
+        let mut all = Vec::new();
+        let mut cursor: Option<Vec<u8>> = None;
+        loop {
+            let page = db.keys_paginated(cursor.as_deref(), 10);
+            all.extend(page.iter().cloned());
+            if page.len() < 10 {
+                break;
+            }
+            cursor = page.last().cloned();
+        }
+
+        let expected: Vec<Vec<u8>> = (0..25u32)
+            .filter(|&i| i != 10)
+            .map(|i| format!("key{:02}", i).into_bytes())
+            .collect();
+        assert_eq!(all, expected);
+
+        // 两页之间的写入：游标之后新增的 key 会出现在下一页
+        let first = db.keys_paginated(None, 3);
+        db.put(b"key025", b"value").unwrap();
+        let second = db.keys_paginated(first.last().map(|k| k.as_slice()), 3);
+        assert_eq!(second[0], b"key025".to_vec());
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().cache_capacity_bytes(1024).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert!(!db.contains_key(b"a"));
+
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"2").unwrap();
+        db.delete(b"b").unwrap();
+        assert!(db.contains_key(b"a"));
+        assert!(!db.contains_key(b"b"));
+
+        // 不经过缓存，不计入命中统计
+        assert_eq!(db.cache_stats().hits, 0);
+
+        // 键空间之间互不可见：只在其他键空间（或没有前缀）中存在的 key 不算存在
+        drop(db);
+        let tenant = |name: &[u8]| Options::builder().key_prefix(name.to_vec()).build();
+        let mut db = Db::open(dir.path(), tenant(b"t2/")).unwrap();
+        db.put(b"shared", b"t2").unwrap();
+        drop(db);
+        let mut db = Db::open(dir.path(), tenant(b"t1/")).unwrap();
+        assert!(!db.contains_key(b"a"));
+        assert!(!db.contains_key(b"shared"));
+        db.put(b"a", b"tenant").unwrap();
+        assert!(db.contains_key(b"a"));
+        assert!(!db.contains_key(b"t1/a"));
+        db.delete(b"a").unwrap();
+        assert!(!db.contains_key(b"a"));
+    }
+
+    #[test]
+    fn test_keys() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.keys().count(), 0);
+
+        for key in [&b"b"[..], b"a", b"c"] {
+            db.put(key, b"value").unwrap();
+        }
+        db.delete(b"c").unwrap();
+        let mut keys: Vec<&[u8]> = db.keys().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![&b"a"[..], b"b"]);
+
+        // 键空间：只列出自己的 key，并去掉前缀
+        drop(db);
+        let opts = Options::builder().key_prefix(b"t1/".to_vec()).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        db.put(b"x", b"tenant").unwrap();
+        assert_eq!(db.keys().collect::<Vec<_>>(), vec![&b"x"[..]]);
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        for key in ["user:2", "user:10", "user:1", "user:1:a", "item:1", "user;"] {
+            db.put(key.as_bytes(), format!("v-{}", key).as_bytes()).unwrap();
+        }
+        db.delete(b"user:2").unwrap();
+
+        let scan = |db: &mut Db, start: &[u8], end: &[u8]| -> Vec<Vec<u8>> {
+            db.scan(start, end).map(|entry| entry.unwrap().0).collect()
+        };
+
+        // 字节序、半开区间，已删除的 key 不出现
+        let entries: Vec<KvPair> = db.scan(b"user:", b"user;").map(Result::unwrap).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"user:1".to_vec(), b"v-user:1".to_vec()),
+                (b"user:10".to_vec(), b"v-user:10".to_vec()),
+                (b"user:1:a".to_vec(), b"v-user:1:a".to_vec()),
+            ]
+        );
+        assert_eq!(
+            scan(&mut db, b"user:1", b"user:1:"),
+            vec![b"user:1".to_vec(), b"user:10".to_vec()]
+        );
+        assert_eq!(db.scan(b"", b"\xff").len(), 5);
+
+        // 空区间、反向区间
+        assert!(scan(&mut db, b"user:1", b"user:1").is_empty());
+        assert!(scan(&mut db, b"user;", b"user:").is_empty());
+        assert!(scan(&mut db, b"a", b"b").is_empty());
+
+        // 键空间内的区间，产生的 key 不含前缀
+        let opts = Options::builder().key_prefix(b"t1/".to_vec()).build();
+        drop(db);
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        db.put(b"user:3", b"tenant").unwrap();
+        assert_eq!(scan(&mut db, b"user:", b"user;"), vec![b"user:3".to_vec()]);
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        let keys = [&b"item:2"[..], b"item:1", b"item;", b"items", b"a\xff\x01", b"b", b"\xff\xff"];
+        for key in keys {
+            db.put(key, key).unwrap();
+        }
+
+        let scan = |db: &mut Db, prefix: &[u8]| -> Vec<Vec<u8>> {
+            db.scan_prefix(prefix).map(|entry| entry.unwrap().0).collect()
+        };
+
+        let entries: Vec<KvPair> = db.scan_prefix(b"item:").map(Result::unwrap).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"item:1".to_vec(), b"item:1".to_vec()),
+                (b"item:2".to_vec(), b"item:2".to_vec()),
+            ]
+        );
+        assert_eq!(scan(&mut db, b"item:3"), Vec::<Vec<u8>>::new());
+
+        // 末尾的 0xFF 向前进位；全是 0xFF 时没有上界
+        assert_eq!(scan(&mut db, b"a\xff"), vec![b"a\xff\x01".to_vec()]);
+        assert_eq!(scan(&mut db, b"\xff"), vec![b"\xff\xff".to_vec()]);
+        assert_eq!(scan(&mut db, b"\xff\xff"), vec![b"\xff\xff".to_vec()]);
+
+        // 空前缀遍历整个数据库
+        assert_eq!(db.scan_prefix(b"").len(), 7);
+
+        // 键空间内：上界也在键空间内，不会越过到其他键空间
+        let opts = Options::builder().key_prefix(b"t1/".to_vec()).build();
+        drop(db);
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        db.put(b"item:3", b"tenant").unwrap();
+        assert_eq!(scan(&mut db, b""), vec![b"item:3".to_vec()]);
+        assert_eq!(scan(&mut db, b"item:"), vec![b"item:3".to_vec()]);
+
+        assert_eq!(prefix_successor(b"item:"), Some(b"item;".to_vec()));
+        assert_eq!(prefix_successor(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"\xff"), None);
+        assert_eq!(prefix_successor(b""), None);
+    }
+
+    #[test]
+    fn test_scan_prefix_each() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        // 倒序写入：key 的顺序与 WAL 中的偏移量顺序相反
+        for i in (0..200u32).rev() {
+            db.put(format!("user:{:03}", i).as_bytes(), &[i as u8; 100]).unwrap();
+        }
+        db.put(b"item:1", b"value").unwrap();
+
+        let mut seen = Vec::new();
+        db.scan_prefix_each(b"user:", |key, value| {
+            assert_eq!(value, [seen.len() as u8; 100]);
+            seen.push(key.to_vec());
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        let expected: Vec<Vec<u8>> =
+            (0..200u32).map(|i| format!("user:{:03}", i).into_bytes()).collect();
+        assert_eq!(seen, expected);
+
+        // 没有匹配时不调用回调
+        db.scan_prefix_each(b"none:", |_, _| panic!("unexpected entry")).unwrap();
+
+        // 提前停止：顺序写入后截掉 WAL 的后半部分，只有停止之后的段会读到被截断的位置
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        for i in 0..200u32 {
+            db.put(format!("user:{:03}", i).as_bytes(), &[0u8; 100]).unwrap();
+        }
+        let wal_len = std::fs::metadata(db.wal.path()).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(db.wal.path()).unwrap();
+        file.set_len(wal_len / 2).unwrap();
+
+        let mut count = 0;
+        db.scan_prefix_each(b"user:", |_, _| {
+            count += 1;
+            if count == 10 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+        assert_eq!(count, 10);
+        assert!(db.scan_prefix_each(b"user:", |_, _| ControlFlow::Continue(())).is_err());
+    }
+
+    #[test]
+    fn test_scan_prefix_limited() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        for i in 0..5u32 {
+            db.put(format!("user:{}", i).as_bytes(), b"0123456789").unwrap();
+        }
+        db.put(b"item:1", b"value").unwrap();
+
+        // 不超限：返回全部，按 key 排序
+        let (entries, truncated) = db.scan_prefix_limited(b"user:", 100, 1 << 20).unwrap();
+        assert!(!truncated);
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0], (b"user:0".to_vec(), b"0123456789".to_vec()));
+        assert_eq!(entries[4].0, b"user:4".to_vec());
+
+        // 条数上限
+        let (entries, truncated) = db.scan_prefix_limited(b"user:", 2, 1 << 20).unwrap();
+        assert!(truncated);
+        assert_eq!(entries.len(), 2);
+
+        // 恰好等于条数上限不算截断
+        let (entries, truncated) = db.scan_prefix_limited(b"user:", 5, 1 << 20).unwrap();
+        assert!(!truncated);
+        assert_eq!(entries.len(), 5);
+
+        // 字节上限：每个键值对 6 + 10 = 16 字节
+        let (entries, truncated) = db.scan_prefix_limited(b"user:", 100, 40).unwrap();
+        assert!(truncated);
+        assert_eq!(entries.len(), 2);
+
+        // 单个键值对就超限
+        let (entries, truncated) = db.scan_prefix_limited(b"user:", 100, 10).unwrap();
+        assert!(truncated);
+        assert!(entries.is_empty());
+
+        // 没有匹配
+        let (entries, truncated) = db.scan_prefix_limited(b"none:", 100, 1 << 20).unwrap();
+        assert!(!truncated);
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options {
+            direct_io: true,
+            ..Options::default()
+        };
+
+        // 文件系统不支持 O_DIRECT 时回退到普通写入，行为应当完全一致
+        {
+            let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+            for i in 0..100u32 {
+                db.put(&i.to_be_bytes(), &vec![i as u8; i as usize * 7]).unwrap();
+            }
+            db.delete(&5u32.to_be_bytes()).unwrap();
+            assert_eq!(db.get(&9u32.to_be_bytes()).unwrap(), Some(vec![9u8; 63]));
+        }
+
+        // 关闭后文件中没有填充
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        let stats = db.last_replay_stats().clone();
+        assert_eq!(stats.truncated_bytes, 0);
+        assert_eq!(stats.valid_records, 101);
+        assert_eq!(db.stats().key_count, 99);
+        assert_eq!(db.get(&99u32.to_be_bytes()).unwrap(), Some(vec![99u8; 693]));
+    }
+
+    #[test]
+    fn test_read_sorted() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        // 写入顺序与 key 顺序相反，value 的偏移量与 key 顺序相反
+        for i in (0..20u32).rev() {
+            db.put(&i.to_be_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.delete(&7u32.to_be_bytes()).unwrap();
+
+        let keys: Vec<Vec<u8>> = [0u32, 3, 7, 19, 3, 42]
+            .iter()
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        let values = db.read_sorted(&keys).unwrap();
+        assert_eq!(
+            values,
+            vec![
+                Some(b"value0".to_vec()),
+                Some(b"value3".to_vec()),
+                None,
+                Some(b"value19".to_vec()),
+                Some(b"value3".to_vec()),
+                None,
+            ]
+        );
+        assert!(db.read_sorted(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_for_each() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"22").unwrap();
+        db.put(b"c", b"333").unwrap();
+        db.delete(b"b").unwrap();
+
+        let mut seen = Vec::new();
+        db.for_each(|key, value| {
+            seen.push((key.to_vec(), value.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        seen.sort();
+        assert_eq!(
+            seen,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"c".to_vec(), b"333".to_vec())
+            ]
+        );
+
+        // 回调返回错误时立即停止
+        let mut visited = 0;
+        let result = db.for_each(|_, _| {
+            visited += 1;
+            Err(Error::ReadOnly)
+        });
+        assert!(matches!(result, Err(Error::ReadOnly)));
+        assert_eq!(visited, 1);
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        for i in 0..10u32 {
+            db.put(b"hot", &i.to_be_bytes()).unwrap();
+        }
+        db.put(b"cold", b"value").unwrap();
+        db.put(b"gone", b"value").unwrap();
+        db.delete(b"gone").unwrap();
+
+        let stats = db.compact().unwrap();
+        assert_eq!(stats.keys_kept, 2);
+        assert_eq!(stats.records_dropped, 13 - 2);
+        assert!(stats.bytes_after < stats.bytes_before);
+        assert_eq!(stats.bytes_after, db.stats().wal_size);
+        assert_eq!(db.get(b"hot").unwrap(), Some(9u32.to_be_bytes().to_vec()));
+        assert_eq!(db.get(b"gone").unwrap(), None);
+
+        // 压缩后继续写入，重新打开数据一致
+        db.put(b"new", b"value").unwrap();
+        drop(db);
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.stats().key_count, 3);
+        assert_eq!(db.get(b"cold").unwrap().as_deref(), Some(b"value" as &[u8]));
+        assert_eq!(db.sequence(b"hot"), Some(10));
+        assert_eq!(db.latest_sequence(), 14);
+    }
+
+    #[test]
+    fn test_compact_index_matches_replay() {
+        // 索引的完整快照：存活的 key（含 REF 记录位置）和墓碑，按 key 排序
+        type Snapshot = (Vec<(Vec<u8>, ValuePos, Option<u64>)>, Vec<(Vec<u8>, Tombstone)>);
+        fn snapshot(db: &Db) -> Snapshot {
+            let live = db
+                .index
+                .prefix_sorted(b"")
+                .into_iter()
+                .map(|(key, pos)| (key.to_vec(), pos, db.index.ref_offset(key)))
+                .collect();
+            let mut tombstones: Vec<_> =
+                db.index.tombstones().map(|(key, t)| (key.clone(), *t)).collect();
+            tombstones.sort_by(|a, b| a.0.cmp(&b.0));
+            (live, tombstones)
+        }
+
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder()
+            .dedup_values(true)
+            .tombstone_ttl(Some(Duration::from_secs(3600)))
+            .build();
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        let value = |i: usize| vec![(i % 5) as u8; (i % 4) * 100];
+        for round in 0..3 {
+            for i in 0..200 {
+                db.put(format!("key{:03}", i).as_bytes(), &value(i + round)).unwrap();
+            }
+        }
+        for i in (0..200).step_by(9) {
+            db.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        db.compact().unwrap();
+
+        // 写入时构建的索引与完整 replay 压缩后的文件得到的索引完全相同
+        let built = snapshot(&db);
+        assert!(built.0.iter().any(|(_, _, ref_offset)| ref_offset.is_some()));
+        assert_eq!(built.1.len(), 23);
+        drop(db);
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(snapshot(&db), built);
+
+        // 每个 key 都指向正确的字节
+        for i in 0..200 {
+            let key = format!("key{:03}", i).into_bytes();
+            let expected = (i % 9 != 0).then(|| value(i + 2));
+            assert_eq!(db.get(&key).unwrap(), expected);
+            assert_eq!(db.verify_key(&key).unwrap(), expected.map(|_| true));
+        }
+    }
+
+    #[test]
+    fn test_compact_crash_recovery() {
+        use crate::wal::COMPACTING_FILENAME;
+
+        // 每个场景：写入数据，模拟压缩在某一步崩溃，重新打开后 WAL 必须恰好是压缩前或
+        // 压缩后的文件，数据完整，临时文件和标记都被清理
+        let fill = |db: &mut Db| {
+            for i in 0..100u32 {
+                db.put(format!("key{}", i % 20).as_bytes(), &i.to_be_bytes()).unwrap();
+            }
+            db.delete(b"key0").unwrap();
+        };
+        let check = |dir: &Path, wal: &[u8], extra: bool| {
+            let mut db = Db::open(dir, Options::default()).unwrap();
+            assert!(db.last_replay_stats().interrupted_compaction);
+            assert_eq!(std::fs::read(dir.join(WAL_FILENAME)).unwrap(), wal);
+            for name in [COMPACTING_FILENAME, COMPACT_TMP_FILENAME, "wal.log.rewrite"] {
+                assert!(!dir.join(name).exists(), "{} left behind", name);
+            }
+            assert_eq!(db.get(b"key0").unwrap(), None);
+            for i in 1..20u32 {
+                let key = format!("key{}", i);
+                assert_eq!(db.get(key.as_bytes()).unwrap(), Some((80 + i).to_be_bytes().to_vec()));
+            }
+            assert_eq!(db.get(b"late").unwrap().is_some(), extra);
+            drop(db);
+            let db = Db::open(dir, Options::default()).unwrap();
+            assert!(!db.last_replay_stats().interrupted_compaction);
+        };
+
+        // 1. 压缩中写入标记和临时文件 `wal.log.compact`，结束后都删除
+        let dir = TempDir::new().unwrap();
+        let marker = dir.path().join(COMPACTING_FILENAME);
+        let seen = Arc::new(AtomicBool::new(false));
+        let observed = Arc::clone(&seen);
+        let (marker_path, tmp_path) = (marker.clone(), dir.path().join(COMPACT_TMP_FILENAME));
+        let opts = Options::builder()
+            .on_compact_progress(move |_, _| {
+                observed.store(marker_path.exists() && tmp_path.exists(), Ordering::SeqCst)
+            })
+            .build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        fill(&mut db);
+        db.compact().unwrap();
+        assert!(seen.load(Ordering::SeqCst));
+        assert!(!marker.exists());
+        assert!(!dir.path().join(COMPACT_TMP_FILENAME).exists());
+        drop(db);
+
+        // 2. 只写了标记，还没有创建临时文件
+        std::fs::write(&marker, b"").unwrap();
+        let compacted = std::fs::read(dir.path().join(WAL_FILENAME)).unwrap();
+        check(dir.path(), &compacted, false);
+
+        // 3. 临时文件写了一半 / 4. 临时文件已写完、还没有 rename：回滚到压缩前，
+        //    快照之后追加到旧 WAL 的写入仍然存在
+        for truncate in [true, false] {
+            let dir = TempDir::new().unwrap();
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            fill(&mut db);
+            let job = db.begin_compaction().unwrap();
+            db.put(b"late", b"value").unwrap();
+            let job = job.run().unwrap();
+            // 进程在这里崩溃：任务的析构函数不会运行
+            std::mem::forget(job);
+            drop(db);
+
+            let tmp = dir.path().join(COMPACT_TMP_FILENAME);
+            assert!(tmp.exists() && dir.path().join(COMPACTING_FILENAME).exists());
+            if truncate {
+                let len = std::fs::metadata(&tmp).unwrap().len();
+                let file = std::fs::OpenOptions::new().write(true).open(&tmp).unwrap();
+                file.set_len(len / 2).unwrap();
+            }
+            let old = std::fs::read(dir.path().join(WAL_FILENAME)).unwrap();
+            check(dir.path(), &old, true);
+        }
+
+        // 5. rename 之后、删除标记之前：保持压缩后的状态
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        fill(&mut db);
+        db.compact().unwrap();
+        db.put(b"late", b"value").unwrap();
+        drop(db);
+        std::fs::write(dir.path().join(COMPACTING_FILENAME), COMPACT_TMP_FILENAME).unwrap();
+        let compacted = std::fs::read(dir.path().join(WAL_FILENAME)).unwrap();
+        check(dir.path(), &compacted, true);
+    }
+
+    #[test]
+    fn test_compact_progress() {
+        use std::sync::Mutex;
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let opts = Options::builder()
+            .sync_on_write(false)
+            .on_compact_progress(move |processed, total| {
+                recorded.lock().unwrap().push((processed, total));
+            })
+            .build();
+
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        // 约 3MB 的存活数据，至少触发几次中间报告
+        let value = vec![7u8; 64 * 1024];
+        for i in 0..48u32 {
+            db.put(&i.to_be_bytes(), &value).unwrap();
+            db.put(&i.to_be_bytes(), &value).unwrap();
+        }
+
+        let stats = db.compact().unwrap();
+        let calls = calls.lock().unwrap().clone();
+        assert!(calls.len() >= 3);
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(calls.iter().all(|&(_, total)| total == stats.bytes_after));
+        assert_eq!(calls.last().unwrap().0, stats.bytes_after);
+    }
+
+    #[test]
+    fn test_compact_concurrent_serves_reads() {
+        use std::sync::atomic::AtomicUsize;
+
+        // 压缩线程写到一半时等待主线程读够 100 次：如果读取被压缩阻塞，这里会超时失败
+        let reads = Arc::new(AtomicUsize::new(0));
+        let observed = reads.clone();
+        let opts = Options::builder()
+            .sync_on_write(false)
+            .on_compact_progress(move |processed, total| {
+                if processed < total {
+                    let deadline = Instant::now() + Duration::from_secs(10);
+                    while observed.load(Ordering::Acquire) < 100 {
+                        assert!(Instant::now() < deadline, "reads blocked by compaction");
+                        std::thread::yield_now();
+                    }
+                }
+            })
+            .build();
+
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        let value = |i: u32, round: u32| format!("{:0>200}", i * 10 + round).into_bytes();
+        for round in 0..2 {
+            for i in 0..10_000u32 {
+                db.put(&i.to_be_bytes(), &value(i, round)).unwrap();
+            }
+        }
+        db.delete(&0u32.to_be_bytes()).unwrap();
+
+        let db = Arc::new(Mutex::new(db));
+        let compactor = Arc::clone(&db);
+        let handle = std::thread::spawn(move || Db::compact_concurrent(&compactor));
+
+        // 压缩期间持续读取 1..5000，并写入新 key、删除 5000 之后的 key
+        let mut written = 0u32;
+        let mut i = 1u32;
+        while !handle.is_finished() {
+            let mut db = db.lock().unwrap();
+            assert_eq!(db.get(&i.to_be_bytes()).unwrap(), Some(value(i, 1)));
+            if i.is_multiple_of(10) && written < 5_000 {
+                db.put(&(100_000 + written).to_be_bytes(), &value(written, 2)).unwrap();
+                db.delete(&(5_000 + written).to_be_bytes()).unwrap();
+                written += 1;
+            }
+            reads.fetch_add(1, Ordering::Release);
+            i = i % 4_999 + 1;
+        }
+        let stats = handle.join().unwrap().unwrap();
+        assert!(reads.load(Ordering::Acquire) >= 100);
+        assert!(written > 0);
+        assert!(stats.bytes_after < stats.bytes_before);
+
+        // 压缩期间的写入都补写到了新 WAL
+        let check = |db: &mut Db| {
+            assert_eq!(db.get(&0u32.to_be_bytes()).unwrap(), None);
+            assert_eq!(db.stats().key_count, 9_999);
+            for i in 1..10_000u32 {
+                let expected = (i < 5_000 || i >= 5_000 + written).then(|| value(i, 1));
+                assert_eq!(db.get(&i.to_be_bytes()).unwrap(), expected, "key {}", i);
+            }
+            for n in 0..written {
+                let got = db.get(&(100_000 + n).to_be_bytes()).unwrap();
+                assert_eq!(got, Some(value(n, 2)));
+            }
+        };
+        let mut db = Arc::try_unwrap(db).ok().unwrap().into_inner().unwrap();
+        check(&mut db);
+        let latest = db.latest_sequence();
+        drop(db);
+
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        check(&mut db);
+        assert_eq!(db.latest_sequence(), latest);
+        assert!(!dir.path().join(COMPACT_TMP_FILENAME).exists());
+    }
+
+    #[test]
+    fn test_compact_concurrent_excludes_other_compactions() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        for i in 0..10u32 {
+            db.put(b"hot", &i.to_be_bytes()).unwrap();
+        }
+
+        // 任务进行期间不能再开始压缩，WAL 不会在快照之后被重写
+        let job = db.begin_compaction().unwrap();
+        assert!(matches!(db.begin_compaction(), Err(Error::CompactionInProgress)));
+        assert!(matches!(db.compact(), Err(Error::CompactionInProgress)));
+        let job = job.run().unwrap();
+        assert!(matches!(db.compact(), Err(Error::CompactionInProgress)));
+
+        let stats = db.finish_compaction(job).unwrap();
+        assert!(stats.bytes_after < stats.bytes_before);
+        assert_eq!(db.get(b"hot").unwrap(), Some(9u32.to_be_bytes().to_vec()));
+        assert!(!dir.path().join(COMPACT_TMP_FILENAME).exists());
+
+        // 任务结束后可以再次开始
+        let db = Mutex::new(db);
+        Db::compact_concurrent(&db).unwrap();
+    }
+
+    #[test]
+    fn test_auto_compact_skipped_during_concurrent_compaction() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"hot", b"0").unwrap();
+
+        let job = db.begin_compaction().unwrap().run().unwrap();
+        db.set_auto_compact_ratio(Some(0.1));
+        for i in 0..10u32 {
+            db.put(b"hot", &i.to_be_bytes()).unwrap();
+        }
+        assert!(db.garbage_ratio() > 0.1);
+
+        // 压缩期间的写入补写到新 WAL，之后的写入照常触发自动压缩
+        db.finish_compaction(job).unwrap();
+        assert!(db.garbage_ratio() > 0.1);
+        db.put(b"hot", b"last").unwrap();
+        assert!(db.garbage_ratio() < 0.1);
+        assert_eq!(db.get(b"hot").unwrap().as_deref(), Some(b"last" as &[u8]));
+    }
+
+    #[test]
+    fn test_failed_auto_compaction_does_not_fail_write() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().auto_compact_ratio(Some(0.5)).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+
+        // 临时文件的位置被目录占住，压缩无法创建新 WAL
+        let blocker = dir.path().join(COMPACT_TMP_FILENAME);
+        std::fs::create_dir(&blocker).unwrap();
+        for i in 0..10u8 {
+            db.put(b"key", &[i; 100]).unwrap();
+        }
+        assert!(matches!(db.take_maintenance_error(), Some(Error::Io(_))));
+        assert!(db.take_maintenance_error().is_none());
+        assert!(!db.is_poisoned());
+        assert_eq!(db.get(b"key").unwrap(), Some(vec![9u8; 100]));
+
+        // 问题解决后，下一次写入照常压缩
+        std::fs::remove_dir(&blocker).unwrap();
+        db.put(b"key", b"last").unwrap();
+        assert!(db.take_maintenance_error().is_none());
+        assert!(db.garbage_ratio() < 0.5);
+        drop(db);
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get(b"key").unwrap().as_deref(), Some(b"last" as &[u8]));
+    }
+
+    #[test]
+    fn test_compact_fails_on_damaged_value() {
+        let dir = TempDir::new().unwrap();
+        let wal_path = dir.path().join(WAL_FILENAME);
+        let shared = b"shared-value-0123456789abcdefghij";
+        let plain = b"plain-value-0123456789";
+        let opts = Options::builder().intern_small_values(Some(64)).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        db.put(b"a", shared).unwrap();
+        // "b" 由指向 "a" 的 value 的 REF 记录写入
+        db.put(b"b", shared).unwrap();
+        db.put(b"c", plain).unwrap();
+        db.delete(b"a").unwrap();
+        db.sync().unwrap();
+        let original = std::fs::read(&wal_path).unwrap();
+
+        // 打开之后磁盘上的 value 发生位翻转：压缩失败，不用新的 CRC 把它写进新文件
+        let damage = |needle: &[u8]| {
+            let mut bytes = original.clone();
+            let pos = bytes.windows(needle.len()).position(|w| w == needle).unwrap();
+            bytes[pos] ^= 0xFF;
+            std::fs::write(&wal_path, &bytes).unwrap();
+            bytes
+        };
+        let damaged = damage(shared);
+        assert!(matches!(db.compact(), Err(Error::CrcMismatch { .. })));
+        assert_eq!(std::fs::read(&wal_path).unwrap(), damaged);
+        assert!(!dir.path().join(COMPACT_TMP_FILENAME).exists());
+
+        let damaged = damage(plain);
+        let db = Mutex::new(db);
+        assert!(matches!(Db::compact_concurrent(&db), Err(Error::CrcMismatch { .. })));
+        assert_eq!(std::fs::read(&wal_path).unwrap(), damaged);
+
+        // 恢复原样后照常压缩
+        std::fs::write(&wal_path, &original).unwrap();
+        let mut db = db.into_inner().unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap().as_deref(), Some(shared as &[u8]));
+        assert_eq!(db.get(b"c").unwrap().as_deref(), Some(plain as &[u8]));
+    }
+
+    #[test]
+    fn test_compact_is_deterministic() {
+        let write = |path: &Path| {
+            let mut db = Db::open(path, Options::default()).unwrap();
+            for i in 0..100u32 {
+                db.put(format!("key{}", i).as_bytes(), &i.to_be_bytes()).unwrap();
+            }
+            for i in 0..100u32 {
+                if i % 3 == 0 {
+                    db.delete(format!("key{}", i).as_bytes()).unwrap();
+                }
+            }
+            db.compact().unwrap();
+        };
+
+        // 两个独立的索引（哈希种子不同），压缩结果逐字节相同
+        let dir = TempDir::new().unwrap();
+        write(&dir.path().join("a"));
+        write(&dir.path().join("b"));
+        let a = std::fs::read(dir.path().join("a").join(WAL_FILENAME)).unwrap();
+        let b = std::fs::read(dir.path().join("b").join(WAL_FILENAME)).unwrap();
+        assert_eq!(a, b);
+
+        // compact_into 与原地压缩的输出一致
+        let mut db = Db::open(dir.path().join("a"), Options::default()).unwrap();
+        db.compact_into(dir.path().join("c")).unwrap();
+        let c = std::fs::read(dir.path().join("c").join(WAL_FILENAME)).unwrap();
+        assert_eq!(a, c);
+    }
+
+    #[test]
+    fn test_retained_tombstones_do_not_retrigger_auto_compaction() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder()
+            .tombstone_ttl(Some(Duration::from_secs(3600)))
+            .auto_compact_ratio(Some(0.3))
+            .build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        for i in 0..100u32 {
+            db.put(format!("key{}", i).as_bytes(), &[7u8; 16]).unwrap();
+        }
+        for i in 0..90u32 {
+            db.delete(format!("key{}", i).as_bytes()).unwrap();
+        }
+        db.compact().unwrap();
+        assert_eq!(db.stats().tombstone_count, 90);
+        assert_eq!(db.stats().dead_bytes, 0);
+
+        // 墓碑在 TTL 之内，压缩无法缩小 WAL：之后的写入不再触发压缩
+        let events = db.subscribe();
+        for i in 0..20u32 {
+            db.put(format!("new{}", i).as_bytes(), &[7u8; 16]).unwrap();
+        }
+        assert_eq!(events.try_iter().filter(|e| e.kind == ChangeKind::Compact).count(), 0);
+        assert_eq!(db.stats().dead_bytes, 0);
+        assert_eq!(db.stats().tombstone_count, 90);
+    }
+
+    #[test]
+    fn test_gc_tombstones() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder()
+            .tombstone_ttl(Some(Duration::from_secs(3600)))
+            .build();
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"2").unwrap();
+        db.delete(b"a").unwrap();
+        db.delete(b"b").unwrap();
+        let size = db.stats().wal_size;
+
+        // TTL 之内：压缩会保留，不能丢弃
+        assert_eq!(db.gc_tombstones().unwrap(), 0);
+        assert_eq!(db.stats().tombstone_count, 2);
+
+        // 超过 TTL，但 follower 只复制到 a 的删除（seq 3），b 的墓碑还要保留
+        db.set_options(Options { tombstone_ttl: Some(Duration::ZERO), ..opts.clone() }).unwrap();
+        db.save_replication_cursor("replica", 3).unwrap();
+        assert_eq!(db.gc_tombstones().unwrap(), 1);
+        assert_eq!(db.stats().tombstone_count, 1);
+        assert_eq!(db.stats().wal_size, size);
+        drop(db);
+
+        // checkpoint 之后重新打开，丢弃的墓碑不会从 WAL 中恢复
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        assert_eq!(db.stats().tombstone_count, 1);
+        db.set_options(Options { tombstone_ttl: None, ..opts }).unwrap();
+        db.save_replication_cursor("replica", 4).unwrap();
+        assert_eq!(db.gc_tombstones().unwrap(), 1);
+        assert_eq!(db.stats().tombstone_count, 0);
+        assert_eq!(db.gc_tombstones().unwrap(), 0);
+        assert_eq!(db.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_tombstone_ttl() {
+        let delete_records = |path: &Path| {
+            WalReader::open(path.join(WAL_FILENAME))
+                .unwrap()
+                .filter(|r| r.as_ref().unwrap().1.kind == RecordKind::Delete)
+                .count()
+        };
+
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder()
+            .tombstone_ttl(Some(Duration::from_secs(3600)))
+            .build();
+        {
+            let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+            db.delete(b"a").unwrap();
+            db.delete(b"never").unwrap();
+            assert_eq!(db.stats().tombstone_count, 2);
+
+            // TTL 之内的墓碑在压缩后保留
+            let stats = db.compact().unwrap();
+            assert_eq!(stats.records_dropped, 4 - 3);
+            assert_eq!(db.stats().tombstone_count, 2);
+            assert_eq!(delete_records(dir.path()), 2);
+            assert_eq!(db.get(b"a").unwrap(), None);
+
+            // 重新写入的 key 不再是墓碑
+            db.put(b"never", b"3").unwrap();
+            assert_eq!(db.stats().tombstone_count, 1);
+            db.checkpoint().unwrap();
+        }
+
+        // 墓碑在 MANIFEST 和 replay 中都能恢复
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        assert_eq!(db.stats().tombstone_count, 1);
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        db.put(b"after", b"checkpoint").unwrap();
+        db.delete(b"after").unwrap();
+        drop(db);
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.stats().tombstone_count, 2);
+
+        // 过期的墓碑被丢弃
+        db.opts.tombstone_ttl = Some(Duration::ZERO);
+        db.compact().unwrap();
+        assert_eq!(db.stats().tombstone_count, 0);
+        assert_eq!(delete_records(dir.path()), 0);
+        assert_eq!(db.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_compact_drops_tombstones_without_ttl() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.delete(b"a").unwrap();
+        assert_eq!(db.stats().tombstone_count, 1);
+
+        db.compact().unwrap();
+        assert_eq!(db.stats().tombstone_count, 0);
+    }
+
+    #[test]
+    fn test_compact_if_needed() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+
+        // 空数据库和没有垃圾的数据库都不需要压缩
+        assert_eq!(db.compact_if_needed(0.0).unwrap(), None);
+        for i in 0..10u32 {
+            db.put(&i.to_be_bytes(), b"old").unwrap();
+        }
+        assert_eq!(db.compact_if_needed(0.0).unwrap(), None);
+
+        // 覆盖写入所有 key：大约一半是垃圾（减去历史下限标记的开销）
+        for i in 0..10u32 {
+            db.put(&i.to_be_bytes(), b"new").unwrap();
+        }
+        assert_eq!(db.compact_if_needed(0.5).unwrap(), None);
+        let stats = db.compact_if_needed(0.3).unwrap().unwrap();
+        assert_eq!(stats.records_dropped, 10);
+
+        // 刚压缩过的 WAL 没有垃圾
+        assert_eq!(db.compact_if_needed(0.0).unwrap(), None);
+        assert_eq!(db.get(&3u32.to_be_bytes()).unwrap().as_deref(), Some(b"new" as &[u8]));
+    }
+
+    #[test]
+    fn test_compact_into() {
+        let src = TempDir::new().unwrap();
+        let dest = TempDir::new().unwrap();
+        let dest_dir = dest.path().join("snapshot");
+
+        let mut db = Db::open(src.path(), Options::default()).unwrap();
+        for i in 0..10u32 {
+            db.put(format!("key{}", i).as_bytes(), b"old").unwrap();
+        }
+        for i in 0..10u32 {
+            db.put(format!("key{}", i).as_bytes(), b"new").unwrap();
+        }
+        db.delete(b"key0").unwrap();
+        db.multi_delete(&[b"key1", b"key2"]).unwrap();
+
+        let wal_size = db.stats().wal_size;
+        let stats = db.compact_into(&dest_dir).unwrap();
+        assert_eq!(stats.bytes_before, wal_size);
+        assert!(stats.bytes_after < stats.bytes_before);
+        assert_eq!(stats.keys_kept, 7);
+        // 10 条旧值 + 3 条新值被删除 + 3 条 DELETE
+        assert_eq!(stats.records_dropped, 16);
+
+        // 源数据库不受影响
+        assert_eq!(db.stats().wal_size, wal_size);
+        assert_eq!(db.get(b"key3").unwrap().as_deref(), Some(b"new" as &[u8]));
+
+        // 目标是一个只包含最新数据的数据库
+        let mut copy = Db::open(&dest_dir, Options::default()).unwrap();
+        assert_eq!(copy.last_replay_stats().valid_records, 7);
+        assert_eq!(copy.stats().wal_size, stats.bytes_after);
+        assert_eq!(copy.get(b"key0").unwrap(), None);
+        assert_eq!(copy.get(b"key9").unwrap().as_deref(), Some(b"new" as &[u8]));
+
+        // 目标中已有数据时拒绝
+        assert!(db.compact_into(&dest_dir).is_err());
+    }
+
+    #[test]
+    fn test_compact_into_counts_records_across_checkpoint() {
+        let src = TempDir::new().unwrap();
+        let dest = TempDir::new().unwrap();
+
+        {
+            let mut db = Db::open(src.path(), Options::default()).unwrap();
+            db.put(b"key", b"v1").unwrap();
+            db.put(b"key", b"v2").unwrap();
+            db.checkpoint().unwrap();
+            db.put(b"key", b"v3").unwrap();
+        }
+
+        // 记录数来自 MANIFEST + 高水位之后的 replay
+        let mut db = Db::open(src.path(), Options::default()).unwrap();
+        assert_eq!(db.last_replay_stats().valid_records, 1);
+        let stats = db.compact_into(dest.path()).unwrap();
+        assert_eq!(stats.records_dropped, 2);
+    }
+
+    #[test]
+    fn test_export_import_snapshot() {
+        use std::io::Cursor;
+
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().tombstone_ttl(Some(Duration::from_secs(3600))).build();
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        let value = |i: usize, round: usize| format!("value:{}:{}", i, round).repeat(i % 5 + 1);
+        for round in 0..2 {
+            for i in 0..1000 {
+                db.put(format!("key{:04}", i).as_bytes(), value(i, round).as_bytes()).unwrap();
+            }
+        }
+        for i in (0..1000).step_by(10) {
+            db.delete(format!("key{:04}", i).as_bytes()).unwrap();
+        }
+
+        let mut snapshot = Vec::new();
+        db.export_snapshot(&mut snapshot).unwrap();
+        let size = db.stats().wal_size;
+
+        // 导入：不 replay 任何记录，内容、序列号和墓碑与源数据库相同
+        let dest = TempDir::new().unwrap();
+        let mut imported =
+            Db::import_snapshot(&mut Cursor::new(&snapshot), dest.path(), opts.clone()).unwrap();
+        let wal_size = imported.stats().wal_size;
+        assert_eq!(imported.replay_stats.replay_from, wal_size);
+        assert_eq!(imported.replay_stats.valid_records, 0);
+        assert_eq!(imported.stats().key_count, 900);
+        assert_eq!(imported.latest_sequence(), db.latest_sequence());
+        assert_eq!(imported.index.tombstone_count(), 100);
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            let expected = (i % 10 != 0).then(|| value(i, 1).into_bytes());
+            assert_eq!(imported.get(key.as_bytes()).unwrap(), expected);
+            assert_eq!(imported.sequence(key.as_bytes()), db.sequence(key.as_bytes()));
+        }
+
+        // WAL 部分与 compact_into 的结果逐字节相同，源数据库不变
+        let compacted = TempDir::new().unwrap();
+        db.compact_into(compacted.path()).unwrap();
+        let compacted_wal = std::fs::read(compacted.path().join(WAL_FILENAME)).unwrap();
+        assert_eq!(std::fs::read(dest.path().join(WAL_FILENAME)).unwrap(), compacted_wal);
+        assert_eq!(db.stats().wal_size, size);
+
+        // 导入后照常读写，重新打开（完整 replay）一致
+        imported.put(b"new", b"value").unwrap();
+        drop(imported);
+        let reopened = Db::open(dest.path(), opts.clone()).unwrap();
+        assert_eq!(reopened.stats().key_count, 901);
+        drop(reopened);
+
+        // 目标已有数据、快照被截断
+        let err = Db::import_snapshot(&mut Cursor::new(&snapshot), dest.path(), opts.clone());
+        assert!(matches!(err, Err(Error::AlreadyExists)));
+        let other = TempDir::new().unwrap();
+        let truncated = &snapshot[100..];
+        let err = Db::import_snapshot(&mut Cursor::new(truncated), other.path(), opts.clone());
+        assert!(matches!(err, Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData));
+        assert!(!other.path().join(WAL_FILENAME).exists());
+
+        // WAL 部分中的 value 被破坏：导入失败，不留下 wal.log 和临时文件
+        let mut damaged = snapshot.clone();
+        let needle = value(5, 1).into_bytes();
+        let pos = damaged.windows(needle.len()).position(|w| w == needle).unwrap();
+        damaged[pos] ^= 0xFF;
+        let err = Db::import_snapshot(&mut Cursor::new(&damaged), other.path(), opts.clone());
+        assert!(matches!(err, Err(Error::CrcMismatch { .. })));
+        assert!(!other.path().join(WAL_FILENAME).exists());
+        assert!(!other.path().join(snapshot::IMPORT_TMP_FILENAME).exists());
+
+        // 目标目录已经被打开：在写入任何文件之前失败
+        let open_dir = TempDir::new().unwrap();
+        let holder = Db::open(open_dir.path(), opts.clone()).unwrap();
+        let err = Db::import_snapshot(&mut Cursor::new(&snapshot), open_dir.path(), opts.clone());
+        assert!(matches!(err, Err(Error::AlreadyOpen)));
+        drop(holder);
+        let imported = Db::import_snapshot(&mut Cursor::new(&snapshot), open_dir.path(), opts);
+        assert_eq!(imported.unwrap().stats().key_count, 900);
+    }
+
+    #[test]
+    fn test_append_marker() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.put(b"key1", b"value1").unwrap();
+            let offset = db.append_marker(b"checkpoint-1").unwrap();
+            assert!(offset > 0);
+            db.put(b"key2", b"value2").unwrap();
+        }
+
+        // 标记不影响索引
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.last_replay_stats().valid_records, 2);
+        assert_eq!(db.stats().key_count, 2);
+        assert_eq!(db.get(b"key2").unwrap().as_deref(), Some(b"value2" as &[u8]));
+
+        let markers: Vec<Vec<u8>> = crate::WalReader::open(dir.path().join(WAL_FILENAME))
+            .unwrap()
+            .map(|item| item.unwrap().1)
+            .filter(|record| record.kind == RecordKind::Noop)
+            .map(|record| record.value)
+            .collect();
+        assert_eq!(markers, vec![b"checkpoint-1".to_vec()]);
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            assert_eq!(db.latest_sequence(), 0);
+
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+            db.put(b"a", b"3").unwrap();
+            assert_eq!(db.sequence(b"a"), Some(3));
+            assert_eq!(db.sequence(b"b"), Some(2));
+
+            db.delete(b"b").unwrap();
+            assert_eq!(db.sequence(b"b"), None);
+            assert_eq!(db.latest_sequence(), 4);
+
+            // 批次中的每条记录都有自己的序列号
+            db.put(b"c", b"4").unwrap();
+            db.rename_key(b"c", b"d").unwrap();
+            assert_eq!(db.sequence(b"d"), Some(6));
+            db.multi_delete(&[b"x", b"y"]).unwrap();
+            assert_eq!(db.latest_sequence(), 9);
+        }
+
+        // replay 恢复到看到的最大序列号（最后两次写入都是删除）
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.latest_sequence(), 9);
+        assert_eq!(db.sequence(b"a"), Some(3));
+        assert_eq!(db.sequence(b"d"), Some(6));
+        db.put(b"e", b"5").unwrap();
+        assert_eq!(db.sequence(b"e"), Some(10));
+
+        // checkpoint 后只 replay 尾部，序列号来自 MANIFEST
+        db.checkpoint().unwrap();
+        db.delete(b"e").unwrap();
+        drop(db);
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        assert!(db.last_replay_stats().replay_from > 0);
+        assert_eq!(db.latest_sequence(), 11);
+        assert_eq!(db.sequence(b"a"), Some(3));
+    }
+
+    #[test]
+    fn test_sequence_survives_compaction() {
+        let src = TempDir::new().unwrap();
+        let dest = TempDir::new().unwrap();
+
+        let mut db = Db::open(src.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"2").unwrap();
+        db.delete(b"b").unwrap();
+        db.compact_into(dest.path()).unwrap();
+
+        // 被删除的写入不在目标中，但序列号高水位被保留
+        let mut copy = Db::open(dest.path(), Options::default()).unwrap();
+        assert_eq!(copy.sequence(b"a"), Some(1));
+        assert_eq!(copy.latest_sequence(), 3);
+        copy.put(b"c", b"3").unwrap();
+        assert_eq!(copy.sequence(b"c"), Some(4));
+    }
+
+    #[test]
+    fn test_sequence_for_v1_wal() {
+        let dir = TempDir::new().unwrap();
+        write_v1_wal(
+            dir.path(),
+            &[
+                Record::put(b"a".to_vec(), b"1".to_vec()).unwrap(),
+                Record::put(b"b".to_vec(), b"2".to_vec()).unwrap(),
+            ],
+        );
+
+        // v1 记录按顺序编号
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            assert_eq!(db.sequence(b"a"), Some(1));
+            assert_eq!(db.sequence(b"b"), Some(2));
+            db.put(b"c", b"3").unwrap();
+            db.delete(b"a").unwrap();
+            assert_eq!(db.sequence(b"c"), Some(3));
+            assert_eq!(db.latest_sequence(), 4);
+        }
 
-        db.put(b"key1", b"value1").unwrap();
-        db.put(b"key2", b"value2").unwrap();
+        // 重新打开后编号一致；升级到 v2 后序列号写入记录
+        let opts = Options {
+            upgrade_format: true,
+            ..Options::default()
+        };
+        let db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.sequence(b"b"), Some(2));
+        assert_eq!(db.sequence(b"c"), Some(3));
+        assert_eq!(db.latest_sequence(), 4);
+        drop(db);
 
-        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
-        assert_eq!(db.get(b"key2").unwrap().as_deref(), Some(b"value2" as &[u8]));
-        assert_eq!(db.get(b"key3").unwrap(), None);
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.format_version(), VERSION);
+        assert_eq!(db.sequence(b"c"), Some(3));
+        assert_eq!(db.latest_sequence(), 4);
     }
 
     #[test]
-    fn test_overwrite() {
+    fn test_changes_since_and_apply_record() {
+        let primary_dir = TempDir::new().unwrap();
+        let replica_dir = TempDir::new().unwrap();
+        let opts = Options {
+            sync_on_write: false,
+            write_buffer_bytes: 4096,
+            ..Options::default()
+        };
+
+        let mut primary = Db::open(primary_dir.path(), opts).unwrap();
+        let mut replica = Db::open(replica_dir.path(), Options::default()).unwrap();
+
+        primary.put(b"a", b"1").unwrap();
+        primary.put(b"b", b"2").unwrap();
+        primary.multi_delete(&[b"a"]).unwrap();
+
+        // 第一次同步：全部变更（包括写缓冲区中的记录）
+        let changes = primary.changes_since(replica.latest_sequence()).unwrap();
+        let seqs: Vec<u64> = changes.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+        for (_, record) in changes {
+            replica.apply_record(record).unwrap();
+        }
+        assert_eq!(replica.latest_sequence(), 3);
+        assert_eq!(replica.get(b"a").unwrap(), None);
+        assert_eq!(replica.get(b"b").unwrap().as_deref(), Some(b"2" as &[u8]));
+
+        // 增量同步
+        primary.put(b"c", b"3").unwrap();
+        primary.put(b"b", b"4").unwrap();
+        let changes = primary.changes_since(replica.latest_sequence()).unwrap();
+        assert_eq!(changes.len(), 2);
+        for (_, record) in changes {
+            replica.apply_record(record).unwrap();
+        }
+        assert_eq!(replica.sequence(b"b"), Some(5));
+        assert_eq!(replica.get(b"b").unwrap().as_deref(), Some(b"4" as &[u8]));
+        assert!(primary.changes_since(5).unwrap().is_empty());
+
+        // follower 重新打开后序列号与主库一致
+        drop(replica);
+        let mut replica = Db::open(replica_dir.path(), Options::default()).unwrap();
+        assert_eq!(replica.latest_sequence(), 5);
+        assert_eq!(replica.get(b"c").unwrap().as_deref(), Some(b"3" as &[u8]));
+    }
+
+    #[test]
+    fn test_apply_record_rejects_records_over_local_limits() {
+        let primary_dir = TempDir::new().unwrap();
+        let replica_dir = TempDir::new().unwrap();
+        let replica_opts = Options {
+            limits: Limits {
+                max_key_size: 8,
+                max_value_size: 16,
+            },
+            ..Options::default()
+        };
+
+        let mut primary = Db::open(primary_dir.path(), Options::default()).unwrap();
+        let mut replica = Db::open(replica_dir.path(), replica_opts.clone()).unwrap();
+        primary.put(b"a", b"1").unwrap();
+        primary.put(b"big", &[7u8; 100]).unwrap();
+        primary.put(b"long-key-123", b"v").unwrap();
+        primary.put(b"z", b"26").unwrap();
+
+        let mut errors = Vec::new();
+        for (_, record) in primary.changes_since(0).unwrap() {
+            if let Err(e) = replica.apply_record(record) {
+                errors.push(e);
+            }
+        }
+        assert!(matches!(errors[0], Error::ValueTooLarge { size: 100, max: 16 }));
+        assert!(matches!(errors[1], Error::KeyTooLarge { size: 12, max: 8 }));
+        assert_eq!(errors.len(), 2);
+        assert_eq!(replica.get(b"big").unwrap(), None);
+
+        // 被拒绝的记录没有写入 WAL，重新打开不会截断后面的记录
+        drop(replica);
+        let mut replica = Db::open(replica_dir.path(), replica_opts).unwrap();
+        assert_eq!(replica.last_replay_stats().truncated_bytes, 0);
+        assert_eq!(replica.get(b"a").unwrap().as_deref(), Some(b"1" as &[u8]));
+        assert_eq!(replica.get(b"z").unwrap().as_deref(), Some(b"26" as &[u8]));
+        replica.compact().unwrap();
+    }
+
+    #[test]
+    fn test_get_all_versions() {
         let dir = TempDir::new().unwrap();
-        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        let opts = Options::builder().write_buffer_bytes(4096).sync_on_write(false).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert!(db.get_all_versions(b"key").unwrap().is_empty());
 
-        db.put(b"key", b"value1").unwrap();
-        db.put(b"key", b"value2").unwrap();
+        db.put(b"key", b"v1").unwrap();
+        db.put(b"other", b"x").unwrap();
+        db.put(b"key", b"v2").unwrap();
+        db.delete(b"key").unwrap();
+        db.multi_delete(&[b"other"]).unwrap();
+        db.put(b"key", b"v3").unwrap();
 
-        assert_eq!(db.get(b"key").unwrap().as_deref(), Some(b"value2" as &[u8]));
+        // 还在写缓冲区中的写入也包括在内
+        let versions = db.get_all_versions(b"key").unwrap();
+        assert_eq!(
+            versions,
+            vec![
+                (1, Some(b"v1".to_vec())),
+                (3, Some(b"v2".to_vec())),
+                (4, None),
+                (6, Some(b"v3".to_vec())),
+            ]
+        );
+        assert_eq!(
+            db.get_all_versions(b"other").unwrap(),
+            vec![(2, Some(b"x".to_vec())), (5, None)]
+        );
+
+        // 压缩之后只剩最新的值
+        db.compact().unwrap();
+        assert_eq!(db.get_all_versions(b"key").unwrap(), vec![(6, Some(b"v3".to_vec()))]);
+        assert!(db.get_all_versions(b"other").unwrap().is_empty());
     }
 
     #[test]
-    fn test_delete() {
+    fn test_changes_since_after_compaction() {
+        let src = TempDir::new().unwrap();
+        let dest = TempDir::new().unwrap();
+
+        let mut db = Db::open(src.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"2").unwrap();
+        db.delete(b"a").unwrap();
+        db.compact_into(dest.path()).unwrap();
+
+        let mut compacted = Db::open(dest.path(), Options::default()).unwrap();
+        compacted.put(b"c", b"3").unwrap();
+
+        // 下限之前的增量已不可用
+        let result = compacted.changes_since(1);
+        assert!(matches!(
+            result,
+            Err(Error::HistoryCompacted {
+                requested: 1,
+                floor: 3
+            })
+        ));
+
+        // 下限之后的增量照常返回
+        let changes = compacted.changes_since(3).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].1.key, b"c".to_vec());
+
+        // 从 0 开始得到完整的当前状态
+        let keys: Vec<Vec<u8>> = compacted
+            .changes_since(0)
+            .unwrap()
+            .into_iter()
+            .map(|(_, record)| record.key)
+            .collect();
+        assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn test_flush_interval() {
         let dir = TempDir::new().unwrap();
-        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        let opts = Options {
+            sync_on_write: false,
+            write_buffer_bytes: 4096,
+            flush_interval: Some(Duration::from_millis(5)),
+            ..Options::default()
+        };
+
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        assert!(db.syncer.is_some());
+        for i in 0..20u32 {
+            db.put(&i.to_be_bytes(), b"value").unwrap();
+        }
+        db.close().unwrap();
+
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.stats().key_count, 20);
+        assert_eq!(db.get(&7u32.to_be_bytes()).unwrap().as_deref(), Some(b"value" as &[u8]));
+        drop(db);
+
+        // 只读模式下不启动后台线程
+        let opts = Options {
+            read_only: true,
+            flush_interval: Some(Duration::from_millis(5)),
+            ..Options::default()
+        };
+        let db = Db::open(dir.path(), opts).unwrap();
+        assert!(db.syncer.is_none());
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_key_prefix() {
+        let dir = TempDir::new().unwrap();
+        let tenant = |prefix: &[u8]| Options::builder().key_prefix(prefix).build();
+
+        {
+            let mut db = Db::open(dir.path(), tenant(b"a/")).unwrap();
+            db.put(b"k1", b"a1").unwrap();
+            db.put(b"k2", b"a2").unwrap();
+            db.put(b"x", b"ax").unwrap();
+            db.delete(b"x").unwrap();
+        }
+        {
+            let mut db = Db::open(dir.path(), tenant(b"b/")).unwrap();
+            db.put(b"k1", b"b1").unwrap();
+            assert_eq!(db.get(b"k2").unwrap(), None);
+            db.rename_key(b"k1", b"k3").unwrap();
+            assert_eq!(db.keys_paginated(None, 10), vec![b"k3".to_vec()]);
+        }
+
+        // 键空间互不相交，返回的 key 不带前缀
+        let mut db = Db::open(dir.path(), tenant(b"a/")).unwrap();
+        assert_eq!(db.get(b"k1").unwrap().as_deref(), Some(b"a1" as &[u8]));
+        assert_eq!(db.get(b"k3").unwrap(), None);
+        assert_eq!(db.keys_paginated(Some(b"k1"), 10), vec![b"k2".to_vec()]);
+        let (entries, _) = db.scan_prefix_limited(b"k", 10, 1 << 20).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"k1".to_vec(), b"a1".to_vec()),
+                (b"k2".to_vec(), b"a2".to_vec()),
+            ]
+        );
+        assert_eq!(db.retain(|key, _| key != b"k2").unwrap(), 1);
+        let mut seen = Vec::new();
+        db.for_each(|key, _| {
+            seen.push(key.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen, vec![b"k1".to_vec()]);
+        assert_eq!(db.get_all_versions(b"x").unwrap().len(), 2);
+
+        // 整个存储的操作看到完整的 key
+        assert_eq!(db.stats().key_count, 2);
+        let mut all = Db::open(dir.path(), Options::builder().read_only(true).build()).unwrap();
+        assert_eq!(all.keys_paginated(None, 10), vec![b"a/k1".to_vec(), b"b/k3".to_vec()]);
+        assert_eq!(all.get(b"b/k3").unwrap().as_deref(), Some(b"b1" as &[u8]));
+    }
 
+    #[test]
+    fn test_dir_lock() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
         db.put(b"key", b"value").unwrap();
+
+        // 第二个可写句柄被拒绝，只读句柄不受影响
+        assert!(matches!(
+            Db::open(dir.path(), Options::default()),
+            Err(Error::AlreadyOpen)
+        ));
+        let read_only = Options::builder().read_only(true).build();
+        let mut follower = Db::open(dir.path(), read_only).unwrap();
+        assert_eq!(follower.get(b"key").unwrap().as_deref(), Some(b"value" as &[u8]));
+
+        // 旧句柄在等待期间关闭，新的打开者重试成功
+        let closer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            db.close().unwrap();
+        });
+        let opts = Options::builder()
+            .open_lock_timeout(Some(Duration::from_secs(5)))
+            .build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        closer.join().unwrap();
         assert_eq!(db.get(b"key").unwrap().as_deref(), Some(b"value" as &[u8]));
+    }
 
-        db.delete(b"key").unwrap();
-        assert_eq!(db.get(b"key").unwrap(), None);
+    #[test]
+    fn test_write_back() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder()
+            .sync_on_write(false)
+            .write_back_bytes(Some(256))
+            .build();
+
+        {
+            let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+            for i in 0..200u32 {
+                db.put(&i.to_be_bytes(), &[i as u8; 40]).unwrap();
+                // 刚写入的数据可能还在队列中，也能立即读到
+                assert_eq!(db.get(&i.to_be_bytes()).unwrap(), Some(vec![i as u8; 40]));
+            }
+            db.delete(&0u32.to_be_bytes()).unwrap();
+
+            // 压缩替换文件后后台线程换成新文件继续写入
+            db.compact().unwrap();
+            db.put(b"after", b"compact").unwrap();
+            assert_eq!(db.get(&7u32.to_be_bytes()).unwrap(), Some(vec![7; 40]));
+            db.sync().unwrap();
+            assert_eq!(std::fs::metadata(db.wal.path()).unwrap().len(), db.stats().wal_size);
+            db.put(b"unsynced", b"written on drop").unwrap();
+        }
+
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.stats().key_count, 201);
+        assert_eq!(db.get(&0u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(db.get(b"after").unwrap().as_deref(), Some(b"compact" as &[u8]));
+        assert_eq!(db.get(b"unsynced").unwrap().as_deref(), Some(b"written on drop" as &[u8]));
     }
 
     #[test]
-    fn test_reopen_persistence() {
+    fn test_scan_resync_rewrites_damaged_wal() {
         let dir = TempDir::new().unwrap();
+        let wal_path = dir.path().join(WAL_FILENAME);
 
-        // 写入数据
         {
             let mut db = Db::open(dir.path(), Options::default()).unwrap();
-            db.put(b"key1", b"value1").unwrap();
-            db.put(b"key2", b"value2").unwrap();
-            db.delete(b"key1").unwrap();
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+            db.put(b"c", b"3").unwrap();
         }
 
-        // 重新打开，验证持久化
+        // 破坏中间那条记录（"b" 的 value 位于文件中间）
+        let bytes = std::fs::read(&wal_path).unwrap();
+        let mut damaged = bytes.clone();
+        let middle = bytes.len() / 2;
+        damaged[middle] ^= 0xFF;
+        std::fs::write(&wal_path, &damaged).unwrap();
+
+        let opts = Options {
+            scan_resync: true,
+            ..Options::default()
+        };
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.last_replay_stats().gaps.len(), 1);
+        assert_eq!(db.get(b"a").unwrap().as_deref(), Some(b"1" as &[u8]));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap().as_deref(), Some(b"3" as &[u8]));
+        drop(db);
+
+        // 已经重写掉损坏的字节，默认模式下也不会再截断
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.last_replay_stats().truncated_bytes, 0);
+        assert_eq!(db.get(b"c").unwrap().as_deref(), Some(b"3" as &[u8]));
+    }
+
+    #[test]
+    fn test_scan_resync_drops_ref_to_damaged_value() {
+        let dir = TempDir::new().unwrap();
+        let wal_path = dir.path().join(WAL_FILENAME);
+        let value = b"shared-value-0123456789abcdefghij";
+        let opts = Options::builder().intern_small_values(Some(64)).build();
+
+        {
+            let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+            db.put(b"a", value).unwrap();
+            // 写成指向 "a" 的 value 的 REF 记录
+            db.put(b"b", value).unwrap();
+            db.put(b"c", b"3").unwrap();
+        }
+
+        // 破坏 "a" 的 value，"b" 的 REF 记录本身完好
+        let mut bytes = std::fs::read(&wal_path).unwrap();
+        let pos = bytes.windows(value.len()).position(|w| w == value).unwrap();
+        bytes[pos] ^= 0xFF;
+        std::fs::write(&wal_path, &bytes).unwrap();
+
+        let opts = Options {
+            scan_resync: true,
+            ..opts
+        };
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.last_replay_stats().gaps.len(), 1);
+        assert_eq!(db.last_replay_stats().dropped_refs, 1);
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap().as_deref(), Some(b"3" as &[u8]));
+        drop(db);
+
+        // 重写后损坏的 value 也没有通过 "b" 留下来
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.last_replay_stats().truncated_bytes, 0);
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap().as_deref(), Some(b"3" as &[u8]));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"before", b"v").unwrap();
+
+        let events = db.subscribe();
+        db.put(b"a", b"1").unwrap();
+        db.multi_delete(&[b"a", b"b"]).unwrap();
+        // 被拒绝的写入不产生事件
+        assert!(db.put(&vec![0u8; 4096], b"v").is_err());
+        db.compact().unwrap();
+
+        let received: Vec<_> = events.try_iter().map(|e| (e.kind, e.key, e.seq)).collect();
+        assert_eq!(
+            received,
+            vec![
+                (ChangeKind::Put, b"a".to_vec(), 2),
+                (ChangeKind::Delete, b"a".to_vec(), 3),
+                (ChangeKind::Delete, b"b".to_vec(), 4),
+                (ChangeKind::Compact, Vec::new(), 4),
+            ]
+        );
+
+        // 接收端 drop 后自动取消订阅
+        drop(events);
+        db.put(b"c", b"1").unwrap();
+        assert_eq!(db.subscribers.senders_len(), 0);
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = TempDir::new().unwrap();
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let id = db.watch(b"config", move |value| {
+            log.lock().unwrap().push(value.map(<[u8]>::to_vec));
+        });
+
+        db.put(b"config", b"1").unwrap();
+        db.put(b"other", b"x").unwrap();
+        // 写入失败不调用回调
+        assert!(db.put(b"config", &vec![0u8; 2 * 1024 * 1024]).is_err());
+        let mut writer = db.put_reserve(b"config", 2).unwrap();
+        writer.write_all(b"22").unwrap();
+        writer.finish().unwrap();
+        db.multi_delete(&[b"config", b"other"]).unwrap();
+        // key 已经不存在，删除不算变更
+        db.delete(b"config").unwrap();
+        db.put(b"tmp", b"3").unwrap();
+        db.rename_key(b"tmp", b"config").unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![Some(b"1".to_vec()), Some(b"22".to_vec()), None, Some(b"3".to_vec())]
+        );
+
+        assert!(db.unwatch(id));
+        assert!(!db.unwatch(id));
+        db.put(b"config", b"4").unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_fail_on_corruption() {
+        let dir = TempDir::new().unwrap();
+        let wal_path = dir.path().join(WAL_FILENAME);
+
         {
             let mut db = Db::open(dir.path(), Options::default()).unwrap();
-            assert_eq!(db.get(b"key1").unwrap(), None);
-            assert_eq!(db.get(b"key2").unwrap().as_deref(), Some(b"value2" as &[u8]));
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+        }
+
+        // 模拟写了一半的尾部记录
+        let mut bytes = std::fs::read(&wal_path).unwrap();
+        bytes.extend_from_slice(b"KVSL\x40\x00");
+        std::fs::write(&wal_path, &bytes).unwrap();
+
+        let opts = Options::builder().fail_on_corruption(true).build();
+        match Db::open(dir.path(), opts) {
+            Err(Error::CorruptedWal {
+                valid_records,
+                truncated_bytes,
+            }) => {
+                assert_eq!(valid_records, 2);
+                assert_eq!(truncated_bytes, 6);
+            }
+            other => panic!("expected CorruptedWal, got {:?}", other.map(|_| ())),
         }
+        // 文件没有被修改
+        assert_eq!(std::fs::read(&wal_path).unwrap(), bytes);
+
+        // 关闭选项后按正常流程截断恢复
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.last_replay_stats().truncated_bytes, 6);
+        assert_eq!(db.get(b"b").unwrap().as_deref(), Some(b"2" as &[u8]));
     }
 
     #[test]
-    fn test_stats() {
+    fn test_put_reserve() {
         let dir = TempDir::new().unwrap();
+        let value: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            let mut writer = db.put_reserve(b"blob", value.len()).unwrap();
+            for chunk in value.chunks(7_000) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.finish().unwrap();
+
+            assert_eq!(db.get(b"blob").unwrap(), Some(value.clone()));
+            assert_eq!(db.sequence(b"blob"), Some(1));
+        }
+
         let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get(b"blob").unwrap(), Some(value));
 
-        db.put(b"key1", b"value1").unwrap();
-        db.put(b"key2", b"value2").unwrap();
+        // 超出大小限制时直接拒绝
+        let result = db.put_reserve(b"huge", Limits::default().max_value_size + 1);
+        assert!(matches!(result, Err(Error::ValueTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_value_reader() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().write_buffer_bytes(1 << 20).sync_on_write(false).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        let value: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        db.put(b"blob", &value).unwrap();
+        db.put(b"empty", b"").unwrap();
+        assert!(db.value_reader(b"missing").unwrap().is_none());
+
+        // 分块读取：value 一部分还在写缓冲区中，一部分已写入文件
+        for sync in [false, true] {
+            if sync {
+                db.sync().unwrap();
+            }
+            let mut reader = db.value_reader(b"blob").unwrap().unwrap();
+            assert_eq!(reader.len(), value.len());
+            let mut chunk = [0u8; 4096];
+            let mut out = Vec::new();
+            loop {
+                let n = reader.read(&mut chunk).unwrap();
+                if n == 0 {
+                    break;
+                }
+                out.extend_from_slice(&chunk[..n]);
+            }
+            assert_eq!(out, value);
+        }
+
+        let mut reader = db.value_reader(b"empty").unwrap().unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(reader.read(&mut [0u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_put_reserve_notifies_after_index_update() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().inline_value_threshold(16).build();
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        let follower = Db::open(dir.path(), Options::builder().read_only(true).build()).unwrap();
+        let follower = Arc::new(Mutex::new(follower));
+
+        // 回调中读取这个 key：此时写入已经提交，读到的是新值
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (log, reader) = (Arc::clone(&seen), Arc::clone(&follower));
+        db.watch(b"config", move |value| {
+            let mut reader = reader.lock().unwrap();
+            reader.tail().unwrap();
+            let read = reader.get(b"config").unwrap();
+            log.lock().unwrap().push((value.map(<[u8]>::to_vec), read));
+        });
+
+        for value in [&b"v1"[..], b"v2"] {
+            let mut writer = db.put_reserve(b"config", value.len()).unwrap();
+            writer.write_all(value).unwrap();
+            writer.finish().unwrap();
+        }
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (Some(b"v1".to_vec()), Some(b"v1".to_vec())),
+                (Some(b"v2".to_vec()), Some(b"v2".to_vec())),
+            ]
+        );
+
+        // 与 `put` 一样，不超过阈值的 value 内联在索引中
+        assert_eq!(db.index.get_inline(b"config"), Some(&b"v2"[..]));
+        let mut writer = db.put_reserve(b"blob", 32).unwrap();
+        writer.write_all(&[7u8; 32]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(db.index.get_inline(b"blob"), None);
+        assert_eq!(db.get(b"blob").unwrap(), Some(vec![7u8; 32]));
+    }
+
+    #[test]
+    fn test_put_reserve_length_mismatch() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options {
+            sync_on_write: false,
+            write_buffer_bytes: 4096,
+            ..Options::default()
+        };
+        let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        let size = db.stats().wal_size;
+
+        // 写少了：finish 失败，记录被放弃
+        let mut writer = db.put_reserve(b"short", 10).unwrap();
+        writer.write_all(b"12345").unwrap();
+        let result = writer.finish();
+        assert!(matches!(
+            result,
+            Err(Error::ValueLengthMismatch {
+                expected: 10,
+                actual: 5
+            })
+        ));
+        assert_eq!(db.stats().wal_size, size);
+
+        // 写多了：超出的那次 write 失败，finish 同样失败
+        let mut writer = db.put_reserve(b"long", 4).unwrap();
+        writer.write_all(b"123").unwrap();
+        assert!(writer.write_all(b"45").is_err());
+        assert!(matches!(
+            writer.finish(),
+            Err(Error::ValueLengthMismatch {
+                expected: 4,
+                actual: 5
+            })
+        ));
+
+        // 没有 finish 就 drop
+        let mut writer = db.put_reserve(b"dropped", 4).unwrap();
+        writer.write_all(b"1234").unwrap();
+        drop(writer);
+
+        assert_eq!(db.get(b"short").unwrap(), None);
+        assert_eq!(db.get(b"long").unwrap(), None);
+        assert_eq!(db.get(b"dropped").unwrap(), None);
+        assert_eq!(db.stats().wal_size, size);
+
+        // 之后的写入不受影响，重新打开没有损坏
+        db.put(b"b", b"2").unwrap();
+        drop(db);
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.last_replay_stats().truncated_bytes, 0);
+        assert_eq!(db.stats().key_count, 2);
+        assert_eq!(db.get(b"a").unwrap().as_deref(), Some(b"1" as &[u8]));
+        assert_eq!(db.latest_sequence(), 2);
+    }
+
+    /// 把记录按 v1 格式写成一个 WAL 文件
+    fn write_v1_wal(dir: &Path, records: &[Record]) {
+        let mut buf = Vec::new();
+        for record in records {
+            record.encode_to_version(&mut buf, 1).unwrap();
+        }
+        std::fs::write(dir.join(WAL_FILENAME), buf).unwrap();
+    }
+
+    /// WAL 文件中每条记录的格式版本
+    fn record_versions(dir: &Path) -> Vec<u8> {
+        let data = std::fs::read(dir.join(WAL_FILENAME)).unwrap();
+        let mut cursor = std::io::Cursor::new(data);
+        let mut versions = Vec::new();
+        while let Some((_, version)) =
+            Record::decode_with_version(&mut cursor, &Limits::default()).unwrap()
+        {
+            versions.push(version);
+        }
+        versions
+    }
+
+    #[test]
+    fn test_v1_wal_keeps_v1_without_upgrade() {
+        let dir = TempDir::new().unwrap();
+        write_v1_wal(
+            dir.path(),
+            &[
+                Record::put(b"key1".to_vec(), b"value1".to_vec()).unwrap(),
+                Record::put(b"key2".to_vec(), b"value2".to_vec()).unwrap(),
+            ],
+        );
+
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.format_version(), 1);
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
+
+        // 新写入继续使用 v1，不混入 v2
+        db.put(b"key3", b"value3").unwrap();
+        db.delete(b"key2").unwrap();
+        drop(db);
+        assert_eq!(record_versions(dir.path()), vec![1, 1, 1, 1]);
+
+        // 新建的数据库使用当前版本
+        let dir2 = TempDir::new().unwrap();
+        let db = Db::open(dir2.path(), Options::default()).unwrap();
+        assert_eq!(db.format_version(), VERSION);
+    }
+
+    #[test]
+    fn test_live_and_dead_bytes_on_v1_wal() {
+        let dir = TempDir::new().unwrap();
+        write_v1_wal(
+            dir.path(),
+            &[
+                Record::put(b"key1".to_vec(), b"old".to_vec()).unwrap(),
+                Record::put(b"key2".to_vec(), b"value2".to_vec()).unwrap(),
+                Record::put(b"key1".to_vec(), b"value1".to_vec()).unwrap(),
+                Record::delete(b"key2".to_vec()).unwrap(),
+            ],
+        );
 
+        // v1 记录没有序列号：存活的只有 key1 的最后一条 PUT，其余都是垃圾
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.format_version(), 1);
+        let live = Record::put_encoded_len(4, 6, false) as u64;
+        assert_eq!(db.live_disk_bytes(), live);
         let stats = db.stats();
-        assert_eq!(stats.key_count, 2);
-        assert!(stats.wal_size > 0);
+        assert_eq!(stats.live_disk_bytes, live);
+        assert_eq!(stats.dead_bytes, stats.wal_size - live);
+
+        // 继续以 v1 写入：新增的 key 同样按 v1 计算
+        db.put(b"key3", b"v").unwrap();
+        let live = live + Record::put_encoded_len(4, 1, false) as u64;
+        assert_eq!(db.live_disk_bytes(), live);
+        assert_eq!(db.stats().dead_bytes, db.stats().wal_size - live);
+
+        // 压缩后升级为当前格式，没有垃圾
+        db.compact().unwrap();
+        assert_eq!(db.format_version(), VERSION);
+        assert_eq!(db.live_disk_bytes(), live + 2 * 8);
+        assert_eq!(db.stats().dead_bytes, 0);
+    }
+
+    #[test]
+    fn test_upgrade_format() {
+        let dir = TempDir::new().unwrap();
+        write_v1_wal(
+            dir.path(),
+            &[
+                Record::put(b"key1".to_vec(), b"old".to_vec()).unwrap(),
+                Record::put(b"key2".to_vec(), b"value2".to_vec()).unwrap(),
+                Record::put(b"key1".to_vec(), b"value1".to_vec()).unwrap(),
+                Record::delete(b"key2".to_vec()).unwrap(),
+                Record::put(b"key3".to_vec(), b"value3".to_vec()).unwrap(),
+            ],
+        );
+
+        let opts = Options {
+            upgrade_format: true,
+            ..Options::default()
+        };
+        {
+            let mut db = Db::open(dir.path(), opts.clone()).unwrap();
+            assert_eq!(db.format_version(), VERSION);
+            assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
+            assert_eq!(db.get(b"key2").unwrap(), None);
+            assert_eq!(db.get(b"key3").unwrap().as_deref(), Some(b"value3" as &[u8]));
+
+            db.put(b"key4", b"value4").unwrap();
+        }
+
+        // 重写只保留最新值（历史下限标记 + 2 条 PUT），之后的写入都是 v2
+        assert_eq!(record_versions(dir.path()), vec![VERSION; 4]);
+
+        // 不带升级选项重新打开，仍然是 v2
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.format_version(), VERSION);
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
+        assert_eq!(db.get(b"key4").unwrap().as_deref(), Some(b"value4" as &[u8]));
+        assert!(!dir.path().join(COMPACT_TMP_FILENAME).exists());
+    }
+
+    #[test]
+    fn test_upgrade_format_after_checkpoint() {
+        let dir = TempDir::new().unwrap();
+        write_v1_wal(
+            dir.path(),
+            &[Record::put(b"key1".to_vec(), b"value1".to_vec()).unwrap()],
+        );
+
+        // v1 数据库写入 checkpoint 后，replay 不再扫描前面的 v1 记录
+        {
+            let mut db = Db::open(dir.path(), Options::default()).unwrap();
+            db.checkpoint().unwrap();
+        }
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.last_replay_stats().valid_records, 0);
+        assert_eq!(db.format_version(), 1);
+        drop(db);
+
+        let opts = Options {
+            upgrade_format: true,
+            ..Options::default()
+        };
+        let mut db = Db::open(dir.path(), opts).unwrap();
+        assert_eq!(db.format_version(), VERSION);
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
+        drop(db);
+
+        // 旧的 MANIFEST 已删除，重新打开走完整 replay
+        let mut db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get(b"key1").unwrap().as_deref(), Some(b"value1" as &[u8]));
     }
 
     #[test]
@@ -507,10 +8882,48 @@ mod tests {
         let dir = TempDir::new().unwrap();
         let opts = Options {
             sync_on_write: false, // 不 fsync，更快
+            ..Options::default()
         };
         let mut db = Db::open(dir.path(), opts).unwrap();
 
         db.put(b"key", b"value").unwrap();
         assert_eq!(db.get(b"key").unwrap().as_deref(), Some(b"value" as &[u8]));
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let parent = TempDir::new().unwrap();
+        let dir = parent.path().join("db");
+        let wal_path = dir.join(WAL_FILENAME);
+
+        let opts = Options::builder().file_mode(Some(0o600)).build();
+        let mut db = Db::open(&dir, opts.clone()).unwrap();
+        assert_eq!(mode(&wal_path), 0o600);
+        assert_eq!(mode(&dir), 0o700);
+
+        // 重写生成的 WAL 和 MANIFEST 沿用同样的权限
+        db.put(b"key", b"v1").unwrap();
+        db.put(b"key", b"v2").unwrap();
+        db.compact().unwrap();
+        assert_eq!(mode(&wal_path), 0o600);
+        db.checkpoint().unwrap();
+        assert_eq!(mode(&dir.join("MANIFEST")), 0o600);
+        drop(db);
+
+        // 已有的数据库不改动权限
+        std::fs::set_permissions(&wal_path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let mut db = Db::open(&dir, opts).unwrap();
+        assert_eq!(mode(&wal_path), 0o640);
+        assert_eq!(db.get(b"key").unwrap().as_deref(), Some(b"v2" as &[u8]));
+
+        // create_new 同样生效
+        let other = parent.path().join("other");
+        Db::create_new(&other, Options::builder().file_mode(Some(0o640)).build()).unwrap();
+        assert_eq!(mode(&other.join(WAL_FILENAME)), 0o640);
+        assert_eq!(mode(&other), 0o750);
+    }
 }
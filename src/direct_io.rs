@@ -0,0 +1,303 @@
+//! 绕过页缓存的 WAL 写入（`Options::direct_io`）
+//!
+//! 本模块实现 `O_DIRECT` 模式下的追加写入。
+//!
+//! ## 对齐要求
+//!
+//! `O_DIRECT` 要求每次写入的内存地址、长度和文件偏移量都按块大小对齐，
+//! 而 WAL 记录是变长的，追加位置几乎从不对齐。做法是在内存中保留文件最后
+//! 一个不完整的块（tail），每次写入时把 tail 和新数据拼在一起、用 0 补齐到整块，
+//! 从 tail 所在块的起始位置整块写入：
+//!
+//! ```text
+//!            block_start
+//!                 │◀──────── 一次写入（整块）────────▶│
+//! | 完整的块 ...  | tail | 新数据 ...          | 0 填充 |
+//!                                           ▲
+//!                                      逻辑末尾
+//! ```
+//!
+//! 写入后文件末尾会有不足一个块的 0 填充，下一次写入会覆盖它。
+//! 正常关闭时文件被截断回逻辑长度；崩溃留下的填充在下次 replay 时被截断。
+//!
+//! ## 平台
+//!
+//! 只在支持 `O_DIRECT` 的 Unix 系统（Linux、Android、FreeBSD）上启用。
+//! 其他平台，或者文件系统不支持 `O_DIRECT`（例如 tmpfs）时，打开返回 `None`，
+//! 调用方回退到普通的缓冲写入。
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+
+/// 块大小无法确定时使用的对齐
+const DEFAULT_ALIGN: usize = 4096;
+
+/// 允许的最大对齐（更大的 `st_blksize` 通常只是建议的 I/O 大小）
+const MAX_ALIGN: usize = 64 * 1024;
+
+/// 按块对齐的 `O_DIRECT` 追加写入器
+#[derive(Debug)]
+pub struct DirectWriter {
+    file: File,
+    /// 块大小（2 的幂）
+    align: usize,
+    /// 最后一个不完整块在文件中的起始位置（按块对齐）
+    block_start: u64,
+    /// 最后一个不完整块中已写入的数据（长度小于 `align`）
+    tail: Vec<u8>,
+    /// 对齐的写缓冲区，在多次写入之间复用
+    scratch: AlignedBuf,
+}
+
+impl DirectWriter {
+    /// 以 `O_DIRECT` 打开已存在的 WAL 文件
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Some(DirectWriter))`: 打开成功，追加位置为当前文件末尾
+    /// - `Ok(None)`: 平台或文件系统不支持 `O_DIRECT`
+    /// - `Err(io::Error)`: 其他 I/O 错误
+    pub fn open(path: &Path) -> io::Result<Option<Self>> {
+        let file = match open_direct_file(path)? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        let blksize = file.metadata()?.blksize() as usize;
+        let align = if blksize.is_power_of_two() && (512..=MAX_ALIGN).contains(&blksize) {
+            blksize
+        } else {
+            DEFAULT_ALIGN
+        };
+
+        Self::new(file, align).map(Some)
+    }
+
+    /// 用已打开的文件创建写入器，追加位置为当前文件末尾
+    ///
+    /// `file` 需要可读可写；`align` 必须是 2 的幂
+    pub fn new(file: File, align: usize) -> io::Result<Self> {
+        debug_assert!(align.is_power_of_two());
+
+        let len = file.metadata()?.len();
+        let mut writer = DirectWriter {
+            file,
+            align,
+            block_start: 0,
+            tail: Vec::with_capacity(align),
+            scratch: AlignedBuf::new(align),
+        };
+        writer.load_tail(len)?;
+
+        Ok(writer)
+    }
+
+    /// 逻辑文件长度（不包括末尾的 0 填充）
+    pub fn len(&self) -> u64 {
+        self.block_start + self.tail.len() as u64
+    }
+
+    /// 底层文件句柄
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// 在逻辑末尾追加数据
+    ///
+    /// 从 tail 所在块开始整块写入，写入后新的不完整块成为 tail
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // 1. 拼接 tail 和新数据，补齐到整块
+        let total = self.tail.len() + data.len();
+        let padded = round_up(total, self.align);
+        let buf = self.scratch.get_mut(padded);
+        buf[..self.tail.len()].copy_from_slice(&self.tail);
+        buf[self.tail.len()..total].copy_from_slice(data);
+        buf[total..].fill(0);
+
+        // 2. 整块写入
+        self.file.write_all_at(buf, self.block_start)?;
+
+        // 3. 最后一个不完整的块成为新的 tail
+        let full = total - total % self.align;
+        self.tail.clear();
+        self.tail.extend_from_slice(&buf[full..total]);
+        self.block_start += full as u64;
+
+        Ok(())
+    }
+
+    /// 把文件截断到 `len`（不能超过当前逻辑长度）
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.load_tail(len)
+    }
+
+    /// 从文件中读出 `len` 所在的不完整块作为 tail
+    fn load_tail(&mut self, len: u64) -> io::Result<()> {
+        let align = self.align as u64;
+        self.block_start = len - len % align;
+        self.tail.clear();
+
+        let tail_len = (len - self.block_start) as usize;
+        if tail_len > 0 {
+            // O_DIRECT 读取同样要求整块对齐，读到文件末尾时会提前结束
+            let buf = self.scratch.get_mut(self.align);
+            let mut read = 0;
+            while read < tail_len {
+                let n = self.file.read_at(&mut buf[read..], self.block_start + read as u64)?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                read += n;
+            }
+            self.tail.extend_from_slice(&buf[..tail_len]);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for DirectWriter {
+    /// 去掉末尾的 0 填充
+    fn drop(&mut self) {
+        let _ = self.file.set_len(self.len());
+    }
+}
+
+/// 起始地址按块对齐的缓冲区
+///
+/// 多分配 `align` 字节，从第一个对齐的位置开始使用，不需要 unsafe 的对齐分配
+#[derive(Debug)]
+struct AlignedBuf {
+    buf: Vec<u8>,
+    align: usize,
+}
+
+impl AlignedBuf {
+    fn new(align: usize) -> Self {
+        AlignedBuf {
+            buf: Vec::new(),
+            align,
+        }
+    }
+
+    /// 获取一段长度为 `len` 的对齐切片（内容未定义）
+    fn get_mut(&mut self, len: usize) -> &mut [u8] {
+        if self.buf.len() < len + self.align {
+            self.buf = vec![0u8; len + self.align];
+        }
+        let start = self.buf.as_ptr().align_offset(self.align);
+        &mut self.buf[start..start + len]
+    }
+}
+
+/// 把 `n` 向上取整到 `align` 的倍数
+fn round_up(n: usize, align: usize) -> usize {
+    n.div_ceil(align) * align
+}
+
+/// 以 `O_DIRECT` 打开文件，不支持时返回 `None`
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn open_direct_file(path: &Path) -> io::Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let opened = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path);
+
+    match opened {
+        Ok(file) => Ok(Some(file)),
+        // 文件系统不支持 O_DIRECT
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// 以 `O_DIRECT` 打开文件，不支持时返回 `None`
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn open_direct_file(_path: &Path) -> io::Result<Option<File>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// 用普通文件测试对齐逻辑（不依赖文件系统是否支持 O_DIRECT）
+    fn open_plain(path: &Path) -> File {
+        OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_unaligned_appends() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut expected = Vec::new();
+        {
+            let mut writer = DirectWriter::new(open_plain(&path), 512).unwrap();
+            for i in 0..50u32 {
+                let chunk = vec![i as u8; 37 + i as usize * 13];
+                writer.write_all(&chunk).unwrap();
+                expected.extend_from_slice(&chunk);
+
+                // 文件末尾被 0 填充到整块，逻辑长度不变
+                assert_eq!(writer.len(), expected.len() as u64);
+                let file_len = std::fs::metadata(&path).unwrap().len();
+                assert_eq!(file_len, round_up(expected.len(), 512) as u64);
+            }
+        }
+
+        // drop 时去掉填充
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        // 重新打开后从不对齐的末尾继续追加
+        let mut writer = DirectWriter::new(open_plain(&path), 512).unwrap();
+        writer.write_all(b"more").unwrap();
+        expected.extend_from_slice(b"more");
+        drop(writer);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_set_len() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut writer = DirectWriter::new(open_plain(&path), 512).unwrap();
+        writer.write_all(&[1u8; 700]).unwrap();
+        writer.set_len(300).unwrap();
+        assert_eq!(writer.len(), 300);
+
+        writer.write_all(&[2u8; 10]).unwrap();
+        drop(writer);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 310);
+        assert!(bytes[..300].iter().all(|&b| b == 1));
+        assert!(bytes[300..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn test_aligned_buf() {
+        let mut buf = AlignedBuf::new(4096);
+        let slice = buf.get_mut(10_000);
+        assert_eq!(slice.len(), 10_000);
+        assert_eq!(slice.as_ptr() as usize % 4096, 0);
+    }
+}
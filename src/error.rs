@@ -12,11 +12,21 @@ use std::fmt;
 use std::io;
 
 /// kvslite 的错误类型
+///
+/// 标记为 `#[non_exhaustive]`：新增错误类型不算破坏性变更，
+/// crate 外部的 `match` 需要一个通配分支（例如按 `Display` 输出或统一当作 I/O 失败处理）。
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// I/O 错误（文件读写、目录创建等）
     Io(io::Error),
 
+    /// 磁盘已满，写入失败（`ErrorKind::StorageFull` 或 `ErrorKind::WriteZero`）
+    ///
+    /// 写失败的记录不会留在 WAL 中，也不会更新索引：之前的数据完好，
+    /// 释放空间后可以直接重试
+    DiskFull(io::Error),
+
     /// 数据损坏：CRC 校验失败
     ///
     /// 包含期望的 CRC 值和实际计算的 CRC 值
@@ -33,7 +43,10 @@ pub enum Error {
         actual: [u8; 4],
     },
 
-    /// 数据损坏：不支持的版本号
+    /// 不支持的版本号
+    ///
+    /// 出现在 CRC 完整的记录上时，通常说明数据由更新版本的 kvslite 写入，
+    /// 打开数据库会返回此错误而不是截断 WAL
     UnsupportedVersion(u8),
 
     /// 数据损坏：无效的记录类型
@@ -61,12 +74,104 @@ pub enum Error {
         size: usize,
         max: usize,
     },
+
+    /// 记录过大，长度超出了格式中 u32 长度字段的范围
+    ///
+    /// 只有把 `Limits` 调到 key + value 接近 4GB 时才会出现
+    RecordTooLarge {
+        size: usize,
+        max: usize,
+    },
+
+    /// 批次中的记录过多，条数超出了 BATCH 头中 u32 计数的范围
+    ///
+    /// 整个批次被拒绝，没有写入任何内容
+    BatchTooLarge {
+        size: usize,
+        max: usize,
+    },
+
+    /// 数据库以只读模式打开，不允许写入
+    ReadOnly,
+
+    /// 流式写入的 value 长度与预留的长度不一致
+    ///
+    /// `put_reserve` 预留了 `expected` 字节，实际写入了 `actual` 字节，记录已被放弃
+    ValueLengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+
+    /// 请求的变更历史已被压缩丢弃
+    ///
+    /// `floor` 之前（含）的被覆盖和被删除的写入已经不在 WAL 中，
+    /// 无法提供 `requested` 之后的完整变更，调用方需要重新做全量同步
+    HistoryCompacted {
+        requested: u64,
+        floor: u64,
+    },
+
+    /// 存储的 value 不是合法的 UTF-8（`get_str` 读取时）
+    Utf8(std::string::FromUtf8Error),
+
+    /// 打开时发现 WAL 损坏，按 `fail_on_corruption` 的要求拒绝恢复
+    ///
+    /// `valid_records` 是损坏位置之前的有效记录数，`truncated_bytes` 是正常恢复时
+    /// 会被丢弃的字节数（尾部截断加上 `scan_resync` 跳过的区间）。WAL 文件保持原样
+    CorruptedWal {
+        valid_records: usize,
+        truncated_bytes: u64,
+    },
+
+    /// 已经有一个后台压缩（`Db::compact_concurrent`）正在进行
+    CompactionInProgress,
+
+    /// 数据库目录已经被另一个可写的 `Db` 打开（同一进程或其他进程）
+    ///
+    /// 设置了 `Options::open_lock_timeout` 时，表示在超时之前锁一直没有被释放
+    AlreadyOpen,
+
+    /// 要求新建数据库（`Db::create_new`、`Db::import_snapshot`），但目录中已经有数据
+    AlreadyExists,
+
+    /// 要求打开已有的数据库（`Db::open_existing`），但目录中没有 `wal.log`
+    NotFound,
+
+    /// 之前的写操作因为 I/O 错误失败，数据库处于中毒状态，拒绝写入
+    ///
+    /// 见 `Db::is_poisoned`：调用 `Db::clear_poison` 或重新打开数据库后才能继续写入；
+    /// 由持有共享锁的线程 panic 引起时只能重新打开
+    Poisoned,
+
+    /// 试图在运行时修改只在打开时生效的选项（见 `Db::set_options`）
+    FixedOption {
+        name: &'static str,
+    },
+
+    /// 读取的范围超出了 WAL 末尾
+    ///
+    /// 从 `offset` 开始读取 `len` 字节，只读到了 `read` 字节。
+    /// 索引指向的位置读不完整，通常说明文件在打开后被外部截断
+    OutOfBounds {
+        offset: u64,
+        len: usize,
+        read: usize,
+    },
+
+    /// 数据损坏：`offset` 处的记录 CRC 正确，但不是索引所指的那条 PUT 记录
+    ///
+    /// 压缩读取存活的 value 时校验它所在的记录，索引与 WAL 不一致时返回此错误，
+    /// 压缩被放弃，`wal.log` 保持不变
+    RecordMismatch {
+        offset: u64,
+    },
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::DiskFull(e) => write!(f, "Disk is full: {}", e),
             Error::CrcMismatch { expected, actual } => {
                 write!(f, "CRC mismatch: expected {:#x}, got {:#x}", expected, actual)
             }
@@ -78,7 +183,11 @@ impl fmt::Display for Error {
                 )
             }
             Error::UnsupportedVersion(v) => {
-                write!(f, "Unsupported version: {}", v)
+                write!(
+                    f,
+                    "Unsupported version: {} (written by a newer kvslite? upgrade to open it)",
+                    v
+                )
             }
             Error::InvalidRecordKind(k) => {
                 write!(f, "Invalid record kind: {}", k)
@@ -92,6 +201,68 @@ impl fmt::Display for Error {
             Error::KeyTooLarge { size, max } => {
                 write!(f, "Key too large: {} bytes (max {})", size, max)
             }
+            Error::RecordTooLarge { size, max } => {
+                write!(f, "Record too large: {} bytes (max {})", size, max)
+            }
+            Error::BatchTooLarge { size, max } => {
+                write!(f, "Batch too large: {} records (max {})", size, max)
+            }
+            Error::ReadOnly => {
+                write!(f, "Database is opened read-only")
+            }
+            Error::ValueLengthMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "Value length mismatch: reserved {} bytes, wrote {}",
+                    expected, actual
+                )
+            }
+            Error::HistoryCompacted { requested, floor } => {
+                write!(
+                    f,
+                    "Changes since sequence {} are no longer available (history compacted through {})",
+                    requested, floor
+                )
+            }
+            Error::Utf8(e) => write!(f, "Value is not valid UTF-8: {}", e),
+            Error::CorruptedWal {
+                valid_records,
+                truncated_bytes,
+            } => {
+                write!(
+                    f,
+                    "WAL is corrupted: recovery would discard {} bytes after {} valid records",
+                    truncated_bytes, valid_records
+                )
+            }
+            Error::CompactionInProgress => {
+                write!(f, "A background compaction is already in progress")
+            }
+            Error::AlreadyOpen => {
+                write!(f, "Database is already opened for writing by another handle")
+            }
+            Error::AlreadyExists => {
+                write!(f, "Database already exists")
+            }
+            Error::NotFound => {
+                write!(f, "Database does not exist")
+            }
+            Error::Poisoned => {
+                write!(f, "Database is poisoned by an earlier write error")
+            }
+            Error::FixedOption { name } => {
+                write!(f, "Option `{}` can only be set when opening the database", name)
+            }
+            Error::OutOfBounds { offset, len, read } => {
+                write!(
+                    f,
+                    "Read out of bounds: {} bytes at offset {}, only {} available",
+                    len, offset, read
+                )
+            }
+            Error::RecordMismatch { offset } => {
+                write!(f, "Record at offset {} does not match the index", offset)
+            }
         }
     }
 }
@@ -99,16 +270,27 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::Io(e) => Some(e),
+            Error::Io(e) | Error::DiskFull(e) => Some(e),
+            Error::Utf8(e) => Some(e),
             _ => None,
         }
     }
 }
 
-/// 从标准 I/O 错误自动转换
+/// 从标准 I/O 错误自动转换（磁盘已满转换为 [`Error::DiskFull`]）
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
-        Error::Io(e)
+        match e.kind() {
+            io::ErrorKind::StorageFull | io::ErrorKind::WriteZero => Error::DiskFull(e),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+/// 从 UTF-8 解码错误自动转换
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        Error::Utf8(e)
     }
 }
 
@@ -129,6 +311,20 @@ mod tests {
             err.to_string(),
             "CRC mismatch: expected 0x1234, got 0x5678"
         );
+        assert_eq!(
+            Error::Poisoned.to_string(),
+            "Database is poisoned by an earlier write error"
+        );
+        assert_eq!(Error::AlreadyExists.to_string(), "Database already exists");
+        assert_eq!(Error::NotFound.to_string(), "Database does not exist");
+        assert_eq!(
+            Error::BatchTooLarge { size: 5, max: 4 }.to_string(),
+            "Batch too large: 5 records (max 4)"
+        );
+        assert_eq!(
+            Error::RecordMismatch { offset: 42 }.to_string(),
+            "Record at offset 42 does not match the index"
+        );
     }
 
     #[test]
@@ -136,5 +332,10 @@ mod tests {
         let io_err = io::Error::new(io::ErrorKind::NotFound, "file not found");
         let err: Error = io_err.into();
         assert!(matches!(err, Error::Io(_)));
+
+        let err: Error = io::Error::from(io::ErrorKind::StorageFull).into();
+        assert!(matches!(err, Error::DiskFull(_)));
+        let err: Error = io::Error::from(io::ErrorKind::WriteZero).into();
+        assert!(matches!(err, Error::DiskFull(_)));
     }
 }
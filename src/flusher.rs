@@ -0,0 +1,314 @@
+//! 后台写回线程
+//!
+//! 本模块实现 `Options::write_back_bytes`：追加的数据交给后台线程写入 WAL 文件，
+//! 前台的写操作不等待 `write` 系统调用。
+//!
+//! ## 设计
+//!
+//! `Wal` 把写缓冲区中的数据整块交给 [`Flusher`]，同时记下这块数据在文件中的起始偏移量。
+//! 后台线程持有写句柄的一个副本，按顺序把每一块写入文件，写完之后才把它从队列中移除。
+//! 因此任何时刻，文件末尾之后、写缓冲区之前的数据都能在队列中找到：
+//!
+//! ```text
+//!            push              write
+//! ┌─────┐  ───────▶ ┌───────┐ ───────▶ ┌──────────┐
+//! │ Wal │           │ queue │          │ wal.log  │
+//! └─────┘  ◀─────── └───────┘          └──────────┘
+//!           read（还在队列中的数据）
+//! ```
+//!
+//! ## 背压
+//!
+//! 队列中的字节数有上限。`push` 时超出上限就阻塞，直到后台线程写完足够的数据，
+//! 内存占用不会因为磁盘跟不上而无限增长。单独一块超过上限时，只要队列为空就可以放入，
+//! 不会永远阻塞。
+//!
+//! ## 错误
+//!
+//! 写入失败后线程停止，队列中的数据保留（仍然可以读取），之后的 `push` 和 `drain`
+//! 都返回这个错误。
+
+use crate::error::Result;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+/// 等待写入文件的一块数据
+struct Chunk {
+    /// 在文件中的起始偏移量
+    offset: u64,
+    /// 数据（后台线程写入时不持有锁，需要共享）
+    data: Arc<[u8]>,
+}
+
+/// 队列状态
+struct State {
+    /// 按偏移量顺序排列、首尾相接的数据块
+    chunks: VecDeque<Chunk>,
+    /// 队列中的总字节数
+    bytes: usize,
+    /// 写入失败时的错误（`io::Error` 不能复制，保存类型和描述）
+    error: Option<(io::ErrorKind, String)>,
+    /// 退出标志：队列写完后线程退出
+    stop: bool,
+}
+
+impl State {
+    /// 后台线程写入失败过时返回该错误
+    fn check(&self) -> Result<()> {
+        match &self.error {
+            Some((kind, message)) => Err(io::Error::new(*kind, message.clone()).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// 前台与后台线程共享的队列
+struct Shared {
+    state: Mutex<State>,
+    /// 队列变化（放入、写完、出错、退出）时通知所有等待者
+    changed: Condvar,
+}
+
+impl Shared {
+    /// 加锁；持锁期间不会 panic，锁中毒时直接取出状态继续使用
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 等待下一次队列变化
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.changed.wait(state).unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// 后台写回线程的句柄
+pub struct Flusher {
+    shared: Arc<Shared>,
+    /// 队列的字节数上限
+    capacity: usize,
+    /// 后台线程（drop 时 join）
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    /// 启动后台线程，把队列中的数据追加到 `file`
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Flusher)`: 线程已启动
+    /// - `Err(Error)`: 如果无法创建线程
+    pub fn spawn(file: File, capacity: usize) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                chunks: VecDeque::new(),
+                bytes: 0,
+                error: None,
+                stop: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            std::thread::Builder::new()
+                .name("kvslite-flusher".to_string())
+                .spawn(move || Self::run(file, shared))?
+        };
+
+        Ok(Flusher {
+            shared,
+            capacity,
+            thread: Some(thread),
+        })
+    }
+
+    /// 队列的字节数上限
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// 把从 `offset` 开始的一块数据放入队列，队列已满时阻塞
+    ///
+    /// `offset` 必须紧接在上一块数据之后。
+    pub fn push(&self, offset: u64, data: Vec<u8>) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.shared.lock();
+        loop {
+            state.check()?;
+            if state.chunks.is_empty() || state.bytes + data.len() <= self.capacity {
+                break;
+            }
+            state = self.shared.wait(state);
+        }
+
+        state.bytes += data.len();
+        state.chunks.push_back(Chunk {
+            offset,
+            data: data.into(),
+        });
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// 等待队列中的数据全部写入文件
+    pub fn drain(&self) -> Result<()> {
+        let mut state = self.shared.lock();
+        loop {
+            state.check()?;
+            if state.chunks.is_empty() {
+                return Ok(());
+            }
+            state = self.shared.wait(state);
+        }
+    }
+
+    /// 从队列中读取 `[offset, offset + len)`
+    ///
+    /// 调用方保证范围不超出队列末尾（之后的数据在 `Wal` 的写缓冲区中）。
+    ///
+    /// - `Some((from, bytes))`: `[from, offset + len)` 在队列中。通常 `from == offset`；
+    ///   后台线程写入失败后不会再有数据写完，范围开头已经写入文件时 `from` 是队首的偏移量，
+    ///   `[offset, from)` 由调用方从文件读取
+    /// - `None`: 范围已经写入文件，从文件读取
+    ///
+    /// 范围一部分已经写入、一部分还在队列中时，等待队首写完再判断。
+    /// 写入失败后队列中的数据保留，照常从队列读取：失败的那块可能只写进了文件的一部分。
+    pub fn read(&self, offset: u64, len: usize) -> Option<(u64, Vec<u8>)> {
+        let end = offset + len as u64;
+        let mut state = self.shared.lock();
+        let from = loop {
+            let front = state.chunks.front()?.offset;
+            if end <= front {
+                return None;
+            }
+            if offset >= front {
+                break offset;
+            }
+            if state.error.is_some() {
+                break front;
+            }
+            state = self.shared.wait(state);
+        };
+
+        // 范围可能跨越多个数据块
+        let len = (end - from) as usize;
+        let mut buf = Vec::with_capacity(len);
+        for chunk in &state.chunks {
+            let pos = from + buf.len() as u64;
+            if chunk.offset + chunk.data.len() as u64 <= pos {
+                continue;
+            }
+            let start = (pos - chunk.offset) as usize;
+            let take = (len - buf.len()).min(chunk.data.len() - start);
+            buf.extend_from_slice(&chunk.data[start..start + take]);
+            if buf.len() == len {
+                break;
+            }
+        }
+        Some((from, buf))
+    }
+
+    /// 线程主循环：依次写入队首的数据块，写完后移出队列
+    fn run(mut file: File, shared: Arc<Shared>) {
+        let mut state = shared.lock();
+        loop {
+            let data = match state.chunks.front() {
+                Some(chunk) => Arc::clone(&chunk.data),
+                None if state.stop => return,
+                None => {
+                    state = shared.wait(state);
+                    continue;
+                }
+            };
+
+            // 写入时不持有锁，前台可以继续放入和读取
+            drop(state);
+            let result = file.write_all(&data);
+            state = shared.lock();
+
+            match result {
+                Ok(()) => {
+                    state.chunks.pop_front();
+                    state.bytes -= data.len();
+                }
+                Err(e) => {
+                    eprintln!("Warning: background WAL write failed: {}", e);
+                    state.error = Some((e.kind(), e.to_string()));
+                    shared.changed.notify_all();
+                    return;
+                }
+            }
+            shared.changed.notify_all();
+        }
+    }
+}
+
+impl Drop for Flusher {
+    /// 通知线程在写完队列后退出，并等待它结束
+    fn drop(&mut self) {
+        self.shared.lock().stop = true;
+        self.shared.changed.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_push_read_and_drain() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("out");
+        let file = OpenOptions::new().create(true).append(true).open(&path).unwrap();
+
+        let flusher = Flusher::spawn(file, 8).unwrap();
+        flusher.push(0, b"hello ".to_vec()).unwrap();
+        // 超过上限时阻塞到前一块写完，不会死锁
+        flusher.push(6, b"world".to_vec()).unwrap();
+
+        // 还在队列中的数据可以读到，写完之后返回 None
+        if let Some(read) = flusher.read(6, 5) {
+            assert_eq!(read, (6, b"world".to_vec()));
+        }
+        flusher.drain().unwrap();
+        assert_eq!(flusher.read(0, 11), None);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+
+        // drop 时写完剩余的数据
+        flusher.push(11, b"!".to_vec()).unwrap();
+        drop(flusher);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world!");
+    }
+
+    #[test]
+    fn test_read_after_write_error() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("out");
+        std::fs::write(&path, b"on disk").unwrap();
+        // 只读打开的句柄：后台线程的第一次写入就会失败
+        let file = File::open(&path).unwrap();
+
+        let flusher = Flusher::spawn(file, 1024).unwrap();
+        flusher.push(7, b" queued".to_vec()).unwrap();
+        assert!(flusher.drain().is_err());
+        assert!(flusher.push(14, b"!".to_vec()).is_err());
+
+        // 已经确认的写入仍然可以从队列读到；开头已经在文件中的部分交给调用方
+        assert_eq!(flusher.read(7, 7), Some((7, b" queued".to_vec())));
+        assert_eq!(flusher.read(8, 3), Some((8, b"que".to_vec())));
+        assert_eq!(flusher.read(4, 10), Some((7, b" queued".to_vec())));
+        assert_eq!(flusher.read(0, 7), None);
+    }
+}
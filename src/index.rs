@@ -0,0 +1,536 @@
+//! 内存索引
+//!
+//! 本模块封装 key -> value 位置的内存索引。
+//!
+//! ## 为什么要封装 HashMap？
+//!
+//! 所有对索引的修改都经过 [`Index::insert`] / [`Index::remove`]，
+//! 这样可以顺带维护一些计数器（例如所有 key 的总字节数），
+//! 让 `Db::stats()` 保持 O(1) 的纯内存计算，而不必每次遍历整个索引。
+//!
+//! ## 内联 value
+//!
+//! 设置了 `inline_threshold` 时，不超过阈值的 value 直接保存在索引条目中，
+//! 读取时不需要访问 WAL。条目仍然保留 value 在 WAL 中的位置：
+//! 压缩、校验和 checkpoint 都只依赖位置，内联只是一份副本。
+//!
+//! ## 共享 value
+//!
+//! 开启 `Options::dedup_values` 压缩后，value 相同的多个 key 指向同一个位置，
+//! 除第一个 key 之外都由一条 REF 记录写入。[`Index::insert_ref`] 另外记下这条 REF
+//! 记录的偏移量，校验时据此找到 key 自己的记录。
+
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// Value 在 WAL 文件中的位置信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValuePos {
+    /// value 的起始偏移量（字节）
+    pub offset: u64,
+    /// value 的长度（字节）
+    pub len: usize,
+    /// 最近一次写入该 key 的序列号
+    pub seq: u64,
+}
+
+/// 墓碑：一个已删除 key 最近一次 DELETE 的信息
+///
+/// 压缩时按 `Options::tombstone_ttl` 决定是否把 DELETE 记录保留下来
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tombstone {
+    /// DELETE 记录的序列号
+    pub seq: u64,
+    /// DELETE 记录的写入时间（UNIX 毫秒），v1 记录没有
+    pub timestamp: Option<u64>,
+}
+
+/// 索引条目：value 位置，以及内联保存的小 value
+#[derive(Debug)]
+struct Entry {
+    pos: ValuePos,
+    /// 不超过 `inline_threshold` 的 value 的副本
+    inline: Option<Box<[u8]>>,
+}
+
+/// 内存索引：key -> value 位置
+#[derive(Debug, Default)]
+pub struct Index {
+    /// key -> 索引条目
+    map: HashMap<Vec<u8>, Entry>,
+    /// 所有 key 的总字节数
+    key_bytes: usize,
+    /// 所有 value 的总字节数（按索引中的长度，不论是否内联）
+    value_bytes: usize,
+    /// 长度不超过这个值的 value 内联保存（0 表示不内联）
+    inline_threshold: usize,
+    /// 所有内联 value 的总字节数
+    inline_bytes: usize,
+    /// 已删除的 key -> 墓碑（key 被重新写入时移除）
+    tombstones: HashMap<Vec<u8>, Tombstone>,
+    /// 带写入时间的墓碑的数量（只有它们可能在压缩时保留）
+    timed_tombstones: usize,
+    /// 带写入时间的墓碑的 key 的总字节数
+    timed_tombstone_key_bytes: usize,
+    /// 由 REF 记录写入的 key -> REF 记录的偏移量（key 被覆盖或删除时移除）
+    refs: HashMap<Vec<u8>, u64>,
+}
+
+impl Index {
+    /// 创建一个空索引（不内联 value）
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Index::default()
+    }
+
+    /// 创建一个空索引，长度不超过 `threshold` 的 value 内联保存（0 表示不内联）
+    pub fn with_inline_threshold(threshold: usize) -> Self {
+        Index {
+            inline_threshold: threshold,
+            ..Index::default()
+        }
+    }
+
+    /// 查找 key 对应的 value 位置
+    pub fn get(&self, key: &[u8]) -> Option<&ValuePos> {
+        self.map.get(key).map(|entry| &entry.pos)
+    }
+
+    /// 查找 key 内联保存的 value
+    pub fn get_inline(&self, key: &[u8]) -> Option<&[u8]> {
+        self.map.get(key)?.inline.as_deref()
+    }
+
+    /// key 是否存在
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// 插入或覆盖 key，返回旧的位置
+    ///
+    /// 只记录位置，不内联 value（value 不在内存中时使用）
+    pub fn insert(&mut self, key: Vec<u8>, pos: ValuePos) -> Option<ValuePos> {
+        self.insert_entry(key, Entry { pos, inline: None })
+    }
+
+    /// 插入或覆盖 key，`value` 不超过内联阈值时同时内联保存，返回旧的位置
+    pub fn insert_value(&mut self, key: Vec<u8>, pos: ValuePos, value: &[u8]) -> Option<ValuePos> {
+        let inline = self.inlines(value.len()).then(|| value.into());
+        self.insert_entry(key, Entry { pos, inline })
+    }
+
+    /// 插入或覆盖一个由 REF 记录写入的 key，返回旧的位置
+    ///
+    /// `pos` 指向被引用的 value，`ref_offset` 是 REF 记录本身的起始偏移量
+    pub fn insert_ref(&mut self, key: Vec<u8>, pos: ValuePos, ref_offset: u64) -> Option<ValuePos> {
+        let old = self.insert(key.clone(), pos);
+        self.refs.insert(key, ref_offset);
+        old
+    }
+
+    /// key 由 REF 记录写入时，返回 REF 记录的偏移量
+    pub fn ref_offset(&self, key: &[u8]) -> Option<u64> {
+        self.refs.get(key).copied()
+    }
+
+    /// 给已存在的 key 补上内联 value（从 WAL 读到 value 之后调用）
+    ///
+    /// value 超过阈值、长度与索引不符或已经内联时什么也不做
+    pub fn fill_inline(&mut self, key: &[u8], value: &[u8]) {
+        if !self.inlines(value.len()) {
+            return;
+        }
+        if let Some(entry) = self.map.get_mut(key) {
+            if entry.inline.is_none() && entry.pos.len == value.len() {
+                entry.inline = Some(value.into());
+                self.inline_bytes += value.len();
+            }
+        }
+    }
+
+    /// 移除 key，返回旧的位置
+    pub fn remove(&mut self, key: &[u8]) -> Option<ValuePos> {
+        let old = self.map.remove(key)?;
+        if !self.refs.is_empty() {
+            self.refs.remove(key);
+        }
+        self.key_bytes -= key.len();
+        Some(self.forget(old))
+    }
+
+    /// 删除 key 并记录墓碑，返回旧的位置
+    ///
+    /// key 不存在时同样记录墓碑：DELETE 记录已经写入 WAL，
+    /// 其他副本上可能还有这个 key
+    pub fn delete(&mut self, key: &[u8], tombstone: Tombstone) -> Option<ValuePos> {
+        if let Some(old) = self.tombstones.insert(key.to_vec(), tombstone) {
+            self.untrack_tombstone(key.len(), &old);
+        }
+        if tombstone.timestamp.is_some() {
+            self.timed_tombstones += 1;
+            self.timed_tombstone_key_bytes += key.len();
+        }
+        self.remove(key)
+    }
+
+    /// 遍历所有墓碑，顺序不确定
+    pub fn tombstones(&self) -> hash_map::Iter<'_, Vec<u8>, Tombstone> {
+        self.tombstones.iter()
+    }
+
+    /// 只保留 `keep` 返回 `true` 的墓碑，返回移除的数量
+    pub fn retain_tombstones<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&[u8], &Tombstone) -> bool,
+    {
+        let before = self.tombstones.len();
+        let (mut timed, mut timed_key_bytes) = (0, 0);
+        self.tombstones.retain(|key, tombstone| {
+            let kept = keep(key, tombstone);
+            if !kept && tombstone.timestamp.is_some() {
+                timed += 1;
+                timed_key_bytes += key.len();
+            }
+            kept
+        });
+        self.timed_tombstones -= timed;
+        self.timed_tombstone_key_bytes -= timed_key_bytes;
+        let removed = before - self.tombstones.len();
+        if removed > 0 {
+            self.tombstones.shrink_to_fit();
+        }
+        removed
+    }
+
+    /// 墓碑的数量
+    pub fn tombstone_count(&self) -> usize {
+        self.tombstones.len()
+    }
+
+    /// 带写入时间的墓碑的数量和它们的 key 的总字节数（O(1)，由删除和覆盖维护）
+    pub fn timed_tombstones(&self) -> (usize, usize) {
+        (self.timed_tombstones, self.timed_tombstone_key_bytes)
+    }
+
+    /// key 的数量
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// 遍历所有 (key, 位置)，顺序不确定
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &ValuePos)> {
+        self.map.iter().map(|(key, entry)| (key, &entry.pos))
+    }
+
+    /// 按字节序返回以 `prefix` 开头、严格大于 `after` 的前 `limit` 个 key
+    ///
+    /// 索引本身是无序的 HashMap，这里每次遍历一遍所有 key：先筛选出大于 `after`
+    /// 的 key，再只对前 `limit` 个排序，复杂度 O(n + limit·log(limit))。
+    pub fn keys_after(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>> {
+        if limit == 0 {
+            return Vec::new();
+        }
+
+        let mut keys: Vec<&Vec<u8>> = self
+            .map
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .filter(|key| after.is_none_or(|after| key.as_slice() > after))
+            .collect();
+
+        if keys.len() > limit {
+            keys.select_nth_unstable(limit - 1);
+            keys.truncate(limit);
+        }
+        keys.sort_unstable();
+
+        keys.into_iter().cloned().collect()
+    }
+
+    /// 按字节序返回所有以 `prefix` 开头的 key 及其位置
+    ///
+    /// 只借用 key，不复制，也不读取 value。
+    pub fn prefix_sorted(&self, prefix: &[u8]) -> Vec<(&[u8], ValuePos)> {
+        let mut entries: Vec<(&[u8], ValuePos)> = self
+            .map
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, entry)| (key.as_slice(), entry.pos))
+            .collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        entries
+    }
+
+    /// 按字节序返回 `[start, end)` 范围内的 key 及其位置
+    ///
+    /// 与 [`Index::prefix_sorted`] 一样遍历所有 key 后排序；`end` 为 `None` 表示没有上界。
+    /// `start >= end` 时返回空列表。
+    pub fn range_sorted(&self, start: &[u8], end: Option<&[u8]>) -> Vec<(&[u8], ValuePos)> {
+        if end.is_some_and(|end| start >= end) {
+            return Vec::new();
+        }
+
+        let mut entries: Vec<(&[u8], ValuePos)> = self
+            .map
+            .iter()
+            .filter(|(key, _)| {
+                key.as_slice() >= start && end.is_none_or(|end| key.as_slice() < end)
+            })
+            .map(|(key, entry)| (key.as_slice(), entry.pos))
+            .collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        entries
+    }
+
+    /// 估算索引占用的内存（字节）
+    ///
+    /// = 已分配的槽位数 × (每个槽位的大小 + 1 字节控制位) + 所有 key 的堆内存
+    ///
+    /// 槽位数使用 `capacity()` 而不是 `len()`，因此大量删除后的多余容量也会被计入，
+    /// 可以用 [`Index::shrink_to_fit`] 回收。这只是估算值，不包括分配器本身的开销。
+    /// 内联 value 的堆内存计入，墓碑不计入（数量见 [`Index::tombstone_count`]）。
+    pub fn memory_bytes(&self) -> usize {
+        let slot_size = size_of::<(Vec<u8>, Entry)>() + 1;
+        self.map.capacity() * slot_size + self.key_bytes + self.inline_bytes
+    }
+
+    /// 所有 key 与 value 的总字节数（O(1)，由插入和删除维护）
+    pub fn payload_bytes(&self) -> u64 {
+        (self.key_bytes + self.value_bytes) as u64
+    }
+
+    /// 预留至少能再容纳 `additional` 个 key 的容量，避免逐步扩容时反复 rehash
+    pub fn reserve(&mut self, additional: usize) {
+        self.map.reserve(additional);
+    }
+
+    /// 不重新分配时最多能容纳的 key 数量
+    pub fn capacity(&self) -> usize {
+        self.map.capacity()
+    }
+
+    /// 释放多余的容量
+    pub fn shrink_to_fit(&mut self) {
+        self.map.shrink_to_fit();
+        self.tombstones.shrink_to_fit();
+        self.refs.shrink_to_fit();
+    }
+
+    /// 长度为 `len` 的 value 是否内联保存
+    pub fn inlines(&self, len: usize) -> bool {
+        len <= self.inline_threshold && self.inline_threshold > 0
+    }
+
+    fn insert_entry(&mut self, key: Vec<u8>, entry: Entry) -> Option<ValuePos> {
+        if !self.tombstones.is_empty() {
+            if let Some(old) = self.tombstones.remove(&key) {
+                self.untrack_tombstone(key.len(), &old);
+            }
+        }
+        if !self.refs.is_empty() {
+            self.refs.remove(&key);
+        }
+        self.inline_bytes += entry.inline.as_ref().map_or(0, |value| value.len());
+        self.value_bytes += entry.pos.len;
+        let key_len = key.len();
+        match self.map.insert(key, entry) {
+            Some(old) => Some(self.forget(old)),
+            None => {
+                self.key_bytes += key_len;
+                None
+            }
+        }
+    }
+
+    /// 扣减被移除或替换的墓碑在带写入时间的墓碑计数中的部分
+    fn untrack_tombstone(&mut self, key_len: usize, tombstone: &Tombstone) {
+        if tombstone.timestamp.is_some() {
+            self.timed_tombstones -= 1;
+            self.timed_tombstone_key_bytes -= key_len;
+        }
+    }
+
+    /// 扣减被移除条目的 value 字节数和内联字节数，返回它的位置
+    fn forget(&mut self, entry: Entry) -> ValuePos {
+        self.value_bytes -= entry.pos.len;
+        self.inline_bytes -= entry.inline.map_or(0, |value| value.len());
+        entry.pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(offset: u64) -> ValuePos {
+        ValuePos {
+            offset,
+            len: 1,
+            seq: offset,
+        }
+    }
+
+    #[test]
+    fn test_key_bytes_accounting() {
+        let mut index = Index::new();
+        assert_eq!(index.key_bytes, 0);
+
+        index.insert(b"abc".to_vec(), pos(0));
+        index.insert(b"de".to_vec(), pos(1));
+        assert_eq!(index.key_bytes, 5);
+
+        // 覆盖已有 key 不改变总字节数
+        assert_eq!(index.insert(b"abc".to_vec(), pos(2)), Some(pos(0)));
+        assert_eq!(index.key_bytes, 5);
+
+        // 删除不存在的 key 不改变总字节数
+        assert_eq!(index.remove(b"missing"), None);
+        assert_eq!(index.key_bytes, 5);
+
+        assert_eq!(index.remove(b"abc"), Some(pos(2)));
+        assert_eq!(index.key_bytes, 2);
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.payload_bytes(), 3);
+    }
+
+    #[test]
+    fn test_refs() {
+        let mut index = Index::new();
+        index.insert(b"a".to_vec(), pos(1));
+        index.insert_ref(b"b".to_vec(), pos(1), 40);
+        index.insert_ref(b"c".to_vec(), pos(1), 60);
+        assert_eq!(index.get(b"b"), Some(&pos(1)));
+        assert_eq!(index.ref_offset(b"a"), None);
+        assert_eq!(index.ref_offset(b"b"), Some(40));
+
+        // 覆盖或删除之后不再是引用
+        index.insert(b"b".to_vec(), pos(2));
+        index.remove(b"c");
+        assert_eq!(index.ref_offset(b"b"), None);
+        assert_eq!(index.ref_offset(b"c"), None);
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let mut index = Index::new();
+        index.insert(b"a".to_vec(), pos(1));
+        let tombstone = Tombstone {
+            seq: 2,
+            timestamp: Some(100),
+        };
+
+        assert_eq!(index.delete(b"a", tombstone), Some(pos(1)));
+        assert_eq!(index.delete(b"missing", tombstone), None);
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.key_bytes, 0);
+        assert_eq!(index.tombstone_count(), 2);
+
+        assert_eq!(index.timed_tombstones(), (2, 8));
+
+        // 重新写入的 key 不再是墓碑
+        index.insert(b"a".to_vec(), pos(3));
+        assert_eq!(index.tombstone_count(), 1);
+        let keys: Vec<&Vec<u8>> = index.tombstones().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![&b"missing".to_vec()]);
+        assert_eq!(index.timed_tombstones(), (1, 7));
+
+        // 没有写入时间的墓碑（v1 记录）不计入；替换和清理时扣减
+        let untimed = Tombstone {
+            seq: 4,
+            timestamp: None,
+        };
+        index.delete(b"missing", untimed);
+        index.delete(b"b", tombstone);
+        assert_eq!(index.timed_tombstones(), (1, 1));
+        assert_eq!(index.retain_tombstones(|key, _| key != b"b"), 1);
+        assert_eq!(index.timed_tombstones(), (0, 0));
+        assert_eq!(index.tombstone_count(), 1);
+    }
+
+    #[test]
+    fn test_inline_values() {
+        let mut index = Index::with_inline_threshold(4);
+        index.insert_value(b"small".to_vec(), pos(0), b"1");
+        index.insert_value(b"large".to_vec(), pos(1), b"12345");
+        index.insert(b"later".to_vec(), pos(2));
+        assert_eq!(index.get_inline(b"small"), Some(&b"1"[..]));
+        assert_eq!(index.get_inline(b"large"), None);
+        assert_eq!(index.get_inline(b"later"), None);
+        assert_eq!(index.inline_bytes, 1);
+
+        // 从 WAL 读到之后补上；长度与索引不符的不补
+        index.fill_inline(b"later", b"22");
+        assert_eq!(index.get_inline(b"later"), None);
+        index.fill_inline(b"later", b"2");
+        assert_eq!(index.get_inline(b"later"), Some(&b"2"[..]));
+        assert_eq!(index.inline_bytes, 2);
+
+        // 覆盖和删除时扣减
+        index.insert(b"small".to_vec(), pos(3));
+        assert_eq!(index.get_inline(b"small"), None);
+        index.remove(b"later");
+        assert_eq!(index.inline_bytes, 0);
+
+        // 阈值为 0 时不内联
+        let mut index = Index::new();
+        index.insert_value(b"a".to_vec(), pos(0), b"1");
+        assert_eq!(index.get_inline(b"a"), None);
+    }
+
+    #[test]
+    fn test_keys_after() {
+        let mut index = Index::new();
+        for key in [b"d", b"a", b"c", b"e", b"b"] {
+            index.insert(key.to_vec(), pos(0));
+        }
+
+        assert_eq!(index.keys_after(b"", None, 2), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(
+            index.keys_after(b"", Some(b"b"), 10),
+            vec![b"c".to_vec(), b"d".to_vec(), b"e".to_vec()]
+        );
+        // after 不需要是已存在的 key
+        assert_eq!(index.keys_after(b"", Some(b"bb"), 1), vec![b"c".to_vec()]);
+        assert!(index.keys_after(b"", Some(b"e"), 10).is_empty());
+        assert!(index.keys_after(b"", None, 0).is_empty());
+    }
+
+    #[test]
+    fn test_prefix_sorted() {
+        let mut index = Index::new();
+        for (i, key) in [&b"user:2"[..], b"item:1", b"user:1", b"user", b"user:10"]
+            .iter()
+            .enumerate()
+        {
+            index.insert(key.to_vec(), pos(i as u64));
+        }
+
+        let keys: Vec<&[u8]> = index
+            .prefix_sorted(b"user:")
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![&b"user:1"[..], b"user:10", b"user:2"]);
+        assert_eq!(index.prefix_sorted(b"").len(), 5);
+        assert!(index.prefix_sorted(b"zzz").is_empty());
+    }
+
+    #[test]
+    fn test_shrink_to_fit_reduces_memory() {
+        let mut index = Index::new();
+        for i in 0..10_000u64 {
+            index.insert(i.to_le_bytes().to_vec(), pos(i));
+        }
+        for i in 0..9_990u64 {
+            index.remove(&i.to_le_bytes());
+        }
+
+        let before = index.memory_bytes();
+        index.shrink_to_fit();
+        let after = index.memory_bytes();
+
+        assert!(after < before);
+        assert!(after >= 10 * 8);
+    }
+}
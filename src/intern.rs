@@ -0,0 +1,99 @@
+//! 写入时的 value 驻留表
+//!
+//! 本模块实现 `Options::intern_small_values`：记住最近写入的小 value 在 WAL 中的位置，
+//! 之后 `put` 相同的 value 时写一条 REF 记录指向已有的那一份，而不是再追加一份副本。
+//!
+//! ## 设计
+//!
+//! 以 value 的内容为键（不是哈希，命中就是逐字节相同），值是 PUT 记录的位置。
+//! 条目数达到 [`INTERN_TABLE_ENTRIES`] 时整张表清空重新积累：
+//! 反复出现的 value 很快就会重新进入表中，不需要维护 LRU 顺序。
+//!
+//! ## 一致性
+//!
+//! WAL 只追加，已经写入的 PUT 记录在下一次重写（压缩、格式升级）之前一直在原位，
+//! 即使持有它的 key 之后被覆盖或删除。因此表中的位置只在 WAL 被重写时失效，
+//! `Db` 在每次替换 WAL 时清空整张表。
+
+use crate::codec::ValueRef;
+use std::collections::HashMap;
+
+/// 驻留表最多保存多少个不同的 value，达到后整张表清空
+pub const INTERN_TABLE_ENTRIES: usize = 4096;
+
+/// value 内容 -> 它在 WAL 中的一份副本
+#[derive(Debug, Default)]
+pub struct InternTable {
+    entries: HashMap<Vec<u8>, ValueRef>,
+}
+
+impl InternTable {
+    /// 创建一个空表
+    pub fn new() -> Self {
+        InternTable::default()
+    }
+
+    /// 查找与 `value` 相同的已写入副本
+    pub fn get(&self, value: &[u8]) -> Option<ValueRef> {
+        self.entries.get(value).copied()
+    }
+
+    /// 记录 `value` 在 WAL 中的位置（已有相同的 value 时保留旧位置）
+    pub fn insert(&mut self, value: &[u8], target: ValueRef) {
+        if self.entries.contains_key(value) {
+            return;
+        }
+        if self.entries.len() >= INTERN_TABLE_ENTRIES {
+            self.clear();
+        }
+        self.entries.insert(value.to_vec(), target);
+    }
+
+    /// 清空（WAL 被重写，所有位置都已失效）
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// 表中 value 的数量
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(offset: u64) -> ValueRef {
+        ValueRef {
+            record_offset: offset,
+            value_offset: offset + 20,
+            len: 32,
+        }
+    }
+
+    #[test]
+    fn test_insert_get_and_reset_when_full() {
+        let mut table = InternTable::new();
+        assert_eq!(table.get(b"flag"), None);
+
+        // 相同的 value 保留第一次的位置
+        table.insert(b"flag", target(0));
+        table.insert(b"flag", target(100));
+        assert_eq!(table.get(b"flag"), Some(target(0)));
+
+        // 填满之后再插入：整张表清空重新开始
+        for i in 1..INTERN_TABLE_ENTRIES {
+            table.insert(format!("value{}", i).as_bytes(), target(i as u64));
+        }
+        assert_eq!(table.len(), INTERN_TABLE_ENTRIES);
+        table.insert(b"new", target(1));
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.get(b"flag"), None);
+        assert_eq!(table.get(b"new"), Some(target(1)));
+
+        table.clear();
+        assert_eq!(table.len(), 0);
+    }
+}
@@ -51,15 +51,39 @@
 //! v0.1 有以下限制：
 //!
 //! - 所有 key 必须能放入内存
-//! - 不支持范围查询
+//! - 范围查询（[`Db::scan`]）需要对索引排序，开销与 key 的总数成正比
 //! - 不支持事务
-//! - 单线程写入（`&mut self` 语义）
+//! - 单线程写入（`&mut self` 语义），多线程共享请使用 [`SharedDb`]
 
+mod batch;
+mod cache;
 mod codec;
+mod cursor;
 mod db;
+#[cfg(unix)]
+mod direct_io;
 mod error;
+mod flusher;
+mod index;
+mod intern;
+mod lock;
+mod manifest;
+mod shared;
+mod snapshot;
+mod subscribe;
+mod syncer;
 mod wal;
 
 // 对外导出核心类型
-pub use db::{Db, Options};
+pub use batch::WriteBatch;
+pub use cache::{CacheResult, CacheStats};
+pub use codec::{Limits, Record, RecordHeader, RecordKind, ValueRef};
+pub use db::{
+    CompactProgress, CompactStats, CompactionStats, ConflictPolicy, Db, DbStats, DiffReport,
+    GetCost, HealthReport, KvPair, Options, OptionsBuilder, RangeIter, ValueReader, ValueWriter,
+    WriteErrorHook,
+};
 pub use error::{Error, Result};
+pub use shared::SharedDb;
+pub use subscribe::{ChangeEvent, ChangeKind, WatchId};
+pub use wal::{ReplayStats, ReplayedRecord, WalReader};
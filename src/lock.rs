@@ -0,0 +1,155 @@
+//! 数据库目录锁
+//!
+//! 两个可写的 `Db` 同时打开同一个目录时，各自的内存索引和 WAL 写入位置会互相覆盖，
+//! 数据很快就会损坏。打开时在目录中的 `LOCK` 文件上加一把独占的文件锁，
+//! 第二个打开者得到 `Error::AlreadyOpen`。
+//!
+//! ## 设计
+//!
+//! 使用操作系统的建议锁（Unix 上是 `flock`，Windows 上是 `LockFileEx`），
+//! 锁跟随文件句柄：`Db` 被 drop 或进程退出（包括崩溃）时自动释放，
+//! 不会留下需要人工清理的陈旧锁。`LOCK` 文件本身不包含任何内容，也不会被删除。
+//!
+//! 只读打开不加锁：只读的 follower 本来就是和可写的主库同时打开同一个目录。
+//!
+//! ## 重试
+//!
+//! 正在关闭的旧进程可能在新进程启动后几毫秒才释放锁。设置了超时时，
+//! 以指数退避（1ms 起，最长 100ms）反复尝试，直到成功或超时。
+
+use crate::error::{Error, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// 锁文件名
+pub const LOCK_FILENAME: &str = "LOCK";
+
+/// 第一次重试前的等待时间
+const INITIAL_BACKOFF: Duration = Duration::from_millis(1);
+
+/// 两次重试之间的最长等待时间
+const MAX_BACKOFF: Duration = Duration::from_millis(100);
+
+/// 持有中的目录锁，drop 时释放
+#[derive(Debug)]
+pub struct DirLock {
+    /// 加了锁的文件句柄（关闭即解锁）
+    file: File,
+}
+
+impl DirLock {
+    /// 获取目录 `dir` 的独占锁
+    ///
+    /// ## 参数
+    ///
+    /// - `dir`: 数据库目录（必须已经存在）
+    /// - `timeout`: 锁被占用时最多等待多久；`None` 表示不等待
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(DirLock)`: 已获得锁
+    /// - `Err(Error::AlreadyOpen)`: 锁被其他句柄占用，并且在超时之前没有释放
+    /// - `Err(Error)`: 如果无法创建或打开锁文件
+    pub fn acquire(dir: &Path, timeout: Option<Duration>) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILENAME))?;
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(DirLock { file }),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+
+            // 超时（或不等待）时放弃；最后一次等待不超过剩余时间
+            let remaining = deadline
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or_default();
+            if remaining.is_zero() {
+                return Err(Error::AlreadyOpen);
+            }
+            std::thread::sleep(backoff.min(remaining));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// `dir` 中的 `LOCK` 文件是否仍是加锁的那个文件
+    ///
+    /// 锁跟随文件句柄而不是路径：`LOCK` 被删除或替换后，另一个进程可以在新文件上加锁，
+    /// 两个可写的 `Db` 就会同时打开同一个目录。非 Unix 平台只检查文件是否存在。
+    pub fn is_held(&self, dir: &Path) -> bool {
+        let on_disk = match std::fs::metadata(dir.join(LOCK_FILENAME)) {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            self.file
+                .metadata()
+                .is_ok_and(|held| held.dev() == on_disk.dev() && held.ino() == on_disk.ino())
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = on_disk;
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_exclusive_and_released_on_drop() {
+        let dir = TempDir::new().unwrap();
+
+        let lock = DirLock::acquire(dir.path(), None).unwrap();
+        assert!(matches!(
+            DirLock::acquire(dir.path(), None),
+            Err(Error::AlreadyOpen)
+        ));
+
+        // 超时之前一直被占用：等待后仍然失败
+        let start = Instant::now();
+        let timeout = Duration::from_millis(20);
+        assert!(matches!(
+            DirLock::acquire(dir.path(), Some(timeout)),
+            Err(Error::AlreadyOpen)
+        ));
+        assert!(start.elapsed() >= timeout);
+
+        // 持有者在等待期间释放，重试成功
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(lock);
+        });
+        DirLock::acquire(dir.path(), Some(Duration::from_secs(5))).unwrap();
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn test_is_held() {
+        let dir = TempDir::new().unwrap();
+        let lock = DirLock::acquire(dir.path(), None).unwrap();
+        assert!(lock.is_held(dir.path()));
+
+        // LOCK 被删除后重新创建：新文件上的锁与这把锁无关
+        std::fs::remove_file(dir.path().join(LOCK_FILENAME)).unwrap();
+        assert!(!lock.is_held(dir.path()));
+        let other = DirLock::acquire(dir.path(), None).unwrap();
+        assert!(other.is_held(dir.path()));
+        #[cfg(unix)]
+        assert!(!lock.is_held(dir.path()));
+    }
+}
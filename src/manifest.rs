@@ -0,0 +1,405 @@
+//! Checkpoint（MANIFEST）文件
+//!
+//! 本模块负责把内存索引序列化到 `MANIFEST` 文件，使 `Db::open` 不必每次
+//! 都从头 replay 整个 WAL。
+//!
+//! ## 文件格式
+//!
+//! ```text
+//! +-------+---------+------------+----------+--------------+----------+-------+---------+-----+
+//! | magic | version | wal_offset | tail_crc | record_count | last_seq | count | entries | ... |
+//! +-------+---------+------------+----------+--------------+----------+-------+---------+-----+
+//!   4B      1B        8B           4B         8B             8B         8B      var
+//!
+//!     ... | tombstone_count (8B) | tombstones | crc32 (4B) |
+//!
+//! entry:     | key_len (4B) | key | value_offset (8B) | value_len (8B) | seq (8B) |
+//!              ref_offset (8B) |
+//! tombstone: | key_len (4B) | key | seq (8B) | timestamp (8B，0 表示没有) |
+//! ```
+//!
+//! - `magic`: 固定值 `KVSM`
+//! - `wal_offset`: 高水位，checkpoint 时 WAL 的写入位置。索引反映了此位置之前的所有记录
+//! - `tail_crc`: WAL 中恰好在高水位结束的那条记录的 CRC32 字段（即 `[wal_offset-4, wal_offset)`）
+//! - `record_count`: 高水位之前 WAL 中 PUT/DELETE 记录的条数（用于统计压缩丢弃的记录）
+//! - `last_seq`: checkpoint 时最后分配的写入序列号
+//! - `ref_offset`: key 由 REF 记录写入时（见 `Options::dedup_values`）是这条记录的偏移量，
+//!   否则为 0（偏移量 0 处不可能是 REF 记录：被引用的 PUT 总在它前面）
+//! - `tombstones`: 已删除 key 的墓碑（见 `Options::tombstone_ttl`）
+//! - `crc32`: 覆盖 `version..tombstones` 的 CRC32 校验和
+//!
+//! ## 打开流程
+//!
+//! 1. 读取 MANIFEST，验证 magic、版本和 CRC
+//! 2. 验证 WAL 仍然与 checkpoint 对应：文件长度 >= 高水位，且高水位前 4 字节等于 `tail_crc`
+//! 3. 用 MANIFEST 中的条目初始化索引，只 replay 高水位之后的记录
+//!
+//! 任何一步失败（文件缺失、半写入、WAL 被截断或重写）都会退回到完整 replay，
+//! 因此 MANIFEST 只是一个加速手段，丢失或损坏都不会影响正确性。
+//!
+//! ## 崩溃安全性
+//!
+//! 写入时先写 `MANIFEST.tmp` 并 fsync，再原子地 rename 为 `MANIFEST`。
+
+use crate::error::Result;
+use crate::wal::{sync_dir, WAL_FILENAME};
+use crc32fast::Hasher;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// MANIFEST 文件名
+const MANIFEST_FILENAME: &str = "MANIFEST";
+
+/// 写入 MANIFEST 时使用的临时文件名
+const MANIFEST_TMP_FILENAME: &str = "MANIFEST.tmp";
+
+/// Magic 字节：KVSM
+const MAGIC: [u8; 4] = *b"KVSM";
+
+/// 当前格式版本
+///
+/// 旧版本的 MANIFEST 会被当作无效文件忽略（退回完整 replay）
+const VERSION: u8 = 5;
+
+/// 固定头部大小：
+/// magic(4) + version(1) + wal_offset(8) + tail_crc(4) + record_count(8) + last_seq(8) + count(8)
+const HEADER_SIZE: usize = 41;
+
+/// 一条索引条目：(key, value 偏移量, value 长度, 序列号, REF 记录偏移量（0 表示没有）)
+pub type ManifestEntry = (Vec<u8>, u64, u64, u64, u64);
+
+/// 一个墓碑：(key, DELETE 的序列号, DELETE 的写入时间)
+pub type ManifestTombstone = (Vec<u8>, u64, Option<u64>);
+
+/// 一次 checkpoint 的内容
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// 高水位：索引反映了 WAL 中此位置之前的所有记录
+    pub wal_offset: u64,
+    /// WAL 中 `[wal_offset-4, wal_offset)` 的 4 个字节（最后一条记录的 CRC）
+    pub tail_crc: u32,
+    /// 高水位之前 WAL 中 PUT/DELETE 记录的条数
+    pub record_count: u64,
+    /// checkpoint 时最后分配的写入序列号
+    pub last_seq: u64,
+    /// 索引条目
+    pub entries: Vec<ManifestEntry>,
+    /// 墓碑
+    pub tombstones: Vec<ManifestTombstone>,
+}
+
+impl Manifest {
+    /// 读取 MANIFEST
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Some(Manifest))`: 文件存在且校验通过
+    /// - `Ok(None)`: 文件不存在，或内容不完整/损坏（调用方应退回完整 replay）
+    /// - `Err(Error)`: 其他 I/O 错误
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        let mut buf = Vec::new();
+        match File::open(dir.join(MANIFEST_FILENAME)) {
+            Ok(mut file) => {
+                file.read_to_end(&mut buf)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self::decode(&buf))
+    }
+
+    /// 原子地写入 MANIFEST（tmp + fsync + rename + fsync 目录）
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(MANIFEST_TMP_FILENAME);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        // MANIFEST 中保存着 key，权限与 WAL 保持一致（见 `Options::file_mode`）
+        if let Ok(metadata) = std::fs::metadata(dir.join(WAL_FILENAME)) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(&self.encode())?;
+        file.sync_data()?;
+        drop(file);
+
+        std::fs::rename(&tmp_path, dir.join(MANIFEST_FILENAME))?;
+        sync_dir(dir)?;
+
+        Ok(())
+    }
+
+    /// 删除 MANIFEST（不存在时忽略）
+    pub fn remove(dir: &Path) -> Result<()> {
+        match std::fs::remove_file(dir.join(MANIFEST_FILENAME)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// 检查 checkpoint 是否仍然对应给定的 WAL 文件
+    ///
+    /// WAL 被截断到高水位之前，或高水位处的记录 CRC 与 checkpoint 时不同
+    /// （WAL 被重写），都说明这个 MANIFEST 已经过期。
+    pub fn matches_wal(&self, wal_path: &Path) -> Result<bool> {
+        let mut file = match File::open(wal_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+
+        if file.metadata()?.len() < self.wal_offset {
+            return Ok(false);
+        }
+        if self.wal_offset == 0 {
+            return Ok(true);
+        }
+        if self.wal_offset < 4 {
+            return Ok(false);
+        }
+
+        let mut tail = [0u8; 4];
+        file.seek(SeekFrom::Start(self.wal_offset - 4))?;
+        file.read_exact(&mut tail)?;
+
+        Ok(u32::from_le_bytes(tail) == self.tail_crc)
+    }
+
+    /// 编码为字节数组
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(
+            HEADER_SIZE
+                + self
+                    .entries
+                    .iter()
+                    .map(|(key, _, _, _, _)| 4 + key.len() + 32)
+                    .sum::<usize>()
+                + 8
+                + self
+                    .tombstones
+                    .iter()
+                    .map(|(key, _, _)| 4 + key.len() + 16)
+                    .sum::<usize>()
+                + 4,
+        );
+
+        buf.extend_from_slice(&MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&self.wal_offset.to_le_bytes());
+        buf.extend_from_slice(&self.tail_crc.to_le_bytes());
+        buf.extend_from_slice(&self.record_count.to_le_bytes());
+        buf.extend_from_slice(&self.last_seq.to_le_bytes());
+        buf.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+
+        for (key, offset, len, seq, ref_offset) in &self.entries {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&offset.to_le_bytes());
+            buf.extend_from_slice(&len.to_le_bytes());
+            buf.extend_from_slice(&seq.to_le_bytes());
+            buf.extend_from_slice(&ref_offset.to_le_bytes());
+        }
+
+        buf.extend_from_slice(&(self.tombstones.len() as u64).to_le_bytes());
+        for (key, seq, timestamp) in &self.tombstones {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&seq.to_le_bytes());
+            buf.extend_from_slice(&timestamp.unwrap_or(0).to_le_bytes());
+        }
+
+        // CRC 覆盖 version..tombstones
+        let crc = {
+            let mut hasher = Hasher::new();
+            hasher.update(&buf[4..]);
+            hasher.finalize()
+        };
+        buf.extend_from_slice(&crc.to_le_bytes());
+
+        buf
+    }
+
+    /// 从字节数组解码，任何不一致都返回 `None`
+    pub fn decode(buf: &[u8]) -> Option<Manifest> {
+        if buf.len() < HEADER_SIZE + 4 || buf[..4] != MAGIC {
+            return None;
+        }
+
+        // 1. 验证 CRC
+        let (body, crc_bytes) = buf.split_at(buf.len() - 4);
+        let stored_crc = u32::from_le_bytes(crc_bytes.try_into().ok()?);
+        let computed_crc = {
+            let mut hasher = Hasher::new();
+            hasher.update(&body[4..]);
+            hasher.finalize()
+        };
+        if stored_crc != computed_crc {
+            return None;
+        }
+
+        // 2. 解析头部
+        let mut reader = SliceReader { buf: body, pos: 4 };
+        if reader.take(1)?[0] != VERSION {
+            return None;
+        }
+        let wal_offset = reader.u64()?;
+        let tail_crc = reader.u32()?;
+        let record_count = reader.u64()?;
+        let last_seq = reader.u64()?;
+        let count = reader.u64()?;
+
+        // 3. 解析条目（count 来自已校验的数据，但仍然不信任它来预分配）
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let key_len = reader.u32()? as usize;
+            let key = reader.take(key_len)?.to_vec();
+            let offset = reader.u64()?;
+            let len = reader.u64()?;
+            let seq = reader.u64()?;
+            let ref_offset = reader.u64()?;
+            entries.push((key, offset, len, seq, ref_offset));
+        }
+
+        // 4. 解析墓碑
+        let tombstone_count = reader.u64()?;
+        let mut tombstones = Vec::new();
+        for _ in 0..tombstone_count {
+            let key_len = reader.u32()? as usize;
+            let key = reader.take(key_len)?.to_vec();
+            let seq = reader.u64()?;
+            let timestamp = Some(reader.u64()?).filter(|&ts| ts != 0);
+            tombstones.push((key, seq, timestamp));
+        }
+
+        // 所有字节都应该被消费
+        if reader.pos != body.len() {
+            return None;
+        }
+
+        Some(Manifest {
+            wal_offset,
+            tail_crc,
+            record_count,
+            last_seq,
+            entries,
+            tombstones,
+        })
+    }
+}
+
+/// 带边界检查的字节读取器
+struct SliceReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let bytes = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sample() -> Manifest {
+        Manifest {
+            wal_offset: 128,
+            tail_crc: 0xDEADBEEF,
+            record_count: 7,
+            last_seq: 9,
+            entries: vec![
+                (b"key1".to_vec(), 22, 6, 3, 0),
+                (b"".to_vec(), 64, 0, 9, 0),
+                (b"dup".to_vec(), 22, 6, 4, 80),
+            ],
+            tombstones: vec![
+                (b"gone".to_vec(), 5, Some(1_700_000_000_000)),
+                (b"v1".to_vec(), 4, None),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(Manifest::load(dir.path()).unwrap(), None);
+
+        let manifest = sample();
+        manifest.store(dir.path()).unwrap();
+        assert_eq!(Manifest::load(dir.path()).unwrap(), Some(manifest));
+
+        Manifest::remove(dir.path()).unwrap();
+        Manifest::remove(dir.path()).unwrap();
+        assert_eq!(Manifest::load(dir.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_torn_manifest_is_ignored() {
+        let encoded = sample().encode();
+
+        // 截断在任意位置都应该被识别
+        for len in 0..encoded.len() {
+            assert_eq!(Manifest::decode(&encoded[..len]), None);
+        }
+
+        // 翻转任意一个字节都应该被识别
+        for i in 0..encoded.len() {
+            let mut corrupted = encoded.clone();
+            corrupted[i] ^= 0x01;
+            assert_eq!(Manifest::decode(&corrupted), None);
+        }
+    }
+
+    #[test]
+    fn test_matches_wal() {
+        let dir = TempDir::new().unwrap();
+        let wal_path = dir.path().join("wal.log");
+
+        let mut data = vec![0u8; 60];
+        data.extend_from_slice(&0xDEADBEEFu32.to_le_bytes());
+        std::fs::write(&wal_path, &data).unwrap();
+
+        let manifest = Manifest {
+            wal_offset: 64,
+            tail_crc: 0xDEADBEEF,
+            record_count: 0,
+            last_seq: 0,
+            entries: Vec::new(),
+            tombstones: Vec::new(),
+        };
+        assert!(manifest.matches_wal(&wal_path).unwrap());
+
+        // WAL 之后追加了新数据：仍然有效
+        data.extend_from_slice(b"more records");
+        std::fs::write(&wal_path, &data).unwrap();
+        assert!(manifest.matches_wal(&wal_path).unwrap());
+
+        // WAL 被重写：尾部 CRC 不同
+        data[62] ^= 0xFF;
+        std::fs::write(&wal_path, &data).unwrap();
+        assert!(!manifest.matches_wal(&wal_path).unwrap());
+
+        // WAL 被截断到高水位之前
+        std::fs::write(&wal_path, &data[..32]).unwrap();
+        assert!(!manifest.matches_wal(&wal_path).unwrap());
+    }
+}
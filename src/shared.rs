@@ -0,0 +1,188 @@
+//! 多线程共享的数据库句柄
+//!
+//! `Db` 的读写都需要 `&mut self`，多线程使用时需要包一层锁。
+//! 本模块的 [`SharedDb`] 就是 `Arc<Mutex<Db>>`，常用操作在内部加锁，
+//! 调用方不必到处写 `db.lock().unwrap().get(..)`。
+//!
+//! ## 锁的粒度
+//!
+//! 每个方法只在调用期间持有锁，返回时立即释放。需要在同一次加锁中完成的
+//! 组合操作（读-改-写）用 [`SharedDb::with_lock`]。
+//!
+//! 压缩是唯一的例外：[`SharedDb::compact`] 使用 [`Db::compact_concurrent`]，
+//! 只在快照和切换时短暂持有锁，其余时间其他线程照常读写。
+//!
+//! ## 锁中毒
+//!
+//! 持有锁的线程 panic 后锁会中毒。`Db` 的每个写操作都是先写 WAL 再更新索引，
+//! 中途 panic 时索引可能缺少一条已经提交的记录；此时压缩会按索引重写 WAL，
+//! 把这条记录永久丢掉。因此锁中毒后数据库也进入中毒状态（见 [`Db::is_poisoned`]）：
+//! 读操作照常进行，写操作和压缩返回 `Error::Poisoned`，重新打开（replay WAL）后恢复。
+
+use crate::db::{CompactStats, Db, DbStats, Options};
+use crate::error::Result;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// 可以在线程间克隆和共享的 [`Db`]
+///
+/// 克隆只复制引用计数，所有克隆指向同一个数据库。最后一个克隆被 drop 时数据库关闭。
+///
+/// ## 示例
+///
+/// ```no_run
+/// use kvslite::{Options, SharedDb};
+///
+/// let db = SharedDb::open("data/db1", Options::default()).unwrap();
+///
+/// let writer = db.clone();
+/// let handle = std::thread::spawn(move || writer.put(b"key", b"value").unwrap());
+/// handle.join().unwrap();
+///
+/// assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+/// ```
+#[derive(Clone)]
+pub struct SharedDb {
+    inner: Arc<Mutex<Db>>,
+}
+
+impl SharedDb {
+    /// 打开或创建数据库，参数与 [`Db::open`] 相同
+    pub fn open<P: AsRef<Path>>(path: P, opts: Options) -> Result<Self> {
+        Ok(SharedDb::new(Db::open(path, opts)?))
+    }
+
+    /// 包装一个已经打开的数据库
+    pub fn new(db: Db) -> Self {
+        SharedDb {
+            inner: Arc::new(Mutex::new(db)),
+        }
+    }
+
+    /// 见 [`Db::get`]
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lock().get(key)
+    }
+
+    /// 见 [`Db::put`]
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.lock().put(key, value)
+    }
+
+    /// 见 [`Db::delete`]
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.lock().delete(key)
+    }
+
+    /// 见 [`Db::stats`]
+    pub fn stats(&self) -> DbStats {
+        self.lock().stats()
+    }
+
+    /// 压缩 WAL，压缩期间其他线程可以继续读写，见 [`Db::compact_concurrent`]
+    pub fn compact(&self) -> Result<CompactStats> {
+        Db::compact_concurrent(&self.inner)
+    }
+
+    /// 持有锁执行 `f`，用于需要原子完成的组合操作
+    ///
+    /// `f` 执行期间其他线程的所有操作都会等待，不要在其中做与数据库无关的耗时工作。
+    ///
+    /// ## 示例
+    ///
+    /// ```no_run
+    /// use kvslite::{Options, SharedDb};
+    ///
+    /// let db = SharedDb::open("data/db1", Options::default()).unwrap();
+    ///
+    /// // 读-改-写：两个线程同时自增也不会丢失更新
+    /// db.with_lock(|db| {
+    ///     let n = db.get(b"counter")?.map_or(0, |v| v[0]);
+    ///     db.put(b"counter", &[n + 1])
+    /// })
+    /// .unwrap();
+    /// ```
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut Db) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Db> {
+        Db::lock_shared(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_shared_across_threads() {
+        let dir = TempDir::new().unwrap();
+        let opts = Options::builder().sync_on_write(false).build();
+        let db = SharedDb::open(dir.path(), opts).unwrap();
+
+        let handles: Vec<_> = (0..4u8)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50u8 {
+                        db.put(&[t, i], &[i]).unwrap();
+                        db.with_lock(|db| {
+                            let n = db.get(b"counter")?.map_or(0, |v| v[0]);
+                            db.put(b"counter", &[n + 1])
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(db.stats().key_count, 4 * 50 + 1);
+        assert_eq!(db.get(&[3, 49]).unwrap(), Some(vec![49]));
+        // 每次自增都在同一次加锁中完成，没有丢失更新
+        assert_eq!(db.get(b"counter").unwrap(), Some(vec![200]));
+
+        db.delete(&[0, 0]).unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.get(&[0, 0]).unwrap(), None);
+        assert_eq!(db.stats().key_count, 200);
+    }
+
+    #[test]
+    fn test_lock_poisoning_poisons_db() {
+        let dir = TempDir::new().unwrap();
+        let db = SharedDb::open(dir.path(), Options::default()).unwrap();
+        db.put(b"key1", b"value1").unwrap();
+
+        let panicking = db.clone();
+        let result = std::thread::spawn(move || {
+            panicking.with_lock(|db| {
+                db.put(b"key2", b"value2").unwrap();
+                panic!("panic while holding the lock");
+            })
+        })
+        .join();
+        assert!(result.is_err());
+
+        // 读照常进行，写和压缩被拒绝；清除中毒状态也无效
+        assert_eq!(db.get(b"key2").unwrap(), Some(b"value2".to_vec()));
+        assert!(matches!(db.put(b"key3", b"value3"), Err(Error::Poisoned)));
+        assert!(matches!(db.compact(), Err(Error::Poisoned)));
+        db.with_lock(|db| db.clear_poison());
+        assert!(db.with_lock(|db| db.is_poisoned()));
+        assert!(matches!(db.delete(b"key1"), Err(Error::Poisoned)));
+        drop(db);
+
+        // 重新打开后恢复
+        let db = SharedDb::open(dir.path(), Options::default()).unwrap();
+        db.put(b"key3", b"value3").unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(db.get(b"key2").unwrap(), Some(b"value2".to_vec()));
+    }
+}
@@ -0,0 +1,141 @@
+//! 单文件快照
+//!
+//! 本模块定义 [`Db::export_snapshot`](crate::Db::export_snapshot) 产生、
+//! [`Db::import_snapshot`](crate::Db::import_snapshot) 读取的文件格式：
+//! 把整个数据库打包成一个可以直接拷贝、上传的文件，导入时不需要 replay。
+//!
+//! ## 文件格式
+//!
+//! ```text
+//! +-----+----------+--------+
+//! | WAL | MANIFEST | footer |
+//! +-----+----------+--------+
+//!
+//! footer: | wal_len (8B) | manifest_len (8B) | version (1B) | magic (4B) |
+//! ```
+//!
+//! - WAL：与压缩后的 `wal.log` 逐字节相同（历史下限标记 + 按 key 排序的 PUT + 保留的墓碑）
+//! - MANIFEST：与 checkpoint 写出的 `MANIFEST` 格式相同，高水位就是 WAL 的末尾
+//! - `magic`: 固定值 `KVSS`，放在文件最末尾，从尾部就能识别文件类型
+//!
+//! ## 导入
+//!
+//! 1. 从文件末尾读取 footer，验证 magic、版本和各部分长度之和
+//! 2. 读取并校验 MANIFEST（自带 CRC），它的高水位必须等于 `wal_len`
+//! 3. 把 WAL 部分原样写成 `wal.log`，MANIFEST 写成 `MANIFEST`
+//! 4. 照常 `open`：MANIFEST 与 WAL 吻合，索引直接从 MANIFEST 构建，不 replay 任何记录
+
+use crate::error::{Error, Result};
+use std::io::{Read, Seek, SeekFrom};
+
+/// Magic 字节：KVSS
+const MAGIC: [u8; 4] = *b"KVSS";
+
+/// 当前格式版本
+const VERSION: u8 = 1;
+
+/// 导入时 WAL 部分先写入这个临时文件，完整后再 rename 为 `wal.log`
+pub const IMPORT_TMP_FILENAME: &str = "wal.log.import";
+
+/// footer 大小：wal_len(8) + manifest_len(8) + version(1) + magic(4)
+pub const FOOTER_SIZE: usize = 21;
+
+/// 快照末尾的 footer，记录各部分的长度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    /// WAL 部分的字节数（从文件开头算起）
+    pub wal_len: u64,
+    /// 紧跟在 WAL 之后的 MANIFEST 的字节数
+    pub manifest_len: u64,
+}
+
+impl Footer {
+    /// 编码为字节数组
+    pub fn encode(&self) -> [u8; FOOTER_SIZE] {
+        let mut buf = [0u8; FOOTER_SIZE];
+        buf[..8].copy_from_slice(&self.wal_len.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.manifest_len.to_le_bytes());
+        buf[16] = VERSION;
+        buf[17..].copy_from_slice(&MAGIC);
+        buf
+    }
+
+    /// 从快照末尾读取并验证 footer
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Footer)`: magic、版本正确，且 WAL + MANIFEST + footer 恰好是整个文件
+    /// - `Err(Error::UnsupportedVersion)`: 由更新版本的 kvslite 导出
+    /// - `Err(Error::Io(InvalidData))`: 不是快照文件，或者文件被截断
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Footer> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        if file_len < FOOTER_SIZE as u64 {
+            return Err(invalid("file is too short"));
+        }
+
+        let mut buf = [0u8; FOOTER_SIZE];
+        reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        reader.read_exact(&mut buf)?;
+        if buf[17..] != MAGIC {
+            return Err(invalid("missing snapshot magic"));
+        }
+        if buf[16] != VERSION {
+            return Err(Error::UnsupportedVersion(buf[16]));
+        }
+
+        let footer = Footer {
+            wal_len: u64::from_le_bytes(buf[..8].try_into().expect("8 bytes")),
+            manifest_len: u64::from_le_bytes(buf[8..16].try_into().expect("8 bytes")),
+        };
+        let expected = footer
+            .wal_len
+            .checked_add(footer.manifest_len)
+            .and_then(|len| len.checked_add(FOOTER_SIZE as u64));
+        if expected != Some(file_len) {
+            return Err(invalid("section lengths do not match the file size"));
+        }
+        Ok(footer)
+    }
+}
+
+/// 快照内容不合法
+pub fn invalid(msg: &str) -> Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid snapshot: {}", msg))
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_footer_roundtrip_and_validation() {
+        let footer = Footer {
+            wal_len: 3,
+            manifest_len: 2,
+        };
+        let mut file = b"walmf".to_vec();
+        file.extend_from_slice(&footer.encode());
+        assert_eq!(Footer::read(&mut Cursor::new(&file)).unwrap(), footer);
+
+        // 截断：长度之和对不上
+        let truncated = file[1..].to_vec();
+        assert!(matches!(Footer::read(&mut Cursor::new(truncated)), Err(Error::Io(_))));
+
+        // 更新的版本
+        let mut newer = file.clone();
+        newer[file.len() - 5] = VERSION + 1;
+        assert!(matches!(
+            Footer::read(&mut Cursor::new(newer)),
+            Err(Error::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+
+        // 不是快照文件
+        assert!(Footer::read(&mut Cursor::new(b"short".to_vec())).is_err());
+        let mut other = file;
+        other.pop();
+        other.push(b'X');
+        assert!(matches!(Footer::read(&mut Cursor::new(other)), Err(Error::Io(_))));
+    }
+}
@@ -0,0 +1,207 @@
+//! 变更通知
+//!
+//! 本模块实现 [`Db::subscribe`](crate::Db::subscribe)：每次写入提交后，
+//! 向所有订阅者广播一个 [`ChangeEvent`]；以及 [`Db::watch`](crate::Db::watch)：
+//! 只关心某一个 key 时，直接用新的 value 调用注册的回调。
+//!
+//! ## 设计
+//!
+//! 订阅者就是 `std::sync::mpsc` 的接收端，`Db` 保存对应的发送端列表。
+//! 写操作都需要 `&mut self`，广播天然是串行的；只有 `subscribe` 需要在
+//! `&self` 下追加发送端，所以列表放在 `Mutex` 中。
+//!
+//! 接收端被 drop 后，下一次广播时 `send` 失败，对应的发送端随即从列表中移除。
+//! 没有订阅者时广播只是一次加锁和判空，不会复制 key。
+//!
+//! ## 时机
+//!
+//! 事件在 WAL 追加成功、索引更新之后才发送，订阅者永远看不到没有提交的写入。
+//! 通道是无界的，订阅者处理不过来时事件在通道中堆积，不会阻塞写入。
+//!
+//! 监视回调在同样的时机、在写入线程上同步执行，回调耗时会直接计入写入的延迟。
+//! 删除一个本来就不存在的 key 不是变更，不调用回调。
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+/// 变更的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// 写入（覆盖）了一个 key
+    Put,
+    /// 删除了一个 key
+    Delete,
+    /// WAL 被压缩（`key` 为空，`seq` 是压缩时的最新序列号）
+    Compact,
+}
+
+/// 一次已提交的变更
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// 变更的类型
+    pub kind: ChangeKind,
+    /// 被修改的 key（`Compact` 时为空）
+    pub key: Vec<u8>,
+    /// 这次写入的序列号
+    pub seq: u64,
+}
+
+/// [`Db::watch`](crate::Db::watch) 返回的句柄，交给 [`Db::unwatch`](crate::Db::unwatch)
+/// 取消监视
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchId(u64);
+
+/// 监视回调：参数是 key 的新 value，删除时为 `None`
+type WatchCallback = Box<dyn Fn(Option<&[u8]>) + Send>;
+
+/// key -> 监视它的回调
+#[derive(Default)]
+struct Watchers {
+    /// 下一个句柄的编号
+    next_id: u64,
+    by_key: HashMap<Vec<u8>, Vec<(WatchId, WatchCallback)>>,
+}
+
+/// 订阅者列表
+#[derive(Default)]
+pub struct Subscribers {
+    senders: Mutex<Vec<Sender<ChangeEvent>>>,
+    watchers: Mutex<Watchers>,
+}
+
+impl Subscribers {
+    /// 新增一个订阅者，返回它的接收端
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.lock().push(tx);
+        rx
+    }
+
+    /// 向所有订阅者广播一个事件，移除已经断开的订阅者
+    pub fn notify(&self, kind: ChangeKind, key: &[u8], seq: u64) {
+        let mut senders = self.lock();
+        if senders.is_empty() {
+            return;
+        }
+
+        let event = ChangeEvent {
+            kind,
+            key: key.to_vec(),
+            seq,
+        };
+        senders.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// 注册一个只监视 `key` 的回调
+    pub fn watch<F>(&self, key: &[u8], f: F) -> WatchId
+    where
+        F: Fn(Option<&[u8]>) + Send + 'static,
+    {
+        let mut watchers = self.lock_watchers();
+        let id = WatchId(watchers.next_id);
+        watchers.next_id += 1;
+        watchers.by_key.entry(key.to_vec()).or_default().push((id, Box::new(f)));
+        id
+    }
+
+    /// 移除一个回调，返回它是否存在
+    pub fn unwatch(&self, id: WatchId) -> bool {
+        let mut watchers = self.lock_watchers();
+        let Some(key) = watchers
+            .by_key
+            .iter()
+            .find(|(_, callbacks)| callbacks.iter().any(|(watch, _)| *watch == id))
+            .map(|(key, _)| key.clone())
+        else {
+            return false;
+        };
+
+        let callbacks = watchers.by_key.get_mut(&key).expect("key was just found");
+        callbacks.retain(|(watch, _)| *watch != id);
+        if callbacks.is_empty() {
+            watchers.by_key.remove(&key);
+        }
+        true
+    }
+
+    /// `key` 是否有回调在监视
+    pub fn is_watched(&self, key: &[u8]) -> bool {
+        self.lock_watchers().by_key.contains_key(key)
+    }
+
+    /// 按注册顺序调用监视 `key` 的回调，`value` 为 `None` 表示删除
+    pub fn fire_watchers(&self, key: &[u8], value: Option<&[u8]>) {
+        let watchers = self.lock_watchers();
+        for (_, callback) in watchers.by_key.get(key).into_iter().flatten() {
+            callback(value);
+        }
+    }
+
+    /// 当前的订阅者数量（包括已断开、尚未被移除的）
+    #[cfg(test)]
+    pub fn senders_len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// 加锁；广播不会在持锁时 panic，锁中毒时直接取出列表继续使用
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Sender<ChangeEvent>>> {
+        self.senders.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 加锁；回调 panic 时锁会中毒，同样直接取出继续使用
+    fn lock_watchers(&self) -> std::sync::MutexGuard<'_, Watchers> {
+        self.watchers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_and_prune() {
+        let subscribers = Subscribers::default();
+        // 没有订阅者时什么也不做
+        subscribers.notify(ChangeKind::Put, b"a", 1);
+
+        let first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+        subscribers.notify(ChangeKind::Put, b"a", 2);
+        assert_eq!(first.try_recv().unwrap().seq, 2);
+        assert_eq!(second.try_recv().unwrap().key, b"a");
+
+        // 断开的订阅者在下一次广播时被移除
+        drop(second);
+        subscribers.notify(ChangeKind::Delete, b"a", 3);
+        assert_eq!(subscribers.senders_len(), 1);
+        assert_eq!(first.try_recv().unwrap().kind, ChangeKind::Delete);
+        assert!(first.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_watch_and_unwatch() {
+        use std::sync::Arc;
+
+        let subscribers = Subscribers::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let id = subscribers.watch(b"a", move |value| {
+            log.lock().unwrap().push(value.map(<[u8]>::to_vec));
+        });
+        assert!(subscribers.is_watched(b"a"));
+        assert!(!subscribers.is_watched(b"b"));
+
+        // 只有被监视的 key 调用回调
+        subscribers.fire_watchers(b"a", Some(b"1"));
+        subscribers.fire_watchers(b"b", Some(b"2"));
+        subscribers.fire_watchers(b"a", None);
+        assert_eq!(*seen.lock().unwrap(), vec![Some(b"1".to_vec()), None]);
+
+        assert!(subscribers.unwatch(id));
+        assert!(!subscribers.unwatch(id));
+        assert!(!subscribers.is_watched(b"a"));
+        subscribers.fire_watchers(b"a", Some(b"3"));
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+}
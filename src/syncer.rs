@@ -0,0 +1,155 @@
+//! 后台 fsync 线程
+//!
+//! 本模块实现 `Options::flush_interval`：按固定间隔在后台线程中 fsync WAL。
+//!
+//! ## 设计
+//!
+//! `Db` 的所有操作都需要 `&mut self`，后台线程无法持有 `Db` 本身。
+//! 线程只持有一个 [`SyncHandle`]（WAL 写句柄的共享副本），
+//! fsync 时只锁住这个句柄，不会阻塞前台的读写：
+//!
+//! ```text
+//! ┌────────────┐  write   ┌──────────┐
+//! │     Db     │ ───────▶ │ wal.log  │
+//! └────────────┘          └──────────┘
+//!                              ▲
+//! ┌────────────┐  fsync        │
+//! │   Syncer   │ ──────────────┘  (SyncHandle)
+//! └────────────┘
+//! ```
+//!
+//! ## 错误
+//!
+//! fsync 失败时线程不退出，把错误记录在 `SyncHandle` 中，下次写操作时由
+//! `Db` 取走并返回（与前台 fsync 失败一样让数据库进入中毒状态）。
+//!
+//! ## 关闭
+//!
+//! `Syncer` 被 drop 时通知线程退出（不需要等到下一个间隔），
+//! 等待线程结束后再执行最后一次 fsync。
+
+use crate::error::Result;
+use crate::wal::SyncHandle;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// 后台 fsync 线程的句柄
+pub struct Syncer {
+    /// 要 fsync 的 WAL 句柄
+    handle: SyncHandle,
+    /// 退出标志，配合条件变量在等待期间唤醒线程
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    /// 后台线程（drop 时 join）
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    /// 启动后台线程，每隔 `interval` fsync 一次
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(Syncer)`: 线程已启动
+    /// - `Err(Error)`: 如果无法创建线程
+    pub fn spawn(handle: SyncHandle, interval: Duration) -> Result<Self> {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+
+        let thread = {
+            let handle = handle.clone();
+            let stop = Arc::clone(&stop);
+            std::thread::Builder::new()
+                .name("kvslite-syncer".to_string())
+                .spawn(move || Self::run(handle, interval, stop))?
+        };
+
+        Ok(Syncer {
+            handle,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// 线程主循环：等待一个间隔（或退出通知），然后 fsync
+    fn run(handle: SyncHandle, interval: Duration, stop: Arc<(Mutex<bool>, Condvar)>) {
+        let (lock, cvar) = &*stop;
+        let mut stopped = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut deadline = Instant::now() + interval;
+
+        loop {
+            // 1. 等到截止时间（条件变量可能被虚假唤醒）
+            let now = Instant::now();
+            if !*stopped && now < deadline {
+                stopped = match cvar.wait_timeout(stopped, deadline - now) {
+                    Ok((guard, _)) => guard,
+                    Err(e) => e.into_inner().0,
+                };
+                continue;
+            }
+            if *stopped {
+                return;
+            }
+
+            // 2. fsync 时不持有退出标志的锁，drop 不会被慢速磁盘阻塞太久
+            drop(stopped);
+            if let Err(e) = handle.sync_data() {
+                handle.set_error(e);
+            }
+            deadline = Instant::now() + interval;
+            stopped = lock.lock().unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Drop for Syncer {
+    /// 通知线程退出并等待它结束，然后执行最后一次 fsync
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.stop;
+        *lock.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        cvar.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = self.handle.sync_data();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Record;
+    use crate::wal::{Wal, WalOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_drop_stops_thread_promptly() {
+        let dir = TempDir::new().unwrap();
+        let (mut wal, _, _) = Wal::open(dir.path(), &WalOptions::default()).unwrap();
+
+        // 间隔很长，drop 也不需要等到下一次 fsync
+        let syncer = Syncer::spawn(wal.sync_handle(), Duration::from_secs(3600)).unwrap();
+        let r = Record::put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        wal.append(&r, false).unwrap();
+
+        let start = Instant::now();
+        drop(syncer);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_syncs_periodically() {
+        let dir = TempDir::new().unwrap();
+        let (mut wal, _, _) = Wal::open(dir.path(), &WalOptions::default()).unwrap();
+
+        let syncer = Syncer::spawn(wal.sync_handle(), Duration::from_millis(5)).unwrap();
+        let r = Record::put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        for _ in 0..10 {
+            wal.append(&r, false).unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        drop(syncer);
+
+        let (_, replayed, _) = Wal::open(dir.path(), &WalOptions::default()).unwrap();
+        assert_eq!(replayed.len(), 10);
+    }
+}
@@ -1,11 +1,11 @@
-/// 集成测试
-///
-/// 测试 kvslite 的完整功能，包括：
-/// - 基本操作（put/get/delete）
-/// - 崩溃恢复
-/// - 边界条件
-
-use kvslite::{Db, Options};
+//! 集成测试
+//!
+//! 测试 kvslite 的完整功能，包括：
+//! - 基本操作（put/get/delete）
+//! - 崩溃恢复
+//! - 边界条件
+
+use kvslite::{Db, DbStats, Error, Options};
 use tempfile::TempDir;
 
 #[test]
@@ -145,9 +145,7 @@ fn test_binary_data() {
 #[test]
 fn test_no_sync_mode() {
     let dir = TempDir::new().unwrap();
-    let opts = Options {
-        sync_on_write: false,
-    };
+    let opts = Options::builder().sync_on_write(false).build();
     let mut db = Db::open(dir.path(), opts).unwrap();
 
     db.put(b"fast_key", b"fast_value").unwrap();
@@ -177,3 +175,28 @@ fn test_stats() {
     let stats = db.stats();
     assert_eq!(stats.key_count, 1);
 }
+
+#[test]
+fn test_forward_compatible_api() {
+    let dir = TempDir::new().unwrap();
+
+    // Options 只能通过构造器或修改默认值来设置
+    let mut opts = Options::builder().sync_on_write(false).build();
+    opts.read_only = false;
+    let mut db = Db::open(dir.path(), opts).unwrap();
+
+    // Error 需要通配分支
+    let large_key = vec![0u8; 64 * 1024];
+    let message = match db.put(&large_key, b"value") {
+        Err(Error::KeyTooLarge { max, .. }) => format!("key limit {}", max),
+        Err(e) => e.to_string(),
+        Ok(()) => panic!("oversized key accepted"),
+    };
+    assert!(message.starts_with("key limit"));
+
+    // DbStats 可以从默认值构造（例如在测试中模拟）
+    let mut stats = DbStats::default();
+    stats.key_count = 3;
+    assert_eq!(stats.key_count, 3);
+    assert_eq!(db.stats().key_count, 0);
+}